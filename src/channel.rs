@@ -0,0 +1,1569 @@
+use elements::bitcoin::Amount;
+#[cfg(test)]
+use elements::hashes::Hash;
+use elements::hashes::sha256;
+use elements::schnorr::XOnlyPublicKey;
+#[cfg(test)]
+use elements::secp256k1_zkp::{Message, SECP256K1, SecretKey};
+use elements::secp256k1_zkp::{PublicKey, ecdsa};
+use elements::{Address, AssetId, LockTime, OutPoint, Transaction, confidential};
+use serde::{Deserialize, Serialize};
+use simplicityhl::CompiledProgram;
+
+use crate::FEE_AMOUNT;
+use crate::error::Error;
+use crate::keys::ChannelKeys;
+#[cfg(test)]
+use crate::script::{HtlcSpend, PtlcSpend};
+use crate::script::{
+    ScriptInspection, build_htlc_script, build_new_commitment_script, build_ptlc_script,
+    derive_address, inspect_scripts,
+};
+#[cfg(test)]
+use crate::sign::{
+    AdaptorSignature, anyprevout_sighash, complete_adaptor, derive_ptlc_key,
+    extract_adaptor_secret, finalize_htlc_spend_transaction, finalize_ptlc_spend_transaction,
+    find_completed_signature, sign_adaptor, sign_anyprevout_input, verify_adaptor,
+};
+use crate::sign::{
+    FeeInput, SignedUpdate, derive_ptlc_pubkey, derive_settlement_pubkey,
+    finalize_setup_spending_transaction, sign_fee_input, sign_settlement_transaction,
+    sign_setup_spending_inputs, sign_setup_spending_transaction, sign_update_transaction,
+};
+use crate::transaction::{
+    IssuedAsset, SpliceContribution, bind_commitment_inputs, build_cooperative_close_transaction,
+    build_settlement_transaction, build_setup_address, build_setup_spending_update_transaction,
+    build_splice_transaction, build_update_transaction, check_state, policy_asset,
+};
+#[cfg(test)]
+use crate::transaction::{build_htlc_success_transaction, build_htlc_timeout_transaction};
+
+/// One of the two parties of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Party {
+    /// The party that opens and funds the channel.
+    Alice,
+    /// The party that accepts the channel.
+    Bob,
+}
+
+impl Party {
+    /// The other party of the channel.
+    pub(crate) fn counterparty(self) -> Party {
+        match self {
+            Party::Alice => Party::Bob,
+            Party::Bob => Party::Alice,
+        }
+    }
+}
+
+/// Order our and the counterparty's values as `(alice, bob)`.
+pub(crate) fn by_party<T>(local_party: Party, local: T, remote: T) -> (T, T) {
+    match local_party {
+        Party::Alice => (local, remote),
+        Party::Bob => (remote, local),
+    }
+}
+
+/// Check that `funding_amount` covers the fees reserved by a channel.
+pub(crate) fn check_funding_amount(funding_amount: Amount) -> Result<(), Error> {
+    if funding_amount <= Amount::from_sat(2 * FEE_AMOUNT) {
+        return Err(Error::Channel(format!(
+            "funding amount of {} does not cover the channel fees",
+            funding_amount
+        )));
+    }
+
+    Ok(())
+}
+
+/// A Hash Time Locked Contract offered by one party to the other.
+///
+/// Each pending HTLC gets its own output in the `Settlement Transaction`, which
+/// the receiver can claim with the preimage of `payment_hash`, and the offerer
+/// can take back from block height `cltv_expiry` on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Htlc {
+    /// The party that offers the payment.
+    pub(crate) offerer: Party,
+    /// The amount of the payment, including the fee of the transaction that
+    /// spends its output.
+    pub(crate) amount: Amount,
+    /// The SHA256 hash of the payment preimage.
+    pub(crate) payment_hash: sha256::Hash,
+    /// The block height from which the offerer can take the payment back.
+    pub(crate) cltv_expiry: u32,
+}
+
+impl Htlc {
+    /// The party that is paid if the preimage is revealed.
+    pub(crate) fn receiver(&self) -> Party {
+        self.offerer.counterparty()
+    }
+}
+
+/// A Point Time Locked Contract offered by one party to the other.
+///
+/// Like an [`Htlc`], each pending PTLC gets its own output in the `Settlement
+/// Transaction`, but it is locked to the `payment_point` `T = t·G` through an
+/// adaptor signature rather than to a hash in the script. Claiming it reveals
+/// the payment secret `t` to the offerer only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Ptlc {
+    /// The party that offers the payment.
+    pub(crate) offerer: Party,
+    /// The amount of the payment, including the fee of the transaction that
+    /// spends its output.
+    pub(crate) amount: Amount,
+    /// The point of the payment secret.
+    pub(crate) payment_point: PublicKey,
+    /// The block height from which the offerer can take the payment back.
+    pub(crate) cltv_expiry: u32,
+}
+
+impl Ptlc {
+    /// The party that is paid if the payment secret is revealed.
+    pub(crate) fn receiver(&self) -> Party {
+        self.offerer.counterparty()
+    }
+}
+
+/// Build the commitment program for `state` of the channel between both
+/// parties update keys and settlement basepoints.
+pub(crate) fn build_commitment_program(
+    alice_update_pk: XOnlyPublicKey,
+    bob_update_pk: XOnlyPublicKey,
+    alice_settlement_basepoint: &PublicKey,
+    bob_settlement_basepoint: &PublicKey,
+    state: u32,
+) -> Result<CompiledProgram, Error> {
+    let settlement_pk = |basepoint| {
+        derive_settlement_pubkey(basepoint, state as u64)
+            .x_only_public_key()
+            .0
+    };
+
+    build_new_commitment_script(
+        alice_update_pk,
+        bob_update_pk,
+        settlement_pk(alice_settlement_basepoint),
+        settlement_pk(bob_settlement_basepoint),
+        state as u64 + 1,
+    )
+}
+
+/// A splice signed by both parties, whose transaction is not confirmed yet.
+///
+/// Until it confirms, the channel keeps its setup output, state and latest
+/// update, which remain valid should the splice never confirm.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PendingSplice {
+    /// The `Splice Transaction`, with its old setup input signed by both parties.
+    pub(crate) transaction: Transaction,
+    /// The amount locked in the new setup output.
+    pub(crate) funding_amount: Amount,
+    /// Alice's balance once the splice confirms.
+    pub(crate) alice_balance: Amount,
+    /// Bob's balance once the splice confirms.
+    pub(crate) bob_balance: Amount,
+    /// The `Update Transaction` for the first state after the splice.
+    pub(crate) update: SignedUpdate,
+    /// The `Settlement Transaction` for the first state after the splice.
+    pub(crate) settlement: Transaction,
+    /// The counterparty's signatures that close the channel at the first state
+    /// after the splice.
+    pub(crate) close_sigs: CloseSignatures,
+}
+
+/// The counterparty's signatures that let a party close the channel at the
+/// latest state with its own keys only.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CloseSignatures {
+    /// The update key signatures of the `Update Transaction` that spends the
+    /// setup outputs, one per input.
+    pub(crate) closing_update: Vec<ecdsa::Signature>,
+}
+
+impl PendingSplice {
+    /// The new setup [`OutPoint`] created by the splice.
+    pub(crate) fn setup_outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid: self.transaction.txid(),
+            vout: 0,
+        }
+    }
+}
+
+/// An ELTOO channel between Alice and Bob.
+///
+/// Only the latest state is kept: a new update and its settlement replace the
+/// previous ones, since older states can always be overridden on-chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Channel {
+    /// Whether the channel lives on Liquid V1 or on Liquid TestnetV1.
+    pub(crate) is_mainnet: bool,
+    /// The [`OutPoint`] that funds the `Setup Address`.
+    pub(crate) setup_outpoint: OutPoint,
+    /// The amount locked in the `Setup Address`.
+    pub(crate) funding_amount: Amount,
+    /// Alice's update key.
+    pub(crate) alice_update_pk: XOnlyPublicKey,
+    /// Bob's update key.
+    pub(crate) bob_update_pk: XOnlyPublicKey,
+    /// Alice's settlement basepoint, from which her settlement key for each
+    /// state is derived.
+    pub(crate) alice_settlement_basepoint: PublicKey,
+    /// Bob's settlement basepoint.
+    pub(crate) bob_settlement_basepoint: PublicKey,
+    /// Alice's settlement payout address.
+    pub(crate) alice_address: Address,
+    /// Bob's settlement payout address.
+    pub(crate) bob_address: Address,
+    /// The index of the channel in the BIP32 derivation of its keys.
+    pub(crate) channel_index: u32,
+    /// Where our seed is kept. The seed itself is never stored alongside
+    /// the channel.
+    pub(crate) key_reference: String,
+    /// Which party we are.
+    pub(crate) local_party: Party,
+    /// The node key of the counterparty, if the channel was opened with a peer.
+    pub(crate) peer_id: Option<PublicKey>,
+    /// The latest state number.
+    pub(crate) state: u32,
+    /// Alice's balance at the latest state.
+    pub(crate) alice_balance: Amount,
+    /// Bob's balance at the latest state.
+    pub(crate) bob_balance: Amount,
+    /// The issued assets carried by the channel, with their balances at the
+    /// latest state.
+    pub(crate) issued_assets: Vec<IssuedAsset>,
+    /// The HTLCs pending at the latest state.
+    pub(crate) htlcs: Vec<Htlc>,
+    /// The PTLCs pending at the latest state.
+    pub(crate) ptlcs: Vec<Ptlc>,
+    /// The `Update Transaction` for the latest state.
+    pub(crate) latest_update: Option<SignedUpdate>,
+    /// The `Settlement Transaction` for the latest state.
+    pub(crate) latest_settlement: Option<Transaction>,
+    /// The counterparty's signatures that close the channel at the latest state.
+    pub(crate) close_sigs: CloseSignatures,
+    /// The splice waiting to confirm, if any.
+    pub(crate) pending_splice: Option<PendingSplice>,
+}
+
+impl Channel {
+    /// Create a new [`Channel`] at state zero, funded by Alice, who
+    /// holds the whole capacity.
+    ///
+    /// Fails if the funding amount does not cover the fees of a close.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        is_mainnet: bool,
+        setup_outpoint: OutPoint,
+        funding_amount: Amount,
+        alice_update_pk: XOnlyPublicKey,
+        bob_update_pk: XOnlyPublicKey,
+        alice_settlement_basepoint: PublicKey,
+        bob_settlement_basepoint: PublicKey,
+        alice_address: Address,
+        bob_address: Address,
+        channel_index: u32,
+        key_reference: String,
+        local_party: Party,
+    ) -> Result<Self, Error> {
+        check_funding_amount(funding_amount)?;
+
+        let mut channel = Channel {
+            is_mainnet,
+            setup_outpoint,
+            funding_amount,
+            alice_update_pk,
+            bob_update_pk,
+            alice_settlement_basepoint,
+            bob_settlement_basepoint,
+            alice_address,
+            bob_address,
+            channel_index,
+            key_reference,
+            local_party,
+            peer_id: None,
+            state: 0,
+            alice_balance: Amount::ZERO,
+            bob_balance: Amount::ZERO,
+            issued_assets: Vec::new(),
+            htlcs: Vec::new(),
+            ptlcs: Vec::new(),
+            latest_update: None,
+            latest_settlement: None,
+            close_sigs: CloseSignatures::default(),
+            pending_splice: None,
+        };
+        channel.alice_balance = channel.capacity();
+
+        Ok(channel)
+    }
+
+    /// The amount that can be split between both parties.
+    ///
+    /// Two fees are reserved from the funding amount: one for the update that
+    /// spends the setup output, and one for the settlement. An update that
+    /// overrides a stale one pays its fee from a separate fee input.
+    pub(crate) fn capacity(&self) -> Amount {
+        self.funding_amount - Amount::from_sat(2 * FEE_AMOUNT)
+    }
+
+    /// The amount held by every commitment output of the channel.
+    pub(crate) fn commitment_amount(&self) -> Amount {
+        self.funding_amount - Amount::from_sat(FEE_AMOUNT)
+    }
+
+    /// Carry `amount` of the issued `asset`, locked in the `Setup Address` at
+    /// `setup_outpoint`, in the channel. Alice funds it, so she holds all of it.
+    ///
+    /// Assets can only be added before the first update, since every signed
+    /// transaction spends one input per asset.
+    pub(crate) fn add_issued_asset(
+        &mut self,
+        asset: AssetId,
+        setup_outpoint: OutPoint,
+        amount: Amount,
+    ) -> Result<(), Error> {
+        if self.state != 0 || self.latest_update.is_some() {
+            return Err(Error::Channel(
+                "issued assets can only be added before the first update".into(),
+            ));
+        }
+        if asset == policy_asset(self.is_mainnet) {
+            return Err(Error::Channel(
+                "the policy asset already funds the channel".into(),
+            ));
+        }
+        if amount == Amount::ZERO {
+            return Err(Error::Channel(format!("no amount of asset {}", asset)));
+        }
+        let outpoints = std::iter::once(self.setup_outpoint).chain(
+            self.issued_assets
+                .iter()
+                .map(|issued| issued.setup_outpoint),
+        );
+        for outpoint in outpoints {
+            if outpoint == setup_outpoint {
+                return Err(Error::Channel(format!(
+                    "setup output {} is already part of the channel",
+                    setup_outpoint
+                )));
+            }
+        }
+        if self.issued_asset(asset).is_some() {
+            return Err(Error::Channel(format!(
+                "asset {} is already part of the channel",
+                asset
+            )));
+        }
+
+        self.issued_assets.push(IssuedAsset {
+            asset,
+            setup_outpoint,
+            alice_balance: amount,
+            bob_balance: Amount::ZERO,
+        });
+
+        Ok(())
+    }
+
+    /// Credit Bob with his `contribution` to the funding amount of a dual-funded
+    /// channel. Alice still pays every fee, out of her own contribution.
+    ///
+    /// Like issued assets, contributions can only be added before the first update.
+    pub(crate) fn add_bob_contribution(&mut self, contribution: Amount) -> Result<(), Error> {
+        if self.state != 0 || self.latest_update.is_some() || self.bob_balance != Amount::ZERO {
+            return Err(Error::Channel(
+                "contributions can only be added before the first update".into(),
+            ));
+        }
+        self.alice_balance = self
+            .alice_balance
+            .checked_sub(contribution)
+            .ok_or_else(|| {
+                Error::Channel(format!(
+                    "Alice's contribution does not cover the channel fees and Bob's {}",
+                    contribution
+                ))
+            })?;
+        self.bob_balance = contribution;
+
+        Ok(())
+    }
+
+    /// The issued `asset` carried by the channel, with its balances at the
+    /// latest state.
+    pub(crate) fn issued_asset(&self, asset: AssetId) -> Option<&IssuedAsset> {
+        self.issued_assets
+            .iter()
+            .find(|issued| issued.asset == asset)
+    }
+
+    /// The channel identifier, derived from the setup [`OutPoint`].
+    pub(crate) fn id(&self) -> String {
+        format!("{}_{}", self.setup_outpoint.txid, self.setup_outpoint.vout)
+    }
+
+    /// Move the channel to the state of `update` with the given balances,
+    /// issued asset balances and pending HTLCs and PTLCs, replacing the previous
+    /// update and settlement, and the counterparty's `close_sigs`.
+    ///
+    /// Returns `false`, leaving the channel untouched, if `update` is not newer
+    /// than the current state. Until the first update is signed, the current
+    /// state 0 is not signed either, so an update for state 0 is newer.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn advance(
+        &mut self,
+        update: SignedUpdate,
+        settlement: Transaction,
+        close_sigs: CloseSignatures,
+        alice_balance: Amount,
+        bob_balance: Amount,
+        issued_assets: Vec<IssuedAsset>,
+        htlcs: Vec<Htlc>,
+        ptlcs: Vec<Ptlc>,
+    ) -> bool {
+        let is_newer = match self.latest_update {
+            Some(_) => update.state > self.state,
+            None => update.state >= self.state,
+        };
+        if !is_newer {
+            return false;
+        }
+
+        self.state = update.state;
+        self.alice_balance = alice_balance;
+        self.bob_balance = bob_balance;
+        self.issued_assets = issued_assets;
+        self.htlcs = htlcs;
+        self.ptlcs = ptlcs;
+        self.latest_update = Some(update);
+        self.latest_settlement = Some(settlement);
+        self.close_sigs = close_sigs;
+
+        true
+    }
+
+    /// Move the channel to a new state with the given balances, signing the
+    /// `Update Transaction` and `Settlement Transaction` for it. Issued asset
+    /// balances are left as they are.
+    pub(crate) fn update(
+        &mut self,
+        alice_balance: Amount,
+        bob_balance: Amount,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<(), Error> {
+        self.update_with_payments(
+            alice_balance,
+            bob_balance,
+            self.issued_assets.clone(),
+            Vec::new(),
+            Vec::new(),
+            alice_keys,
+            bob_keys,
+        )
+    }
+
+    /// Move the channel to a new state with the given balances, `issued_assets`
+    /// balances and pending `htlcs` and `ptlcs`, signing the `Update Transaction`
+    /// and `Settlement Transaction` for it.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn update_with_payments(
+        &mut self,
+        alice_balance: Amount,
+        bob_balance: Amount,
+        issued_assets: Vec<IssuedAsset>,
+        htlcs: Vec<Htlc>,
+        ptlcs: Vec<Ptlc>,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<(), Error> {
+        self.sign_state(
+            self.state + 1,
+            alice_balance,
+            bob_balance,
+            issued_assets,
+            htlcs,
+            ptlcs,
+            alice_keys,
+            bob_keys,
+        )
+    }
+
+    /// Sign state 0, which pays back the initial balances, before the funding
+    /// transaction is broadcast.
+    ///
+    /// Its update spends the setup output like any other, so it refunds each
+    /// party's contribution if the counterparty disappears, and every later
+    /// update outranks it.
+    pub(crate) fn sign_initial_state(
+        &mut self,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<(), Error> {
+        if self.state != 0 || self.latest_update.is_some() {
+            return Err(Error::Channel("the initial state is already signed".into()));
+        }
+
+        self.sign_state(
+            0,
+            self.alice_balance,
+            self.bob_balance,
+            self.issued_assets.clone(),
+            Vec::new(),
+            Vec::new(),
+            alice_keys,
+            bob_keys,
+        )
+    }
+
+    /// Move the channel to `state`, signing its `Update Transaction` and
+    /// `Settlement Transaction` with both parties keys.
+    #[allow(clippy::too_many_arguments)]
+    fn sign_state(
+        &mut self,
+        state: u32,
+        alice_balance: Amount,
+        bob_balance: Amount,
+        issued_assets: Vec<IssuedAsset>,
+        htlcs: Vec<Htlc>,
+        ptlcs: Vec<Ptlc>,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<(), Error> {
+        self.check_allocation(alice_balance, bob_balance, &issued_assets, &htlcs, &ptlcs)?;
+
+        let update = self.build_update(state)?;
+        let signed_update = sign_update_transaction(
+            &update,
+            self.commitment_amount(),
+            &self.issued_assets,
+            &alice_keys.update_sk,
+            &bob_keys.update_sk,
+            self.is_mainnet,
+        )?;
+
+        let settlement = self.sign_settlement_for_state(
+            state,
+            (alice_balance, bob_balance),
+            &issued_assets,
+            &htlcs,
+            &ptlcs,
+            OutPoint::default(),
+            self.commitment_amount(),
+            alice_keys,
+            bob_keys,
+        )?;
+        let (_, remote_keys) = by_party(self.local_party, bob_keys, alice_keys);
+        let close_sigs = self.sign_close(state, remote_keys)?;
+
+        self.advance(
+            signed_update,
+            settlement,
+            close_sigs,
+            alice_balance,
+            bob_balance,
+            issued_assets,
+            htlcs,
+            ptlcs,
+        );
+
+        Ok(())
+    }
+
+    /// Check that `funding` created the setup output of the channel, holding
+    /// exactly the funding amount of L-BTC at the `Setup Address`.
+    pub(crate) fn check_funding_transaction(&self, funding: &Transaction) -> Result<(), Error> {
+        let setup_address =
+            build_setup_address(self.alice_update_pk, self.bob_update_pk, self.is_mainnet)?;
+        let setup_output = (funding.txid() == self.setup_outpoint.txid)
+            .then(|| funding.output.get(self.setup_outpoint.vout as usize))
+            .flatten();
+        let funded = setup_output.is_some_and(|output| {
+            output.script_pubkey == setup_address.script_pubkey()
+                && output.asset.explicit() == Some(policy_asset(self.is_mainnet))
+                && output.value.explicit() == Some(self.funding_amount.to_sat())
+        });
+        if !funded {
+            return Err(Error::Channel(format!(
+                "setup output {} does not hold {} at {}",
+                self.setup_outpoint, self.funding_amount, setup_address
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check that `alice_balance` and `bob_balance` add up to the capacity.
+    pub(crate) fn check_balances(
+        &self,
+        alice_balance: Amount,
+        bob_balance: Amount,
+    ) -> Result<(), Error> {
+        self.check_allocation(alice_balance, bob_balance, &self.issued_assets, &[], &[])
+    }
+
+    /// Check that `alice_balance`, `bob_balance` and the `htlcs` and `ptlcs`
+    /// amounts add up to the capacity, and that every payment can pay for its
+    /// own spend.
+    ///
+    /// The `issued_assets` must be the ones of the channel, in the same order,
+    /// with balances that add up to the amount the channel holds of each.
+    pub(crate) fn check_allocation(
+        &self,
+        alice_balance: Amount,
+        bob_balance: Amount,
+        issued_assets: &[IssuedAsset],
+        htlcs: &[Htlc],
+        ptlcs: &[Ptlc],
+    ) -> Result<(), Error> {
+        if self.pending_splice.is_some() {
+            return Err(Error::Channel(
+                "the channel cannot be updated until its splice confirms".into(),
+            ));
+        }
+        if issued_assets.len() != self.issued_assets.len() {
+            return Err(Error::Channel(format!(
+                "expected balances for {} issued assets, got {}",
+                self.issued_assets.len(),
+                issued_assets.len()
+            )));
+        }
+        for (issued, current) in issued_assets.iter().zip(&self.issued_assets) {
+            if issued.asset != current.asset || issued.setup_outpoint != current.setup_outpoint {
+                return Err(Error::Channel(format!(
+                    "asset {} is not carried by the channel at this position",
+                    issued.asset
+                )));
+            }
+            if issued.alice_balance.checked_add(issued.bob_balance) != Some(current.amount()) {
+                return Err(Error::Channel(format!(
+                    "balances of asset {} must add up to {}",
+                    issued.asset,
+                    current.amount()
+                )));
+            }
+        }
+
+        let payments = htlcs
+            .iter()
+            .map(|htlc| (htlc.amount, htlc.cltv_expiry))
+            .chain(ptlcs.iter().map(|ptlc| (ptlc.amount, ptlc.cltv_expiry)));
+
+        let mut total = alice_balance.checked_add(bob_balance);
+        for (amount, cltv_expiry) in payments {
+            if amount <= Amount::from_sat(FEE_AMOUNT) {
+                return Err(Error::Channel(format!(
+                    "payment of {} does not cover the fee of its spend",
+                    amount
+                )));
+            }
+            if LockTime::from_height(cltv_expiry).is_err() {
+                return Err(Error::Channel(format!(
+                    "payment expiry {} is not a block height",
+                    cltv_expiry
+                )));
+            }
+            total = total.and_then(|total| total.checked_add(amount));
+        }
+
+        if total != Some(self.capacity()) {
+            return Err(Error::Channel(format!(
+                "balances and payments must add up to the channel capacity of {}",
+                self.capacity()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The update key of `party`.
+    pub(crate) fn update_pk(&self, party: Party) -> XOnlyPublicKey {
+        match party {
+            Party::Alice => self.alice_update_pk,
+            Party::Bob => self.bob_update_pk,
+        }
+    }
+
+    /// The settlement payout address of `party`.
+    pub(crate) fn address(&self, party: Party) -> &Address {
+        match party {
+            Party::Alice => &self.alice_address,
+            Party::Bob => &self.bob_address,
+        }
+    }
+
+    /// The settlement key of `party` for `state`, derived from its basepoint.
+    pub(crate) fn settlement_pk(&self, party: Party, state: u32) -> XOnlyPublicKey {
+        let basepoint = match party {
+            Party::Alice => &self.alice_settlement_basepoint,
+            Party::Bob => &self.bob_settlement_basepoint,
+        };
+
+        derive_settlement_pubkey(basepoint, state as u64)
+            .x_only_public_key()
+            .0
+    }
+
+    /// Build the unsigned `Update Transaction` for `state`.
+    ///
+    /// It is signed to spend a commitment output of the channel, overriding a
+    /// stale update, with its fee paid by a separate fee input. The update that
+    /// spends the setup outputs is built by [`Channel::build_closing_update`].
+    pub(crate) fn build_update(&self, state: u32) -> Result<Transaction, Error> {
+        check_state(state)?;
+        let commitment_address = derive_address(&self.commitment_program(state)?, self.is_mainnet);
+
+        Ok(build_update_transaction(
+            OutPoint::default(),
+            self.commitment_amount(),
+            &commitment_address,
+            state,
+            &self.issued_assets,
+            self.is_mainnet,
+        ))
+    }
+
+    /// Build the unsigned `Settlement Transaction` for `state` paying `(alice, bob)`
+    /// balances and the balances of `issued_assets`, and locking each of `htlcs`
+    /// and then `ptlcs` in its own output, from the commitment output at
+    /// `commitment_prevout` of `input_amount`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn build_settlement(
+        &self,
+        state: u32,
+        balances: (Amount, Amount),
+        issued_assets: &[IssuedAsset],
+        htlcs: &[Htlc],
+        ptlcs: &[Ptlc],
+        commitment_prevout: OutPoint,
+        input_amount: Amount,
+    ) -> Result<Transaction, Error> {
+        let mut payment_outputs = Vec::with_capacity(htlcs.len() + ptlcs.len());
+        for htlc in htlcs {
+            let program = self.htlc_program(state, htlc)?;
+            payment_outputs.push((derive_address(&program, self.is_mainnet), htlc.amount));
+        }
+        for ptlc in ptlcs {
+            let program = self.ptlc_program(state, ptlc)?;
+            payment_outputs.push((derive_address(&program, self.is_mainnet), ptlc.amount));
+        }
+
+        Ok(build_settlement_transaction(
+            commitment_prevout,
+            input_amount,
+            &self.alice_address,
+            balances.0,
+            &self.bob_address,
+            balances.1,
+            issued_assets,
+            &payment_outputs,
+            self.is_mainnet,
+        ))
+    }
+
+    /// Build the program of the output that locks `htlc` in the
+    /// `Settlement Transaction` for `state`.
+    ///
+    /// Both paths are signed with the settlement keys of `state`, so an HTLC
+    /// spend signature cannot be replayed against another state's outputs.
+    pub(crate) fn htlc_program(&self, state: u32, htlc: &Htlc) -> Result<CompiledProgram, Error> {
+        build_htlc_script(
+            self.settlement_pk(htlc.receiver(), state),
+            self.settlement_pk(htlc.offerer, state),
+            htlc.payment_hash,
+            htlc.cltv_expiry,
+        )
+    }
+
+    /// The [`OutPoint`] of the output that locks the pending HTLC at `index`
+    /// in the confirmed `Settlement Transaction` for the latest state.
+    ///
+    /// HTLC outputs follow the balance outputs, including those of issued
+    /// assets, in the order of the HTLCs.
+    #[cfg(test)]
+    pub(crate) fn htlc_outpoint(
+        &self,
+        settlement: &Transaction,
+        index: usize,
+    ) -> Result<OutPoint, Error> {
+        if index >= self.htlcs.len() {
+            return Err(Error::Channel(format!(
+                "no pending HTLC at index {}",
+                index
+            )));
+        }
+
+        Ok(OutPoint {
+            txid: settlement.txid(),
+            vout: (self.balance_outputs() + index) as u32,
+        })
+    }
+
+    /// The number of balance outputs in the `Settlement Transaction` for the
+    /// latest state, issued assets included. Empty balances get no output.
+    #[cfg(test)]
+    fn balance_outputs(&self) -> usize {
+        let asset_balances = self
+            .issued_assets
+            .iter()
+            .flat_map(|issued| [issued.alice_balance, issued.bob_balance]);
+
+        [self.alice_balance, self.bob_balance]
+            .into_iter()
+            .chain(asset_balances)
+            .filter(|balance| *balance > Amount::ZERO)
+            .count()
+    }
+
+    /// Build and sign the `HTLC Success Transaction` that claims the pending
+    /// `htlc` at `htlc_prevout` with its `preimage`, paying its receiver.
+    #[cfg(test)]
+    pub(crate) fn build_signed_htlc_success(
+        &self,
+        htlc: &Htlc,
+        htlc_prevout: OutPoint,
+        preimage: [u8; 32],
+        receiver_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        if sha256::Hash::hash(&preimage) != htlc.payment_hash {
+            return Err(Error::Channel(format!(
+                "preimage does not match the payment hash {}",
+                htlc.payment_hash
+            )));
+        }
+
+        let transaction = build_htlc_success_transaction(
+            htlc_prevout,
+            htlc.amount,
+            self.address(htlc.receiver()),
+            self.is_mainnet,
+        );
+        let signature = sign_anyprevout_input(
+            &transaction,
+            htlc.amount,
+            &receiver_keys.settlement_sk(self.state),
+            self.is_mainnet,
+        );
+
+        finalize_htlc_spend_transaction(
+            &transaction,
+            &self.htlc_program(self.state, htlc)?,
+            &HtlcSpend::Success {
+                preimage,
+                signature,
+            },
+        )
+    }
+
+    /// Build and sign the `HTLC Timeout Transaction` that refunds the pending
+    /// `htlc` at `htlc_prevout` to its offerer once it has expired.
+    #[cfg(test)]
+    pub(crate) fn build_signed_htlc_timeout(
+        &self,
+        htlc: &Htlc,
+        htlc_prevout: OutPoint,
+        offerer_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        let transaction = build_htlc_timeout_transaction(
+            htlc_prevout,
+            htlc.amount,
+            self.address(htlc.offerer),
+            htlc.cltv_expiry,
+            self.is_mainnet,
+        );
+        let signature = sign_anyprevout_input(
+            &transaction,
+            htlc.amount,
+            &offerer_keys.settlement_sk(self.state),
+            self.is_mainnet,
+        );
+
+        finalize_htlc_spend_transaction(
+            &transaction,
+            &self.htlc_program(self.state, htlc)?,
+            &HtlcSpend::Timeout { signature },
+        )
+    }
+
+    /// Build the program of the output that locks `ptlc` in the
+    /// `Settlement Transaction` for `state`.
+    pub(crate) fn ptlc_program(&self, state: u32, ptlc: &Ptlc) -> Result<CompiledProgram, Error> {
+        build_ptlc_script(
+            self.settlement_pk(ptlc.receiver(), state),
+            derive_ptlc_pubkey(
+                &self.settlement_pk(ptlc.offerer, state),
+                &ptlc.payment_point,
+            )?,
+            ptlc.cltv_expiry,
+        )
+    }
+
+    /// The [`OutPoint`] of the output that locks the pending PTLC at `index`
+    /// in the confirmed `Settlement Transaction` for the latest state.
+    ///
+    /// PTLC outputs follow the HTLC outputs, in the order of the PTLCs.
+    #[cfg(test)]
+    pub(crate) fn ptlc_outpoint(
+        &self,
+        settlement: &Transaction,
+        index: usize,
+    ) -> Result<OutPoint, Error> {
+        if index >= self.ptlcs.len() {
+            return Err(Error::Channel(format!(
+                "no pending PTLC at index {}",
+                index
+            )));
+        }
+
+        Ok(OutPoint {
+            txid: settlement.txid(),
+            vout: (self.balance_outputs() + self.htlcs.len() + index) as u32,
+        })
+    }
+
+    /// Sign the success spend of the pending `ptlc` as its offerer, encrypted
+    /// to its payment point.
+    ///
+    /// The receiver keeps this [`AdaptorSignature`], which it can only complete
+    /// into the offerer's signature with the payment secret. The success spend
+    /// does not commit to the outpoint of the PTLC output, so it can be signed
+    /// before the `Settlement Transaction` confirms.
+    #[cfg(test)]
+    pub(crate) fn sign_ptlc_adaptor(
+        &self,
+        ptlc: &Ptlc,
+        offerer_keys: &ChannelKeys,
+    ) -> Result<AdaptorSignature, Error> {
+        let ptlc_sk =
+            derive_ptlc_key(&offerer_keys.settlement_sk(self.state), &ptlc.payment_point)?;
+
+        sign_adaptor(
+            &self.ptlc_success_message(ptlc),
+            &ptlc_sk,
+            &ptlc.payment_point,
+        )
+    }
+
+    /// Verify the offerer's [`AdaptorSignature`] for the success spend of the
+    /// pending `ptlc`.
+    #[cfg(test)]
+    pub(crate) fn verify_ptlc_adaptor(
+        &self,
+        ptlc: &Ptlc,
+        adaptor: &AdaptorSignature,
+    ) -> Result<(), Error> {
+        let offerer_pk = derive_ptlc_pubkey(
+            &self.settlement_pk(ptlc.offerer, self.state),
+            &ptlc.payment_point,
+        )?;
+
+        verify_adaptor(
+            &self.ptlc_success_message(ptlc),
+            &offerer_pk,
+            &ptlc.payment_point,
+            adaptor,
+        )
+    }
+
+    /// Build and sign the success spend of the pending `ptlc` at `ptlc_prevout`,
+    /// completing the offerer's `adaptor` with the `payment_secret`.
+    #[cfg(test)]
+    pub(crate) fn build_signed_ptlc_success(
+        &self,
+        ptlc: &Ptlc,
+        ptlc_prevout: OutPoint,
+        adaptor: &AdaptorSignature,
+        payment_secret: &SecretKey,
+        receiver_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        if payment_secret.public_key(SECP256K1) != ptlc.payment_point {
+            return Err(Error::Channel(format!(
+                "secret does not match the payment point {}",
+                ptlc.payment_point
+            )));
+        }
+
+        let transaction = build_htlc_success_transaction(
+            ptlc_prevout,
+            ptlc.amount,
+            self.address(ptlc.receiver()),
+            self.is_mainnet,
+        );
+        let receiver_signature = sign_anyprevout_input(
+            &transaction,
+            ptlc.amount,
+            &receiver_keys.settlement_sk(self.state),
+            self.is_mainnet,
+        );
+
+        finalize_ptlc_spend_transaction(
+            &transaction,
+            &self.ptlc_program(self.state, ptlc)?,
+            &PtlcSpend::Success {
+                receiver_signature,
+                offerer_signature: complete_adaptor(adaptor, payment_secret)?,
+            },
+        )
+    }
+
+    /// Build and sign the refund of the pending `ptlc` at `ptlc_prevout` to its
+    /// offerer once it has expired.
+    #[cfg(test)]
+    pub(crate) fn build_signed_ptlc_timeout(
+        &self,
+        ptlc: &Ptlc,
+        ptlc_prevout: OutPoint,
+        offerer_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        let transaction = build_htlc_timeout_transaction(
+            ptlc_prevout,
+            ptlc.amount,
+            self.address(ptlc.offerer),
+            ptlc.cltv_expiry,
+            self.is_mainnet,
+        );
+        let signature = sign_anyprevout_input(
+            &transaction,
+            ptlc.amount,
+            &derive_ptlc_key(&offerer_keys.settlement_sk(self.state), &ptlc.payment_point)?,
+            self.is_mainnet,
+        );
+
+        finalize_ptlc_spend_transaction(
+            &transaction,
+            &self.ptlc_program(self.state, ptlc)?,
+            &PtlcSpend::Timeout { signature },
+        )
+    }
+
+    /// Extract the payment secret of `ptlc` from `success`, the on-chain spend
+    /// that completed the offerer's `adaptor`.
+    #[cfg(test)]
+    pub(crate) fn extract_ptlc_secret(
+        &self,
+        ptlc: &Ptlc,
+        adaptor: &AdaptorSignature,
+        success: &Transaction,
+    ) -> Result<SecretKey, Error> {
+        // The Simplicity witness comes first on the witness stack.
+        let witness = success
+            .input
+            .first()
+            .and_then(|input| input.witness.script_witness.first())
+            .ok_or_else(|| Error::Channel("PTLC spend has no witness".into()))?;
+        let signature = find_completed_signature(adaptor, &ptlc.payment_point, witness)
+            .ok_or_else(|| Error::Channel("PTLC spend did not complete the adaptor".into()))?;
+
+        extract_adaptor_secret(adaptor, &ptlc.payment_point, &signature)
+    }
+
+    /// The [`Message`] signed for the success spend of `ptlc`, which only
+    /// depends on its amount and receiver.
+    #[cfg(test)]
+    fn ptlc_success_message(&self, ptlc: &Ptlc) -> Message {
+        let transaction = build_htlc_success_transaction(
+            OutPoint::default(),
+            ptlc.amount,
+            self.address(ptlc.receiver()),
+            self.is_mainnet,
+        );
+
+        anyprevout_sighash(
+            &transaction,
+            0,
+            policy_asset(self.is_mainnet),
+            ptlc.amount,
+            self.is_mainnet,
+        )
+    }
+
+    /// Build the commitment program of the output created by the
+    /// `Update Transaction` for `state`.
+    ///
+    /// It can be spent by the settlement for `state` or by any update for a
+    /// later state. Only public keys are involved, so either party can build it.
+    pub(crate) fn commitment_program(&self, state: u32) -> Result<CompiledProgram, Error> {
+        build_commitment_program(
+            self.alice_update_pk,
+            self.bob_update_pk,
+            &self.alice_settlement_basepoint,
+            &self.bob_settlement_basepoint,
+            state,
+        )
+    }
+
+    /// Inspect the setup and commitment scripts of the channel at `state`.
+    pub(crate) fn inspect(&self, state: u32) -> Result<ScriptInspection, Error> {
+        let settlement_pk = |basepoint| {
+            derive_settlement_pubkey(basepoint, state as u64)
+                .x_only_public_key()
+                .0
+        };
+
+        inspect_scripts(
+            self.alice_update_pk,
+            self.bob_update_pk,
+            settlement_pk(&self.alice_settlement_basepoint),
+            settlement_pk(&self.bob_settlement_basepoint),
+            state,
+            self.is_mainnet,
+        )
+    }
+
+    /// Build the unsigned `Update Transaction` for `state` that spends the
+    /// setup outputs, starting a unilateral close of the channel.
+    pub(crate) fn build_closing_update(&self, state: u32) -> Result<Transaction, Error> {
+        let commitment_address = derive_address(&self.commitment_program(state)?, self.is_mainnet);
+
+        Ok(build_setup_spending_update_transaction(
+            self.setup_outpoint,
+            self.funding_amount,
+            &commitment_address,
+            state,
+            &self.issued_assets,
+            self.is_mainnet,
+        ))
+    }
+
+    /// Sign the `Update Transaction` for `state` that spends the setup outputs
+    /// with `keys`, for the counterparty to close the channel on its own.
+    pub(crate) fn sign_close(
+        &self,
+        state: u32,
+        keys: &ChannelKeys,
+    ) -> Result<CloseSignatures, Error> {
+        let closing_update = self.build_closing_update(state)?;
+
+        Ok(CloseSignatures {
+            closing_update: sign_setup_spending_inputs(
+                &closing_update,
+                self.funding_amount,
+                &self.issued_assets,
+                &self.alice_update_pk,
+                &self.bob_update_pk,
+                &keys.update_sk,
+            ),
+        })
+    }
+
+    /// Build and sign the `Update Transaction` for the latest state that spends
+    /// the setup outputs, starting a unilateral close of the channel.
+    ///
+    /// Only our own `keys` are needed: the counterparty's signatures were
+    /// received along with the latest state.
+    pub(crate) fn build_signed_closing_update(
+        &self,
+        keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        self.check_local_keys(keys)?;
+        let update = self.build_closing_update(self.state)?;
+        let local_sigs = sign_setup_spending_inputs(
+            &update,
+            self.funding_amount,
+            &self.issued_assets,
+            &self.alice_update_pk,
+            &self.bob_update_pk,
+            &keys.update_sk,
+        );
+        let remote_sigs = &self.close_sigs.closing_update;
+        if remote_sigs.len() != local_sigs.len() {
+            return Err(Error::Channel(format!(
+                "no counterparty signatures of the closing update for state {}",
+                self.state
+            )));
+        }
+
+        let signatures: Vec<_> = local_sigs
+            .into_iter()
+            .zip(remote_sigs.iter().copied())
+            .map(|(local, remote)| by_party(self.local_party, local, remote))
+            .collect();
+
+        Ok(finalize_setup_spending_transaction(
+            &update,
+            &self.alice_update_pk,
+            &self.bob_update_pk,
+            &signatures,
+        ))
+    }
+
+    /// Check that `keys` are our keys of the channel.
+    fn check_local_keys(&self, keys: &ChannelKeys) -> Result<(), Error> {
+        if keys.update_pk() != self.update_pk(self.local_party) {
+            return Err(Error::Key(format!(
+                "keys are not {:?}'s keys of channel {}",
+                self.local_party,
+                self.id()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The fees reserved from the funding amount for a unilateral close. A
+    /// cooperative close pays its fee out of them.
+    pub(crate) fn fee_reserve(&self) -> Amount {
+        self.funding_amount - self.capacity()
+    }
+
+    /// Check that a cooperative close can pay `fee` out of the fee reserve.
+    pub(crate) fn check_closing_fee(&self, fee: Amount) -> Result<(), Error> {
+        if fee == Amount::ZERO || fee > self.fee_reserve() {
+            return Err(Error::Channel(format!(
+                "closing fee of {} is not within the fee reserve of {}",
+                fee,
+                self.fee_reserve()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Build the unsigned `Cooperative Close Transaction` paying the balances
+    /// of the latest state and `fee`.
+    ///
+    /// Alice funded the fee reserve, so she gets back whatever `fee` leaves of
+    /// it. Pending HTLCs and PTLCs would have no output, so they must be
+    /// resolved before the channel can be closed this way.
+    pub(crate) fn build_cooperative_close(&self, fee: Amount) -> Result<Transaction, Error> {
+        self.check_closing_fee(fee)?;
+        if !self.htlcs.is_empty() || !self.ptlcs.is_empty() {
+            return Err(Error::Channel(
+                "pending HTLCs and PTLCs must be resolved before a cooperative close".into(),
+            ));
+        }
+
+        Ok(build_cooperative_close_transaction(
+            self.setup_outpoint,
+            self.funding_amount,
+            &self.alice_address,
+            self.alice_balance + self.fee_reserve() - fee,
+            &self.bob_address,
+            self.bob_balance,
+            &self.issued_assets,
+            self.is_mainnet,
+        ))
+    }
+
+    /// Build and sign the `Cooperative Close Transaction` paying `fee`.
+    pub(crate) fn build_signed_cooperative_close(
+        &self,
+        fee: Amount,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        let close = self.build_cooperative_close(fee)?;
+
+        Ok(sign_setup_spending_transaction(
+            &close,
+            self.funding_amount,
+            &self.issued_assets,
+            &alice_keys.update_sk,
+            &bob_keys.update_sk,
+        ))
+    }
+
+    /// Splice `alice` and `bob` contributions into and out of the channel,
+    /// returning the `Splice Transaction` with the old setup output signed.
+    ///
+    /// The update and settlement for the next state, spending the new setup
+    /// output, are signed right away but only take over once the splice
+    /// confirms, in [`Channel::confirm_splice`]. Alice pays the splice fee,
+    /// like every other fee of the channel.
+    pub(crate) fn splice(
+        &mut self,
+        alice: &SpliceContribution,
+        bob: &SpliceContribution,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        if self.pending_splice.is_some() {
+            return Err(Error::Channel("a splice is already pending".into()));
+        }
+        if !self.issued_assets.is_empty() || !self.htlcs.is_empty() || !self.ptlcs.is_empty() {
+            return Err(Error::Channel(
+                "only channels without issued assets or pending payments can be spliced".into(),
+            ));
+        }
+
+        let setup_address =
+            build_setup_address(self.alice_update_pk, self.bob_update_pk, self.is_mainnet)?;
+        let transaction = build_splice_transaction(
+            self.setup_outpoint,
+            self.funding_amount,
+            &setup_address,
+            alice,
+            bob,
+            self.is_mainnet,
+        )?;
+        let confidential::Value::Explicit(funding_amount) = transaction.output[0].value else {
+            return Err(Error::Channel("setup amount is not explicit".into()));
+        };
+
+        let splice_balance = |balance: Amount, party: &SpliceContribution, fee: u64| {
+            (balance + party.splice_in.amount)
+                .checked_sub(party.splice_out_amount() + Amount::from_sat(fee))
+                .ok_or_else(|| Error::Channel("splice-out exceeds the balance".into()))
+        };
+        let alice_balance = splice_balance(self.alice_balance, alice, FEE_AMOUNT)?;
+        let bob_balance = splice_balance(self.bob_balance, bob, 0)?;
+
+        // Sign the next state of the channel as it will be after the splice.
+        let mut spliced = self.clone();
+        spliced.setup_outpoint = OutPoint {
+            txid: transaction.txid(),
+            vout: 0,
+        };
+        spliced.funding_amount = Amount::from_sat(funding_amount);
+        spliced.update(alice_balance, bob_balance, alice_keys, bob_keys)?;
+        let close_sigs = spliced.close_sigs;
+        let (update, settlement) = spliced
+            .latest_update
+            .zip(spliced.latest_settlement)
+            .expect("an update signs both transactions");
+
+        let signed_transaction = sign_setup_spending_transaction(
+            &transaction,
+            self.funding_amount,
+            &[],
+            &alice_keys.update_sk,
+            &bob_keys.update_sk,
+        );
+        self.pending_splice = Some(PendingSplice {
+            transaction: signed_transaction.clone(),
+            funding_amount: Amount::from_sat(funding_amount),
+            alice_balance,
+            bob_balance,
+            update,
+            settlement,
+            close_sigs,
+        });
+
+        Ok(signed_transaction)
+    }
+
+    /// Move the channel to the setup output and state created by its pending
+    /// splice, once the `Splice Transaction` has confirmed.
+    ///
+    /// This changes the channel identifier, which follows the setup output.
+    pub(crate) fn confirm_splice(&mut self) -> Result<(), Error> {
+        let splice = self
+            .pending_splice
+            .take()
+            .ok_or_else(|| Error::Channel("no splice is pending".into()))?;
+
+        self.setup_outpoint = splice.setup_outpoint();
+        self.funding_amount = splice.funding_amount;
+        self.advance(
+            splice.update,
+            splice.settlement,
+            splice.close_sigs,
+            splice.alice_balance,
+            splice.bob_balance,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+
+        Ok(())
+    }
+
+    /// Build the signed `Settlement Transaction` for the latest state that
+    /// spends the commitment outputs at `commitment_prevout` of `input_amount`.
+    ///
+    /// Every commitment output of the channel holds the same amount, whether
+    /// its update spent the setup output or overrode a stale one, so the stored
+    /// settlement is only bound to it.
+    pub(crate) fn build_signed_settlement(
+        &self,
+        commitment_prevout: OutPoint,
+        input_amount: Amount,
+    ) -> Result<Transaction, Error> {
+        if input_amount != self.commitment_amount() {
+            return Err(Error::Channel(format!(
+                "no settlement is signed for a commitment output of {}",
+                input_amount
+            )));
+        }
+
+        let mut settlement = self
+            .latest_settlement
+            .clone()
+            .ok_or_else(|| Error::Channel("channel has no signed settlement".into()))?;
+        bind_commitment_inputs(&mut settlement, commitment_prevout);
+
+        Ok(settlement)
+    }
+
+    /// Sign the fee output at `outpoint` with our `keys`, to pay the fee of the
+    /// latest `Update Transaction` when it overrides a stale one.
+    ///
+    /// The output must hold exactly [`FEE_AMOUNT`] at our fee address.
+    pub(crate) fn sign_fee_input(
+        &self,
+        outpoint: OutPoint,
+        keys: &ChannelKeys,
+    ) -> Result<FeeInput, Error> {
+        self.check_local_keys(keys)?;
+        let latest_update = self
+            .latest_update
+            .as_ref()
+            .ok_or_else(|| Error::Channel("channel has no signed update".into()))?;
+
+        Ok(sign_fee_input(
+            &latest_update.transaction,
+            outpoint,
+            &keys.fee_sk,
+        ))
+    }
+
+    /// Build and sign the `Settlement Transaction` paying `(alice, bob)`
+    /// balances, `issued_assets` balances, `htlcs` and `ptlcs` for `state`.
+    #[allow(clippy::too_many_arguments)]
+    fn sign_settlement_for_state(
+        &self,
+        state: u32,
+        balances: (Amount, Amount),
+        issued_assets: &[IssuedAsset],
+        htlcs: &[Htlc],
+        ptlcs: &[Ptlc],
+        commitment_prevout: OutPoint,
+        input_amount: Amount,
+        alice_keys: &ChannelKeys,
+        bob_keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        let settlement = self.build_settlement(
+            state,
+            balances,
+            issued_assets,
+            htlcs,
+            ptlcs,
+            commitment_prevout,
+            input_amount,
+        )?;
+
+        sign_settlement_transaction(
+            &settlement,
+            input_amount,
+            issued_assets,
+            &self.commitment_program(state)?,
+            &alice_keys.settlement_sk(state),
+            &bob_keys.settlement_sk(state),
+            self.is_mainnet,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::keys::SEED_LEN;
+
+    use super::*;
+
+    #[test]
+    fn claim_and_refund_payment_outputs() {
+        let alice_keys = ChannelKeys::derive(&[0x0a; SEED_LEN], 0, false).unwrap();
+        let bob_keys = ChannelKeys::derive(&[0x0b; SEED_LEN], 0, false).unwrap();
+        let address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let mut channel = Channel::new(
+            false,
+            OutPoint::default(),
+            Amount::from_sat(10_000),
+            alice_keys.update_pk(),
+            bob_keys.update_pk(),
+            alice_keys.settlement_basepoint(),
+            bob_keys.settlement_basepoint(),
+            address.clone(),
+            address,
+            0,
+            "alice.seed".to_string(),
+            Party::Alice,
+        )
+        .unwrap();
+        channel.sign_initial_state(&alice_keys, &bob_keys).unwrap();
+
+        // Alice offers Bob an HTLC and a PTLC of 300 sats each.
+        let preimage = [0x42; 32];
+        let htlc = Htlc {
+            offerer: Party::Alice,
+            amount: Amount::from_sat(300),
+            payment_hash: sha256::Hash::hash(&preimage),
+            cltv_expiry: 3_000_000,
+        };
+        let payment_secret = SecretKey::from_slice(&[0x43; 32]).unwrap();
+        let ptlc = Ptlc {
+            offerer: Party::Alice,
+            amount: Amount::from_sat(300),
+            payment_point: payment_secret.public_key(SECP256K1),
+            cltv_expiry: 3_000_000,
+        };
+        channel
+            .update_with_payments(
+                Amount::from_sat(9262),
+                Amount::ZERO,
+                Vec::new(),
+                vec![htlc],
+                vec![ptlc],
+                &alice_keys,
+                &bob_keys,
+            )
+            .unwrap();
+        let settlement = channel.latest_settlement.clone().unwrap();
+
+        // Bob has no balance output, so the payments follow Alice's.
+        let htlc_prevout = channel.htlc_outpoint(&settlement, 0).unwrap();
+        assert_eq!(htlc_prevout.vout, 1);
+        assert!(channel.htlc_outpoint(&settlement, 1).is_err());
+        let ptlc_prevout = channel.ptlc_outpoint(&settlement, 0).unwrap();
+        assert_eq!(ptlc_prevout.vout, 2);
+
+        // Bob claims the HTLC with its preimage, or Alice takes it back.
+        assert!(
+            channel
+                .build_signed_htlc_success(&htlc, htlc_prevout, [0x00; 32], &bob_keys)
+                .is_err()
+        );
+        let success = channel
+            .build_signed_htlc_success(&htlc, htlc_prevout, preimage, &bob_keys)
+            .unwrap();
+        assert_eq!(success.input[0].previous_output, htlc_prevout);
+        let timeout = channel
+            .build_signed_htlc_timeout(&htlc, htlc_prevout, &alice_keys)
+            .unwrap();
+        assert_eq!(timeout.lock_time, LockTime::from_consensus(3_000_000));
+
+        // Bob claims the PTLC by completing Alice's adaptor signature, which
+        // reveals the payment secret to her.
+        let adaptor = channel.sign_ptlc_adaptor(&ptlc, &alice_keys).unwrap();
+        assert!(channel.verify_ptlc_adaptor(&ptlc, &adaptor).is_ok());
+        let wrong_secret = SecretKey::from_slice(&[0x44; 32]).unwrap();
+        assert!(
+            channel
+                .build_signed_ptlc_success(&ptlc, ptlc_prevout, &adaptor, &wrong_secret, &bob_keys)
+                .is_err()
+        );
+        let success = channel
+            .build_signed_ptlc_success(&ptlc, ptlc_prevout, &adaptor, &payment_secret, &bob_keys)
+            .unwrap();
+        assert_eq!(
+            channel
+                .extract_ptlc_secret(&ptlc, &adaptor, &success)
+                .unwrap(),
+            payment_secret
+        );
+        let timeout = channel
+            .build_signed_ptlc_timeout(&ptlc, ptlc_prevout, &alice_keys)
+            .unwrap();
+        assert_eq!(timeout.lock_time, LockTime::from_consensus(3_000_000));
+    }
+}
@@ -0,0 +1,1384 @@
+//! Command-Line Interface
+//!
+//! Every subcommand prints a single JSON object to stdout. Logs go to stderr.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use elements::bitcoin::Amount;
+use elements::bitcoin::hex::DisplayHex;
+use elements::secp256k1_zkp::{PublicKey, SECP256K1, XOnlyPublicKey};
+use elements::{Address, AssetId, OutPoint, Txid};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::FEE_AMOUNT;
+use crate::channel::{Channel, Party};
+use crate::close::{build_settlement_for, force_close};
+use crate::error::Error;
+use crate::esplora::{
+    LIQUIDV1_MAIN_URL, LIQUIDV1_TEST_URL, broadcast_transaction, get_confirmation_height,
+};
+use crate::faucet::get_testnet_coins;
+use crate::keys::{
+    ChannelKeys, derive_node_key, export_seed, generate_seed, import_seed, read_seed_file,
+    write_seed_file,
+};
+use crate::peer::{
+    Peer, close_channel, open_channel, open_dual_funded_channel, propose_update, serve,
+};
+use crate::script::build_setup_address_program;
+use crate::store::ChannelStore;
+use crate::tower::{WatchedChannel, Watchtower};
+use crate::transaction::{
+    FundingContribution, SpliceContribution, TransactionSummary, build_fee_address,
+    build_setup_address, check_address_network, export_transaction, import_transaction,
+    policy_asset,
+};
+use crate::watcher::{ChannelTip, find_channel_tip, override_stale_update};
+
+/// SimplEltoo: ELTOO payment channels on Liquid with SimplicityHL.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// The network to operate on.
+    #[arg(long, value_enum, global = true, default_value_t = Network::LiquidTestnet)]
+    network: Network,
+
+    /// The directory where channels are stored.
+    #[arg(long, global = true, default_value = ".simpleltoo")]
+    store: PathBuf,
+
+    /// A custom Esplora API URL, ending in `/`.
+    #[arg(long, global = true)]
+    esplora_url: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The networks SimplEltoo can operate on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Network {
+    /// Liquid V1.
+    Liquid,
+    /// Liquid TestnetV1.
+    LiquidTestnet,
+}
+
+/// The transactions of a channel that can be exported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ChannelTransaction {
+    /// The latest `Update Transaction`, without its witness.
+    Update,
+    /// The signed `Settlement Transaction` for the latest state, bound to its
+    /// commitment output once that is on-chain, and a template before.
+    Settlement,
+    /// The signed transaction of the pending splice.
+    Splice,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generate a new seed and write it to a seed file.
+    Keygen {
+        /// Where to write the seed file.
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Import a hex seed into a seed file.
+    ImportKey {
+        /// The hex seed.
+        #[arg(long)]
+        seed: String,
+        /// Where to write the seed file.
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Export the hex seed of a seed file.
+    ExportKey {
+        /// The seed file.
+        #[arg(long)]
+        seed_file: PathBuf,
+    },
+    /// Show the public keys of a channel derived from a seed file, and the fee
+    /// address paying for overrides.
+    Pubkey {
+        /// The seed file.
+        #[arg(long)]
+        seed_file: PathBuf,
+        /// The index of the channel.
+        #[arg(long, default_value_t = 0)]
+        channel_index: u32,
+    },
+    /// Derive the 2-of-2 `Setup Address` between Alice and Bob.
+    SetupAddress {
+        /// Alice's update public key.
+        #[arg(long)]
+        alice_pk: XOnlyPublicKey,
+        /// Bob's update public key.
+        #[arg(long)]
+        bob_pk: XOnlyPublicKey,
+    },
+    /// Open a channel funded at the `Setup Address` and sign its state 0, which
+    /// refunds both parties. Fails if the channel is stored already.
+    Fund {
+        #[command(flatten)]
+        keys: KeyArgs,
+        /// The txid of the transaction that funds the `Setup Address`.
+        #[arg(long)]
+        txid: Txid,
+        /// The output index that funds the `Setup Address`.
+        #[arg(long)]
+        vout: u32,
+        /// The funding amount, in sats.
+        #[arg(long)]
+        amount: u64,
+        /// Alice's payout address.
+        #[arg(long)]
+        alice_address: Address,
+        /// Bob's payout address.
+        #[arg(long)]
+        bob_address: Address,
+        /// The index of the channel in both parties seeds.
+        #[arg(long, default_value_t = 0)]
+        channel_index: u32,
+        /// An issued asset funded by Alice at the `Setup Address`, as
+        /// `asset_id:txid:vout:amount`. Can be repeated.
+        #[arg(long = "issued-asset")]
+        issued_assets: Vec<String>,
+        /// Bob's share of the funding amount, in sats, refunded to him at state 0.
+        #[arg(long, default_value_t = 0)]
+        bob_contribution: u64,
+    },
+    /// Move a channel to a new state with the given balance for Alice.
+    Update {
+        #[command(flatten)]
+        keys: KeyArgs,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// Alice's new balance, in sats. Bob gets the rest of the capacity.
+        #[arg(long)]
+        alice_balance: u64,
+        /// Alice's new balance of an issued asset, as `asset_id:amount`. Bob
+        /// gets the rest of the asset. Can be repeated.
+        #[arg(long = "asset-balance")]
+        asset_balances: Vec<String>,
+    },
+    /// Open a channel funded by us with a peer, as Alice. Fails if the channel
+    /// is stored already.
+    Open {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The index of the channel in our seed.
+        #[arg(long, default_value_t = 0)]
+        channel_index: u32,
+        /// The peer's `node_id@host:port`.
+        #[arg(long)]
+        peer: String,
+        /// The txid of the unbroadcast transaction that funds the `Setup Address`.
+        #[arg(long)]
+        txid: Txid,
+        /// The output index that funds the `Setup Address`.
+        #[arg(long)]
+        vout: u32,
+        /// The funding amount, in sats.
+        #[arg(long)]
+        amount: u64,
+        /// Our payout address.
+        #[arg(long)]
+        address: Address,
+    },
+    /// Open a channel funded by both us and a peer, as Alice, and build its
+    /// funding transaction.
+    OpenDualFunded {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The index of the channel in our seed.
+        #[arg(long, default_value_t = 0)]
+        channel_index: u32,
+        /// The peer's `node_id@host:port`.
+        #[arg(long)]
+        peer: String,
+        /// Our contribution to the funding amount, in sats.
+        #[arg(long)]
+        amount: u64,
+        /// An L-BTC output paying for our contribution, as `txid:vout:amount`.
+        /// Can be repeated.
+        #[arg(long = "input")]
+        inputs: Vec<String>,
+        /// Where the change of our inputs goes.
+        #[arg(long)]
+        change_address: Option<Address>,
+        /// Our share of the funding transaction fee, in sats.
+        #[arg(long, default_value_t = FEE_AMOUNT)]
+        funding_fee: u64,
+        /// Our payout address.
+        #[arg(long)]
+        address: Address,
+    },
+    /// Accept channels from peers and sign the states they propose, as Bob.
+    Listen {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The first index of new channels in our seed.
+        #[arg(long, default_value_t = 0)]
+        channel_index: u32,
+        /// The `host:port` to listen on.
+        #[arg(long, default_value = "127.0.0.1:9735")]
+        bind: String,
+        /// Our payout address.
+        #[arg(long)]
+        address: Address,
+        /// The fee we propose for cooperative closes, in sats.
+        #[arg(long, default_value_t = FEE_AMOUNT)]
+        closing_fee: u64,
+        /// Our contribution to the next dual-funded channel, in sats.
+        #[arg(long, default_value_t = 0)]
+        contribution: u64,
+        /// An L-BTC output paying for our contribution, as `txid:vout:amount`.
+        /// Can be repeated.
+        #[arg(long = "input")]
+        inputs: Vec<String>,
+        /// Where the change of our inputs goes.
+        #[arg(long)]
+        change_address: Option<Address>,
+        /// Our share of the funding transaction fee, in sats.
+        #[arg(long, default_value_t = 0)]
+        funding_fee: u64,
+    },
+    /// Move a channel to a new state with the given balance for Alice, signed with the peer.
+    Propose {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The peer's `host:port`.
+        #[arg(long)]
+        peer: String,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// Alice's new balance, in sats. Bob gets the rest of the capacity.
+        #[arg(long)]
+        alice_balance: u64,
+    },
+    /// Splice funds into or out of a channel. The spliced channel takes over
+    /// once the `Splice Transaction` confirms.
+    Splice {
+        #[command(flatten)]
+        keys: KeyArgs,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// Splice Bob's funds instead of Alice's.
+        #[arg(long)]
+        bob: bool,
+        /// The amount added to the channel, in sats.
+        #[arg(long, default_value_t = 0)]
+        splice_in: u64,
+        /// An L-BTC output paying for the splice-in, as `txid:vout:amount`.
+        /// Can be repeated.
+        #[arg(long = "input")]
+        inputs: Vec<String>,
+        /// Where the change of the inputs goes.
+        #[arg(long)]
+        change_address: Option<Address>,
+        /// The output paid out of the balance, as `address:amount`.
+        #[arg(long)]
+        splice_out: Option<String>,
+    },
+    /// Move a channel to its splice once the `Splice Transaction` confirmed.
+    ConfirmSplice {
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+    },
+    /// Broadcast the `Cooperative Close Transaction` paying the latest balances.
+    CooperativeClose {
+        #[command(flatten)]
+        keys: KeyArgs,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The fee of the closing transaction, in sats.
+        #[arg(long, default_value_t = FEE_AMOUNT)]
+        fee: u64,
+    },
+    /// Negotiate the `Cooperative Close Transaction` with the peer and broadcast it.
+    Shutdown {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The peer's `host:port`.
+        #[arg(long)]
+        peer: String,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The fee we propose for the closing transaction, in sats.
+        #[arg(long, default_value_t = FEE_AMOUNT)]
+        fee: u64,
+    },
+    /// Broadcast the `Update Transaction` for the latest state.
+    Close {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+    },
+    /// Override a stale update published by the counterparty with the latest one.
+    Override {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The output paying the fee of the override, as `txid:vout`. It must
+        /// hold exactly the fee at our fee address.
+        #[arg(long)]
+        fee_input: String,
+    },
+    /// Hand the latest signed state of a channel over to a watchtower.
+    Watch {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The directory where the watchtower keeps its channels.
+        #[arg(long)]
+        tower: PathBuf,
+        /// The output paying the fee of an override, as `txid:vout`. It must
+        /// hold exactly the fee at our fee address.
+        #[arg(long)]
+        fee_input: String,
+    },
+    /// Run a watchtower, overriding the stale updates of the channels it watches.
+    Tower {
+        /// The directory where the watchtower keeps its channels.
+        #[arg(long)]
+        tower: PathBuf,
+        /// How often to check the watched channels, in seconds.
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Close the channel unilaterally, from the latest update through to its
+    /// settlement, resuming a close that was interrupted.
+    ForceClose {
+        /// Our seed file.
+        #[arg(long)]
+        seed: PathBuf,
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The output paying the fee of an override, should a stale update be
+        /// published, as `txid:vout`. It must hold exactly the fee at our fee
+        /// address.
+        #[arg(long)]
+        fee_input: Option<String>,
+    },
+    /// Broadcast the `Settlement Transaction` once the CSV delay has passed.
+    Settle {
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+    },
+    /// Show the stored and on-chain status of channels.
+    Status {
+        /// The channel identifier. Shows every stored channel if omitted.
+        #[arg(long)]
+        channel: Option<String>,
+    },
+    /// Show the populated commitment program, its CMR and address, the setup
+    /// witness script and every spend path of a channel at a state.
+    Inspect {
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The state to inspect. Defaults to the latest state.
+        #[arg(long)]
+        state: Option<u32>,
+    },
+    /// Export a transaction of a channel as consensus hex.
+    ExportTransaction {
+        /// The channel identifier.
+        #[arg(long)]
+        channel: String,
+        /// The transaction to export.
+        #[arg(long, value_enum)]
+        transaction: ChannelTransaction,
+    },
+    /// Decode a consensus hex transaction into a human-readable summary.
+    DecodeTransaction {
+        /// The consensus hex transaction.
+        #[arg(long)]
+        hex: String,
+    },
+    /// Broadcast a consensus hex transaction, such as one signed offline.
+    BroadcastTransaction {
+        /// The consensus hex transaction.
+        #[arg(long)]
+        hex: String,
+    },
+    /// Request L-BTC TestnetV1 coins to an address.
+    Faucet {
+        /// The address to fund.
+        #[arg(long)]
+        address: Address,
+    },
+}
+
+/// The seed files of both parties.
+#[derive(Debug, clap::Args)]
+struct KeyArgs {
+    /// Alice's seed file.
+    #[arg(long)]
+    alice_seed: PathBuf,
+    /// Bob's seed file.
+    #[arg(long)]
+    bob_seed: PathBuf,
+}
+
+impl Network {
+    /// Whether this is Liquid V1.
+    pub(crate) fn is_mainnet(self) -> bool {
+        self == Network::Liquid
+    }
+
+    /// The default Esplora API URL.
+    fn esplora_url(self) -> &'static str {
+        match self {
+            Network::Liquid => LIQUIDV1_MAIN_URL,
+            Network::LiquidTestnet => LIQUIDV1_TEST_URL,
+        }
+    }
+}
+
+impl KeyArgs {
+    /// Derive both parties [`ChannelKeys`] for the channel at `channel_index`.
+    fn derive(
+        &self,
+        channel_index: u32,
+        is_mainnet: bool,
+    ) -> Result<(ChannelKeys, ChannelKeys), Error> {
+        let alice_seed = read_seed_file(&self.alice_seed)?;
+        let bob_seed = read_seed_file(&self.bob_seed)?;
+
+        Ok((
+            ChannelKeys::derive(&alice_seed, channel_index, is_mainnet)?,
+            ChannelKeys::derive(&bob_seed, channel_index, is_mainnet)?,
+        ))
+    }
+}
+
+/// Run a parsed [`Cli`] command, returning its JSON output.
+pub async fn run(cli: Cli) -> Result<Value, Error> {
+    let is_mainnet = cli.network.is_mainnet();
+    let url = cli
+        .esplora_url
+        .clone()
+        .unwrap_or_else(|| cli.network.esplora_url().to_string());
+
+    match cli.command {
+        Command::Keygen { output } => {
+            write_seed_file(&output, &generate_seed())?;
+
+            Ok(json!({ "seed_file": output }))
+        }
+        Command::ImportKey { seed, output } => {
+            write_seed_file(&output, &import_seed(&seed)?)?;
+
+            Ok(json!({ "seed_file": output }))
+        }
+        Command::ExportKey { seed_file } => Ok(json!({
+            "seed": export_seed(&read_seed_file(&seed_file)?),
+        })),
+        Command::Pubkey {
+            seed_file,
+            channel_index,
+        } => {
+            let seed = read_seed_file(&seed_file)?;
+            let keys = ChannelKeys::derive(&seed, channel_index, is_mainnet)?;
+            let node_sk = derive_node_key(&seed, is_mainnet)?;
+
+            Ok(json!({
+                "node_id": node_sk.public_key(SECP256K1).to_string(),
+                "channel_index": channel_index,
+                "update_pk": keys.update_pk().to_string(),
+                "settlement_basepoint": keys.settlement_basepoint().to_string(),
+                "fee_address": build_fee_address(keys.fee_pk(), is_mainnet).to_string(),
+            }))
+        }
+        Command::SetupAddress { alice_pk, bob_pk } => Ok(json!({
+            "address": build_setup_address(alice_pk, bob_pk, is_mainnet)?.to_string(),
+            "witness_script": build_setup_address_program(&alice_pk, &bob_pk)
+                .as_bytes()
+                .to_lower_hex_string(),
+        })),
+        Command::Fund {
+            keys,
+            txid,
+            vout,
+            amount,
+            alice_address,
+            bob_address,
+            channel_index,
+            issued_assets,
+            bob_contribution,
+        } => {
+            check_address_network(&alice_address, is_mainnet)?;
+            check_address_network(&bob_address, is_mainnet)?;
+            let (alice_keys, bob_keys) = keys.derive(channel_index, is_mainnet)?;
+            let setup_outpoint = OutPoint { txid, vout };
+            let funding_amount = Amount::from_sat(amount);
+
+            let mut channel = Channel::new(
+                is_mainnet,
+                setup_outpoint,
+                funding_amount,
+                alice_keys.update_pk(),
+                bob_keys.update_pk(),
+                alice_keys.settlement_basepoint(),
+                bob_keys.settlement_basepoint(),
+                alice_address,
+                bob_address,
+                channel_index,
+                keys.alice_seed.display().to_string(),
+                Party::Alice,
+            )?;
+            let bob_contribution = Amount::from_sat(bob_contribution);
+            channel.add_bob_contribution(bob_contribution)?;
+            for issued_asset in &issued_assets {
+                let (asset, setup_outpoint, amount) = parse_issued_asset(issued_asset)?;
+                channel.add_issued_asset(asset, setup_outpoint, amount)?;
+            }
+
+            channel.sign_initial_state(&alice_keys, &bob_keys)?;
+            let refund = channel.build_signed_closing_update(&alice_keys)?;
+
+            ChannelStore::open(&cli.store)?.create(&channel)?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "setup_address": build_setup_address(
+                    channel.alice_update_pk,
+                    channel.bob_update_pk,
+                    is_mainnet,
+                )?
+                .to_string(),
+                "refund_update_transaction": export_transaction(&refund),
+            }))
+        }
+        Command::Update {
+            keys,
+            channel,
+            alice_balance,
+            asset_balances,
+        } => {
+            let store = ChannelStore::open(&cli.store)?;
+            let mut channel = store.load(&channel)?;
+            let (alice_keys, bob_keys) = keys.derive(channel.channel_index, channel.is_mainnet)?;
+
+            let alice_balance = Amount::from_sat(alice_balance);
+            let bob_balance = channel
+                .capacity()
+                .checked_sub(alice_balance)
+                .ok_or_else(|| Error::Channel("balance exceeds the channel capacity".into()))?;
+
+            let mut issued_assets = channel.issued_assets.clone();
+            for asset_balance in &asset_balances {
+                let (asset, alice_asset_balance) = parse_asset_balance(asset_balance)?;
+                let issued = issued_assets
+                    .iter_mut()
+                    .find(|issued| issued.asset == asset)
+                    .ok_or_else(|| {
+                        Error::Channel(format!("asset {} is not part of the channel", asset))
+                    })?;
+                issued.bob_balance = issued
+                    .amount()
+                    .checked_sub(alice_asset_balance)
+                    .ok_or_else(|| {
+                        Error::Channel(format!("balance exceeds the amount of asset {}", asset))
+                    })?;
+                issued.alice_balance = alice_asset_balance;
+            }
+            channel.update_with_payments(
+                alice_balance,
+                bob_balance,
+                issued_assets,
+                Vec::new(),
+                Vec::new(),
+                &alice_keys,
+                &bob_keys,
+            )?;
+            store.save(&channel)?;
+
+            Ok(channel_json(&channel))
+        }
+        Command::Open {
+            seed,
+            channel_index,
+            peer,
+            txid,
+            vout,
+            amount,
+            address,
+        } => {
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel_index, is_mainnet)?;
+            let node_sk = derive_node_key(&seed_bytes, is_mainnet)?;
+            let store = ChannelStore::open(&cli.store)?;
+
+            let (node_id, peer_address) = parse_peer(&peer)?;
+            let mut peer = Peer::connect(peer_address, &node_sk, &node_id).await?;
+            let channel = open_channel(
+                &mut peer,
+                &keys,
+                channel_index,
+                seed.display().to_string(),
+                OutPoint { txid, vout },
+                Amount::from_sat(amount),
+                address,
+                is_mainnet,
+            )
+            .await?;
+            let refund = channel.build_signed_closing_update(&keys)?;
+            store.create(&channel)?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "setup_address": build_setup_address(
+                    channel.alice_update_pk,
+                    channel.bob_update_pk,
+                    is_mainnet,
+                )?
+                .to_string(),
+                "refund_update_transaction": export_transaction(&refund),
+            }))
+        }
+        Command::OpenDualFunded {
+            seed,
+            channel_index,
+            peer,
+            amount,
+            inputs,
+            change_address,
+            funding_fee,
+            address,
+        } => {
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel_index, is_mainnet)?;
+            let node_sk = derive_node_key(&seed_bytes, is_mainnet)?;
+            let store = ChannelStore::open(&cli.store)?;
+            let contribution =
+                parse_funding_contribution(amount, &inputs, change_address, funding_fee)?;
+
+            let (node_id, peer_address) = parse_peer(&peer)?;
+            let mut peer = Peer::connect(peer_address, &node_sk, &node_id).await?;
+            let (channel, funding) = open_dual_funded_channel(
+                &mut peer,
+                &keys,
+                channel_index,
+                seed.display().to_string(),
+                &contribution,
+                address,
+                is_mainnet,
+            )
+            .await?;
+            let refund = channel.build_signed_closing_update(&keys)?;
+            store.create(&channel)?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "alice_balance": channel.alice_balance.to_sat(),
+                "bob_balance": channel.bob_balance.to_sat(),
+                "funding_transaction": export_transaction(&funding),
+                "refund_update_transaction": export_transaction(&refund),
+            }))
+        }
+        Command::Listen {
+            seed,
+            channel_index,
+            bind,
+            address,
+            closing_fee,
+            contribution,
+            inputs,
+            change_address,
+            funding_fee,
+        } => {
+            let seed_bytes = read_seed_file(&seed)?;
+            let node_sk = derive_node_key(&seed_bytes, is_mainnet)?;
+            let store = ChannelStore::open(&cli.store)?;
+            let contribution = match contribution {
+                0 => None,
+                amount => Some(parse_funding_contribution(
+                    amount,
+                    &inputs,
+                    change_address,
+                    funding_fee,
+                )?),
+            };
+            let listener = TcpListener::bind(bind.as_str()).await?;
+            info!(
+                "Listening on {}@{}",
+                node_sk.public_key(SECP256K1),
+                listener.local_addr()?
+            );
+
+            serve(
+                listener,
+                store,
+                seed_bytes,
+                seed.display().to_string(),
+                channel_index,
+                address,
+                Amount::from_sat(closing_fee),
+                contribution,
+                url,
+                is_mainnet,
+            )
+            .await?;
+
+            Ok(json!({}))
+        }
+        Command::Propose {
+            seed,
+            peer,
+            channel,
+            alice_balance,
+        } => {
+            let store = ChannelStore::open(&cli.store)?;
+            let mut channel = store.load(&channel)?;
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel.channel_index, channel.is_mainnet)?;
+            let node_sk = derive_node_key(&seed_bytes, channel.is_mainnet)?;
+            let node_id = channel
+                .peer_id
+                .ok_or_else(|| Error::Channel("channel was not opened with a peer".into()))?;
+
+            let alice_balance = Amount::from_sat(alice_balance);
+            let bob_balance = channel
+                .capacity()
+                .checked_sub(alice_balance)
+                .ok_or_else(|| Error::Channel("balance exceeds the channel capacity".into()))?;
+
+            let mut peer = Peer::connect(peer.as_str(), &node_sk, &node_id).await?;
+            propose_update(&mut peer, &mut channel, &keys, alice_balance, bob_balance).await?;
+            store.save(&channel)?;
+
+            Ok(channel_json(&channel))
+        }
+        Command::Splice {
+            keys,
+            channel,
+            bob,
+            splice_in,
+            inputs,
+            change_address,
+            splice_out,
+        } => {
+            let store = ChannelStore::open(&cli.store)?;
+            let mut channel = store.load(&channel)?;
+            let (alice_keys, bob_keys) = keys.derive(channel.channel_index, channel.is_mainnet)?;
+
+            let contribution = SpliceContribution {
+                splice_in: parse_funding_contribution(splice_in, &inputs, change_address, 0)?,
+                splice_out: splice_out.as_deref().map(parse_splice_out).transpose()?,
+            };
+            let (alice, bob) = match bob {
+                false => (contribution, SpliceContribution::default()),
+                true => (SpliceContribution::default(), contribution),
+            };
+            let splice = channel.splice(&alice, &bob, &alice_keys, &bob_keys)?;
+            store.save(&channel)?;
+
+            // The spliced-in inputs still have to be signed before broadcasting.
+            Ok(json!({
+                "channel": channel.id(),
+                "splice_transaction": export_transaction(&splice),
+            }))
+        }
+        Command::ConfirmSplice { channel } => {
+            let store = ChannelStore::open(&cli.store)?;
+            let mut channel = store.load(&channel)?;
+            let splice_txid = channel
+                .pending_splice
+                .as_ref()
+                .ok_or_else(|| Error::Channel("no splice is pending".into()))?
+                .setup_outpoint()
+                .txid;
+            if get_confirmation_height(&url, &splice_txid).await?.is_none() {
+                return Err(Error::Channel(format!(
+                    "splice {} is not confirmed yet",
+                    splice_txid
+                )));
+            }
+
+            let old_channel_id = channel.id();
+            channel.confirm_splice()?;
+            store.save(&channel)?;
+            store.remove(&old_channel_id)?;
+
+            Ok(channel_json(&channel))
+        }
+        Command::CooperativeClose { keys, channel, fee } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            let (alice_keys, bob_keys) = keys.derive(channel.channel_index, channel.is_mainnet)?;
+
+            let close = channel.build_signed_cooperative_close(
+                Amount::from_sat(fee),
+                &alice_keys,
+                &bob_keys,
+            )?;
+            let txid = broadcast_transaction(&url, &close).await?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "state": channel.state,
+                "fee": fee,
+                "txid": txid.to_string(),
+            }))
+        }
+        Command::Shutdown {
+            seed,
+            peer,
+            channel,
+            fee,
+        } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel.channel_index, channel.is_mainnet)?;
+            let node_sk = derive_node_key(&seed_bytes, channel.is_mainnet)?;
+            let node_id = channel
+                .peer_id
+                .ok_or_else(|| Error::Channel("channel was not opened with a peer".into()))?;
+
+            let mut peer = Peer::connect(peer.as_str(), &node_sk, &node_id).await?;
+            let close = close_channel(&mut peer, &channel, &keys, Amount::from_sat(fee)).await?;
+            let txid = broadcast_transaction(&url, &close).await?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "state": channel.state,
+                "fee": close.fee_in(policy_asset(channel.is_mainnet)),
+                "txid": txid.to_string(),
+            }))
+        }
+        Command::Close { seed, channel } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel.channel_index, channel.is_mainnet)?;
+
+            let update = channel.build_signed_closing_update(&keys)?;
+            let txid = broadcast_transaction(&url, &update).await?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "state": channel.state,
+                "txid": txid.to_string(),
+            }))
+        }
+        Command::Override {
+            seed,
+            channel,
+            fee_input,
+        } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            let latest_update = channel
+                .latest_update
+                .as_ref()
+                .ok_or_else(|| Error::Channel("channel has no signed update".into()))?;
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel.channel_index, channel.is_mainnet)?;
+            let fee_input = channel.sign_fee_input(parse_outpoint(&fee_input)?, &keys)?;
+
+            let outcome = override_stale_update(
+                &url,
+                channel.setup_outpoint,
+                latest_update,
+                &fee_input,
+                |state| channel.commitment_program(state),
+            )
+            .await?;
+
+            Ok(json!({ "channel": channel.id(), "outcome": format!("{:?}", outcome) }))
+        }
+        Command::Watch {
+            seed,
+            channel,
+            tower,
+            fee_input,
+        } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel.channel_index, channel.is_mainnet)?;
+            let fee_input = channel.sign_fee_input(parse_outpoint(&fee_input)?, &keys)?;
+            let watched = WatchedChannel::from_channel(&channel, fee_input)?;
+            Watchtower::open(&tower)?.watch(&watched)?;
+
+            Ok(json!({ "channel": watched.id(), "state": watched.state() }))
+        }
+        Command::Tower { tower, interval } => {
+            let tower = Watchtower::open(&tower)?;
+            info!("Watching {} channels", tower.channels()?.len());
+            tower.run(&url, Duration::from_secs(interval)).await?;
+
+            Ok(json!({}))
+        }
+        Command::ForceClose {
+            seed,
+            channel,
+            fee_input,
+        } => {
+            let store = ChannelStore::open(&cli.store)?;
+            let channel = store.load(&channel)?;
+            let seed_bytes = read_seed_file(&seed)?;
+            let keys = ChannelKeys::derive(&seed_bytes, channel.channel_index, channel.is_mainnet)?;
+            let fee_outpoint = fee_input.as_deref().map(parse_outpoint).transpose()?;
+
+            let txid = force_close(&url, &store, &channel, &keys, fee_outpoint).await?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "state": channel.state,
+                "txid": txid.to_string(),
+            }))
+        }
+        Command::Settle { channel } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+
+            let commitment = match find_channel_tip(&url, channel.setup_outpoint).await? {
+                ChannelTip::Update {
+                    transaction, state, ..
+                } if state == channel.state => transaction,
+                tip => {
+                    return Err(Error::Channel(format!(
+                        "the latest state is not on-chain: {:?}",
+                        tip
+                    )));
+                }
+            };
+            let settlement = build_settlement_for(&channel, &commitment)?;
+            let txid = broadcast_transaction(&url, &settlement).await?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "state": channel.state,
+                "txid": txid.to_string(),
+            }))
+        }
+        Command::Status { channel } => {
+            let store = ChannelStore::open(&cli.store)?;
+            let channel_ids = match channel {
+                Some(channel) => vec![channel],
+                None => store.list()?,
+            };
+
+            let mut channels = Vec::with_capacity(channel_ids.len());
+            for channel_id in channel_ids {
+                let channel = store.load(&channel_id)?;
+                let on_chain = match find_channel_tip(&url, channel.setup_outpoint).await? {
+                    ChannelTip::Unspent => json!({ "status": "open" }),
+                    ChannelTip::Update {
+                        transaction,
+                        state,
+                        confirmed,
+                    } => json!({
+                        "status": "closing",
+                        "state": state,
+                        "txid": transaction.txid().to_string(),
+                        "confirmed": confirmed,
+                    }),
+                    ChannelTip::Settled => json!({ "status": "settled" }),
+                };
+
+                let mut status = channel_json(&channel);
+                status["on_chain"] = on_chain;
+                channels.push(status);
+            }
+
+            Ok(json!({ "channels": channels }))
+        }
+        Command::Inspect { channel, state } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            let state = state.unwrap_or(channel.state);
+            let inspection = channel.inspect(state)?;
+
+            let spend_paths: Vec<_> = inspection
+                .spend_paths
+                .iter()
+                .map(|path| {
+                    json!({
+                        "output": path.output,
+                        "spent_by": path.spent_by,
+                        "witness": path.witness,
+                        "conditions": path.conditions,
+                    })
+                })
+                .collect();
+
+            Ok(json!({
+                "channel": channel.id(),
+                "state": state,
+                "setup_address": inspection.setup_address.to_string(),
+                "setup_witness_script": inspection
+                    .setup_witness_script
+                    .as_bytes()
+                    .to_lower_hex_string(),
+                "setup_witness_script_asm": inspection.setup_witness_script.asm(),
+                "commitment_source": inspection.commitment_source,
+                "commitment_cmr": inspection.commitment_cmr,
+                "commitment_address": inspection.commitment_address.to_string(),
+                "spend_paths": spend_paths,
+            }))
+        }
+        Command::ExportTransaction {
+            channel,
+            transaction,
+        } => {
+            let channel = ChannelStore::open(&cli.store)?.load(&channel)?;
+            // The stored settlement spends a placeholder outpoint until the
+            // commitment output of the latest state is on-chain.
+            let mut template = false;
+            let exported = match transaction {
+                ChannelTransaction::Update => channel
+                    .latest_update
+                    .as_ref()
+                    .map(|update| update.transaction.clone()),
+                ChannelTransaction::Settlement => {
+                    match find_channel_tip(&url, channel.setup_outpoint).await? {
+                        ChannelTip::Update {
+                            transaction: commitment,
+                            state,
+                            ..
+                        } if state == channel.state => {
+                            Some(build_settlement_for(&channel, &commitment)?)
+                        }
+                        _ => {
+                            template = true;
+                            channel.latest_settlement.clone()
+                        }
+                    }
+                }
+                ChannelTransaction::Splice => channel
+                    .pending_splice
+                    .as_ref()
+                    .map(|splice| splice.transaction.clone()),
+            }
+            .ok_or_else(|| {
+                Error::Channel(format!("channel has no {:?} transaction", transaction))
+            })?;
+
+            Ok(json!({
+                "channel": channel.id(),
+                "transaction": export_transaction(&exported),
+                "template": template,
+                "summary": TransactionSummary::new(&exported, channel.is_mainnet),
+            }))
+        }
+        Command::DecodeTransaction { hex } => {
+            let transaction = import_transaction(&hex)?;
+
+            Ok(serde_json::to_value(TransactionSummary::new(
+                &transaction,
+                is_mainnet,
+            ))?)
+        }
+        Command::BroadcastTransaction { hex } => {
+            let transaction = import_transaction(&hex)?;
+            let txid = broadcast_transaction(&url, &transaction).await?;
+
+            Ok(json!({ "txid": txid.to_string() }))
+        }
+        Command::Faucet { address } => {
+            if is_mainnet {
+                return Err(Error::Channel("the faucet only works on testnet".into()));
+            }
+            let txid = get_testnet_coins(&address).await?;
+
+            Ok(json!({ "address": address.to_string(), "txid": txid.to_string() }))
+        }
+    }
+}
+
+/// The JSON summary of a [`Channel`].
+fn channel_json(channel: &Channel) -> Value {
+    json!({
+        "channel": channel.id(),
+        "state": channel.state,
+        "funding_amount": channel.funding_amount.to_sat(),
+        "capacity": channel.capacity().to_sat(),
+        "alice_balance": channel.alice_balance.to_sat(),
+        "bob_balance": channel.bob_balance.to_sat(),
+        "issued_assets": channel
+            .issued_assets
+            .iter()
+            .map(|issued| {
+                json!({
+                    "asset": issued.asset.to_string(),
+                    "amount": issued.amount().to_sat(),
+                    "alice_balance": issued.alice_balance.to_sat(),
+                    "bob_balance": issued.bob_balance.to_sat(),
+                })
+            })
+            .collect::<Vec<_>>(),
+        "alice_update_pk": channel.alice_update_pk.to_string(),
+        "bob_update_pk": channel.bob_update_pk.to_string(),
+        "pending_splice": channel
+            .pending_splice
+            .as_ref()
+            .map(|splice| splice.setup_outpoint().txid.to_string()),
+    })
+}
+
+/// Parse an `asset_id:txid:vout:amount` issued asset funded at the `Setup Address`.
+fn parse_issued_asset(issued_asset: &str) -> Result<(AssetId, OutPoint, Amount), Error> {
+    let invalid = || {
+        Error::Channel(format!(
+            "expected asset_id:txid:vout:amount, got {}",
+            issued_asset
+        ))
+    };
+    let [asset, txid, vout, amount]: [&str; 4] = issued_asset
+        .split(':')
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| invalid())?;
+
+    let asset = AssetId::from_str(asset).map_err(|_| invalid())?;
+    let outpoint = OutPoint {
+        txid: Txid::from_str(txid)?,
+        vout: vout.parse().map_err(|_| invalid())?,
+    };
+    let amount = Amount::from_sat(amount.parse().map_err(|_| invalid())?);
+
+    Ok((asset, outpoint, amount))
+}
+
+/// Build a [`FundingContribution`] of `amount` sats out of `txid:vout:amount`
+/// `inputs`, paying `fee` sats towards the funding transaction.
+fn parse_funding_contribution(
+    amount: u64,
+    inputs: &[String],
+    change_address: Option<Address>,
+    fee: u64,
+) -> Result<FundingContribution, Error> {
+    let inputs = inputs
+        .iter()
+        .map(|input| parse_funding_input(input))
+        .collect::<Result<_, _>>()?;
+
+    FundingContribution::new(
+        Amount::from_sat(amount),
+        inputs,
+        change_address,
+        Amount::from_sat(fee),
+    )
+}
+
+/// Parse a `txid:vout:amount` input of a funding transaction.
+fn parse_funding_input(input: &str) -> Result<(OutPoint, Amount), Error> {
+    let invalid = || Error::Channel(format!("expected txid:vout:amount, got {}", input));
+    let [txid, vout, amount]: [&str; 3] = input
+        .split(':')
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| invalid())?;
+
+    let outpoint = OutPoint {
+        txid: Txid::from_str(txid)?,
+        vout: vout.parse().map_err(|_| invalid())?,
+    };
+    let amount = Amount::from_sat(amount.parse().map_err(|_| invalid())?);
+
+    Ok((outpoint, amount))
+}
+
+/// Parse a `txid:vout` output.
+fn parse_outpoint(outpoint: &str) -> Result<OutPoint, Error> {
+    let invalid = || Error::Channel(format!("expected txid:vout, got {}", outpoint));
+    let (txid, vout) = outpoint.split_once(':').ok_or_else(invalid)?;
+
+    Ok(OutPoint {
+        txid: Txid::from_str(txid)?,
+        vout: vout.parse().map_err(|_| invalid())?,
+    })
+}
+
+/// Parse an `address:amount` splice-out.
+fn parse_splice_out(splice_out: &str) -> Result<(Address, Amount), Error> {
+    let invalid = || Error::Channel(format!("expected address:amount, got {}", splice_out));
+    let (address, amount) = splice_out.rsplit_once(':').ok_or_else(invalid)?;
+
+    Ok((
+        Address::from_str(address).map_err(|_| invalid())?,
+        Amount::from_sat(amount.parse().map_err(|_| invalid())?),
+    ))
+}
+
+/// Parse an `asset_id:amount` balance of an issued asset.
+fn parse_asset_balance(asset_balance: &str) -> Result<(AssetId, Amount), Error> {
+    let invalid = || Error::Channel(format!("expected asset_id:amount, got {}", asset_balance));
+    let (asset, amount) = asset_balance.split_once(':').ok_or_else(invalid)?;
+
+    Ok((
+        AssetId::from_str(asset).map_err(|_| invalid())?,
+        Amount::from_sat(amount.parse().map_err(|_| invalid())?),
+    ))
+}
+
+/// Split a `node_id@host:port` peer into its node key and address.
+fn parse_peer(peer: &str) -> Result<(PublicKey, &str), Error> {
+    let (node_id, address) = peer
+        .split_once('@')
+        .ok_or_else(|| Error::Peer(format!("expected node_id@host:port, got {}", peer)))?;
+    let node_id = node_id
+        .parse()
+        .map_err(|e| Error::Peer(format!("invalid node id {}: {}", node_id, e)))?;
+
+    Ok((node_id, address))
+}
+
+#[cfg(test)]
+mod tests {
+    use elements::Txid;
+    use elements::hashes::Hash;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::keys::SEED_LEN;
+
+    use super::*;
+
+    #[test]
+    fn parse_arguments() {
+        let txid = Txid::from_byte_array([0x01; 32]);
+        let asset = AssetId::from_inner(elements::hashes::sha256::Midstate([0x11; 32]));
+
+        let (parsed_asset, outpoint, amount) =
+            parse_issued_asset(&format!("{}:{}:2:5000", asset, txid)).unwrap();
+        assert_eq!(parsed_asset, asset);
+        assert_eq!(outpoint, OutPoint { txid, vout: 2 });
+        assert_eq!(amount, Amount::from_sat(5000));
+        assert!(parse_issued_asset(&format!("{}:{}:2", asset, txid)).is_err());
+        assert!(parse_issued_asset(&format!("{}:{}:2:lots", asset, txid)).is_err());
+
+        let (outpoint, amount) = parse_funding_input(&format!("{}:1:3000", txid)).unwrap();
+        assert_eq!(outpoint, OutPoint { txid, vout: 1 });
+        assert_eq!(amount, Amount::from_sat(3000));
+        assert!(parse_funding_input(&format!("{}:1", txid)).is_err());
+        assert!(parse_funding_input(&format!("{}:-1:3000", txid)).is_err());
+        assert!(parse_funding_input("txid:1:3000").is_err());
+
+        let node_id = derive_node_key(&[0x0b; SEED_LEN], false)
+            .unwrap()
+            .public_key(SECP256K1);
+        let (parsed_id, address) = parse_peer(&format!("{}@127.0.0.1:9735", node_id)).unwrap();
+        assert_eq!(parsed_id, node_id);
+        assert_eq!(address, "127.0.0.1:9735");
+        assert!(parse_peer("127.0.0.1:9735").is_err());
+        assert!(parse_peer("02ab@127.0.0.1:9735").is_err());
+    }
+
+    /// Answer every request like an Esplora server on which nothing is spent.
+    async fn serve_unspent_esplora(listener: TcpListener) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+
+            let body = r#"{"spent":false}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn fund_update_and_status() {
+        let dir = std::env::temp_dir().join(format!("simpleltoo-cli-{}", std::process::id()));
+        let store = dir.join("store");
+        let alice_seed = dir.join("alice.seed");
+        let bob_seed = dir.join("bob.seed");
+        std::fs::create_dir_all(&dir).unwrap();
+        write_seed_file(&alice_seed, &[0x0a; SEED_LEN]).unwrap();
+        write_seed_file(&bob_seed, &[0x0b; SEED_LEN]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(serve_unspent_esplora(listener));
+
+        let txid = Txid::from_byte_array([0x01; 32]).to_string();
+        let command = |args: &[&str]| {
+            let mut argv = vec!["simpleltoo", "--store", store.to_str().unwrap()];
+            argv.extend(["--esplora-url", &url]);
+            argv.extend(args);
+            run(Cli::parse_from(argv))
+        };
+        let keys = [
+            "--alice-seed",
+            alice_seed.to_str().unwrap(),
+            "--bob-seed",
+            bob_seed.to_str().unwrap(),
+        ];
+        let fund = |amount: &'static str| {
+            let mut args = vec!["fund"];
+            args.extend(keys);
+            args.extend(["--txid", &txid, "--vout", "0", "--amount", amount]);
+            args.extend(["--alice-address", ALICE_RESOLUTION_ADDRESS]);
+            args.extend(["--bob-address", ALICE_RESOLUTION_ADDRESS]);
+            args
+        };
+
+        // A funding amount that does not cover the fees is refused.
+        assert!(command(&fund("138")).await.is_err());
+
+        let funded = command(&fund("10000")).await.unwrap();
+        let channel = funded["channel"].as_str().unwrap().to_string();
+        assert_eq!(channel, format!("{}_0", txid));
+        assert!(funded["refund_update_transaction"].is_string());
+
+        // The channel is only funded once.
+        assert!(command(&fund("20000")).await.is_err());
+
+        let mut update = vec!["update"];
+        update.extend(keys);
+        update.extend(["--channel", &channel, "--alice-balance", "4000"]);
+        let updated = command(&update).await.unwrap();
+        assert_eq!(updated["state"], 1);
+        assert_eq!(updated["alice_balance"], 4000);
+        assert_eq!(updated["bob_balance"], 10000 - 2 * FEE_AMOUNT - 4000);
+
+        // A balance above the capacity is refused, and the state is kept.
+        update[update.len() - 1] = "20000";
+        assert!(command(&update).await.is_err());
+
+        let status = command(&["status"]).await.unwrap();
+        let channels = status["channels"].as_array().unwrap();
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0]["channel"], channel.as_str());
+        assert_eq!(channels[0]["state"], 1);
+        assert_eq!(channels[0]["funding_amount"], 10000);
+        assert_eq!(channels[0]["on_chain"]["status"], "open");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
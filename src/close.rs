@@ -0,0 +1,360 @@
+//! Unilateral Close
+//!
+//! Drives a channel from its setup output to its settlement without the help of
+//! the counterparty: publish the `Update Transaction` for the latest state, wait
+//! for it to confirm, wait out the CSV delay, then publish the `Settlement
+//! Transaction`. Progress is persisted after each step, so a close interrupted
+//! by a crash resumes where it stopped.
+
+use std::future::Future;
+use std::time::Duration;
+
+use elements::bitcoin::Amount;
+use elements::{OutPoint, Transaction, Txid, confidential};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::channel::Channel;
+use crate::error::Error;
+use crate::esplora::{
+    OutputSpend, broadcast_transaction, get_confirmation_height, get_output_spend, get_tip_height,
+    get_transaction, is_server_error,
+};
+use crate::keys::ChannelKeys;
+use crate::store::ChannelStore;
+use crate::watcher::{ChannelTip, WatchOutcome, find_channel_tip, override_stale_update};
+use crate::{CSV_DELAY, FEE_AMOUNT};
+
+/// How long to wait between two looks at the chain.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many times a failing Esplora request is sent before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long to wait before retrying a failed Esplora request, doubled after
+/// each attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The last step a unilateral close went through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CloseProgress {
+    /// The `Update Transaction` for the latest state was broadcast.
+    UpdateBroadcast { txid: Txid },
+    /// The `Update Transaction` for the latest state confirmed at `height`.
+    UpdateConfirmed { txid: Txid, height: u32 },
+    /// The `Settlement Transaction` was broadcast.
+    SettlementBroadcast { txid: Txid },
+    /// The `Settlement Transaction` confirmed, closing the channel.
+    Settled { txid: Txid },
+}
+
+/// Close `channel` unilaterally, returning the [`Txid`] of its confirmed
+/// `Settlement Transaction`.
+///
+/// Resumes from the progress saved in `store`, if any. Only our own `keys`
+/// are needed: the counterparty's signatures that spend the setup output were
+/// received along with the latest state. Should a stale update be published,
+/// ours overrides it with its fee paid by the output at `fee_outpoint`, which
+/// must hold exactly [`FEE_AMOUNT`] at our fee address.
+pub(crate) async fn force_close(
+    url: &str,
+    store: &ChannelStore,
+    channel: &Channel,
+    keys: &ChannelKeys,
+    fee_outpoint: Option<OutPoint>,
+) -> Result<Txid, Error> {
+    let channel_id = channel.id();
+    let mut progress = store.load_close_progress(&channel_id)?;
+
+    loop {
+        let next = match progress {
+            None => publish_latest_update(url, channel, keys, fee_outpoint).await?,
+            Some(CloseProgress::UpdateBroadcast { .. }) => {
+                match wait_for_latest_update(url, channel).await? {
+                    Some(next) => next,
+                    // A stale update got in first, so ours has to be rebound to it.
+                    None => publish_latest_update(url, channel, keys, fee_outpoint).await?,
+                }
+            }
+            Some(CloseProgress::UpdateConfirmed { txid, height }) => {
+                publish_settlement(url, channel, txid, height).await?
+            }
+            Some(CloseProgress::SettlementBroadcast { txid }) => {
+                wait_for_confirmation(url, &txid).await?;
+                CloseProgress::Settled { txid }
+            }
+            Some(CloseProgress::Settled { txid }) => return Ok(txid),
+        };
+
+        info!("Channel {} close progress: {:?}", channel_id, next);
+        store.save_close_progress(&channel_id, &next)?;
+        progress = Some(next);
+    }
+}
+
+/// Publish the `Update Transaction` for the latest state, spending the setup
+/// output, or overriding the stale update at the tip of the channel.
+async fn publish_latest_update(
+    url: &str,
+    channel: &Channel,
+    keys: &ChannelKeys,
+    fee_outpoint: Option<OutPoint>,
+) -> Result<CloseProgress, Error> {
+    let latest_update = channel
+        .latest_update
+        .as_ref()
+        .ok_or_else(|| Error::Channel("channel has no signed update".into()))?;
+
+    loop {
+        let tip = retry(|| find_channel_tip(url, channel.setup_outpoint)).await?;
+        match tip {
+            ChannelTip::Unspent => {
+                let update = channel.build_signed_closing_update(keys)?;
+                let txid = retry(|| broadcast_transaction(url, &update)).await?;
+
+                return Ok(CloseProgress::UpdateBroadcast { txid });
+            }
+            ChannelTip::Update {
+                transaction, state, ..
+            } if state == channel.state => {
+                return Ok(CloseProgress::UpdateBroadcast {
+                    txid: transaction.txid(),
+                });
+            }
+            ChannelTip::Update { state, .. } if state > channel.state => {
+                return Err(Error::Channel(format!(
+                    "state {} was published, but ours is {}",
+                    state, channel.state
+                )));
+            }
+            ChannelTip::Update { .. } => {
+                let fee_outpoint = fee_outpoint.ok_or_else(|| {
+                    Error::Channel(format!(
+                        "a stale update was published, and overriding it needs an output of \
+                         {} sats at our fee address",
+                        FEE_AMOUNT
+                    ))
+                })?;
+                let fee_input = channel.sign_fee_input(fee_outpoint, keys)?;
+                let outcome = retry(|| {
+                    override_stale_update(
+                        url,
+                        channel.setup_outpoint,
+                        latest_update,
+                        &fee_input,
+                        |s| channel.commitment_program(s),
+                    )
+                })
+                .await?;
+                match outcome {
+                    WatchOutcome::Overridden { txid, .. } => {
+                        return Ok(CloseProgress::UpdateBroadcast { txid });
+                    }
+                    // The stale update must confirm before it can be overridden.
+                    _ => sleep(POLL_INTERVAL).await,
+                }
+            }
+            ChannelTip::Settled => {
+                return Err(Error::Channel("channel is already settled".into()));
+            }
+        }
+    }
+}
+
+/// Wait for the `Update Transaction` for the latest state to confirm.
+///
+/// Returns `None` if a stale update confirmed instead, so ours has to be
+/// rebound to it.
+async fn wait_for_latest_update(
+    url: &str,
+    channel: &Channel,
+) -> Result<Option<CloseProgress>, Error> {
+    loop {
+        let tip = retry(|| find_channel_tip(url, channel.setup_outpoint)).await?;
+        match tip {
+            ChannelTip::Update {
+                transaction,
+                state,
+                confirmed: true,
+            } if state == channel.state => {
+                let txid = transaction.txid();
+                let height = wait_for_confirmation(url, &txid).await?;
+
+                return Ok(Some(CloseProgress::UpdateConfirmed { txid, height }));
+            }
+            ChannelTip::Update {
+                state,
+                confirmed: true,
+                ..
+            } if state < channel.state => return Ok(None),
+            ChannelTip::Settled => {
+                return Err(Error::Channel("channel is already settled".into()));
+            }
+            _ => sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Publish the `Settlement Transaction` spending the commitment output of the
+/// update `update_txid`, once the CSV delay from its confirmation at `height`
+/// has passed.
+async fn publish_settlement(
+    url: &str,
+    channel: &Channel,
+    update_txid: Txid,
+    height: u32,
+) -> Result<CloseProgress, Error> {
+    // The settlement can be mined in the block `CSV_DELAY` after the update.
+    let mature_height = height + u32::from(CSV_DELAY) - 1;
+    while retry(|| get_tip_height(url)).await? < mature_height {
+        sleep(POLL_INTERVAL).await;
+    }
+
+    // The settlement may have been broadcast before a crash.
+    let commitment_prevout = OutPoint {
+        txid: update_txid,
+        vout: 0,
+    };
+    if let OutputSpend::Spent { txid, .. } =
+        retry(|| get_output_spend(url, &commitment_prevout)).await?
+    {
+        return Ok(CloseProgress::SettlementBroadcast { txid });
+    }
+
+    let commitment = retry(|| get_transaction(url, &update_txid)).await?;
+    let settlement = build_settlement_for(channel, &commitment)?;
+    let txid = retry(|| broadcast_transaction(url, &settlement)).await?;
+
+    Ok(CloseProgress::SettlementBroadcast { txid })
+}
+
+/// Build the signed `Settlement Transaction` for the latest state that spends
+/// the commitment outputs of the `Update Transaction` `commitment`.
+pub(crate) fn build_settlement_for(
+    channel: &Channel,
+    commitment: &Transaction,
+) -> Result<Transaction, Error> {
+    let commitment_prevout = OutPoint {
+        txid: commitment.txid(),
+        vout: 0,
+    };
+    let confidential::Value::Explicit(commitment_amount) = commitment.output[0].value else {
+        return Err(Error::Channel("commitment amount is not explicit".into()));
+    };
+
+    channel.build_signed_settlement(commitment_prevout, Amount::from_sat(commitment_amount))
+}
+
+/// Wait for [`Txid`] to confirm, returning its confirmation height.
+async fn wait_for_confirmation(url: &str, txid: &Txid) -> Result<u32, Error> {
+    loop {
+        if let Some(height) = retry(|| get_confirmation_height(url, txid)).await? {
+            return Ok(height);
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Run the Esplora request `request`, retrying it with an exponential backoff
+/// while it fails with a transient error.
+async fn retry<T, F, Fut>(mut request: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                warn!(
+                    "Esplora request failed (attempt {}/{}), retrying in {:?}: {}",
+                    attempt, MAX_ATTEMPTS, delay, e
+                );
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `error` may go away by retrying the request that caused it: the
+/// connection failed, or the server is busy or failing.
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Bitreq(_) => true,
+        Error::EsploraStatus { status_code, .. } => is_server_error(*status_code),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use elements::hashes::Hash;
+
+    use super::*;
+
+    fn status(status_code: i32) -> Error {
+        Error::EsploraStatus {
+            url: "https://liquid.network/api/tx".into(),
+            status_code,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_transient_errors() {
+        let attempts = &AtomicU32::new(0);
+        let result = retry(|| async move {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(status(503)),
+                1 => Err(status(429)),
+                _ => Ok(42),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Other errors are not retried.
+        let attempts = &AtomicU32::new(0);
+        let result: Result<(), Error> = retry(|| async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(Error::Channel("no".into()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // Nor are transient ones forever.
+        let attempts = &AtomicU32::new(0);
+        let result: Result<(), Error> = retry(|| async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(status(502))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn only_connection_and_server_errors_are_transient() {
+        assert!(is_transient(&status(500)));
+        assert!(is_transient(&status(429)));
+        assert!(!is_transient(&status(400)));
+        assert!(!is_transient(&status(404)));
+        assert!(!is_transient(
+            &Error::TransactionNotFound(Txid::all_zeros())
+        ));
+        assert!(!is_transient(&Error::BroadcastRejected {
+            txid: Txid::all_zeros(),
+            reason: "bad-txns-inputs-missingorspent".into(),
+        }));
+        assert!(!is_transient(&Error::EsploraResponse(
+            "invalid height".into()
+        )));
+    }
+}
@@ -4,7 +4,7 @@ use thiserror::Error;
 
 /// Unified error variants.
 #[derive(Debug, Error)]
-pub(crate) enum Error {
+pub enum Error {
     #[error("Bitreq error: {0}")]
     Bitreq(#[from] bitreq::Error),
 
@@ -22,4 +22,61 @@ pub(crate) enum Error {
 
     #[error("Hex parsing error: {0}")]
     HexParsing(#[from] elements::bitcoin::hex::HexToArrayError),
+
+    #[error("Hex decoding error: {0}")]
+    HexDecoding(#[from] elements::bitcoin::hex::HexToBytesError),
+
+    #[error("Consensus encoding error: {0}")]
+    Encode(#[from] elements::encode::Error),
+
+    #[error("Key error: {0}")]
+    Key(String),
+
+    #[error("Alice and Bob must use distinct keys, both are {0}")]
+    DuplicateKeys(String),
+
+    #[error("Channel error: {0}")]
+    Channel(String),
+
+    #[error("Channel store error: {0}")]
+    Store(String),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Simplicity witness error: {0}")]
+    Witness(String),
+
+    #[error("Simplicity execution error: {0}")]
+    Execution(String),
+
+    #[error("Control block not found for the program's CMR")]
+    ControlBlock,
+
+    #[error("Unexpected response from Esplora: {0}")]
+    EsploraResponse(String),
+
+    #[error("Esplora returned status code {status_code} for {url}")]
+    EsploraStatus { url: String, status_code: i32 },
+
+    #[error("Transaction {0} not found")]
+    TransactionNotFound(elements::Txid),
+
+    #[error("Transaction {txid} was rejected: {reason}")]
+    BroadcastRejected {
+        txid: elements::Txid,
+        reason: String,
+    },
+
+    #[error("Invalid signature for {0}")]
+    Signature(String),
+
+    #[error("Invalid peer message: {0}")]
+    Message(String),
+
+    #[error("Peer protocol error: {0}")]
+    Peer(String),
+
+    #[error("Noise handshake failed: {0}")]
+    Handshake(String),
 }
@@ -1,25 +1,47 @@
 #![allow(unused)]
 
-use elements::{Transaction, Txid};
+use std::str::FromStr;
+
+use elements::encode::serialize_hex;
+use elements::{OutPoint, Transaction, Txid};
 use lwk_wollet::{ElementsNetwork, clients::asyncr::EsploraClient as AsyncClient};
+use serde::Deserialize;
 
 pub(crate) const LIQUIDV1_MAIN_URL: &str = "https://liquid.network/api/";
 pub(crate) const LIQUIDV1_TEST_URL: &str = "https://liquid.network/liquidtestnet/api/";
 
 use crate::error::Error;
+use crate::transaction::import_transaction;
 
 /// Create a new Esplora [`AsyncClient`].
 pub(crate) fn create_client(url: &str, network: ElementsNetwork) -> Result<AsyncClient, Error> {
     Ok(AsyncClient::new(network, url))
 }
 
-/// Broadcast a [`Transaction`] through Esplora.
+/// Broadcast a [`Transaction`] through Esplora's REST API at `url`.
+///
+/// A transaction the node refuses fails with [`Error::BroadcastRejected`], so it
+/// is not mistaken for a transient failure.
 pub(crate) async fn broadcast_transaction(
-    client: &AsyncClient,
+    url: &str,
     transaction: &Transaction,
 ) -> Result<Txid, Error> {
-    let txid = client.broadcast(transaction).await?;
-    Ok(txid)
+    let url = format!("{}tx", url);
+    let response = bitreq::post(&url)
+        .with_body(serialize_hex(transaction))
+        .send_async()
+        .await?;
+
+    match response.status_code {
+        200 => Ok(Txid::from_str(response.as_str()?.trim())?),
+        status_code if is_server_error(status_code) => {
+            Err(Error::EsploraStatus { url, status_code })
+        }
+        _ => Err(Error::BroadcastRejected {
+            txid: transaction.txid(),
+            reason: response.as_str()?.trim().to_string(),
+        }),
+    }
 }
 
 /// Get [`Transaction`]s by [`Txid`]s.
@@ -31,6 +53,102 @@ pub(crate) async fn get_transactions(
     Ok(transactions)
 }
 
+/// Get the [`Transaction`] [`Txid`] through Esplora's REST API at `url`.
+pub(crate) async fn get_transaction(url: &str, txid: &Txid) -> Result<Transaction, Error> {
+    let url = format!("{}tx/{}/hex", url, txid);
+    let response = match get(&url).await {
+        Err(Error::EsploraStatus {
+            status_code: 404, ..
+        }) => return Err(Error::TransactionNotFound(*txid)),
+        response => response?,
+    };
+
+    import_transaction(response.as_str()?)
+}
+
+/// The spending status of a transaction output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputSpend {
+    /// The output is unspent.
+    Unspent,
+    /// The output is spent by [`Txid`], which may still be unconfirmed.
+    Spent { txid: Txid, confirmed: bool },
+}
+
+/// Esplora's `GET /tx/:txid/outspend/:vout` response.
+#[derive(Deserialize)]
+struct OutspendResponse {
+    spent: bool,
+    txid: Option<String>,
+    status: Option<StatusResponse>,
+}
+
+/// Esplora's transaction status object.
+#[derive(Deserialize)]
+struct StatusResponse {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+/// Get the spending status of an [`OutPoint`] through Esplora's REST API at `url`.
+pub(crate) async fn get_output_spend(url: &str, outpoint: &OutPoint) -> Result<OutputSpend, Error> {
+    let url = format!("{}tx/{}/outspend/{}", url, outpoint.txid, outpoint.vout);
+    let response = get(&url).await?;
+
+    let outspend: OutspendResponse = serde_json::from_str(response.as_str()?)?;
+    match (outspend.spent, outspend.txid) {
+        (true, Some(txid)) => Ok(OutputSpend::Spent {
+            txid: Txid::from_str(&txid)?,
+            confirmed: outspend.status.is_some_and(|status| status.confirmed),
+        }),
+        _ => Ok(OutputSpend::Unspent),
+    }
+}
+
+/// Get the height of the block that confirmed [`Txid`] through Esplora's REST
+/// API at `url`, or `None` if it is unconfirmed.
+pub(crate) async fn get_confirmation_height(url: &str, txid: &Txid) -> Result<Option<u32>, Error> {
+    let url = format!("{}tx/{}/status", url, txid);
+    let response = get(&url).await?;
+
+    let status: StatusResponse = serde_json::from_str(response.as_str()?)?;
+    match status.confirmed {
+        true => Ok(status.block_height),
+        false => Ok(None),
+    }
+}
+
+/// Get the height of the chain tip through Esplora's REST API at `url`.
+pub(crate) async fn get_tip_height(url: &str) -> Result<u32, Error> {
+    let url = format!("{}blocks/tip/height", url);
+    let response = get(&url).await?;
+
+    response
+        .as_str()?
+        .trim()
+        .parse()
+        .map_err(|e| Error::EsploraResponse(format!("{} returned an invalid height: {}", url, e)))
+}
+
+/// Send a `GET` request to `url`, failing unless it succeeds.
+async fn get(url: &str) -> Result<bitreq::Response, Error> {
+    let response = bitreq::get(url).send_async().await?;
+    if response.status_code != 200 {
+        return Err(Error::EsploraStatus {
+            url: url.to_string(),
+            status_code: response.status_code,
+        });
+    }
+
+    Ok(response)
+}
+
+/// Whether an HTTP `status_code` reports a busy or failing server, rather than
+/// a bad request.
+pub(crate) fn is_server_error(status_code: i32) -> bool {
+    status_code == 429 || (500..600).contains(&status_code)
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::LazyLock;
@@ -0,0 +1,518 @@
+//! Channel Factories
+//!
+//! A channel factory is a multi-party channel whose settlement pays, before the
+//! participants' balances, the setup outputs of two-party sub-channels. Opening,
+//! resizing and closing sub-channels is done off-chain, by moving the factory to
+//! a state with another allocation; only the factory's setup output is on chain
+//! until the factory closes.
+//!
+//! The txid of the factory's settlement depends on the commitment output it
+//! spends, so the setup outpoint of a sub-channel is only known once the factory
+//! settles. The update that spends a setup output is signed with ECDSA for that
+//! outpoint, so until then neither party could close the sub-channel on its
+//! own, and a balance moved in it would only hold if the counterparty signed a
+//! closing update later. Sub-channels are therefore not safe to use before the
+//! factory settles: they are bound to their setup output at state 0, and only
+//! move to a new state once bound.
+//!
+//! Like multi-party channels, factories are not reachable from the CLI yet and
+//! are only built for their tests.
+
+use elements::bitcoin::Amount;
+use elements::schnorr::XOnlyPublicKey;
+use elements::{Address, OutPoint, Transaction, confidential};
+
+use crate::FEE_AMOUNT;
+use crate::channel::Channel;
+use crate::error::Error;
+use crate::keys::ChannelKeys;
+use crate::multiparty::{MultipartyChannel, ProposedState, StateSignatures};
+use crate::transaction::build_setup_address;
+
+/// A two-party channel funded by a factory's settlement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SubChannel {
+    /// The positions of Alice and Bob among the factory's participants.
+    pub(crate) parties: (usize, usize),
+    /// Alice's update key in the sub-channel.
+    pub(crate) alice_update_pk: XOnlyPublicKey,
+    /// Bob's update key in the sub-channel.
+    pub(crate) bob_update_pk: XOnlyPublicKey,
+    /// The amount locked in the sub-channel's setup output.
+    pub(crate) funding_amount: Amount,
+}
+
+impl SubChannel {
+    /// The 2-of-2 `Setup Address` of the sub-channel.
+    pub(crate) fn setup_address(&self, is_mainnet: bool) -> Result<Address, Error> {
+        build_setup_address(self.alice_update_pk, self.bob_update_pk, is_mainnet)
+    }
+}
+
+/// A multi-party channel allocating part of its capacity to sub-channels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ChannelFactory {
+    /// The multi-party channel at the root of the factory.
+    pub(crate) root: MultipartyChannel,
+    /// The sub-channels at the current state, in the order of their setup
+    /// outputs in the settlement.
+    pub(crate) sub_channels: Vec<SubChannel>,
+}
+
+impl ChannelFactory {
+    /// Turn the multi-party channel `root`, which has no sub-channels yet, into a factory.
+    pub(crate) fn new(root: MultipartyChannel) -> Result<Self, Error> {
+        if !root.outputs.is_empty() {
+            return Err(Error::Channel(
+                "the root channel already pays other outputs".into(),
+            ));
+        }
+
+        Ok(ChannelFactory {
+            root,
+            sub_channels: Vec::new(),
+        })
+    }
+
+    /// Build the unsigned transactions for the next state of the factory,
+    /// allocating `sub_channels` and leaving `balances` to the participants.
+    pub(crate) fn propose_allocation(
+        &self,
+        sub_channels: &[SubChannel],
+        balances: Vec<Amount>,
+    ) -> Result<ProposedState, Error> {
+        let mut outputs = Vec::with_capacity(sub_channels.len());
+        for sub_channel in sub_channels {
+            self.check_sub_channel(sub_channel)?;
+            outputs.push((
+                sub_channel.setup_address(self.root.is_mainnet)?,
+                sub_channel.funding_amount,
+            ));
+        }
+
+        self.root.propose_with_outputs(balances, outputs)
+    }
+
+    /// Move the factory to `proposal`, which allocates `sub_channels`, once
+    /// every participant signed it.
+    pub(crate) fn apply_allocation(
+        &mut self,
+        proposal: ProposedState,
+        sub_channels: Vec<SubChannel>,
+        signatures: &[StateSignatures],
+    ) -> Result<(), Error> {
+        if self.propose_allocation(&sub_channels, proposal.balances.clone())? != proposal {
+            return Err(Error::Channel(
+                "proposal does not allocate these sub-channels".into(),
+            ));
+        }
+
+        self.root.apply(proposal, signatures)?;
+        self.sub_channels = sub_channels;
+
+        Ok(())
+    }
+
+    /// Move the factory to a state allocating `sub_channels` and leaving
+    /// `balances` to the participants, signed with every participant's `keys`,
+    /// in participant order.
+    pub(crate) fn allocate(
+        &mut self,
+        sub_channels: Vec<SubChannel>,
+        balances: Vec<Amount>,
+        keys: &[ChannelKeys],
+    ) -> Result<(), Error> {
+        let proposal = self.propose_allocation(&sub_channels, balances)?;
+        let signatures = keys
+            .iter()
+            .map(|keys| self.root.sign(&proposal, keys))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.apply_allocation(proposal, sub_channels, &signatures)
+    }
+
+    /// Point `channel`, the sub-channel at `index`, at its setup output in the
+    /// factory's confirmed `settlement`.
+    ///
+    /// This changes the identifier of `channel`. The counterparty's signatures
+    /// of the closing update commit to the old setup output, so they are
+    /// dropped: the sub-channel needs a new state before it can be closed.
+    ///
+    /// Fails if `channel` moved past state 0, as no balance moved before the
+    /// factory settled could be closed on.
+    pub(crate) fn bind_sub_channel(
+        &self,
+        channel: &mut Channel,
+        index: usize,
+        settlement: &Transaction,
+    ) -> Result<(), Error> {
+        let sub_channel = self
+            .sub_channels
+            .get(index)
+            .ok_or_else(|| Error::Channel(format!("no sub-channel at position {}", index)))?;
+        if channel.state != 0 {
+            return Err(Error::Channel(format!(
+                "channel {} moved to state {} before its factory settled",
+                channel.id(),
+                channel.state
+            )));
+        }
+        if channel.alice_update_pk != sub_channel.alice_update_pk
+            || channel.bob_update_pk != sub_channel.bob_update_pk
+            || channel.funding_amount != sub_channel.funding_amount
+        {
+            return Err(Error::Channel(format!(
+                "channel {} is not the sub-channel at position {}",
+                channel.id(),
+                index
+            )));
+        }
+
+        let script_pubkey = sub_channel
+            .setup_address(self.root.is_mainnet)?
+            .script_pubkey();
+        let funding_value = confidential::Value::Explicit(sub_channel.funding_amount.to_sat());
+        let is_funded = settlement.output.get(index).is_some_and(|output| {
+            output.script_pubkey == script_pubkey && output.value == funding_value
+        });
+        if !is_funded {
+            return Err(Error::Channel(format!(
+                "settlement {} does not fund the sub-channel at position {}",
+                settlement.txid(),
+                index
+            )));
+        }
+
+        channel.setup_outpoint = OutPoint {
+            txid: settlement.txid(),
+            vout: index as u32,
+        };
+        channel.close_sigs.closing_update.clear();
+
+        Ok(())
+    }
+
+    /// Check that `sub_channel` is between two distinct participants and can
+    /// pay for its own unilateral close.
+    fn check_sub_channel(&self, sub_channel: &SubChannel) -> Result<(), Error> {
+        let (alice, bob) = sub_channel.parties;
+        let participants = self.root.participants.len();
+        if alice == bob || alice >= participants || bob >= participants {
+            return Err(Error::Channel(format!(
+                "sub-channel parties {:?} are not two participants of the factory",
+                sub_channel.parties
+            )));
+        }
+        if sub_channel.funding_amount <= Amount::from_sat(2 * FEE_AMOUNT) {
+            return Err(Error::Channel(format!(
+                "sub-channel of {} does not cover the fees of its close",
+                sub_channel.funding_amount
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::Txid;
+    use elements::hashes::Hash;
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::channel::Party;
+    use crate::keys::SEED_LEN;
+    use crate::multiparty::Participant;
+    use crate::transaction::{bind_commitment_inputs, lock_time_state};
+
+    use super::*;
+
+    /// The seeds of the factory's participants.
+    const SEEDS: [u8; 3] = [0x0a, 0x0b, 0x0c];
+
+    /// The channel index of the factory's keys. Sub-channels use the next ones.
+    const FACTORY_INDEX: u32 = 0;
+
+    fn keys(participant: usize, channel_index: u32) -> ChannelKeys {
+        ChannelKeys::derive(&[SEEDS[participant]; SEED_LEN], channel_index, false).unwrap()
+    }
+
+    fn address() -> Address {
+        Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap()
+    }
+
+    /// A sub-channel between participants `alice` and `bob`, with their keys
+    /// at `channel_index`.
+    fn sub_channel(alice: usize, bob: usize, channel_index: u32, amount: u64) -> SubChannel {
+        SubChannel {
+            parties: (alice, bob),
+            alice_update_pk: keys(alice, channel_index).update_pk(),
+            bob_update_pk: keys(bob, channel_index).update_pk(),
+            funding_amount: Amount::from_sat(amount),
+        }
+    }
+
+    /// The two-party channel of `sub_channel`, before the factory settles.
+    fn open_sub_channel(sub_channel: &SubChannel, channel_index: u32) -> Channel {
+        let (alice, bob) = sub_channel.parties;
+        Channel::new(
+            false,
+            OutPoint::default(),
+            sub_channel.funding_amount,
+            sub_channel.alice_update_pk,
+            sub_channel.bob_update_pk,
+            keys(alice, channel_index).settlement_basepoint(),
+            keys(bob, channel_index).settlement_basepoint(),
+            address(),
+            address(),
+            channel_index,
+            String::new(),
+            Party::Alice,
+        )
+        .unwrap()
+    }
+
+    /// A factory between three participants, where participants 0 and 1 and
+    /// participants 1 and 2 have a sub-channel at state 1, and participants 0
+    /// and 1 and participants 0 and 2 have one at state 2.
+    fn factory() -> (ChannelFactory, Vec<ChannelKeys>, Transaction) {
+        let factory_keys: Vec<_> = (0..SEEDS.len()).map(|i| keys(i, FACTORY_INDEX)).collect();
+        let participants = factory_keys
+            .iter()
+            .map(|keys| Participant::from_keys(keys, address()))
+            .collect();
+        let root = MultipartyChannel::new(
+            false,
+            OutPoint {
+                txid: Txid::from_byte_array([0x01; 32]),
+                vout: 0,
+            },
+            Amount::from_sat(20_000),
+            participants,
+        )
+        .unwrap();
+        let mut factory = ChannelFactory::new(root).unwrap();
+        let capacity = factory.root.capacity().to_sat();
+
+        factory
+            .allocate(
+                vec![sub_channel(0, 1, 1, 6_000), sub_channel(1, 2, 2, 5_000)],
+                vec![
+                    Amount::from_sat(capacity - 11_000 - 2_000),
+                    Amount::from_sat(2_000),
+                    Amount::ZERO,
+                ],
+                &factory_keys,
+            )
+            .unwrap();
+        let stale_update = factory
+            .root
+            .build_signed_closing_update(&factory_keys[1])
+            .unwrap();
+
+        // The sub-channel between participants 1 and 2 is closed into their
+        // balances, and participants 0 and 2 open one.
+        factory
+            .allocate(
+                vec![sub_channel(0, 1, 1, 6_000), sub_channel(0, 2, 3, 4_000)],
+                vec![
+                    Amount::from_sat(capacity - 10_000 - 5_000),
+                    Amount::from_sat(2_000),
+                    Amount::from_sat(3_000),
+                ],
+                &factory_keys,
+            )
+            .unwrap();
+
+        (factory, factory_keys, stale_update)
+    }
+
+    #[test]
+    fn reject_invalid_sub_channels() {
+        let (mut factory, factory_keys, _) = factory();
+        let capacity = factory.root.capacity().to_sat();
+        let balances = vec![
+            Amount::from_sat(capacity - 6_000),
+            Amount::ZERO,
+            Amount::ZERO,
+        ];
+
+        // A participant cannot open a sub-channel with itself.
+        let with_itself = sub_channel(1, 1, 4, 6_000);
+        assert!(
+            factory
+                .propose_allocation(&[with_itself], balances.clone())
+                .is_err()
+        );
+        // Nor with someone outside the factory.
+        let with_stranger = SubChannel {
+            parties: (0, SEEDS.len()),
+            ..sub_channel(0, 1, 4, 6_000)
+        };
+        assert!(
+            factory
+                .propose_allocation(&[with_stranger], balances.clone())
+                .is_err()
+        );
+        // Sub-channels must be able to close on their own.
+        assert!(
+            factory
+                .propose_allocation(&[sub_channel(0, 1, 4, 2 * FEE_AMOUNT)], balances.clone())
+                .is_err()
+        );
+        // Balances and sub-channels must add up to the factory's capacity.
+        assert!(
+            factory
+                .propose_allocation(&[sub_channel(0, 1, 4, 7_000)], balances.clone())
+                .is_err()
+        );
+
+        // A proposal is applied with the sub-channels it was built for.
+        let proposal = factory
+            .propose_allocation(&[sub_channel(0, 1, 4, 6_000)], balances)
+            .unwrap();
+        let signatures: Vec<_> = factory_keys
+            .iter()
+            .map(|keys| factory.root.sign(&proposal, keys).unwrap())
+            .collect();
+        assert!(
+            factory
+                .clone()
+                .apply_allocation(
+                    proposal.clone(),
+                    vec![sub_channel(0, 2, 4, 6_000)],
+                    &signatures
+                )
+                .is_err()
+        );
+        factory
+            .apply_allocation(proposal, vec![sub_channel(0, 1, 4, 6_000)], &signatures)
+            .unwrap();
+        assert_eq!(factory.root.state, 3);
+    }
+
+    #[test]
+    fn close_factory_into_sub_channels() {
+        let (factory, factory_keys, _) = factory();
+
+        // Participants 0 and 1 open their sub-channel, but move no balance in
+        // it before the factory settles: neither could close on it yet.
+        let mut channel = open_sub_channel(&factory.sub_channels[0], 1);
+        let (alice_keys, bob_keys) = (keys(0, 1), keys(1, 1));
+        let mut early = channel.clone();
+        early
+            .update(
+                Amount::from_sat(1_000),
+                early.capacity() - Amount::from_sat(1_000),
+                &alice_keys,
+                &bob_keys,
+            )
+            .unwrap();
+
+        // The factory closes from its setup output.
+        let update = factory
+            .root
+            .build_signed_closing_update(&factory_keys[1])
+            .unwrap();
+        let mut settlement = factory.root.latest_settlement.clone().unwrap();
+        bind_commitment_inputs(
+            &mut settlement,
+            OutPoint {
+                txid: update.txid(),
+                vout: 0,
+            },
+        );
+
+        // Its settlement funds both sub-channels, then pays the balances.
+        let funded: Vec<_> = settlement.output[..2]
+            .iter()
+            .map(|output| output.value.explicit().unwrap())
+            .collect();
+        assert_eq!(funded, [6_000, 4_000]);
+        assert_eq!(settlement.output.len(), 2 + 3 + 1);
+
+        // The sub-channel closes from its setup output in the settlement.
+        let mut other = open_sub_channel(&factory.sub_channels[1], 3);
+        assert!(
+            factory
+                .bind_sub_channel(&mut other, 0, &settlement)
+                .is_err()
+        );
+        assert!(
+            factory
+                .bind_sub_channel(&mut early, 0, &settlement)
+                .is_err()
+        );
+        factory
+            .bind_sub_channel(&mut channel, 0, &settlement)
+            .unwrap();
+
+        // Once bound, its first state signs a closing update for its setup output.
+        assert!(channel.build_signed_closing_update(&alice_keys).is_err());
+        channel
+            .update(
+                Amount::from_sat(2_000),
+                channel.capacity() - Amount::from_sat(2_000),
+                &alice_keys,
+                &bob_keys,
+            )
+            .unwrap();
+        let sub_update = channel.build_signed_closing_update(&alice_keys).unwrap();
+        assert_eq!(
+            sub_update.input[0].previous_output,
+            OutPoint {
+                txid: settlement.txid(),
+                vout: 0,
+            }
+        );
+        assert_eq!(lock_time_state(sub_update.lock_time), Some(1));
+    }
+
+    #[test]
+    fn close_factory_from_stale_state() {
+        let (factory, factory_keys, stale_update) = factory();
+
+        // A participant publishes the state where participants 1 and 2 still
+        // had a sub-channel, and the others override it with the latest state.
+        let stale_prevout = OutPoint {
+            txid: stale_update.txid(),
+            vout: 0,
+        };
+        let fee_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x02; 32]),
+            vout: 1,
+        };
+        let fee_input = factory
+            .root
+            .sign_fee_input(fee_outpoint, &factory_keys[0])
+            .unwrap();
+        let update = factory
+            .root
+            .rebind_latest_update(stale_prevout, 1, &fee_input)
+            .unwrap();
+        assert_eq!(update.input[0].previous_output, stale_prevout);
+
+        // The commitment output holds the same amount, so the stored settlement
+        // spends it and allocates the sub-channels of the latest state.
+        let settlement = factory
+            .root
+            .build_signed_settlement(
+                OutPoint {
+                    txid: update.txid(),
+                    vout: 0,
+                },
+                factory.root.commitment_amount(),
+            )
+            .unwrap();
+        let latest = factory.root.latest_settlement.as_ref().unwrap();
+        assert_eq!(settlement.output, latest.output);
+
+        let mut channel = open_sub_channel(&factory.sub_channels[1], 3);
+        factory
+            .bind_sub_channel(&mut channel, 1, &settlement)
+            .unwrap();
+        assert_eq!(channel.setup_outpoint.vout, 1);
+    }
+}
@@ -0,0 +1,250 @@
+//! Fuzzing
+//!
+//! Entry points for the `cargo-fuzz` targets in `fuzz/`, one per kind of data
+//! an attacker controls: encrypted frames, the peer messages they carry, the
+//! signatures carried in witnesses, and whole transactions. None of them may
+//! panic, whatever their input.
+//!
+//! Transactions are verified against the channel between the update keys of
+//! the first setup address test vector, so that the seed corpus built from the
+//! test vectors reaches the signature checks.
+
+use std::sync::OnceLock;
+
+use elements::bitcoin::Amount;
+use elements::encode::{deserialize, serialize};
+use elements::hashes::Hash;
+use elements::secp256k1_zkp::{self, PublicKey, SECP256K1, SecretKey, XOnlyPublicKey};
+use elements::secp256k1_zkp::{ecdsa, schnorr};
+use elements::{OutPoint, Transaction, Txid};
+use simplicityhl::CompiledProgram;
+
+use crate::FEE_AMOUNT;
+use crate::message::{LENGTH_PREFIX_LEN, MAX_FRAME_LEN, Message};
+use crate::noise::{TAG_LEN, Transport};
+use crate::script::{build_new_commitment_script, derive_address};
+use crate::sign::{
+    AdaptorSignature, FeeInput, SignedUpdate, derive_settlement_key, find_completed_signature,
+    sign_adaptor, sign_fee_input, sign_update_transaction, verify_anyprevout_input,
+    verify_setup_spending_input,
+};
+use crate::transaction::{build_update_transaction, rebind_update_transaction};
+
+/// The amount of the setup output spent by fuzzed closing updates.
+const FUNDING_AMOUNT: Amount = Amount::from_sat(100_000);
+
+/// The amount of the commitment outputs spent by fuzzed updates and
+/// settlements.
+const COMMITMENT_AMOUNT: Amount = Amount::from_sat(100_000 - FEE_AMOUNT);
+
+/// The sending and receiving keys of the peer whose frames are fuzzed.
+const FRAME_KEYS: ([u8; 32], [u8; 32]) = ([0x01; 32], [0x02; 32]);
+
+/// Alice's and Bob's update keys.
+fn update_sks() -> (SecretKey, SecretKey) {
+    (
+        SecretKey::from_slice(&[0xcd; 32]).unwrap(),
+        SecretKey::from_slice(&[0xee; 32]).unwrap(),
+    )
+}
+
+/// Alice's and Bob's update public keys.
+fn update_pks() -> (XOnlyPublicKey, XOnlyPublicKey) {
+    let (alice_update_sk, bob_update_sk) = update_sks();
+
+    (
+        alice_update_sk.x_only_public_key(SECP256K1).0,
+        bob_update_sk.x_only_public_key(SECP256K1).0,
+    )
+}
+
+/// The `Update Transaction` of state 1 signed by Alice and Bob, the commitment
+/// program of state 0 that stale updates lock their outputs with, and the fee
+/// input that pays for the update once rebound.
+fn signed_update() -> &'static (SignedUpdate, CompiledProgram, FeeInput) {
+    static SIGNED_UPDATE: OnceLock<(SignedUpdate, CompiledProgram, FeeInput)> = OnceLock::new();
+
+    SIGNED_UPDATE.get_or_init(|| {
+        let (alice_update_sk, bob_update_sk) = update_sks();
+        let (alice_update_pk, bob_update_pk) = update_pks();
+        let commitment_program = |state| {
+            build_new_commitment_script(
+                alice_update_pk,
+                bob_update_pk,
+                derive_settlement_key(&alice_update_sk, state)
+                    .x_only_public_key(SECP256K1)
+                    .0,
+                derive_settlement_key(&bob_update_sk, state)
+                    .x_only_public_key(SECP256K1)
+                    .0,
+                state,
+            )
+            .unwrap()
+        };
+
+        let update = build_update_transaction(
+            OutPoint::default(),
+            COMMITMENT_AMOUNT,
+            &derive_address(&commitment_program(1), false),
+            1,
+            &[],
+            false,
+        );
+        let signed_update = sign_update_transaction(
+            &update,
+            COMMITMENT_AMOUNT,
+            &[],
+            &alice_update_sk,
+            &bob_update_sk,
+            false,
+        )
+        .unwrap();
+        let fee_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x02; 32]),
+            vout: 1,
+        };
+        let fee_sk = SecretKey::from_slice(&[0xfe; 32]).unwrap();
+        let fee_input = sign_fee_input(&update, fee_outpoint, &fee_sk);
+
+        (signed_update, commitment_program(0), fee_input)
+    })
+}
+
+/// An adaptor signature, and its adaptor point, to look for in witnesses.
+fn adaptor() -> &'static (AdaptorSignature, PublicKey) {
+    static ADAPTOR: OnceLock<(AdaptorSignature, PublicKey)> = OnceLock::new();
+
+    ADAPTOR.get_or_init(|| {
+        let secret_key = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let adaptor_point = SecretKey::from_slice(&[0x42; 32])
+            .unwrap()
+            .public_key(SECP256K1);
+        let message = secp256k1_zkp::Message::from_digest([0x01; 32]);
+
+        (
+            sign_adaptor(&message, &secret_key, &adaptor_point).unwrap(),
+            adaptor_point,
+        )
+    })
+}
+
+/// Decode the body of a peer message frame. Any message decoded must survive
+/// a round-trip through its encoding.
+pub fn peer_message(data: &[u8]) {
+    if let Ok(message) = Message::decode(data) {
+        let frame = message.encode();
+        assert_eq!(
+            Message::decode(&frame[LENGTH_PREFIX_LEN..]).ok(),
+            Some(message)
+        );
+    }
+}
+
+/// Decrypt a frame the way a peer connection reads one: its encrypted length
+/// prefix, then a body of that length holding a peer message. Any frame the
+/// peer encrypts must decrypt back to its body.
+pub fn transport_frame(data: &[u8]) {
+    let remote_node_id = SecretKey::from_slice(&[0x0b; 32])
+        .unwrap()
+        .public_key(SECP256K1);
+    let (sending_key, receiving_key) = FRAME_KEYS;
+
+    let mut peer = Transport::with_keys(sending_key, receiving_key, remote_node_id);
+    let mut transport = Transport::with_keys(receiving_key, sending_key, remote_node_id);
+    let frame = peer.encrypt_frame(data.len() as u32, data);
+    let (length, body) = frame.split_at(LENGTH_PREFIX_LEN + TAG_LEN);
+    assert_eq!(
+        transport.decrypt_length(length.try_into().unwrap()).ok(),
+        Some(data.len() as u32)
+    );
+    assert_eq!(transport.decrypt_body(body).ok(), Some(data.to_vec()));
+
+    let mut transport = Transport::with_keys(receiving_key, sending_key, remote_node_id);
+    let Some((length, rest)) = data.split_first_chunk::<{ LENGTH_PREFIX_LEN + TAG_LEN }>() else {
+        return;
+    };
+    let Ok(length) = transport.decrypt_length(length) else {
+        return;
+    };
+    if length > MAX_FRAME_LEN {
+        return;
+    }
+    if let Some(body) = rest.get(..length as usize + TAG_LEN)
+        && let Ok(body) = transport.decrypt_body(body)
+    {
+        peer_message(&body);
+    }
+}
+
+/// Parse a witness element as each kind of signature it may carry: a DER
+/// ECDSA signature followed by its sighash byte, a BIP340 signature, or a
+/// completed adaptor signature anywhere in a serialized Simplicity witness.
+pub fn witness_signatures(data: &[u8]) {
+    if let Some((_, der)) = data.split_last() {
+        let _ = ecdsa::Signature::from_der(der);
+    }
+    let _ = schnorr::Signature::from_slice(data);
+
+    let (adaptor, adaptor_point) = adaptor();
+    let _ = find_completed_signature(adaptor, adaptor_point, data);
+}
+
+/// Parse a transaction, then verify each signature in the witness of its first
+/// input: ECDSA ones as signatures of a closing `Update Transaction` spending
+/// the setup output, and BIP340 ones as signatures of a commitment output
+/// spend. Finally, rebind the signed update of state 1 to the transaction, as
+/// if the peer had published it as a stale `Update Transaction`.
+pub fn transaction_verification(data: &[u8]) {
+    let Ok(transaction) = deserialize::<Transaction>(data) else {
+        return;
+    };
+    assert_eq!(
+        deserialize::<Transaction>(&serialize(&transaction)).ok(),
+        Some(transaction.clone())
+    );
+
+    let (alice_update_pk, bob_update_pk) = update_pks();
+    if let Some(input) = transaction.input.first() {
+        for element in &input.witness.script_witness {
+            if let Some((_, der)) = element.split_last()
+                && let Ok(signature) = ecdsa::Signature::from_der(der)
+            {
+                for signer_pk in [&alice_update_pk, &bob_update_pk] {
+                    let _ = verify_setup_spending_input(
+                        &transaction,
+                        0,
+                        FUNDING_AMOUNT,
+                        &alice_update_pk,
+                        &bob_update_pk,
+                        signer_pk,
+                        &signature,
+                    );
+                }
+            }
+            if let Ok(signature) = schnorr::Signature::from_slice(element) {
+                let _ = verify_anyprevout_input(
+                    &transaction,
+                    COMMITMENT_AMOUNT,
+                    &alice_update_pk,
+                    &signature,
+                    false,
+                );
+            }
+        }
+    }
+
+    let (signed_update, stale_program, fee_input) = signed_update();
+    if let Ok(rebound) =
+        rebind_update_transaction(signed_update, &transaction, stale_program, fee_input)
+    {
+        let stale_prevout = OutPoint {
+            txid: transaction.txid(),
+            vout: 0,
+        };
+        assert_eq!(rebound.input[0].previous_output, stale_prevout);
+        assert_eq!(
+            rebound.input.last().unwrap().previous_output,
+            fee_input.outpoint
+        );
+    }
+}
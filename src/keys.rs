@@ -0,0 +1,342 @@
+//! Seeds, Channel Keys and Key Files
+//!
+//! Every channel key is derived from a single BIP32 seed, so backing up the
+//! seed is enough to restore the keys of every channel:
+//!
+//! ```text
+//! m / 7070' / coin_type' / channel_index' / role'
+//! ```
+//!
+//! Where `coin_type` is `1776` on Liquid V1 and `1` on Liquid TestnetV1 (SLIP-44),
+//! and `role` is `0` for the update key, `1` for the settlement root key and `2`
+//! for the fee key, whose outputs pay the fee of updates that override a stale one.
+//! The last hardened channel index is reserved for the node key, which
+//! authenticates us to peers: `m / 7070' / coin_type' / 2147483647'`.
+//! Per-state settlement keys are derived from the settlement root key with
+//! [`derive_settlement_key`]. Its public key, the settlement basepoint, is sent
+//! to the counterparty once, who derives our settlement public key for any state
+//! with [`derive_settlement_pubkey`](crate::sign::derive_settlement_pubkey).
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use elements::bitcoin::NetworkKind;
+use elements::bitcoin::bip32::{ChildNumber, Xpriv};
+use elements::bitcoin::hex::{DisplayHex, FromHex};
+use elements::secp256k1_zkp::{PublicKey, SECP256K1, SecretKey, XOnlyPublicKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use crate::error::Error;
+use crate::sign::derive_settlement_key;
+
+/// The size of a seed, in bytes.
+pub(crate) const SEED_LEN: usize = 32;
+
+/// The BIP32 purpose of SimplEltoo channel keys.
+const ELTOO_PURPOSE: u32 = 7070;
+/// The SLIP-44 coin type of Liquid V1.
+const LIQUIDV1_MAIN_COIN_TYPE: u32 = 1776;
+/// The SLIP-44 coin type of every testnet.
+const TEST_COIN_TYPE: u32 = 1;
+
+/// The channel index reserved for the node key.
+const NODE_KEY_INDEX: u32 = (1 << 31) - 1;
+
+/// The role of a channel key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+enum KeyRole {
+    /// The update key, which is the same for every state.
+    Update = 0,
+    /// The root of the per-state settlement keys.
+    SettlementRoot = 1,
+    /// The key of the outputs paying the fee of an override.
+    Fee = 2,
+}
+
+/// One party's keys for a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ChannelKeys {
+    /// The update key.
+    pub(crate) update_sk: SecretKey,
+    /// The root of the per-state settlement keys.
+    pub(crate) settlement_root_sk: SecretKey,
+    /// The key of the outputs paying the fee of an override.
+    pub(crate) fee_sk: SecretKey,
+}
+
+impl ChannelKeys {
+    /// Derive the keys of the channel at `channel_index` from a BIP32 seed.
+    pub(crate) fn derive(
+        seed: &[u8; SEED_LEN],
+        channel_index: u32,
+        is_mainnet: bool,
+    ) -> Result<Self, Error> {
+        if channel_index == NODE_KEY_INDEX {
+            return Err(Error::Key(format!(
+                "channel index {} is reserved for the node key",
+                channel_index
+            )));
+        }
+
+        Ok(ChannelKeys {
+            update_sk: derive_hardened(seed, is_mainnet, &[channel_index, KeyRole::Update as u32])?,
+            settlement_root_sk: derive_hardened(
+                seed,
+                is_mainnet,
+                &[channel_index, KeyRole::SettlementRoot as u32],
+            )?,
+            fee_sk: derive_hardened(seed, is_mainnet, &[channel_index, KeyRole::Fee as u32])?,
+        })
+    }
+
+    /// The update [`XOnlyPublicKey`].
+    pub(crate) fn update_pk(&self) -> XOnlyPublicKey {
+        self.update_sk.x_only_public_key(SECP256K1).0
+    }
+
+    /// The settlement basepoint, shared with the counterparty when opening
+    /// the channel.
+    pub(crate) fn settlement_basepoint(&self) -> PublicKey {
+        self.settlement_root_sk.public_key(SECP256K1)
+    }
+
+    /// The settlement [`SecretKey`] for `state`.
+    pub(crate) fn settlement_sk(&self, state: u32) -> SecretKey {
+        derive_settlement_key(&self.settlement_root_sk, state as u64)
+    }
+
+    /// The fee [`PublicKey`], which the fee address pays to.
+    pub(crate) fn fee_pk(&self) -> PublicKey {
+        self.fee_sk.public_key(SECP256K1)
+    }
+}
+
+/// Derive the node key, which authenticates us to peers, from a BIP32 seed.
+pub(crate) fn derive_node_key(seed: &[u8; SEED_LEN], is_mainnet: bool) -> Result<SecretKey, Error> {
+    derive_hardened(seed, is_mainnet, &[NODE_KEY_INDEX])
+}
+
+/// Derive the key at `m / 7070' / coin_type' / path'` from a BIP32 seed.
+fn derive_hardened(
+    seed: &[u8; SEED_LEN],
+    is_mainnet: bool,
+    path: &[u32],
+) -> Result<SecretKey, Error> {
+    let (network, coin_type) = match is_mainnet {
+        true => (NetworkKind::Main, LIQUIDV1_MAIN_COIN_TYPE),
+        false => (NetworkKind::Test, TEST_COIN_TYPE),
+    };
+    let master = Xpriv::new_master(network, seed).map_err(|e| Error::Key(e.to_string()))?;
+
+    let path = [ELTOO_PURPOSE, coin_type]
+        .iter()
+        .chain(path)
+        .map(|&index| ChildNumber::from_hardened_idx(index))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::Key(e.to_string()))?;
+    let xpriv = master
+        .derive_priv(SECP256K1, &path)
+        .map_err(|e| Error::Key(e.to_string()))?;
+
+    Ok(xpriv.private_key)
+}
+
+/// Generate a new seed from the operating system's secure RNG.
+pub(crate) fn generate_seed() -> [u8; SEED_LEN] {
+    let mut seed = [0u8; SEED_LEN];
+    OsRng.fill_bytes(&mut seed);
+
+    seed
+}
+
+/// Export a seed as hex.
+pub(crate) fn export_seed(seed: &[u8; SEED_LEN]) -> String {
+    seed.to_lower_hex_string()
+}
+
+/// Import a seed from hex.
+pub(crate) fn import_seed(hex: &str) -> Result<[u8; SEED_LEN], Error> {
+    <[u8; SEED_LEN]>::from_hex(hex.trim()).map_err(|e| Error::Key(e.to_string()))
+}
+
+/// Write a seed to a new seed file, readable only by its owner.
+///
+/// Refuses to overwrite an existing file, so a seed cannot be lost by mistake.
+pub(crate) fn write_seed_file(path: &Path, seed: &[u8; SEED_LEN]) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(export_seed(seed).as_bytes())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Read a seed from a seed file.
+pub(crate) fn read_seed_file(path: &Path) -> Result<[u8; SEED_LEN], Error> {
+    import_seed(&fs::read_to_string(path)?)
+        .map_err(|e| Error::Key(format!("{}: {}", path.display(), e)))
+}
+
+/// Check that Alice's and Bob's keys are distinct.
+///
+/// If both parties used the same key, either of them could sign for the
+/// other, and the 2-of-2 would be a 1-of-1.
+pub(crate) fn ensure_distinct_keys(
+    alice_pk: &XOnlyPublicKey,
+    bob_pk: &XOnlyPublicKey,
+) -> Result<(), Error> {
+    if alice_pk == bob_pk {
+        return Err(Error::DuplicateKeys(alice_pk.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Check that no two participants of a multi-party channel share a key.
+#[cfg(test)]
+pub(crate) fn ensure_all_distinct_keys(pks: &[XOnlyPublicKey]) -> Result<(), Error> {
+    for (index, pk) in pks.iter().enumerate() {
+        for other_pk in &pks[index + 1..] {
+            ensure_distinct_keys(pk, other_pk)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::sign::derive_settlement_pubkey;
+
+    use super::*;
+
+    #[test]
+    fn seed_roundtrip() {
+        let alice_seed = generate_seed();
+        let bob_seed = generate_seed();
+        assert_ne!(alice_seed, bob_seed);
+
+        let exported = export_seed(&alice_seed);
+        assert_eq!(exported.len(), 2 * SEED_LEN);
+        assert_eq!(import_seed(&exported).unwrap(), alice_seed);
+        assert!(import_seed("not a seed").is_err());
+
+        let path = std::env::temp_dir().join(format!("simpleltoo-{}.seed", std::process::id()));
+        write_seed_file(&path, &alice_seed).unwrap();
+        assert!(write_seed_file(&path, &bob_seed).is_err());
+        assert_eq!(read_seed_file(&path).unwrap(), alice_seed);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn derive_channel_keys() {
+        let seed = [0x42; SEED_LEN];
+
+        // Restoring from the same seed yields the same keys.
+        let keys = ChannelKeys::derive(&seed, 0, false).unwrap();
+        assert_eq!(keys, ChannelKeys::derive(&seed, 0, false).unwrap());
+
+        // Each channel, network and role gets its own keys.
+        let other_channel = ChannelKeys::derive(&seed, 1, false).unwrap();
+        let mainnet = ChannelKeys::derive(&seed, 0, true).unwrap();
+        assert_ne!(keys.update_sk, other_channel.update_sk);
+        assert_ne!(keys.update_sk, mainnet.update_sk);
+        assert_ne!(keys.update_sk, keys.settlement_root_sk);
+        assert_ne!(keys.fee_sk, keys.update_sk);
+        assert_ne!(keys.fee_sk, keys.settlement_root_sk);
+
+        // The node key is apart from every channel's keys.
+        let node_sk = derive_node_key(&seed, false).unwrap();
+        assert_ne!(node_sk, keys.update_sk);
+        assert_eq!(node_sk, derive_node_key(&seed, false).unwrap());
+        assert!(ChannelKeys::derive(&seed, NODE_KEY_INDEX, false).is_err());
+
+        // Each state gets its own settlement key.
+        assert_ne!(keys.settlement_sk(1), keys.settlement_sk(2));
+        assert_eq!(
+            keys.settlement_sk(1),
+            derive_settlement_key(&keys.settlement_root_sk, 1)
+        );
+
+        // The counterparty derives the same settlement public key from the basepoint.
+        assert_eq!(
+            keys.settlement_sk(1).public_key(SECP256K1),
+            derive_settlement_pubkey(&keys.settlement_basepoint(), 1)
+        );
+    }
+
+    #[test]
+    fn settlement_key_vectors() {
+        // (settlement root, state, basepoint, settlement key, settlement public key)
+        let vectors = [
+            (
+                [0x11; 32],
+                0,
+                "034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa",
+                "3a6a1ae5b474a6ec14e8f78724796cc63f8dc10872e85daddf78431235d7b293",
+                "02ccd2b0c921aa5e5698251d86ef411e9f80c9497d8c0c9f37af2b583085cbe46e",
+            ),
+            (
+                [0x11; 32],
+                1,
+                "034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa",
+                "b8885bfcc57dfc587f221509e0d469c4da948c4e9a2a37d383a69dd16c9426d9",
+                "034241dd6399bc611722abcc02b45b29950966640e40f3376c4a3220d7659ea8f8",
+            ),
+            (
+                [0x11; 32],
+                42,
+                "034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa",
+                "463407ed6e3585b766d949d65636268272e14074b9dffec58e562b2569fc5636",
+                "02c02df5aec14c97311ccaf3c5b019977d91ba69889d6308a65535f384267edc6f",
+            ),
+            (
+                [0xcd; 32],
+                u32::MAX as u64,
+                "02b98a7fb8cc007048625b6446ad49a1b3a722df8c1ca975b87160023e14d19097",
+                "218888174c27d1f6cfb4e9cc1a233c1005398342f6c7721acc61aab3ed73300d",
+                "027fd33d01beca0648c050f2cb165d89e09d0649c5798378b1b07aaf9aa0facc1e",
+            ),
+        ];
+
+        for (root, state, basepoint, settlement_sk, settlement_pk) in vectors {
+            let root = SecretKey::from_slice(&root).unwrap();
+            let basepoint = PublicKey::from_str(basepoint).unwrap();
+            assert_eq!(root.public_key(SECP256K1), basepoint);
+
+            let secret = derive_settlement_key(&root, state);
+            let public = derive_settlement_pubkey(&basepoint, state);
+            assert_eq!(secret.display_secret().to_string(), settlement_sk);
+            assert_eq!(public.to_string(), settlement_pk);
+            assert_eq!(secret.public_key(SECP256K1), public);
+        }
+    }
+
+    #[test]
+    fn reject_duplicate_keys() {
+        let alice_pk = ChannelKeys::derive(&generate_seed(), 0, false)
+            .unwrap()
+            .update_pk();
+        let bob_pk = ChannelKeys::derive(&generate_seed(), 0, false)
+            .unwrap()
+            .update_pk();
+
+        assert!(ensure_distinct_keys(&alice_pk, &bob_pk).is_ok());
+        assert!(matches!(
+            ensure_distinct_keys(&alice_pk, &alice_pk),
+            Err(Error::DuplicateKeys(_))
+        ));
+    }
+}
@@ -0,0 +1,76 @@
+//! SimplEltoo
+//!
+//! Eltoo payment channel implementation with SimplycityHL.
+//!
+//! Eltoo, also called LN Symmetry, is a proposed protocol that allows for
+//! securing lightning transactions without the current penalty mechanism,
+//! since publishing an old channel state does is harmless and does not incur
+//! in loss of funds via the penalty transaction. It also allows LN nodes to
+//! only store the latest channel state.
+//!
+//! Using SimplicityHL support on Liquid, we can leverage OP_CTV to implement
+//! Eltoo.
+
+use elements::hashes::sha256::{self, Midstate};
+
+pub use crate::cli::{Cli, run};
+pub use crate::error::Error;
+
+mod channel;
+mod cli;
+mod close;
+mod error;
+mod esplora;
+#[cfg(test)]
+mod factory;
+mod faucet;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+mod keys;
+mod message;
+#[cfg(test)]
+mod multiparty;
+mod noise;
+mod peer;
+mod script;
+mod sign;
+mod store;
+mod tower;
+mod transaction;
+#[cfg(test)]
+mod vectors;
+mod watcher;
+
+// L-BTC Testnet [`AssetId`] midstate.
+pub(crate) const LBTC_TEST_MIDSTATE: Midstate = sha256::Midstate([
+    0x14, 0x4c, 0x65, 0x43, 0x44, 0xaa, 0x71, 0x6d, 0x6f, 0x3a, 0xbc, 0xc1, 0xca, 0x90, 0xe5, 0x64,
+    0x1e, 0x4e, 0x2a, 0x7f, 0x63, 0x3b, 0xc0, 0x9f, 0xe3, 0xba, 0xf6, 0x45, 0x85, 0x81, 0x9a, 0x49,
+]);
+
+/// The default fee value, in sats.
+pub(crate) const FEE_AMOUNT: u64 = 69;
+
+/// The CSV of 10 blocks, from the Eltoo paper.
+///
+/// It is a block-based BIP68 relative lock, so it fits in the low 16 bits of
+/// the `sequence` of a `Settlement Transaction` input.
+pub(crate) const CSV_DELAY: u16 = 10;
+
+/// The offset of state numbers in the `lock_time` of `Update Transaction`s.
+///
+/// As in the Eltoo paper, states are encoded as timestamps in the past, from
+/// 1985 onwards: a `lock_time` of at least 500,000,000 is read as a UNIX time,
+/// so every update is final as soon as it is signed, whatever the state.
+pub(crate) const STATE_LOCK_TIME_OFFSET: u32 = 500_000_000;
+
+/// The highest state number.
+///
+/// Its `lock_time` is 1,500,000,000 (July 2017), before the Liquid genesis
+/// block, so it is below the median time past of any block. Higher states
+/// would encode timestamps that are not reached yet, or ever.
+pub(crate) const MAX_STATE: u32 = 1_000_000_000;
+
+/// Alice's resolution address.
+#[cfg(test)]
+pub(crate) const ALICE_RESOLUTION_ADDRESS: &str =
+    "tex1p53ct8hcvnr7zznfjawxwetycthxyv6c06vh4dk2zymc3c3laps5q94kptw";
@@ -0,0 +1,613 @@
+//! Peer Messages
+//!
+//! The wire protocol spoken between the two parties of a channel. Every message
+//! is sent in a length-prefixed frame:
+//!
+//! ```text
+//! length (u32) || version (u8) || type (u16) || payload
+//! ```
+//!
+//! Where `length` counts the bytes that follow it. Integers are big-endian,
+//! keys and signatures use their fixed-size serializations, and addresses are
+//! sent as a `u16` length followed by their UTF-8 string.
+
+use std::str::FromStr;
+
+use elements::bitcoin::Amount;
+use elements::hashes::Hash;
+use elements::secp256k1_zkp::{PublicKey, XOnlyPublicKey, ecdsa, schnorr};
+use elements::{Address, OutPoint, Txid};
+
+use crate::error::Error;
+
+/// The version of the wire protocol.
+pub(crate) const PROTOCOL_VERSION: u8 = 1;
+
+/// The maximum length of a frame, after its length prefix.
+pub(crate) const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+/// The length of the frame length prefix.
+pub(crate) const LENGTH_PREFIX_LEN: usize = 4;
+
+const OPEN_CHANNEL: u16 = 1;
+const ACCEPT_CHANNEL: u16 = 2;
+const FUNDING_CREATED: u16 = 3;
+const UPDATE_PROPOSED: u16 = 5;
+const UPDATE_SIGNED: u16 = 6;
+const SETTLEMENT_SIGNED: u16 = 7;
+const SHUTDOWN: u16 = 8;
+const CLOSING_SIGNED: u16 = 9;
+const OPEN_DUAL_FUNDED_CHANNEL: u16 = 10;
+const ACCEPT_DUAL_FUNDED_CHANNEL: u16 = 11;
+const TX_ADD_INPUT: u16 = 12;
+const TX_ADD_OUTPUT: u16 = 13;
+const TX_COMPLETE: u16 = 14;
+
+/// A message exchanged between the parties of a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Message {
+    OpenChannel(OpenChannel),
+    AcceptChannel(AcceptChannel),
+    FundingCreated(FundingCreated),
+    UpdateProposed(UpdateProposed),
+    UpdateSigned(UpdateSigned),
+    SettlementSigned(SettlementSigned),
+    Shutdown(Shutdown),
+    ClosingSigned(ClosingSigned),
+    OpenDualFundedChannel(OpenDualFundedChannel),
+    AcceptDualFundedChannel(AcceptDualFundedChannel),
+    TxAddInput(TxAddInput),
+    TxAddOutput(TxAddOutput),
+    TxComplete(TxComplete),
+}
+
+/// Alice asks Bob to open a channel she funds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OpenChannel {
+    /// Whether the channel lives on Liquid V1 or on Liquid TestnetV1.
+    pub(crate) is_mainnet: bool,
+    /// The amount Alice locks in the `Setup Address`.
+    pub(crate) funding_amount: Amount,
+    /// Alice's update key.
+    pub(crate) update_pk: XOnlyPublicKey,
+    /// Alice's settlement basepoint.
+    pub(crate) settlement_basepoint: PublicKey,
+    /// Alice's settlement payout address.
+    pub(crate) payout_address: Address,
+}
+
+/// Bob accepts Alice's channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct AcceptChannel {
+    /// Bob's update key.
+    pub(crate) update_pk: XOnlyPublicKey,
+    /// Bob's settlement basepoint.
+    pub(crate) settlement_basepoint: PublicKey,
+    /// Bob's settlement payout address.
+    pub(crate) payout_address: Address,
+}
+
+/// Alice tells Bob which output funds the `Setup Address`. Both parties then
+/// sign state 0, which refunds them, before she broadcasts the funding
+/// transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FundingCreated {
+    /// The output of the unbroadcast funding transaction.
+    pub(crate) setup_outpoint: OutPoint,
+}
+
+/// A party proposes to move the channel to a new state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct UpdateProposed {
+    /// The setup [`OutPoint`] of the channel.
+    pub(crate) channel: OutPoint,
+    /// The new state number.
+    pub(crate) state: u32,
+    /// Alice's balance at the new state.
+    pub(crate) alice_balance: Amount,
+    /// Bob's balance at the new state.
+    pub(crate) bob_balance: Amount,
+}
+
+/// A party's signatures of the `Update Transaction` for a state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct UpdateSigned {
+    /// The setup [`OutPoint`] of the channel.
+    pub(crate) channel: OutPoint,
+    /// The state number of the update.
+    pub(crate) state: u32,
+    /// The update key signature of the update that overrides a stale one.
+    pub(crate) signature: schnorr::Signature,
+    /// The update key signature of the update that spends the setup output.
+    pub(crate) closing_signature: ecdsa::Signature,
+}
+
+/// A party's signatures of the `Settlement Transaction` for a state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SettlementSigned {
+    /// The setup [`OutPoint`] of the channel.
+    pub(crate) channel: OutPoint,
+    /// The state number of the settlement.
+    pub(crate) state: u32,
+    /// The settlement key signature.
+    pub(crate) signature: schnorr::Signature,
+}
+
+/// A party asks to close the channel cooperatively.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Shutdown {
+    /// The setup [`OutPoint`] of the channel.
+    pub(crate) channel: OutPoint,
+}
+
+/// A party's signature of the cooperative closing transaction paying `fee`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ClosingSigned {
+    /// The setup [`OutPoint`] of the channel.
+    pub(crate) channel: OutPoint,
+    /// The fee paid by the closing transaction.
+    pub(crate) fee: Amount,
+    /// The update key signature of the closing transaction.
+    pub(crate) signature: ecdsa::Signature,
+}
+
+/// Alice asks Bob to open a channel they both fund.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OpenDualFundedChannel {
+    /// Whether the channel lives on Liquid V1 or on Liquid TestnetV1.
+    pub(crate) is_mainnet: bool,
+    /// The amount Alice contributes to the `Setup Address`.
+    pub(crate) funding_amount: Amount,
+    /// Alice's update key.
+    pub(crate) update_pk: XOnlyPublicKey,
+    /// Alice's settlement basepoint.
+    pub(crate) settlement_basepoint: PublicKey,
+    /// Alice's settlement payout address.
+    pub(crate) payout_address: Address,
+}
+
+/// Bob accepts Alice's dual-funded channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct AcceptDualFundedChannel {
+    /// The amount Bob contributes to the `Setup Address`.
+    pub(crate) funding_amount: Amount,
+    /// Bob's update key.
+    pub(crate) update_pk: XOnlyPublicKey,
+    /// Bob's settlement basepoint.
+    pub(crate) settlement_basepoint: PublicKey,
+    /// Bob's settlement payout and refund address.
+    pub(crate) payout_address: Address,
+}
+
+/// A party adds one of its inputs to the funding transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TxAddInput {
+    /// The spent output.
+    pub(crate) previous_output: OutPoint,
+    /// The explicit L-BTC amount of the spent output.
+    pub(crate) amount: Amount,
+}
+
+/// A party adds its change output to the funding transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TxAddOutput {
+    /// The change address.
+    pub(crate) address: Address,
+    /// The L-BTC amount of the change.
+    pub(crate) amount: Amount,
+}
+
+/// A party has added all its inputs and outputs to the funding transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TxComplete;
+
+impl Message {
+    /// The name of the message type.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Message::OpenChannel(_) => "open_channel",
+            Message::AcceptChannel(_) => "accept_channel",
+            Message::FundingCreated(_) => "funding_created",
+            Message::UpdateProposed(_) => "update_proposed",
+            Message::UpdateSigned(_) => "update_signed",
+            Message::SettlementSigned(_) => "settlement_signed",
+            Message::Shutdown(_) => "shutdown",
+            Message::ClosingSigned(_) => "closing_signed",
+            Message::OpenDualFundedChannel(_) => "open_dual_funded_channel",
+            Message::AcceptDualFundedChannel(_) => "accept_dual_funded_channel",
+            Message::TxAddInput(_) => "tx_add_input",
+            Message::TxAddOutput(_) => "tx_add_output",
+            Message::TxComplete(_) => "tx_complete",
+        }
+    }
+
+    /// The wire identifier of the message type.
+    fn message_type(&self) -> u16 {
+        match self {
+            Message::OpenChannel(_) => OPEN_CHANNEL,
+            Message::AcceptChannel(_) => ACCEPT_CHANNEL,
+            Message::FundingCreated(_) => FUNDING_CREATED,
+            Message::UpdateProposed(_) => UPDATE_PROPOSED,
+            Message::UpdateSigned(_) => UPDATE_SIGNED,
+            Message::SettlementSigned(_) => SETTLEMENT_SIGNED,
+            Message::Shutdown(_) => SHUTDOWN,
+            Message::ClosingSigned(_) => CLOSING_SIGNED,
+            Message::OpenDualFundedChannel(_) => OPEN_DUAL_FUNDED_CHANNEL,
+            Message::AcceptDualFundedChannel(_) => ACCEPT_DUAL_FUNDED_CHANNEL,
+            Message::TxAddInput(_) => TX_ADD_INPUT,
+            Message::TxAddOutput(_) => TX_ADD_OUTPUT,
+            Message::TxComplete(_) => TX_COMPLETE,
+        }
+    }
+
+    /// Encode the message into a frame, including its length prefix.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut frame = vec![0u8; LENGTH_PREFIX_LEN];
+        frame.push(PROTOCOL_VERSION);
+        frame.extend_from_slice(&self.message_type().to_be_bytes());
+
+        match self {
+            Message::OpenChannel(m) => {
+                frame.push(m.is_mainnet as u8);
+                write_amount(&mut frame, m.funding_amount);
+                frame.extend_from_slice(&m.update_pk.serialize());
+                frame.extend_from_slice(&m.settlement_basepoint.serialize());
+                write_address(&mut frame, &m.payout_address);
+            }
+            Message::AcceptChannel(m) => {
+                frame.extend_from_slice(&m.update_pk.serialize());
+                frame.extend_from_slice(&m.settlement_basepoint.serialize());
+                write_address(&mut frame, &m.payout_address);
+            }
+            Message::FundingCreated(m) => {
+                write_outpoint(&mut frame, &m.setup_outpoint);
+            }
+            Message::UpdateProposed(m) => {
+                write_outpoint(&mut frame, &m.channel);
+                frame.extend_from_slice(&m.state.to_be_bytes());
+                write_amount(&mut frame, m.alice_balance);
+                write_amount(&mut frame, m.bob_balance);
+            }
+            Message::UpdateSigned(m) => {
+                write_outpoint(&mut frame, &m.channel);
+                frame.extend_from_slice(&m.state.to_be_bytes());
+                frame.extend_from_slice(&m.signature.serialize());
+                frame.extend_from_slice(&m.closing_signature.serialize_compact());
+            }
+            Message::SettlementSigned(m) => {
+                write_outpoint(&mut frame, &m.channel);
+                frame.extend_from_slice(&m.state.to_be_bytes());
+                frame.extend_from_slice(&m.signature.serialize());
+            }
+            Message::Shutdown(m) => {
+                write_outpoint(&mut frame, &m.channel);
+            }
+            Message::ClosingSigned(m) => {
+                write_outpoint(&mut frame, &m.channel);
+                write_amount(&mut frame, m.fee);
+                frame.extend_from_slice(&m.signature.serialize_compact());
+            }
+            Message::OpenDualFundedChannel(m) => {
+                frame.push(m.is_mainnet as u8);
+                write_amount(&mut frame, m.funding_amount);
+                frame.extend_from_slice(&m.update_pk.serialize());
+                frame.extend_from_slice(&m.settlement_basepoint.serialize());
+                write_address(&mut frame, &m.payout_address);
+            }
+            Message::AcceptDualFundedChannel(m) => {
+                write_amount(&mut frame, m.funding_amount);
+                frame.extend_from_slice(&m.update_pk.serialize());
+                frame.extend_from_slice(&m.settlement_basepoint.serialize());
+                write_address(&mut frame, &m.payout_address);
+            }
+            Message::TxAddInput(m) => {
+                write_outpoint(&mut frame, &m.previous_output);
+                write_amount(&mut frame, m.amount);
+            }
+            Message::TxAddOutput(m) => {
+                write_address(&mut frame, &m.address);
+                write_amount(&mut frame, m.amount);
+            }
+            Message::TxComplete(_) => {}
+        }
+
+        let length = (frame.len() - LENGTH_PREFIX_LEN) as u32;
+        frame[..LENGTH_PREFIX_LEN].copy_from_slice(&length.to_be_bytes());
+
+        frame
+    }
+
+    /// Decode a message from the body of a frame, after its length prefix.
+    pub(crate) fn decode(body: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes: body };
+
+        let version = reader.u8()?;
+        if version != PROTOCOL_VERSION {
+            return Err(Error::Message(format!(
+                "unsupported protocol version {}",
+                version
+            )));
+        }
+
+        let message = match reader.u16()? {
+            OPEN_CHANNEL => Message::OpenChannel(OpenChannel {
+                is_mainnet: reader.bool()?,
+                funding_amount: reader.amount()?,
+                update_pk: reader.x_only_public_key()?,
+                settlement_basepoint: reader.public_key()?,
+                payout_address: reader.address()?,
+            }),
+            ACCEPT_CHANNEL => Message::AcceptChannel(AcceptChannel {
+                update_pk: reader.x_only_public_key()?,
+                settlement_basepoint: reader.public_key()?,
+                payout_address: reader.address()?,
+            }),
+            FUNDING_CREATED => Message::FundingCreated(FundingCreated {
+                setup_outpoint: reader.outpoint()?,
+            }),
+            UPDATE_PROPOSED => Message::UpdateProposed(UpdateProposed {
+                channel: reader.outpoint()?,
+                state: reader.u32()?,
+                alice_balance: reader.amount()?,
+                bob_balance: reader.amount()?,
+            }),
+            UPDATE_SIGNED => Message::UpdateSigned(UpdateSigned {
+                channel: reader.outpoint()?,
+                state: reader.u32()?,
+                signature: reader.schnorr_signature()?,
+                closing_signature: reader.ecdsa_signature()?,
+            }),
+            SETTLEMENT_SIGNED => Message::SettlementSigned(SettlementSigned {
+                channel: reader.outpoint()?,
+                state: reader.u32()?,
+                signature: reader.schnorr_signature()?,
+            }),
+            SHUTDOWN => Message::Shutdown(Shutdown {
+                channel: reader.outpoint()?,
+            }),
+            CLOSING_SIGNED => Message::ClosingSigned(ClosingSigned {
+                channel: reader.outpoint()?,
+                fee: reader.amount()?,
+                signature: reader.ecdsa_signature()?,
+            }),
+            OPEN_DUAL_FUNDED_CHANNEL => Message::OpenDualFundedChannel(OpenDualFundedChannel {
+                is_mainnet: reader.bool()?,
+                funding_amount: reader.amount()?,
+                update_pk: reader.x_only_public_key()?,
+                settlement_basepoint: reader.public_key()?,
+                payout_address: reader.address()?,
+            }),
+            ACCEPT_DUAL_FUNDED_CHANNEL => {
+                Message::AcceptDualFundedChannel(AcceptDualFundedChannel {
+                    funding_amount: reader.amount()?,
+                    update_pk: reader.x_only_public_key()?,
+                    settlement_basepoint: reader.public_key()?,
+                    payout_address: reader.address()?,
+                })
+            }
+            TX_ADD_INPUT => Message::TxAddInput(TxAddInput {
+                previous_output: reader.outpoint()?,
+                amount: reader.amount()?,
+            }),
+            TX_ADD_OUTPUT => Message::TxAddOutput(TxAddOutput {
+                address: reader.address()?,
+                amount: reader.amount()?,
+            }),
+            TX_COMPLETE => Message::TxComplete(TxComplete),
+            message_type => {
+                return Err(Error::Message(format!(
+                    "unknown message type {}",
+                    message_type
+                )));
+            }
+        };
+
+        if !reader.bytes.is_empty() {
+            return Err(Error::Message(format!(
+                "{} trailing bytes after {}",
+                reader.bytes.len(),
+                message.name()
+            )));
+        }
+
+        Ok(message)
+    }
+}
+
+/// Append an [`Amount`], in sats.
+fn write_amount(buf: &mut Vec<u8>, amount: Amount) {
+    buf.extend_from_slice(&amount.to_sat().to_be_bytes());
+}
+
+/// Append an [`OutPoint`] as its txid followed by its vout.
+fn write_outpoint(buf: &mut Vec<u8>, outpoint: &OutPoint) {
+    buf.extend_from_slice(outpoint.txid.as_byte_array());
+    buf.extend_from_slice(&outpoint.vout.to_be_bytes());
+}
+
+/// Append an [`Address`] as a `u16` length followed by its string.
+fn write_address(buf: &mut Vec<u8>, address: &Address) {
+    let address = address.to_string();
+    buf.extend_from_slice(&(address.len() as u16).to_be_bytes());
+    buf.extend_from_slice(address.as_bytes());
+}
+
+/// Reads the fields of a message payload in order.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::Message("truncated message".into()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(Error::Message(format!("invalid boolean {}", byte))),
+        }
+    }
+
+    fn amount(&mut self) -> Result<Amount, Error> {
+        Ok(Amount::from_sat(u64::from_be_bytes(self.array()?)))
+    }
+
+    fn outpoint(&mut self) -> Result<OutPoint, Error> {
+        Ok(OutPoint {
+            txid: Txid::from_byte_array(self.array()?),
+            vout: self.u32()?,
+        })
+    }
+
+    fn x_only_public_key(&mut self) -> Result<XOnlyPublicKey, Error> {
+        XOnlyPublicKey::from_slice(self.take(32)?)
+            .map_err(|e| Error::Message(format!("invalid public key: {}", e)))
+    }
+
+    fn public_key(&mut self) -> Result<PublicKey, Error> {
+        PublicKey::from_slice(self.take(33)?)
+            .map_err(|e| Error::Message(format!("invalid public key: {}", e)))
+    }
+
+    fn ecdsa_signature(&mut self) -> Result<ecdsa::Signature, Error> {
+        ecdsa::Signature::from_compact(self.take(64)?)
+            .map_err(|e| Error::Message(format!("invalid signature: {}", e)))
+    }
+
+    fn schnorr_signature(&mut self) -> Result<schnorr::Signature, Error> {
+        schnorr::Signature::from_slice(self.take(64)?)
+            .map_err(|e| Error::Message(format!("invalid signature: {}", e)))
+    }
+
+    fn address(&mut self) -> Result<Address, Error> {
+        let len = self.u16()? as usize;
+        let address = std::str::from_utf8(self.take(len)?)
+            .map_err(|e| Error::Message(format!("invalid address: {}", e)))?;
+
+        Address::from_str(address).map_err(|e| Error::Message(format!("invalid address: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use elements::schnorr::Keypair;
+    use elements::secp256k1_zkp::{Message as SighashMessage, SECP256K1, SecretKey};
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+
+    use super::*;
+
+    #[test]
+    fn message_roundtrip() {
+        let sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let digest = SighashMessage::from_digest([0x42; 32]);
+        let ecdsa_sig = SECP256K1.sign_ecdsa(&digest, &sk);
+        let schnorr_sig =
+            SECP256K1.sign_schnorr_no_aux_rand(&digest, &Keypair::from_secret_key(SECP256K1, &sk));
+        let channel = OutPoint {
+            txid: Txid::from_byte_array([0x07; 32]),
+            vout: 1,
+        };
+
+        let messages = [
+            Message::OpenChannel(OpenChannel {
+                is_mainnet: false,
+                funding_amount: Amount::from_sat(2140),
+                update_pk: sk.x_only_public_key(SECP256K1).0,
+                settlement_basepoint: sk.public_key(SECP256K1),
+                payout_address: Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap(),
+            }),
+            Message::FundingCreated(FundingCreated {
+                setup_outpoint: channel,
+            }),
+            Message::UpdateProposed(UpdateProposed {
+                channel,
+                state: 3,
+                alice_balance: Amount::from_sat(1000),
+                bob_balance: Amount::from_sat(933),
+            }),
+            Message::UpdateSigned(UpdateSigned {
+                channel,
+                state: 3,
+                signature: schnorr_sig,
+                closing_signature: ecdsa_sig,
+            }),
+            Message::SettlementSigned(SettlementSigned {
+                channel,
+                state: 3,
+                signature: schnorr_sig,
+            }),
+            Message::Shutdown(Shutdown { channel }),
+            Message::AcceptDualFundedChannel(AcceptDualFundedChannel {
+                funding_amount: Amount::from_sat(1000),
+                update_pk: sk.x_only_public_key(SECP256K1).0,
+                settlement_basepoint: sk.public_key(SECP256K1),
+                payout_address: Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap(),
+            }),
+            Message::TxAddInput(TxAddInput {
+                previous_output: channel,
+                amount: Amount::from_sat(5000),
+            }),
+            Message::TxAddOutput(TxAddOutput {
+                address: Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap(),
+                amount: Amount::from_sat(2791),
+            }),
+            Message::TxComplete(TxComplete),
+        ];
+
+        for message in messages {
+            let frame = message.encode();
+            let length = u32::from_be_bytes(frame[..LENGTH_PREFIX_LEN].try_into().unwrap());
+            assert_eq!(length as usize, frame.len() - LENGTH_PREFIX_LEN);
+            assert_eq!(
+                Message::decode(&frame[LENGTH_PREFIX_LEN..]).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn reject_malformed_messages() {
+        let frame = Message::Shutdown(Shutdown {
+            channel: OutPoint::default(),
+        })
+        .encode();
+        let body = &frame[LENGTH_PREFIX_LEN..];
+
+        // Truncated payload.
+        assert!(Message::decode(&body[..body.len() - 1]).is_err());
+        // Trailing bytes.
+        assert!(Message::decode(&[body, &[0x00]].concat()).is_err());
+        // Unsupported version.
+        assert!(Message::decode(&[&[PROTOCOL_VERSION + 1], &body[1..]].concat()).is_err());
+        // Unknown message type.
+        assert!(Message::decode(&[PROTOCOL_VERSION, 0xff, 0xff]).is_err());
+    }
+}
@@ -0,0 +1,714 @@
+//! Multi-party Channels
+//!
+//! ELTOO channels between any number of participants. The setup output is an
+//! n-of-n multisig between their update keys, and the commitment programs are
+//! generated for the participant set: every participant signs each update and
+//! settlement, and each settlement pays every participant their balance.
+//!
+//! Participants are ordered, and every key, signature and balance follows that
+//! order. The first participant funds the channel and pays every fee. Only
+//! L-BTC balances are supported, without HTLCs or PTLCs. Besides the balances,
+//! a settlement can pay fixed outputs, which a channel factory uses for the
+//! setup outputs of its sub-channels.
+//!
+//! No command opens a multi-party channel yet, so the module is only built for
+//! its tests.
+
+use elements::bitcoin::Amount;
+use elements::schnorr::{Signature, XOnlyPublicKey};
+use elements::secp256k1_zkp::{PublicKey, ecdsa};
+use elements::{Address, OutPoint, Transaction};
+use simplicityhl::CompiledProgram;
+
+use crate::FEE_AMOUNT;
+use crate::error::Error;
+use crate::keys::ChannelKeys;
+use crate::script::{build_multiparty_commitment_script, derive_address};
+use crate::sign::{
+    FeeInput, MultipartySignedUpdate, derive_settlement_pubkey,
+    finalize_multiparty_settlement_transaction, finalize_multiparty_setup_spending_transaction,
+    sign_anyprevout_input, sign_fee_input, sign_multiparty_setup_spending_input,
+    verify_anyprevout_input, verify_multiparty_setup_spending_input,
+};
+use crate::transaction::{
+    bind_commitment_inputs, build_multiparty_settlement_transaction,
+    build_multiparty_setup_address, build_setup_spending_update_transaction,
+    build_update_transaction, check_state, rebind_multiparty_update_transaction,
+};
+
+/// A participant of a multi-party channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Participant {
+    /// The update key, the same for every state.
+    pub(crate) update_pk: XOnlyPublicKey,
+    /// The basepoint the per-state settlement keys are derived from.
+    pub(crate) settlement_basepoint: PublicKey,
+    /// The settlement payout address.
+    pub(crate) address: Address,
+}
+
+impl Participant {
+    /// The participant holding `keys`, paid out at `address`.
+    pub(crate) fn from_keys(keys: &ChannelKeys, address: Address) -> Self {
+        Participant {
+            update_pk: keys.update_pk(),
+            settlement_basepoint: keys.settlement_basepoint(),
+            address,
+        }
+    }
+
+    /// The settlement key for `state`, derived from the basepoint.
+    pub(crate) fn settlement_pk(&self, state: u32) -> XOnlyPublicKey {
+        derive_settlement_pubkey(&self.settlement_basepoint, state as u64)
+            .x_only_public_key()
+            .0
+    }
+}
+
+/// The unsigned transactions of a proposed state, for every participant to sign.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProposedState {
+    /// The state number.
+    pub(crate) state: u32,
+    /// Every participant's balance.
+    pub(crate) balances: Vec<Amount>,
+    /// The outputs paid before the balances.
+    pub(crate) outputs: Vec<(Address, Amount)>,
+    /// The unsigned `Update Transaction`.
+    pub(crate) update: Transaction,
+    /// The unsigned `Update Transaction` that spends the setup output.
+    pub(crate) closing_update: Transaction,
+    /// The unsigned `Settlement Transaction`.
+    pub(crate) settlement: Transaction,
+}
+
+/// One participant's signatures on a [`ProposedState`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct StateSignatures {
+    /// The update key signature on the `Update Transaction`.
+    pub(crate) update_sig: Signature,
+    /// The settlement key signature on the `Settlement Transaction`.
+    pub(crate) settlement_sig: Signature,
+    /// The update key signature on the `Update Transaction` that spends the
+    /// setup output.
+    pub(crate) closing_update_sig: ecdsa::Signature,
+}
+
+/// An ELTOO channel between two or more participants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MultipartyChannel {
+    /// Whether the channel is on Liquid mainnet.
+    pub(crate) is_mainnet: bool,
+    /// The setup output funding the channel.
+    pub(crate) setup_outpoint: OutPoint,
+    /// The amount locked in the setup output.
+    pub(crate) funding_amount: Amount,
+    /// The participants, in order.
+    pub(crate) participants: Vec<Participant>,
+    /// The current state number.
+    pub(crate) state: u32,
+    /// Every participant's balance at the current state.
+    pub(crate) balances: Vec<Amount>,
+    /// The outputs the settlement for the current state pays before the balances.
+    pub(crate) outputs: Vec<(Address, Amount)>,
+    /// The latest `Update Transaction`, signed by every participant.
+    pub(crate) latest_update: Option<MultipartySignedUpdate>,
+    /// The `Settlement Transaction` for the current state, signed by every participant.
+    pub(crate) latest_settlement: Option<Transaction>,
+    /// Every participant's signature on the `Update Transaction` for the
+    /// current state that spends the setup output, in participant order.
+    pub(crate) closing_update_sigs: Vec<ecdsa::Signature>,
+}
+
+impl MultipartyChannel {
+    /// Create a new [`MultipartyChannel`] at state zero, funded by the first
+    /// participant, who holds the whole capacity.
+    ///
+    /// Fails with fewer than two participants, or if two of them share a key.
+    pub(crate) fn new(
+        is_mainnet: bool,
+        setup_outpoint: OutPoint,
+        funding_amount: Amount,
+        participants: Vec<Participant>,
+    ) -> Result<Self, Error> {
+        if participants.len() < 2 {
+            return Err(Error::Channel(format!(
+                "a channel needs at least two participants, got {}",
+                participants.len()
+            )));
+        }
+
+        let mut channel = MultipartyChannel {
+            is_mainnet,
+            setup_outpoint,
+            funding_amount,
+            balances: vec![Amount::ZERO; participants.len()],
+            outputs: Vec::new(),
+            participants,
+            state: 0,
+            latest_update: None,
+            latest_settlement: None,
+            closing_update_sigs: Vec::new(),
+        };
+        // Also checks that the update keys are distinct.
+        channel.setup_address()?;
+        channel.balances[0] = channel.capacity();
+
+        Ok(channel)
+    }
+
+    /// The channel identifier, derived from the setup [`OutPoint`].
+    pub(crate) fn id(&self) -> String {
+        format!("{}_{}", self.setup_outpoint.txid, self.setup_outpoint.vout)
+    }
+
+    /// The update keys of every participant.
+    pub(crate) fn update_pks(&self) -> Vec<XOnlyPublicKey> {
+        self.participants
+            .iter()
+            .map(|participant| participant.update_pk)
+            .collect()
+    }
+
+    /// The n-of-n `Setup Address` of the channel.
+    pub(crate) fn setup_address(&self) -> Result<Address, Error> {
+        build_multiparty_setup_address(&self.update_pks(), self.is_mainnet)
+    }
+
+    /// The amount that can be split between the participants, once the fees of
+    /// the closing update and the settlement are reserved, as in a two-party
+    /// channel.
+    pub(crate) fn capacity(&self) -> Amount {
+        self.funding_amount - Amount::from_sat(2 * FEE_AMOUNT)
+    }
+
+    /// The amount held by every commitment output of the channel.
+    pub(crate) fn commitment_amount(&self) -> Amount {
+        self.funding_amount - Amount::from_sat(FEE_AMOUNT)
+    }
+
+    /// The program locking the commitment output of the update for `state`.
+    pub(crate) fn commitment_program(&self, state: u32) -> Result<CompiledProgram, Error> {
+        let settlement_pks: Vec<_> = self
+            .participants
+            .iter()
+            .map(|participant| participant.settlement_pk(state))
+            .collect();
+
+        build_multiparty_commitment_script(&self.update_pks(), &settlement_pks, state as u64 + 1)
+    }
+
+    /// Build the unsigned transactions for the next state with `balances`,
+    /// keeping the outputs of the current state.
+    pub(crate) fn propose(&self, balances: Vec<Amount>) -> Result<ProposedState, Error> {
+        self.propose_with_outputs(balances, self.outputs.clone())
+    }
+
+    /// Build the unsigned transactions for the next state with `balances`,
+    /// whose settlement pays `outputs` first.
+    pub(crate) fn propose_with_outputs(
+        &self,
+        balances: Vec<Amount>,
+        outputs: Vec<(Address, Amount)>,
+    ) -> Result<ProposedState, Error> {
+        self.check_balances(&balances, &outputs)?;
+
+        let state = self.state + 1;
+        check_state(state)?;
+        let commitment_address = derive_address(&self.commitment_program(state)?, self.is_mainnet);
+        let update = build_update_transaction(
+            OutPoint::default(),
+            self.commitment_amount(),
+            &commitment_address,
+            state,
+            &[],
+            self.is_mainnet,
+        );
+        let closing_update = build_setup_spending_update_transaction(
+            self.setup_outpoint,
+            self.funding_amount,
+            &commitment_address,
+            state,
+            &[],
+            self.is_mainnet,
+        );
+        let settlement = build_multiparty_settlement_transaction(
+            OutPoint::default(),
+            self.commitment_amount(),
+            &outputs,
+            &self.payouts(&balances),
+            self.is_mainnet,
+        );
+
+        Ok(ProposedState {
+            state,
+            balances,
+            outputs,
+            update,
+            closing_update,
+            settlement,
+        })
+    }
+
+    /// Sign `proposal` with one participant's `keys`.
+    pub(crate) fn sign(
+        &self,
+        proposal: &ProposedState,
+        keys: &ChannelKeys,
+    ) -> Result<StateSignatures, Error> {
+        self.participant_index(&keys.update_pk())?;
+
+        Ok(StateSignatures {
+            update_sig: sign_anyprevout_input(
+                &proposal.update,
+                self.commitment_amount(),
+                &keys.update_sk,
+                self.is_mainnet,
+            ),
+            settlement_sig: sign_anyprevout_input(
+                &proposal.settlement,
+                self.commitment_amount(),
+                &keys.settlement_sk(proposal.state),
+                self.is_mainnet,
+            ),
+            closing_update_sig: sign_multiparty_setup_spending_input(
+                &proposal.closing_update,
+                0,
+                self.funding_amount,
+                &self.update_pks(),
+                &keys.update_sk,
+            ),
+        })
+    }
+
+    /// Move the channel to `proposal` once every participant signed it, with
+    /// one set of `signatures` per participant, in participant order.
+    pub(crate) fn apply(
+        &mut self,
+        proposal: ProposedState,
+        signatures: &[StateSignatures],
+    ) -> Result<(), Error> {
+        if proposal.state != self.state + 1 {
+            return Err(Error::Channel(format!(
+                "expected state {}, got {}",
+                self.state + 1,
+                proposal.state
+            )));
+        }
+        if signatures.len() != self.participants.len() {
+            return Err(Error::Channel(format!(
+                "expected signatures from {} participants, got {}",
+                self.participants.len(),
+                signatures.len()
+            )));
+        }
+        // The transactions are rebuilt, so no participant signs something else.
+        let rebuilt =
+            self.propose_with_outputs(proposal.balances.clone(), proposal.outputs.clone())?;
+        if rebuilt != proposal {
+            return Err(Error::Channel("proposal does not match the channel".into()));
+        }
+
+        let update_pks = self.update_pks();
+        for (participant, signatures) in self.participants.iter().zip(signatures) {
+            verify_anyprevout_input(
+                &proposal.update,
+                self.commitment_amount(),
+                &participant.update_pk,
+                &signatures.update_sig,
+                self.is_mainnet,
+            )?;
+            verify_anyprevout_input(
+                &proposal.settlement,
+                self.commitment_amount(),
+                &participant.settlement_pk(proposal.state),
+                &signatures.settlement_sig,
+                self.is_mainnet,
+            )?;
+            verify_multiparty_setup_spending_input(
+                &proposal.closing_update,
+                0,
+                self.funding_amount,
+                &update_pks,
+                &participant.update_pk,
+                &signatures.closing_update_sig,
+            )?;
+        }
+
+        let settlement_sigs: Vec<_> = signatures.iter().map(|sigs| sigs.settlement_sig).collect();
+        let settlement = finalize_multiparty_settlement_transaction(
+            &proposal.settlement,
+            &self.commitment_program(proposal.state)?,
+            &settlement_sigs,
+        )?;
+
+        self.state = proposal.state;
+        self.balances = proposal.balances;
+        self.outputs = proposal.outputs;
+        self.latest_update = Some(MultipartySignedUpdate {
+            state: proposal.state,
+            transaction: proposal.update,
+            signatures: signatures.iter().map(|sigs| sigs.update_sig).collect(),
+        });
+        self.latest_settlement = Some(settlement);
+        self.closing_update_sigs = signatures
+            .iter()
+            .map(|sigs| sigs.closing_update_sig)
+            .collect();
+
+        Ok(())
+    }
+
+    /// Move the channel to a new state with `balances`, signed with every
+    /// participant's `keys`, in participant order.
+    pub(crate) fn update(
+        &mut self,
+        balances: Vec<Amount>,
+        keys: &[ChannelKeys],
+    ) -> Result<(), Error> {
+        let proposal = self.propose(balances)?;
+        let signatures = keys
+            .iter()
+            .map(|keys| self.sign(&proposal, keys))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.apply(proposal, &signatures)
+    }
+
+    /// Build and sign the `Update Transaction` for the latest state that spends
+    /// the setup output, starting a unilateral close of the channel.
+    ///
+    /// Only the closing participant's own `keys` are needed: the other
+    /// participants' signatures were collected along with the latest state.
+    pub(crate) fn build_signed_closing_update(
+        &self,
+        keys: &ChannelKeys,
+    ) -> Result<Transaction, Error> {
+        let index = self.participant_index(&keys.update_pk())?;
+        if self.closing_update_sigs.len() != self.participants.len() {
+            return Err(Error::Channel(format!(
+                "no signatures of the closing update for state {}",
+                self.state
+            )));
+        }
+
+        let update_pks = self.update_pks();
+        let commitment_address =
+            derive_address(&self.commitment_program(self.state)?, self.is_mainnet);
+        let update = build_setup_spending_update_transaction(
+            self.setup_outpoint,
+            self.funding_amount,
+            &commitment_address,
+            self.state,
+            &[],
+            self.is_mainnet,
+        );
+        let mut signatures = self.closing_update_sigs.clone();
+        signatures[index] = sign_multiparty_setup_spending_input(
+            &update,
+            0,
+            self.funding_amount,
+            &update_pks,
+            &keys.update_sk,
+        );
+
+        Ok(finalize_multiparty_setup_spending_transaction(
+            &update,
+            &update_pks,
+            &signatures,
+        ))
+    }
+
+    /// Bind the settlement for the latest state to the commitment output at
+    /// `commitment_prevout` of `input_amount`.
+    ///
+    /// Every commitment output holds [`Self::commitment_amount`], so the stored
+    /// settlement spends whichever update confirmed.
+    pub(crate) fn build_signed_settlement(
+        &self,
+        commitment_prevout: OutPoint,
+        input_amount: Amount,
+    ) -> Result<Transaction, Error> {
+        if input_amount != self.commitment_amount() {
+            return Err(Error::Channel(format!(
+                "commitment output holds {input_amount}, expected {}",
+                self.commitment_amount()
+            )));
+        }
+        let mut settlement = self
+            .latest_settlement
+            .clone()
+            .ok_or_else(|| Error::Channel("channel has no signed settlement".into()))?;
+        bind_commitment_inputs(&mut settlement, commitment_prevout);
+
+        Ok(settlement)
+    }
+
+    /// Sign, with the first participant's `keys`, a fee input at `outpoint` for
+    /// the latest update, so it can be bound to a stale commitment output.
+    pub(crate) fn sign_fee_input(
+        &self,
+        outpoint: OutPoint,
+        keys: &ChannelKeys,
+    ) -> Result<FeeInput, Error> {
+        if self.participant_index(&keys.update_pk())? != 0 {
+            return Err(Error::Channel(
+                "only the first participant pays the fees".into(),
+            ));
+        }
+        let latest_update = self
+            .latest_update
+            .as_ref()
+            .ok_or_else(|| Error::Channel("channel has no signed update".into()))?;
+
+        Ok(sign_fee_input(
+            &latest_update.transaction,
+            outpoint,
+            &keys.fee_sk,
+        ))
+    }
+
+    /// Bind the latest update to the commitment output of the stale update for
+    /// `stale_state` at `stale_prevout`, overriding it, with its fee paid by
+    /// `fee_input`.
+    pub(crate) fn rebind_latest_update(
+        &self,
+        stale_prevout: OutPoint,
+        stale_state: u32,
+        fee_input: &FeeInput,
+    ) -> Result<Transaction, Error> {
+        let latest_update = self
+            .latest_update
+            .as_ref()
+            .ok_or_else(|| Error::Channel("channel has no signed update".into()))?;
+
+        rebind_multiparty_update_transaction(
+            latest_update,
+            stale_prevout,
+            &self.commitment_program(stale_state)?,
+            fee_input,
+        )
+    }
+
+    /// Check that there is one balance per participant, and that they add up
+    /// to the capacity along with the `outputs`.
+    fn check_balances(
+        &self,
+        balances: &[Amount],
+        outputs: &[(Address, Amount)],
+    ) -> Result<(), Error> {
+        if balances.len() != self.participants.len() {
+            return Err(Error::Channel(format!(
+                "expected balances for {} participants, got {}",
+                self.participants.len(),
+                balances.len()
+            )));
+        }
+
+        let total = balances
+            .iter()
+            .chain(outputs.iter().map(|(_, amount)| amount))
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(*amount));
+        if total != Some(self.capacity()) {
+            return Err(Error::Channel(format!(
+                "balances and outputs must add up to the channel capacity of {}",
+                self.capacity()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Pair each participant's payout address with its balance.
+    fn payouts(&self, balances: &[Amount]) -> Vec<(Address, Amount)> {
+        self.participants
+            .iter()
+            .zip(balances)
+            .map(|(participant, balance)| (participant.address.clone(), *balance))
+            .collect()
+    }
+
+    /// The position of the participant with `update_pk`.
+    fn participant_index(&self, update_pk: &XOnlyPublicKey) -> Result<usize, Error> {
+        self.participants
+            .iter()
+            .position(|participant| participant.update_pk == *update_pk)
+            .ok_or_else(|| Error::Channel(format!("{} is not a participant", update_pk)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::Txid;
+    use elements::hashes::Hash;
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::keys::SEED_LEN;
+    use crate::transaction::lock_time_state;
+
+    use super::*;
+
+    fn three_party_channel() -> (MultipartyChannel, Vec<ChannelKeys>) {
+        let address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let keys: Vec<_> = [0x0a, 0x0b, 0x0c]
+            .iter()
+            .map(|byte| ChannelKeys::derive(&[*byte; SEED_LEN], 0, false).unwrap())
+            .collect();
+        let participants = keys
+            .iter()
+            .map(|keys| Participant::from_keys(keys, address.clone()))
+            .collect();
+        let channel = MultipartyChannel::new(
+            false,
+            OutPoint {
+                txid: Txid::from_byte_array([0x01; 32]),
+                vout: 0,
+            },
+            Amount::from_sat(10_000),
+            participants,
+        )
+        .unwrap();
+
+        (channel, keys)
+    }
+
+    #[test]
+    fn three_party_updates_and_settlement() {
+        let (mut channel, keys) = three_party_channel();
+        assert_eq!(
+            channel.balances,
+            [channel.capacity(), Amount::ZERO, Amount::ZERO]
+        );
+
+        let balances = vec![
+            Amount::from_sat(5_000),
+            Amount::from_sat(3_000),
+            channel.capacity() - Amount::from_sat(8_000),
+        ];
+        channel.update(balances.clone(), &keys).unwrap();
+        // Any participant closes on their own with the others' signatures
+        // collected along with the state, one per participant, then the setup
+        // program.
+        let stale_update = channel.build_signed_closing_update(&keys[2]).unwrap();
+        assert_eq!(stale_update.input[0].witness.script_witness.len(), 4);
+        assert_eq!(
+            channel.build_signed_closing_update(&keys[0]).unwrap(),
+            stale_update
+        );
+        let update_pks = channel.update_pks();
+        for (participant, signature) in channel
+            .participants
+            .iter()
+            .zip(&channel.closing_update_sigs)
+        {
+            verify_multiparty_setup_spending_input(
+                &stale_update,
+                0,
+                channel.funding_amount,
+                &update_pks,
+                &participant.update_pk,
+                signature,
+            )
+            .unwrap();
+        }
+        let stranger = ChannelKeys::derive(&[0x0d; SEED_LEN], 0, false).unwrap();
+        assert!(channel.build_signed_closing_update(&stranger).is_err());
+
+        // Every participant must sign, and balances must add up.
+        let proposal = channel.propose(balances.clone()).unwrap();
+        let signatures: Vec<_> = keys[..2]
+            .iter()
+            .map(|keys| channel.sign(&proposal, keys).unwrap())
+            .collect();
+        assert!(channel.clone().apply(proposal, &signatures).is_err());
+        assert!(channel.propose(balances[..2].to_vec()).is_err());
+        assert!(
+            channel
+                .propose(vec![Amount::ZERO, Amount::ZERO, Amount::ZERO])
+                .is_err()
+        );
+
+        // Signatures in the wrong order are refused.
+        let proposal = channel.propose(balances.clone()).unwrap();
+        let mut signatures: Vec<_> = keys
+            .iter()
+            .map(|keys| channel.sign(&proposal, keys).unwrap())
+            .collect();
+        signatures.swap(0, 1);
+        assert!(channel.clone().apply(proposal, &signatures).is_err());
+
+        let balances = vec![
+            Amount::from_sat(4_000),
+            Amount::from_sat(1_000),
+            channel.capacity() - Amount::from_sat(5_000),
+        ];
+        channel.update(balances.clone(), &keys).unwrap();
+        assert_eq!(channel.state, 2);
+
+        // The settlement pays all three balances, and is signed by all three.
+        let settlement = channel.latest_settlement.as_ref().unwrap();
+        let paid: Vec<_> = settlement.output[..3]
+            .iter()
+            .map(|output| Amount::from_sat(output.value.explicit().unwrap()))
+            .collect();
+        assert_eq!(paid, balances);
+        assert_eq!(settlement.input[0].witness.script_witness.len(), 4);
+
+        // The latest update overrides the stale one.
+        let stale_prevout = OutPoint {
+            txid: stale_update.txid(),
+            vout: 0,
+        };
+        let fee_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x02; 32]),
+            vout: 1,
+        };
+        assert!(channel.sign_fee_input(fee_outpoint, &keys[1]).is_err());
+        let fee_input = channel.sign_fee_input(fee_outpoint, &keys[0]).unwrap();
+        let rebound = channel
+            .rebind_latest_update(stale_prevout, 1, &fee_input)
+            .unwrap();
+        assert_eq!(rebound.input[0].previous_output, stale_prevout);
+        assert_eq!(rebound.input[1].previous_output, fee_outpoint);
+        assert_eq!(rebound.output[0].value, stale_update.output[0].value);
+
+        let settlement = channel
+            .build_signed_settlement(
+                OutPoint {
+                    txid: rebound.txid(),
+                    vout: 0,
+                },
+                channel.commitment_amount(),
+            )
+            .unwrap();
+        assert_eq!(settlement.input[0].previous_output.txid, rebound.txid());
+        assert_eq!(lock_time_state(rebound.lock_time), Some(2));
+    }
+
+    #[test]
+    fn reject_shared_keys() {
+        let (channel, keys) = three_party_channel();
+        let mut participants = channel.participants.clone();
+        participants[2] = participants[0].clone();
+        assert!(
+            MultipartyChannel::new(
+                false,
+                OutPoint::default(),
+                Amount::from_sat(10_000),
+                participants
+            )
+            .is_err()
+        );
+        assert!(
+            MultipartyChannel::new(
+                false,
+                OutPoint::default(),
+                Amount::from_sat(10_000),
+                channel.participants[..1].to_vec()
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,477 @@
+//! Encrypted Transport
+//!
+//! Before any channel message, peers run a `Noise_XK_secp256k1_ChaChaPoly_SHA256`
+//! handshake keyed by their static node keys, as in BOLT 8:
+//!
+//! ```text
+//! -> e, es
+//! <- e, ee
+//! -> s, se
+//! ```
+//!
+//! The initiator must know the responder's node key in advance, and proves its
+//! own in the last act, so both peers are authenticated once the handshake
+//! completes. Afterwards, every frame is sent as its encrypted `u32` length
+//! followed by its encrypted body, each with a 16-byte tag.
+
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use elements::hashes::hmac::{Hmac, HmacEngine};
+use elements::hashes::{Hash, HashEngine, sha256};
+use elements::secp256k1_zkp::ecdh::SharedSecret;
+use elements::secp256k1_zkp::{PublicKey, SECP256K1, SecretKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::Error;
+
+/// The Noise protocol name, hashed into the initial handshake state.
+const PROTOCOL_NAME: &[u8] = b"Noise_XK_secp256k1_ChaChaPoly_SHA256";
+
+/// The prologue, which binds the handshake to this protocol.
+const PROLOGUE: &[u8] = b"simpleltoo";
+
+/// The handshake version.
+const HANDSHAKE_VERSION: u8 = 0;
+
+/// The length of an AEAD tag.
+pub(crate) const TAG_LEN: usize = 16;
+
+/// The length of the first and second acts.
+const ACT_ONE_LEN: usize = 1 + 33 + TAG_LEN;
+const ACT_TWO_LEN: usize = 1 + 33 + TAG_LEN;
+/// The length of the third act.
+const ACT_THREE_LEN: usize = 1 + 33 + TAG_LEN + TAG_LEN;
+
+/// The ciphers of an established connection and the peer's node key.
+pub(crate) struct Transport {
+    sender: CipherState,
+    receiver: CipherState,
+    remote_node_id: PublicKey,
+}
+
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("sender", &self.sender)
+            .field("receiver", &self.receiver)
+            .field("remote_node_id", &self.remote_node_id)
+            .finish()
+    }
+}
+
+impl Transport {
+    /// A transport keyed by fixed `sending_key` and `receiving_key` rather than
+    /// by a handshake, for the fuzzing targets and their seed corpus.
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(crate) fn with_keys(
+        sending_key: [u8; 32],
+        receiving_key: [u8; 32],
+        remote_node_id: PublicKey,
+    ) -> Self {
+        Transport {
+            sender: CipherState::new(sending_key),
+            receiver: CipherState::new(receiving_key),
+            remote_node_id,
+        }
+    }
+
+    /// The authenticated node key of the peer.
+    pub(crate) fn remote_node_id(&self) -> PublicKey {
+        self.remote_node_id
+    }
+
+    /// Encrypt the `length` prefix and `body` of a frame.
+    pub(crate) fn encrypt_frame(&mut self, length: u32, body: &[u8]) -> Vec<u8> {
+        let mut encrypted = self.sender.encrypt(&[], &length.to_be_bytes());
+        encrypted.extend(self.sender.encrypt(&[], body));
+
+        encrypted
+    }
+
+    /// Decrypt the length prefix of a frame, which is followed by its
+    /// encrypted body of `length + TAG_LEN` bytes.
+    pub(crate) fn decrypt_length(&mut self, encrypted: &[u8; 4 + TAG_LEN]) -> Result<u32, Error> {
+        let length = self.receiver.decrypt(&[], encrypted)?;
+
+        Ok(u32::from_be_bytes(length.try_into().expect("4 bytes")))
+    }
+
+    /// Decrypt the body of a frame.
+    pub(crate) fn decrypt_body(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        self.receiver.decrypt(&[], encrypted)
+    }
+}
+
+/// Run the handshake as the initiator, expecting the responder to hold the
+/// node key `remote_node_id`.
+pub(crate) async fn initiate<S>(
+    stream: &mut S,
+    local_node_sk: &SecretKey,
+    remote_node_id: &PublicKey,
+) -> Result<Transport, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new(remote_node_id);
+    let ephemeral_sk = generate_ephemeral_key();
+    let ephemeral_pk = ephemeral_sk.public_key(SECP256K1);
+
+    // Act one: -> e, es
+    state.mix_hash(&ephemeral_pk.serialize());
+    let temp_k1 = state.mix_key(&ecdh(remote_node_id, &ephemeral_sk));
+    let tag = state.encrypt_and_hash(&temp_k1, 0, &[]);
+    stream
+        .write_all(&[&[HANDSHAKE_VERSION], &ephemeral_pk.serialize()[..], &tag].concat())
+        .await?;
+
+    // Act two: <- e, ee
+    let mut act_two = [0u8; ACT_TWO_LEN];
+    stream.read_exact(&mut act_two).await?;
+    let remote_ephemeral_pk = parse_act(&act_two)?;
+    state.mix_hash(&remote_ephemeral_pk.serialize());
+    let temp_k2 = state.mix_key(&ecdh(&remote_ephemeral_pk, &ephemeral_sk));
+    state.decrypt_and_hash(&temp_k2, 0, &act_two[34..])?;
+
+    // Act three: -> s, se
+    let local_node_id = local_node_sk.public_key(SECP256K1);
+    let encrypted_node_id = state.encrypt_and_hash(&temp_k2, 1, &local_node_id.serialize());
+    let temp_k3 = state.mix_key(&ecdh(&remote_ephemeral_pk, local_node_sk));
+    let tag = state.encrypt_and_hash(&temp_k3, 0, &[]);
+    stream
+        .write_all(&[&[HANDSHAKE_VERSION], &encrypted_node_id[..], &tag].concat())
+        .await?;
+    stream.flush().await?;
+
+    let (sending_key, receiving_key) = state.split();
+
+    Ok(Transport {
+        sender: CipherState::new(sending_key),
+        receiver: CipherState::new(receiving_key),
+        remote_node_id: *remote_node_id,
+    })
+}
+
+/// Run the handshake as the responder, learning the initiator's node key.
+pub(crate) async fn respond<S>(
+    stream: &mut S,
+    local_node_sk: &SecretKey,
+) -> Result<Transport, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new(&local_node_sk.public_key(SECP256K1));
+
+    // Act one: -> e, es
+    let mut act_one = [0u8; ACT_ONE_LEN];
+    stream.read_exact(&mut act_one).await?;
+    let remote_ephemeral_pk = parse_act(&act_one)?;
+    state.mix_hash(&remote_ephemeral_pk.serialize());
+    let temp_k1 = state.mix_key(&ecdh(&remote_ephemeral_pk, local_node_sk));
+    state.decrypt_and_hash(&temp_k1, 0, &act_one[34..])?;
+
+    // Act two: <- e, ee
+    let ephemeral_sk = generate_ephemeral_key();
+    let ephemeral_pk = ephemeral_sk.public_key(SECP256K1);
+    state.mix_hash(&ephemeral_pk.serialize());
+    let temp_k2 = state.mix_key(&ecdh(&remote_ephemeral_pk, &ephemeral_sk));
+    let tag = state.encrypt_and_hash(&temp_k2, 0, &[]);
+    stream
+        .write_all(&[&[HANDSHAKE_VERSION], &ephemeral_pk.serialize()[..], &tag].concat())
+        .await?;
+    stream.flush().await?;
+
+    // Act three: -> s, se
+    let mut act_three = [0u8; ACT_THREE_LEN];
+    stream.read_exact(&mut act_three).await?;
+    if act_three[0] != HANDSHAKE_VERSION {
+        return Err(Error::Handshake(format!(
+            "unsupported handshake version {}",
+            act_three[0]
+        )));
+    }
+    let remote_node_id = state.decrypt_and_hash(&temp_k2, 1, &act_three[1..1 + 33 + TAG_LEN])?;
+    let remote_node_id = PublicKey::from_slice(&remote_node_id)
+        .map_err(|e| Error::Handshake(format!("invalid node key: {}", e)))?;
+    let temp_k3 = state.mix_key(&ecdh(&remote_node_id, &ephemeral_sk));
+    state.decrypt_and_hash(&temp_k3, 0, &act_three[1 + 33 + TAG_LEN..])?;
+
+    let (receiving_key, sending_key) = state.split();
+
+    Ok(Transport {
+        sender: CipherState::new(sending_key),
+        receiver: CipherState::new(receiving_key),
+        remote_node_id,
+    })
+}
+
+/// The chaining key and handshake hash of a handshake in progress.
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    handshake_hash: sha256::Hash,
+}
+
+impl SymmetricState {
+    /// Start a handshake with the responder's node key.
+    fn new(responder_node_id: &PublicKey) -> Self {
+        let protocol_hash = sha256::Hash::hash(PROTOCOL_NAME);
+        let mut state = SymmetricState {
+            chaining_key: protocol_hash.to_byte_array(),
+            handshake_hash: protocol_hash,
+        };
+        state.mix_hash(PROLOGUE);
+        state.mix_hash(&responder_node_id.serialize());
+
+        state
+    }
+
+    /// h = SHA256(h || data)
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut engine = sha256::Hash::engine();
+        engine.input(self.handshake_hash.as_byte_array());
+        engine.input(data);
+        self.handshake_hash = sha256::Hash::from_engine(engine);
+    }
+
+    /// ck, temp_k = HKDF(ck, input_key_material)
+    fn mix_key(&mut self, input_key_material: &[u8]) -> [u8; 32] {
+        let (chaining_key, temp_key) = hkdf(&self.chaining_key, input_key_material);
+        self.chaining_key = chaining_key;
+
+        temp_key
+    }
+
+    /// Encrypt `plaintext` with the handshake hash as associated data, and mix
+    /// the ciphertext into the hash.
+    fn encrypt_and_hash(&mut self, key: &[u8; 32], nonce: u64, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt(key, nonce, self.handshake_hash.as_byte_array(), plaintext);
+        self.mix_hash(&ciphertext);
+
+        ciphertext
+    }
+
+    /// Decrypt `ciphertext` with the handshake hash as associated data, and mix
+    /// the ciphertext into the hash.
+    fn decrypt_and_hash(
+        &mut self,
+        key: &[u8; 32],
+        nonce: u64,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let plaintext = decrypt(key, nonce, self.handshake_hash.as_byte_array(), ciphertext)
+            .map_err(|_| Error::Handshake("peer failed to authenticate".into()))?;
+        self.mix_hash(ciphertext);
+
+        Ok(plaintext)
+    }
+
+    /// Derive the initiator's and responder's sending keys.
+    fn split(&self) -> ([u8; 32], [u8; 32]) {
+        hkdf(&self.chaining_key, &[])
+    }
+}
+
+/// One direction of an established connection.
+struct CipherState {
+    key: [u8; 32],
+    nonce: u64,
+}
+
+/// Only the nonce is shown, so that logging a connection leaks no key.
+impl fmt::Debug for CipherState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherState")
+            .field("key", &"<redacted>")
+            .field("nonce", &self.nonce)
+            .finish()
+    }
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        CipherState { key, nonce: 0 }
+    }
+
+    fn encrypt(&mut self, associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = encrypt(&self.key, self.nonce, associated_data, plaintext);
+        self.nonce += 1;
+
+        ciphertext
+    }
+
+    fn decrypt(&mut self, associated_data: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = decrypt(&self.key, self.nonce, associated_data, ciphertext)
+            .map_err(|_| Error::Peer("message authentication failed".into()))?;
+        self.nonce += 1;
+
+        Ok(plaintext)
+    }
+}
+
+/// Check the version of the first or second act and parse its ephemeral key.
+fn parse_act(act: &[u8; ACT_ONE_LEN]) -> Result<PublicKey, Error> {
+    if act[0] != HANDSHAKE_VERSION {
+        return Err(Error::Handshake(format!(
+            "unsupported handshake version {}",
+            act[0]
+        )));
+    }
+
+    PublicKey::from_slice(&act[1..34])
+        .map_err(|e| Error::Handshake(format!("invalid ephemeral key: {}", e)))
+}
+
+/// Generate an ephemeral [`SecretKey`] from the operating system's secure RNG.
+fn generate_ephemeral_key() -> SecretKey {
+    loop {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        if let Ok(secret_key) = SecretKey::from_slice(&bytes) {
+            break secret_key;
+        }
+    }
+}
+
+/// SHA256 of the compressed ECDH point.
+fn ecdh(public_key: &PublicKey, secret_key: &SecretKey) -> [u8; 32] {
+    SharedSecret::new(public_key, secret_key).secret_bytes()
+}
+
+/// HKDF-SHA256 with `salt` as the salt, producing two 32-byte keys.
+fn hkdf(salt: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut engine = HmacEngine::<sha256::Hash>::new(salt);
+    engine.input(input_key_material);
+    let prk = Hmac::from_engine(engine);
+
+    let mut engine = HmacEngine::<sha256::Hash>::new(prk.as_byte_array());
+    engine.input(&[0x01]);
+    let first = Hmac::from_engine(engine);
+
+    let mut engine = HmacEngine::<sha256::Hash>::new(prk.as_byte_array());
+    engine.input(first.as_byte_array());
+    engine.input(&[0x02]);
+    let second = Hmac::from_engine(engine);
+
+    (first.to_byte_array(), second.to_byte_array())
+}
+
+/// The ChaCha20-Poly1305 nonce: 32 zero bits followed by the little-endian counter.
+fn nonce_bytes(nonce: u64) -> [u8; 12] {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&nonce.to_le_bytes());
+
+    bytes
+}
+
+fn encrypt(key: &[u8; 32], nonce: u64, associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            Nonce::from_slice(&nonce_bytes(nonce)),
+            Payload {
+                msg: plaintext,
+                aad: associated_data,
+            },
+        )
+        .expect("plaintext fits in a ChaCha20-Poly1305 message")
+}
+
+fn decrypt(
+    key: &[u8; 32],
+    nonce: u64,
+    associated_data: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, chacha20poly1305::aead::Error> {
+    ChaCha20Poly1305::new(Key::from_slice(key)).decrypt(
+        Nonce::from_slice(&nonce_bytes(nonce)),
+        Payload {
+            msg: ciphertext,
+            aad: associated_data,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(
+        initiator_sk: &SecretKey,
+        responder_sk: &SecretKey,
+        expected_responder: &PublicKey,
+    ) -> (Result<Transport, Error>, Result<Transport, Error>) {
+        let (mut initiator_stream, mut responder_stream) = tokio::io::duplex(1024);
+
+        // Each side drops its stream when done, so a failed handshake does
+        // not leave the other side waiting.
+        tokio::join!(
+            async move { initiate(&mut initiator_stream, initiator_sk, expected_responder).await },
+            async move { respond(&mut responder_stream, responder_sk).await },
+        )
+    }
+
+    #[tokio::test]
+    async fn handshake_and_encrypted_frames() {
+        let alice_sk = SecretKey::from_slice(&[0x0a; 32]).unwrap();
+        let bob_sk = SecretKey::from_slice(&[0x0b; 32]).unwrap();
+        let bob_id = bob_sk.public_key(SECP256K1);
+
+        let (alice, bob) = handshake(&alice_sk, &bob_sk, &bob_id).await;
+        let (mut alice, mut bob) = (alice.unwrap(), bob.unwrap());
+        assert_eq!(alice.remote_node_id(), bob_id);
+        assert_eq!(bob.remote_node_id(), alice_sk.public_key(SECP256K1));
+
+        // Each direction uses its own key and nonces.
+        for body in [&b"open_channel"[..], &b"update_proposed"[..]] {
+            let frame = alice.encrypt_frame(body.len() as u32, body);
+            let (length, encrypted_body) = frame.split_at(4 + TAG_LEN);
+            let length = bob.decrypt_length(length.try_into().unwrap()).unwrap();
+            assert_eq!(length as usize, body.len());
+            assert_eq!(bob.decrypt_body(encrypted_body).unwrap(), body);
+        }
+        let frame = bob.encrypt_frame(2, b"ok");
+        assert_eq!(
+            alice
+                .decrypt_length(frame[..4 + TAG_LEN].try_into().unwrap())
+                .unwrap(),
+            2
+        );
+
+        // A tampered frame is rejected.
+        let mut frame = alice.encrypt_frame(2, b"ok");
+        frame[0] ^= 0x01;
+        assert!(
+            bob.decrypt_length(frame[..4 + TAG_LEN].try_into().unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn debug_redacts_keys() {
+        let remote_node_id = SecretKey::from_slice(&[0x0b; 32])
+            .unwrap()
+            .public_key(SECP256K1);
+        let transport = Transport::with_keys([0xab; 32], [0xcd; 32], remote_node_id);
+
+        let debug = format!("{:?}", transport);
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains("171, 171"));
+        assert!(!debug.contains("205, 205"));
+    }
+
+    #[tokio::test]
+    async fn reject_unauthenticated_peer() {
+        let alice_sk = SecretKey::from_slice(&[0x0a; 32]).unwrap();
+        let bob_sk = SecretKey::from_slice(&[0x0b; 32]).unwrap();
+        let mallory_id = SecretKey::from_slice(&[0x0c; 32])
+            .unwrap()
+            .public_key(SECP256K1);
+
+        // Alice expects Mallory, so Bob cannot authenticate her first act.
+        let (alice, bob) = handshake(&alice_sk, &bob_sk, &mallory_id).await;
+        assert!(matches!(bob, Err(Error::Handshake(_))));
+        assert!(alice.is_err());
+    }
+}
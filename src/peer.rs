@@ -0,0 +1,1756 @@
+//! Peer Connections
+//!
+//! Carries [`Message`]s between the two parties of a channel over TCP, and
+//! runs the exchanges that open a channel and move it to a new state, so that
+//! each party only ever signs with its own keys. Every connection starts
+//! with the [`crate::noise`] handshake, so messages are only exchanged with
+//! authenticated peers, and channels only accept updates from the peer that
+//! opened them.
+//!
+//! Moving to a new state follows the ELTOO ordering: both `Settlement Transaction`
+//! signatures are exchanged before any `Update Transaction` signature, so no
+//! party holds a complete update without the settlement that spends it.
+//!
+//! Dual-funded channels build their funding transaction interactively: each
+//! party adds its inputs and change with `tx_add_input` and `tx_add_output`
+//! until its `tx_complete`, and both sign state 0, which refunds every
+//! contribution, before either signs its inputs.
+//!
+//! Closing cooperatively starts with a `shutdown` from each party, after which
+//! they trade `closing_signed` fee proposals until both sign the same fee.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use elements::bitcoin::Amount;
+use elements::encode::serialize_hex;
+use elements::secp256k1_zkp::{PublicKey, SecretKey, ecdsa, schnorr};
+use elements::{Address, OutPoint, Transaction};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::channel::{Channel, CloseSignatures, Party, by_party, check_funding_amount};
+use crate::error::Error;
+use crate::esplora::{get_confirmation_height, get_transaction};
+use crate::keys::{ChannelKeys, SEED_LEN, derive_node_key};
+use crate::message::{
+    AcceptChannel, AcceptDualFundedChannel, ClosingSigned, FundingCreated, LENGTH_PREFIX_LEN,
+    MAX_FRAME_LEN, Message, OpenChannel, OpenDualFundedChannel, SettlementSigned, Shutdown,
+    TxAddInput, TxAddOutput, TxComplete, UpdateProposed, UpdateSigned,
+};
+use crate::noise::{TAG_LEN, Transport, initiate, respond};
+use crate::sign::{
+    SignedUpdate, finalize_settlement_transaction, finalize_setup_spending_transaction,
+    sign_anyprevout_input, sign_setup_spending_input, verify_anyprevout_input,
+    verify_setup_spending_input,
+};
+use crate::store::ChannelStore;
+use crate::transaction::{
+    FundingContribution, build_funding_transaction, build_setup_address, check_address_network,
+};
+
+/// The most `closing_signed` messages received while negotiating the fee of a
+/// cooperative close.
+const MAX_CLOSING_ROUNDS: usize = 16;
+
+/// The most inputs a peer may add to the funding transaction of a dual-funded
+/// channel.
+const MAX_FUNDING_INPUTS: usize = 64;
+
+/// How long a peer may take to complete the handshake or send the next frame.
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Receive the next message from `$peer`, failing unless it is a `$variant`.
+macro_rules! expect_message {
+    ($peer:expr, $variant:ident) => {
+        match $peer.receive().await? {
+            Message::$variant(message) => message,
+            other => {
+                return Err(Error::Peer(format!(
+                    "expected {}, got {}",
+                    stringify!($variant),
+                    other.name()
+                )));
+            }
+        }
+    };
+}
+
+/// An authenticated and encrypted connection to the other party of a channel.
+#[derive(Debug)]
+pub(crate) struct Peer {
+    stream: TcpStream,
+    transport: Transport,
+}
+
+impl Peer {
+    /// Connect to the peer with node key `remote_node_id` listening at `address`.
+    pub(crate) async fn connect(
+        address: impl ToSocketAddrs,
+        local_node_sk: &SecretKey,
+        remote_node_id: &PublicKey,
+    ) -> Result<Self, Error> {
+        let mut stream = with_timeout(TcpStream::connect(address)).await?;
+        let transport = with_timeout(initiate(&mut stream, local_node_sk, remote_node_id)).await?;
+
+        Ok(Peer { stream, transport })
+    }
+
+    /// Authenticate the peer of an accepted [`TcpStream`].
+    pub(crate) async fn accept(
+        mut stream: TcpStream,
+        local_node_sk: &SecretKey,
+    ) -> Result<Self, Error> {
+        let transport = with_timeout(respond(&mut stream, local_node_sk)).await?;
+
+        Ok(Peer { stream, transport })
+    }
+
+    /// The authenticated node key of the peer.
+    pub(crate) fn remote_node_id(&self) -> PublicKey {
+        self.transport.remote_node_id()
+    }
+
+    /// Send a message.
+    pub(crate) async fn send(&mut self, message: &Message) -> Result<(), Error> {
+        let frame = message.encode();
+        let (length, body) = frame.split_at(LENGTH_PREFIX_LEN);
+        let length = u32::from_be_bytes(length.try_into().expect("length prefix"));
+
+        self.stream
+            .write_all(&self.transport.encrypt_frame(length, body))
+            .await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// Receive the next message.
+    ///
+    /// Fails with an [`std::io::ErrorKind::UnexpectedEof`] error if the peer
+    /// closed the connection, and with an [`std::io::ErrorKind::TimedOut`]
+    /// error if it sends nothing for [`PEER_TIMEOUT`].
+    pub(crate) async fn receive(&mut self) -> Result<Message, Error> {
+        with_timeout(self.receive_frame()).await
+    }
+
+    /// Read and decrypt the next frame.
+    async fn receive_frame(&mut self) -> Result<Message, Error> {
+        let mut length = [0u8; LENGTH_PREFIX_LEN + TAG_LEN];
+        self.stream.read_exact(&mut length).await?;
+
+        let length = self.transport.decrypt_length(&length)?;
+        if length > MAX_FRAME_LEN {
+            return Err(Error::Message(format!(
+                "frame of {} bytes exceeds the limit of {}",
+                length, MAX_FRAME_LEN
+            )));
+        }
+
+        let mut body = vec![0u8; length as usize + TAG_LEN];
+        self.stream.read_exact(&mut body).await?;
+
+        Message::decode(&self.transport.decrypt_body(&body)?)
+    }
+}
+
+/// Run `future`, failing with an [`std::io::ErrorKind::TimedOut`] error if it
+/// takes longer than [`PEER_TIMEOUT`].
+async fn with_timeout<T, E>(future: impl Future<Output = Result<T, E>>) -> Result<T, E>
+where
+    E: From<std::io::Error>,
+{
+    timeout(PEER_TIMEOUT, future)
+        .await
+        .map_err(std::io::Error::from)?
+}
+
+/// Open a channel funded by us, Alice, with the peer.
+///
+/// `setup_outpoint` is the output of the unbroadcast funding transaction that
+/// pays `funding_amount` to the `Setup Address`. Returns the channel at state
+/// 0, signed by both parties, which refunds us: only then is it safe to
+/// broadcast the funding transaction.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn open_channel(
+    peer: &mut Peer,
+    keys: &ChannelKeys,
+    channel_index: u32,
+    key_reference: String,
+    setup_outpoint: OutPoint,
+    funding_amount: Amount,
+    payout_address: Address,
+    is_mainnet: bool,
+) -> Result<Channel, Error> {
+    check_funding_amount(funding_amount)?;
+    check_address_network(&payout_address, is_mainnet)?;
+
+    peer.send(&Message::OpenChannel(OpenChannel {
+        is_mainnet,
+        funding_amount,
+        update_pk: keys.update_pk(),
+        settlement_basepoint: keys.settlement_basepoint(),
+        payout_address: payout_address.clone(),
+    }))
+    .await?;
+    let accept = expect_message!(peer, AcceptChannel);
+    check_address_network(&accept.payout_address, is_mainnet)?;
+
+    // Refuse a counterparty that reuses our update key.
+    build_setup_address(keys.update_pk(), accept.update_pk, is_mainnet)?;
+    let mut channel = Channel::new(
+        is_mainnet,
+        setup_outpoint,
+        funding_amount,
+        keys.update_pk(),
+        accept.update_pk,
+        keys.settlement_basepoint(),
+        accept.settlement_basepoint,
+        payout_address.clone(),
+        accept.payout_address,
+        channel_index,
+        key_reference,
+        Party::Alice,
+    )?;
+    channel.peer_id = Some(peer.remote_node_id());
+
+    peer.send(&Message::FundingCreated(FundingCreated { setup_outpoint }))
+        .await?;
+    sign_initial_state(peer, &mut channel, keys).await?;
+    info!("Opened channel {}", channel.id());
+
+    Ok(channel)
+}
+
+/// Accept the channel Alice asked to open in `open`, as Bob.
+pub(crate) async fn accept_channel(
+    peer: &mut Peer,
+    open: OpenChannel,
+    keys: &ChannelKeys,
+    channel_index: u32,
+    key_reference: String,
+    payout_address: Address,
+    is_mainnet: bool,
+) -> Result<Channel, Error> {
+    if open.is_mainnet != is_mainnet {
+        return Err(Error::Peer("the channel is on another network".into()));
+    }
+    check_funding_amount(open.funding_amount)?;
+    check_address_network(&open.payout_address, is_mainnet)?;
+    check_address_network(&payout_address, is_mainnet)?;
+    build_setup_address(open.update_pk, keys.update_pk(), is_mainnet)?;
+
+    peer.send(&Message::AcceptChannel(AcceptChannel {
+        update_pk: keys.update_pk(),
+        settlement_basepoint: keys.settlement_basepoint(),
+        payout_address: payout_address.clone(),
+    }))
+    .await?;
+    let funding_created = expect_message!(peer, FundingCreated);
+
+    let mut channel = Channel::new(
+        is_mainnet,
+        funding_created.setup_outpoint,
+        open.funding_amount,
+        open.update_pk,
+        keys.update_pk(),
+        open.settlement_basepoint,
+        keys.settlement_basepoint(),
+        open.payout_address.clone(),
+        payout_address,
+        channel_index,
+        key_reference,
+        Party::Bob,
+    )?;
+    channel.peer_id = Some(peer.remote_node_id());
+
+    receive_initial_state(peer, &mut channel, keys).await?;
+    info!("Accepted channel {}", channel.id());
+
+    Ok(channel)
+}
+
+/// Open a channel funded by both us, Alice, and the peer.
+///
+/// Both parties add their inputs and change output of `contribution` to the
+/// funding transaction, Alice first, then sign state 0 of the channel, which
+/// refunds each contribution. Returns the channel at state 0 and the funding
+/// transaction, whose inputs each party still has to sign.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn open_dual_funded_channel(
+    peer: &mut Peer,
+    keys: &ChannelKeys,
+    channel_index: u32,
+    key_reference: String,
+    contribution: &FundingContribution,
+    payout_address: Address,
+    is_mainnet: bool,
+) -> Result<(Channel, Transaction), Error> {
+    check_funding_amount(contribution.amount)?;
+    check_address_network(&payout_address, is_mainnet)?;
+
+    peer.send(&Message::OpenDualFundedChannel(OpenDualFundedChannel {
+        is_mainnet,
+        funding_amount: contribution.amount,
+        update_pk: keys.update_pk(),
+        settlement_basepoint: keys.settlement_basepoint(),
+        payout_address: payout_address.clone(),
+    }))
+    .await?;
+    let accept = expect_message!(peer, AcceptDualFundedChannel);
+    check_address_network(&accept.payout_address, is_mainnet)?;
+    let setup_address = build_setup_address(keys.update_pk(), accept.update_pk, is_mainnet)?;
+
+    send_contribution(peer, contribution).await?;
+    let remote_contribution = receive_contribution(peer, accept.funding_amount).await?;
+    let funding = build_funding_transaction(
+        &setup_address,
+        contribution,
+        &remote_contribution,
+        is_mainnet,
+    )?;
+
+    let mut channel = Channel::new(
+        is_mainnet,
+        OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        },
+        contribution.amount + remote_contribution.amount,
+        keys.update_pk(),
+        accept.update_pk,
+        keys.settlement_basepoint(),
+        accept.settlement_basepoint,
+        payout_address.clone(),
+        accept.payout_address,
+        channel_index,
+        key_reference,
+        Party::Alice,
+    )?;
+    channel.add_bob_contribution(remote_contribution.amount)?;
+    channel.peer_id = Some(peer.remote_node_id());
+
+    peer.send(&Message::FundingCreated(FundingCreated {
+        setup_outpoint: channel.setup_outpoint,
+    }))
+    .await?;
+    sign_initial_state(peer, &mut channel, keys).await?;
+    info!("Opened dual-funded channel {}", channel.id());
+
+    Ok((channel, funding))
+}
+
+/// Accept the dual-funded channel Alice asked to open in `open`, as Bob,
+/// adding our `contribution`.
+///
+/// Returns the channel at state 0, signed by both parties, which refunds our
+/// contribution, and the funding transaction: only then is it safe to sign
+/// our inputs of the funding transaction.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn accept_dual_funded_channel(
+    peer: &mut Peer,
+    open: OpenDualFundedChannel,
+    keys: &ChannelKeys,
+    channel_index: u32,
+    key_reference: String,
+    contribution: &FundingContribution,
+    payout_address: Address,
+    is_mainnet: bool,
+) -> Result<(Channel, Transaction), Error> {
+    if open.is_mainnet != is_mainnet {
+        return Err(Error::Peer("the channel is on another network".into()));
+    }
+    check_funding_amount(open.funding_amount)?;
+    check_address_network(&open.payout_address, is_mainnet)?;
+    check_address_network(&payout_address, is_mainnet)?;
+    let setup_address = build_setup_address(open.update_pk, keys.update_pk(), is_mainnet)?;
+
+    peer.send(&Message::AcceptDualFundedChannel(AcceptDualFundedChannel {
+        funding_amount: contribution.amount,
+        update_pk: keys.update_pk(),
+        settlement_basepoint: keys.settlement_basepoint(),
+        payout_address: payout_address.clone(),
+    }))
+    .await?;
+
+    let remote_contribution = receive_contribution(peer, open.funding_amount).await?;
+    send_contribution(peer, contribution).await?;
+    let funding = build_funding_transaction(
+        &setup_address,
+        &remote_contribution,
+        contribution,
+        is_mainnet,
+    )?;
+
+    let mut channel = Channel::new(
+        is_mainnet,
+        OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        },
+        remote_contribution.amount + contribution.amount,
+        open.update_pk,
+        keys.update_pk(),
+        open.settlement_basepoint,
+        keys.settlement_basepoint(),
+        open.payout_address.clone(),
+        payout_address,
+        channel_index,
+        key_reference,
+        Party::Bob,
+    )?;
+    channel.add_bob_contribution(contribution.amount)?;
+    channel.peer_id = Some(peer.remote_node_id());
+
+    let funding_created = expect_message!(peer, FundingCreated);
+    check_channel(&channel, funding_created.setup_outpoint)?;
+    receive_initial_state(peer, &mut channel, keys).await?;
+    info!("Accepted dual-funded channel {}", channel.id());
+
+    Ok((channel, funding))
+}
+
+/// Add the inputs and change output of our `contribution` to the funding
+/// transaction.
+async fn send_contribution(
+    peer: &mut Peer,
+    contribution: &FundingContribution,
+) -> Result<(), Error> {
+    for (previous_output, amount) in &contribution.inputs {
+        peer.send(&Message::TxAddInput(TxAddInput {
+            previous_output: *previous_output,
+            amount: *amount,
+        }))
+        .await?;
+    }
+    if let Some((address, amount)) = &contribution.change {
+        peer.send(&Message::TxAddOutput(TxAddOutput {
+            address: address.clone(),
+            amount: *amount,
+        }))
+        .await?;
+    }
+
+    peer.send(&Message::TxComplete(TxComplete)).await
+}
+
+/// Receive the inputs and change output the peer adds to the funding
+/// transaction to contribute `amount`, until its `tx_complete`.
+async fn receive_contribution(
+    peer: &mut Peer,
+    amount: Amount,
+) -> Result<FundingContribution, Error> {
+    let mut contribution = FundingContribution {
+        amount,
+        ..Default::default()
+    };
+    loop {
+        match peer.receive().await? {
+            Message::TxAddInput(add_input) => {
+                if contribution.inputs.len() == MAX_FUNDING_INPUTS {
+                    return Err(Error::Peer(format!(
+                        "more than {} funding inputs",
+                        MAX_FUNDING_INPUTS
+                    )));
+                }
+                contribution
+                    .inputs
+                    .push((add_input.previous_output, add_input.amount));
+            }
+            Message::TxAddOutput(add_output) => {
+                if contribution.change.is_some() {
+                    return Err(Error::Peer("more than one change output".into()));
+                }
+                contribution.change = Some((add_output.address, add_output.amount));
+            }
+            Message::TxComplete(_) => break,
+            other => {
+                return Err(Error::Peer(format!(
+                    "expected funding inputs and outputs, got {}",
+                    other.name()
+                )));
+            }
+        }
+    }
+
+    // The peer's inputs must pay for its contribution.
+    contribution.fee()?;
+
+    Ok(contribution)
+}
+
+/// Sign state 0 of the new `channel` with the peer, as Alice, after our
+/// `funding_created`.
+async fn sign_initial_state(
+    peer: &mut Peer,
+    channel: &mut Channel,
+    keys: &ChannelKeys,
+) -> Result<(), Error> {
+    let balances = (channel.alice_balance, channel.bob_balance);
+    let pending = PendingState::new(channel, keys, 0, balances)?;
+
+    trade_signatures_as_proposer(peer, channel, pending).await
+}
+
+/// Sign state 0 of the new `channel` with the peer, as Bob, after Alice's
+/// `funding_created`.
+async fn receive_initial_state(
+    peer: &mut Peer,
+    channel: &mut Channel,
+    keys: &ChannelKeys,
+) -> Result<(), Error> {
+    let balances = (channel.alice_balance, channel.bob_balance);
+    let pending = PendingState::new(channel, keys, 0, balances)?;
+
+    trade_signatures(peer, channel, pending).await
+}
+
+/// Propose to move `channel` to a new state with the given balances, and sign
+/// it with the peer.
+pub(crate) async fn propose_update(
+    peer: &mut Peer,
+    channel: &mut Channel,
+    keys: &ChannelKeys,
+    alice_balance: Amount,
+    bob_balance: Amount,
+) -> Result<(), Error> {
+    check_policy_asset_only(channel)?;
+    channel.check_balances(alice_balance, bob_balance)?;
+    let state = channel.state + 1;
+
+    peer.send(&Message::UpdateProposed(UpdateProposed {
+        channel: channel.setup_outpoint,
+        state,
+        alice_balance,
+        bob_balance,
+    }))
+    .await?;
+    let pending = PendingState::new(channel, keys, state, (alice_balance, bob_balance))?;
+
+    trade_signatures_as_proposer(peer, channel, pending).await
+}
+
+/// Trade the signatures of the state we proposed in `pending` with the peer,
+/// and move `channel` to it.
+async fn trade_signatures_as_proposer(
+    peer: &mut Peer,
+    channel: &mut Channel,
+    pending: PendingState,
+) -> Result<(), Error> {
+    let remote_settlement = expect_message!(peer, SettlementSigned);
+    pending.verify_settlement(channel, &remote_settlement)?;
+    peer.send(&pending.settlement_signed(channel)).await?;
+
+    // We now hold both settlement signatures, so we can give away our update signature.
+    peer.send(&pending.update_signed(channel)).await?;
+    let remote_update = expect_message!(peer, UpdateSigned);
+    pending.verify_update(channel, &remote_update)?;
+
+    pending.apply(channel, remote_update, remote_settlement)
+}
+
+/// Sign the new state the peer proposed in `proposal` for `channel`.
+pub(crate) async fn receive_update(
+    peer: &mut Peer,
+    channel: &mut Channel,
+    keys: &ChannelKeys,
+    proposal: UpdateProposed,
+) -> Result<(), Error> {
+    check_channel(channel, proposal.channel)?;
+    check_policy_asset_only(channel)?;
+    if proposal.state != channel.state + 1 {
+        return Err(Error::Peer(format!(
+            "proposed state {} does not follow state {}",
+            proposal.state, channel.state
+        )));
+    }
+    channel.check_balances(proposal.alice_balance, proposal.bob_balance)?;
+
+    let pending = PendingState::new(
+        channel,
+        keys,
+        proposal.state,
+        (proposal.alice_balance, proposal.bob_balance),
+    )?;
+
+    trade_signatures(peer, channel, pending).await
+}
+
+/// Trade the signatures of the state the peer proposed in `pending`, and move
+/// `channel` to it.
+async fn trade_signatures(
+    peer: &mut Peer,
+    channel: &mut Channel,
+    pending: PendingState,
+) -> Result<(), Error> {
+    peer.send(&pending.settlement_signed(channel)).await?;
+
+    let remote_settlement = expect_message!(peer, SettlementSigned);
+    pending.verify_settlement(channel, &remote_settlement)?;
+    let remote_update = expect_message!(peer, UpdateSigned);
+    pending.verify_update(channel, &remote_update)?;
+    peer.send(&pending.update_signed(channel)).await?;
+
+    pending.apply(channel, remote_update, remote_settlement)
+}
+
+/// Close `channel` cooperatively with the peer, proposing to pay `fee`.
+///
+/// Returns the `Cooperative Close Transaction` signed by both parties, with the
+/// fee they agreed on.
+pub(crate) async fn close_channel(
+    peer: &mut Peer,
+    channel: &Channel,
+    keys: &ChannelKeys,
+    fee: Amount,
+) -> Result<Transaction, Error> {
+    check_policy_asset_only(channel)?;
+    channel.build_cooperative_close(fee)?;
+
+    peer.send(&Message::Shutdown(Shutdown {
+        channel: channel.setup_outpoint,
+    }))
+    .await?;
+    let shutdown = expect_message!(peer, Shutdown);
+    check_channel(channel, shutdown.channel)?;
+
+    negotiate_closing_fee(peer, channel, keys, fee, true).await
+}
+
+/// Close `channel` cooperatively after the peer's `shutdown`, preferring to pay `fee`.
+pub(crate) async fn receive_shutdown(
+    peer: &mut Peer,
+    channel: &Channel,
+    keys: &ChannelKeys,
+    shutdown: Shutdown,
+    fee: Amount,
+) -> Result<Transaction, Error> {
+    check_channel(channel, shutdown.channel)?;
+    check_policy_asset_only(channel)?;
+    channel.build_cooperative_close(fee)?;
+
+    peer.send(&Message::Shutdown(Shutdown {
+        channel: channel.setup_outpoint,
+    }))
+    .await?;
+
+    negotiate_closing_fee(peer, channel, keys, fee, false).await
+}
+
+/// Trade `closing_signed` messages with the peer until both parties signed the
+/// `Cooperative Close Transaction` for the same fee.
+///
+/// The party that sent the first `shutdown` proposes first. Each answer either
+/// accepts the fee the peer proposed, or counters with a fee halfway between
+/// both proposals, so the proposals converge.
+async fn negotiate_closing_fee(
+    peer: &mut Peer,
+    channel: &Channel,
+    keys: &ChannelKeys,
+    fee: Amount,
+    proposes_first: bool,
+) -> Result<Transaction, Error> {
+    let mut local_fee = None;
+    if proposes_first {
+        peer.send(&closing_signed(channel, keys, fee)?).await?;
+        local_fee = Some(fee);
+    }
+
+    for _ in 0..MAX_CLOSING_ROUNDS {
+        let remote = expect_message!(peer, ClosingSigned);
+        check_channel(channel, remote.channel)?;
+        let close = channel.build_cooperative_close(remote.fee)?;
+        verify_setup_spending_input(
+            &close,
+            0,
+            channel.funding_amount,
+            &channel.alice_update_pk,
+            &channel.bob_update_pk,
+            &channel.update_pk(channel.local_party.counterparty()),
+            &remote.signature,
+        )?;
+
+        // The peer accepted our last proposal, or we accept theirs.
+        if local_fee != Some(remote.fee) {
+            let next_fee = next_closing_fee(local_fee.unwrap_or(fee), remote.fee);
+            peer.send(&closing_signed(channel, keys, next_fee)?).await?;
+            local_fee = Some(next_fee);
+            if next_fee != remote.fee {
+                continue;
+            }
+        }
+
+        let local_sig = sign_closing(channel, keys, &close);
+        let (alice_sig, bob_sig) = by_party(channel.local_party, local_sig, remote.signature);
+        info!(
+            "Closing channel {} cooperatively with a fee of {}",
+            channel.id(),
+            remote.fee
+        );
+
+        return Ok(finalize_setup_spending_transaction(
+            &close,
+            &channel.alice_update_pk,
+            &channel.bob_update_pk,
+            &[(alice_sig, bob_sig)],
+        ));
+    }
+
+    Err(Error::Peer(format!(
+        "no agreement on the closing fee after {} proposals",
+        MAX_CLOSING_ROUNDS
+    )))
+}
+
+/// Our `closing_signed` message proposing `fee`.
+fn closing_signed(channel: &Channel, keys: &ChannelKeys, fee: Amount) -> Result<Message, Error> {
+    let close = channel.build_cooperative_close(fee)?;
+
+    Ok(Message::ClosingSigned(ClosingSigned {
+        channel: channel.setup_outpoint,
+        fee,
+        signature: sign_closing(channel, keys, &close),
+    }))
+}
+
+/// Sign the `Cooperative Close Transaction` `close` with our update key.
+fn sign_closing(channel: &Channel, keys: &ChannelKeys, close: &Transaction) -> ecdsa::Signature {
+    sign_setup_spending_input(
+        close,
+        0,
+        channel.funding_amount,
+        &channel.alice_update_pk,
+        &channel.bob_update_pk,
+        &keys.update_sk,
+    )
+}
+
+/// The fee to answer a `remote_fee` proposal with, after proposing `local_fee`.
+///
+/// Returns `remote_fee` to accept it once both proposals are at most one sat
+/// apart, and the fee halfway between them otherwise.
+fn next_closing_fee(local_fee: Amount, remote_fee: Amount) -> Amount {
+    if local_fee.to_sat().abs_diff(remote_fee.to_sat()) <= 1 {
+        return remote_fee;
+    }
+
+    Amount::from_sat((local_fee.to_sat() + remote_fee.to_sat()) / 2)
+}
+
+/// Serve peers connecting on `listener`, accepting new channels and signing
+/// the states they propose, until the listener fails.
+///
+/// Peers are authenticated with the node key derived from `seed`, and each is
+/// served by its own task, so a slow or silent peer does not hold up the others.
+///
+/// New channels are derived from `seed` at the first channel index from
+/// `channel_index` that no stored channel uses, and refused if a stored one has
+/// the same setup outpoint. Later states are only signed once Esplora at
+/// `esplora_url` shows the setup output confirmed with the funding amount.
+/// Cooperative closes start from a proposal to pay `closing_fee`.
+///
+/// The first dual-funded channel gets our `contribution`, if any, since its
+/// inputs can only be spent once. Later ones are accepted without contributing.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve(
+    listener: TcpListener,
+    store: ChannelStore,
+    seed: [u8; SEED_LEN],
+    key_reference: String,
+    channel_index: u32,
+    payout_address: Address,
+    closing_fee: Amount,
+    contribution: Option<FundingContribution>,
+    esplora_url: String,
+    is_mainnet: bool,
+) -> Result<(), Error> {
+    let node_sk = derive_node_key(&seed, is_mainnet)?;
+    let context = Arc::new(ServeContext {
+        store,
+        seed,
+        key_reference,
+        next_channel_index: Mutex::new(channel_index),
+        payout_address,
+        closing_fee,
+        contribution: Mutex::new(contribution),
+        busy_channels: Mutex::new(HashSet::new()),
+        esplora_url,
+        is_mainnet,
+    });
+
+    loop {
+        let (stream, address) = listener.accept().await?;
+        info!("Peer connected from {}", address);
+
+        tokio::spawn(serve_peer(stream, address, node_sk, Arc::clone(&context)));
+    }
+}
+
+/// Authenticate the peer connected from `address` on `stream`, then handle
+/// its messages until it disconnects or misbehaves.
+async fn serve_peer(
+    stream: TcpStream,
+    address: SocketAddr,
+    node_sk: SecretKey,
+    context: Arc<ServeContext>,
+) {
+    let mut peer = match Peer::accept(stream, &node_sk).await {
+        Ok(peer) => peer,
+        Err(e) => {
+            warn!("Rejecting peer {}: {}", address, e);
+            return;
+        }
+    };
+    info!(
+        "Authenticated peer {} as {}",
+        address,
+        peer.remote_node_id()
+    );
+
+    loop {
+        let message = match peer.receive().await {
+            Ok(message) => message,
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                warn!("Dropping peer {}: {}", address, e);
+                break;
+            }
+        };
+
+        // A misbehaving peer only costs us its connection.
+        if let Err(e) = handle_message(&mut peer, message, &context).await {
+            warn!("Dropping peer {}: {}", address, e);
+            break;
+        }
+    }
+}
+
+/// What [`serve`] needs to handle a message, shared by every connection.
+struct ServeContext {
+    store: ChannelStore,
+    seed: [u8; SEED_LEN],
+    key_reference: String,
+    /// The first channel index that may be given to a new channel.
+    next_channel_index: Mutex<u32>,
+    payout_address: Address,
+    closing_fee: Amount,
+    contribution: Mutex<Option<FundingContribution>>,
+    /// The setup [`OutPoint`]s of the channels a connection is moving to a
+    /// new state or closing.
+    busy_channels: Mutex<HashSet<OutPoint>>,
+    /// The Esplora API the funding of a channel is checked against.
+    esplora_url: String,
+    is_mainnet: bool,
+}
+
+impl ServeContext {
+    /// Reserve the index of a new channel, which no stored channel uses and no
+    /// other connection got.
+    fn reserve_channel_index(&self) -> Result<u32, Error> {
+        let mut next = self
+            .next_channel_index
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let channel_index = next_channel_index(&self.store, *next)?;
+        *next = channel_index.saturating_add(1);
+
+        Ok(channel_index)
+    }
+
+    /// Load the channel funded at `setup_outpoint` for an exchange with the
+    /// peer, failing if another connection is in one already.
+    ///
+    /// The channel is released when the returned guard is dropped.
+    fn load_channel(
+        &self,
+        peer: &Peer,
+        setup_outpoint: OutPoint,
+    ) -> Result<(Channel, BusyChannel<'_>), Error> {
+        if !self
+            .busy_channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(setup_outpoint)
+        {
+            return Err(Error::Peer(format!(
+                "channel {}_{} is busy with another connection",
+                setup_outpoint.txid, setup_outpoint.vout
+            )));
+        }
+        let busy = BusyChannel {
+            context: self,
+            setup_outpoint,
+        };
+
+        Ok((load_peer_channel(peer, &self.store, setup_outpoint)?, busy))
+    }
+}
+
+/// A channel a connection is busy with, see [`ServeContext::load_channel`].
+struct BusyChannel<'a> {
+    context: &'a ServeContext,
+    setup_outpoint: OutPoint,
+}
+
+impl Drop for BusyChannel<'_> {
+    fn drop(&mut self) {
+        self.context
+            .busy_channels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.setup_outpoint);
+    }
+}
+
+/// Handle a message that starts an exchange with the peer.
+async fn handle_message(
+    peer: &mut Peer,
+    message: Message,
+    context: &ServeContext,
+) -> Result<(), Error> {
+    match message {
+        Message::OpenChannel(open) => {
+            let channel_index = context.reserve_channel_index()?;
+            let keys = ChannelKeys::derive(&context.seed, channel_index, context.is_mainnet)?;
+            let channel = accept_channel(
+                peer,
+                open,
+                &keys,
+                channel_index,
+                context.key_reference.clone(),
+                context.payout_address.clone(),
+                context.is_mainnet,
+            )
+            .await?;
+
+            context.store.create(&channel)
+        }
+        Message::OpenDualFundedChannel(open) => {
+            let channel_index = context.reserve_channel_index()?;
+            let keys = ChannelKeys::derive(&context.seed, channel_index, context.is_mainnet)?;
+
+            // No other connection may spend our inputs while this one uses them.
+            let contribution = context
+                .contribution
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            let accepted = accept_dual_funded_channel(
+                peer,
+                open,
+                &keys,
+                channel_index,
+                context.key_reference.clone(),
+                &contribution.clone().unwrap_or_default(),
+                context.payout_address.clone(),
+                context.is_mainnet,
+            )
+            .await;
+            if accepted.is_err() {
+                *context
+                    .contribution
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = contribution;
+            }
+            let (channel, funding) = accepted?;
+            info!(
+                "Channel {} is funded by {}",
+                channel.id(),
+                serialize_hex(&funding)
+            );
+
+            context.store.create(&channel)
+        }
+        Message::UpdateProposed(proposal) => {
+            let (mut channel, _busy) = context.load_channel(peer, proposal.channel)?;
+            check_funded(&context.esplora_url, &channel).await?;
+            let keys =
+                ChannelKeys::derive(&context.seed, channel.channel_index, channel.is_mainnet)?;
+            receive_update(peer, &mut channel, &keys, proposal).await?;
+
+            context.store.save(&channel)
+        }
+        Message::Shutdown(shutdown) => {
+            let (channel, _busy) = context.load_channel(peer, shutdown.channel)?;
+            let keys =
+                ChannelKeys::derive(&context.seed, channel.channel_index, channel.is_mainnet)?;
+            let close =
+                receive_shutdown(peer, &channel, &keys, shutdown, context.closing_fee).await?;
+            info!(
+                "Channel {} can be closed with {}",
+                channel.id(),
+                serialize_hex(&close)
+            );
+
+            Ok(())
+        }
+        message => Err(Error::Peer(format!("unexpected {}", message.name()))),
+    }
+}
+
+/// Load the stored channel funded at `setup_outpoint`, failing unless it was
+/// opened with the peer.
+fn load_peer_channel(
+    peer: &Peer,
+    store: &ChannelStore,
+    setup_outpoint: OutPoint,
+) -> Result<Channel, Error> {
+    let channel_id = format!("{}_{}", setup_outpoint.txid, setup_outpoint.vout);
+    let channel = store.load(&channel_id)?;
+    if channel.peer_id != Some(peer.remote_node_id()) {
+        return Err(Error::Peer(format!(
+            "channel {} was not opened with this peer",
+            channel_id
+        )));
+    }
+
+    Ok(channel)
+}
+
+/// Check through Esplora at `url` that the setup output of `channel` is
+/// confirmed with the funding amount, before any balance is moved to us.
+async fn check_funded(url: &str, channel: &Channel) -> Result<(), Error> {
+    let txid = channel.setup_outpoint.txid;
+    if get_confirmation_height(url, &txid).await?.is_none() {
+        return Err(Error::Peer(format!(
+            "funding transaction {} of channel {} is not confirmed",
+            txid,
+            channel.id()
+        )));
+    }
+    let funding = get_transaction(url, &txid).await?;
+
+    channel.check_funding_transaction(&funding)
+}
+
+/// The first channel index from `from` that no stored channel uses.
+fn next_channel_index(store: &ChannelStore, from: u32) -> Result<u32, Error> {
+    let mut used = Vec::new();
+    for channel_id in store.list()? {
+        used.push(store.load(&channel_id)?.channel_index);
+    }
+
+    (from..=u32::MAX)
+        .find(|index| !used.contains(index))
+        .ok_or_else(|| Error::Key("no channel index left".into()))
+}
+
+/// Check that a message is about `channel`.
+fn check_channel(channel: &Channel, outpoint: OutPoint) -> Result<(), Error> {
+    if outpoint != channel.setup_outpoint {
+        return Err(Error::Peer(format!(
+            "message for channel {}_{} instead of {}",
+            outpoint.txid,
+            outpoint.vout,
+            channel.id()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check that `channel` carries no issued assets, whose inputs and balances
+/// are not part of the wire protocol yet.
+fn check_policy_asset_only(channel: &Channel) -> Result<(), Error> {
+    if !channel.issued_assets.is_empty() {
+        return Err(Error::Peer(format!(
+            "channel {} carries issued assets, which cannot be updated over the wire yet",
+            channel.id()
+        )));
+    }
+
+    Ok(())
+}
+
+/// A state both parties are signing, with our own signatures.
+///
+/// Besides the update and settlement, each party signs the update that spends
+/// the setup output, so that the other can close the channel with its own keys
+/// only.
+struct PendingState {
+    state: u32,
+    balances: (Amount, Amount),
+    update: Transaction,
+    settlement: Transaction,
+    closing_update: Transaction,
+    local_update_sig: schnorr::Signature,
+    local_settlement_sig: schnorr::Signature,
+    local_close_sigs: CloseSignatures,
+}
+
+impl PendingState {
+    /// Build the transactions of `state` and sign them with our keys.
+    fn new(
+        channel: &Channel,
+        keys: &ChannelKeys,
+        state: u32,
+        balances: (Amount, Amount),
+    ) -> Result<Self, Error> {
+        let update = channel.build_update(state)?;
+        let settlement = channel.build_settlement(
+            state,
+            balances,
+            &[],
+            &[],
+            &[],
+            OutPoint::default(),
+            channel.commitment_amount(),
+        )?;
+
+        Ok(PendingState {
+            state,
+            balances,
+            closing_update: channel.build_closing_update(state)?,
+            local_close_sigs: channel.sign_close(state, keys)?,
+            local_update_sig: sign_anyprevout_input(
+                &update,
+                channel.commitment_amount(),
+                &keys.update_sk,
+                channel.is_mainnet,
+            ),
+            local_settlement_sig: sign_anyprevout_input(
+                &settlement,
+                channel.commitment_amount(),
+                &keys.settlement_sk(state),
+                channel.is_mainnet,
+            ),
+            update,
+            settlement,
+        })
+    }
+
+    /// Our `update_signed` message.
+    fn update_signed(&self, channel: &Channel) -> Message {
+        Message::UpdateSigned(UpdateSigned {
+            channel: channel.setup_outpoint,
+            state: self.state,
+            signature: self.local_update_sig,
+            closing_signature: self.local_close_sigs.closing_update[0],
+        })
+    }
+
+    /// Our `settlement_signed` message.
+    fn settlement_signed(&self, channel: &Channel) -> Message {
+        Message::SettlementSigned(SettlementSigned {
+            channel: channel.setup_outpoint,
+            state: self.state,
+            signature: self.local_settlement_sig,
+        })
+    }
+
+    /// Verify the peer's signatures of the update.
+    fn verify_update(&self, channel: &Channel, remote: &UpdateSigned) -> Result<(), Error> {
+        self.check_state(channel, remote.channel, remote.state)?;
+        let remote_update_pk = channel.update_pk(channel.local_party.counterparty());
+
+        verify_anyprevout_input(
+            &self.update,
+            channel.commitment_amount(),
+            &remote_update_pk,
+            &remote.signature,
+            channel.is_mainnet,
+        )?;
+        verify_setup_spending_input(
+            &self.closing_update,
+            0,
+            channel.funding_amount,
+            &channel.alice_update_pk,
+            &channel.bob_update_pk,
+            &remote_update_pk,
+            &remote.closing_signature,
+        )
+    }
+
+    /// Verify the peer's signatures of the settlement.
+    fn verify_settlement(&self, channel: &Channel, remote: &SettlementSigned) -> Result<(), Error> {
+        self.check_state(channel, remote.channel, remote.state)?;
+        let remote_settlement_pk =
+            channel.settlement_pk(channel.local_party.counterparty(), self.state);
+
+        verify_anyprevout_input(
+            &self.settlement,
+            channel.commitment_amount(),
+            &remote_settlement_pk,
+            &remote.signature,
+            channel.is_mainnet,
+        )
+    }
+
+    /// Check that a signature message is about this state of `channel`.
+    fn check_state(&self, channel: &Channel, outpoint: OutPoint, state: u32) -> Result<(), Error> {
+        check_channel(channel, outpoint)?;
+        if state != self.state {
+            return Err(Error::Peer(format!(
+                "signature for state {} instead of {}",
+                state, self.state
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Move `channel` to this state with both parties signatures, keeping the
+    /// peer's signatures that close it.
+    fn apply(
+        self,
+        channel: &mut Channel,
+        remote_update: UpdateSigned,
+        remote_settlement: SettlementSigned,
+    ) -> Result<(), Error> {
+        let (alice_update_sig, bob_update_sig) = by_party(
+            channel.local_party,
+            self.local_update_sig,
+            remote_update.signature,
+        );
+        let (alice_settlement_sig, bob_settlement_sig) = by_party(
+            channel.local_party,
+            self.local_settlement_sig,
+            remote_settlement.signature,
+        );
+        let close_sigs = CloseSignatures {
+            closing_update: vec![remote_update.closing_signature],
+        };
+
+        let update = SignedUpdate {
+            state: self.state,
+            transaction: self.update,
+            alice_sig: alice_update_sig,
+            bob_sig: bob_update_sig,
+            asset_sigs: Vec::new(),
+        };
+        let settlement = finalize_settlement_transaction(
+            &self.settlement,
+            &channel.commitment_program(self.state)?,
+            &alice_settlement_sig,
+            &bob_settlement_sig,
+            &[],
+        )?;
+
+        // HTLCs and PTLCs are not part of the wire protocol yet, so peer
+        // states only carry balances.
+        if !channel.advance(
+            update,
+            settlement,
+            close_sigs,
+            self.balances.0,
+            self.balances.1,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        ) {
+            return Err(Error::Channel(format!(
+                "state {} is not newer than {}",
+                self.state, channel.state
+            )));
+        }
+        info!("Channel {} moved to state {}", channel.id(), channel.state);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::hashes::Hash;
+    use elements::secp256k1_zkp::SECP256K1;
+    use elements::{Txid, confidential};
+
+    use crate::script::{CommitmentPath, execute_commitment_spend};
+    use crate::sign::{sign_setup_spending_transaction, verify_fee_input};
+    use crate::transaction::rebind_update_transaction;
+    use crate::{ALICE_RESOLUTION_ADDRESS, FEE_AMOUNT};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn open_update_and_close_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let payout_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let setup_outpoint = OutPoint {
+            txid: Txid::from_str(
+                "000000000000000000001cd7e92aaf365e841cdd39f19360139b7baef188992f",
+            )
+            .unwrap(),
+            vout: 0,
+        };
+        let alice_keys = ChannelKeys::derive(&[0x0a; SEED_LEN], 0, false).unwrap();
+        let bob_keys = ChannelKeys::derive(&[0x0b; SEED_LEN], 0, false).unwrap();
+        let alice_node_sk = derive_node_key(&[0x0a; SEED_LEN], false).unwrap();
+        let bob_node_sk = derive_node_key(&[0x0b; SEED_LEN], false).unwrap();
+        let bob_node_id = bob_node_sk.public_key(SECP256K1);
+
+        // Bob accepts the channel and signs one update.
+        let bob_address = payout_address.clone();
+        let bob = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut peer = Peer::accept(stream, &bob_node_sk).await.unwrap();
+
+            let Message::OpenChannel(open) = peer.receive().await.unwrap() else {
+                panic!("expected open_channel");
+            };
+            let mut channel = accept_channel(
+                &mut peer,
+                open,
+                &bob_keys,
+                0,
+                "bob.seed".to_string(),
+                bob_address,
+                false,
+            )
+            .await
+            .unwrap();
+
+            let Message::UpdateProposed(proposal) = peer.receive().await.unwrap() else {
+                panic!("expected update_proposed");
+            };
+            receive_update(&mut peer, &mut channel, &bob_keys, proposal)
+                .await
+                .unwrap();
+
+            let Message::Shutdown(shutdown) = peer.receive().await.unwrap() else {
+                panic!("expected shutdown");
+            };
+            let close = receive_shutdown(
+                &mut peer,
+                &channel,
+                &bob_keys,
+                shutdown,
+                Amount::from_sat(69),
+            )
+            .await
+            .unwrap();
+
+            (channel, close)
+        });
+
+        // Alice opens the channel and pays Bob.
+        let mut peer = Peer::connect(address, &alice_node_sk, &bob_node_id)
+            .await
+            .unwrap();
+        let mut alice_channel = open_channel(
+            &mut peer,
+            &alice_keys,
+            0,
+            "alice.seed".to_string(),
+            setup_outpoint,
+            Amount::from_sat(2140),
+            payout_address,
+            false,
+        )
+        .await
+        .unwrap();
+
+        // Before funding the channel, Alice holds state 0, which refunds her.
+        assert_eq!(alice_channel.state, 0);
+        let refund = alice_channel
+            .build_signed_closing_update(&alice_keys)
+            .unwrap();
+        assert_eq!(refund.input[0].previous_output, setup_outpoint);
+        let refund_settlement = alice_channel.latest_settlement.clone().unwrap();
+        assert_eq!(
+            refund_settlement.output[0].value,
+            confidential::Value::Explicit(alice_channel.capacity().to_sat())
+        );
+
+        let capacity = alice_channel.capacity();
+        let bob_balance = Amount::from_sat(500);
+        propose_update(
+            &mut peer,
+            &mut alice_channel,
+            &alice_keys,
+            capacity - bob_balance,
+            bob_balance,
+        )
+        .await
+        .unwrap();
+
+        // Alice would rather pay a higher fee than Bob, and they meet in between.
+        let close = close_channel(
+            &mut peer,
+            &alice_channel,
+            &alice_keys,
+            Amount::from_sat(130),
+        )
+        .await
+        .unwrap();
+
+        // Both sides end up with the same fully signed state.
+        let (bob_channel, bob_close) = bob.await.unwrap();
+        assert_eq!(alice_channel.state, 1);
+        assert_eq!(bob_channel.state, alice_channel.state);
+        assert_eq!(bob_channel.bob_balance, bob_balance);
+        assert_eq!(bob_channel.latest_update, alice_channel.latest_update);
+        assert_eq!(
+            bob_channel.latest_settlement,
+            alice_channel.latest_settlement
+        );
+        assert_eq!(bob_channel.local_party, Party::Bob);
+
+        // Should Bob publish the refund, state 1 outranks it, with Alice paying
+        // the fee from her fee address.
+        let latest_update = alice_channel.latest_update.as_ref().unwrap();
+        let refund_program = alice_channel.commitment_program(0).unwrap();
+        let fee_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x03; 32]),
+            vout: 1,
+        };
+        let fee_input = alice_channel
+            .sign_fee_input(fee_outpoint, &alice_keys)
+            .unwrap();
+        let rebound =
+            rebind_update_transaction(latest_update, &refund, &refund_program, &fee_input).unwrap();
+        assert_eq!(rebound.input.last().unwrap().previous_output, fee_outpoint);
+        assert!(verify_fee_input(&rebound, &fee_input).is_ok());
+        assert!(
+            execute_commitment_spend(
+                &refund_program,
+                CommitmentPath::Update,
+                &latest_update.alice_sig,
+                &latest_update.bob_sig,
+                &rebound,
+                0,
+                alice_channel.commitment_amount(),
+                false,
+            )
+            .is_ok()
+        );
+
+        // Each side can close the channel on its own, with its own keys and the
+        // signatures it received: starting from the setup output...
+        let closing_update = sign_setup_spending_transaction(
+            &alice_channel.build_closing_update(1).unwrap(),
+            alice_channel.funding_amount,
+            &[],
+            &alice_keys.update_sk,
+            &bob_keys.update_sk,
+        );
+        // ...and settling the update that overrode a stale one, whose commitment
+        // output holds the same amount.
+        assert_eq!(rebound.output[0].value, refund.output[0].value);
+        let commitment_prevout = OutPoint {
+            txid: rebound.txid(),
+            vout: 0,
+        };
+        for (channel, keys) in [(&alice_channel, &alice_keys), (&bob_channel, &bob_keys)] {
+            assert_eq!(
+                channel.build_signed_closing_update(keys).unwrap(),
+                closing_update
+            );
+            let settlement = channel
+                .build_signed_settlement(commitment_prevout, channel.commitment_amount())
+                .unwrap();
+            assert_eq!(settlement.input[0].previous_output, commitment_prevout);
+        }
+        assert!(matches!(
+            bob_channel.build_signed_closing_update(&alice_keys),
+            Err(Error::Key(_))
+        ));
+
+        // And the same cooperative close, paying Bob straight from the setup output.
+        assert_eq!(bob_close, close);
+        assert_eq!(close.input[0].previous_output, setup_outpoint);
+        assert_eq!(close.input[0].witness.script_witness.len(), 3);
+        assert_eq!(close.output[1].value, confidential::Value::Explicit(500));
+        assert!(close.output[2].is_fee());
+        assert_eq!(close.output[2].value, confidential::Value::Explicit(109));
+
+        // Each side knows who it opened the channel with.
+        assert_eq!(alice_channel.peer_id, Some(bob_node_id));
+        assert_eq!(
+            bob_channel.peer_id,
+            Some(alice_node_sk.public_key(SECP256K1))
+        );
+    }
+
+    #[tokio::test]
+    async fn open_dual_funded_channel_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let payout_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let input = |byte| OutPoint {
+            txid: Txid::from_byte_array([byte; 32]),
+            vout: 0,
+        };
+        let alice_keys = ChannelKeys::derive(&[0x0a; SEED_LEN], 0, false).unwrap();
+        let bob_keys = ChannelKeys::derive(&[0x0b; SEED_LEN], 0, false).unwrap();
+        let alice_node_sk = derive_node_key(&[0x0a; SEED_LEN], false).unwrap();
+        let bob_node_sk = derive_node_key(&[0x0b; SEED_LEN], false).unwrap();
+        let bob_node_id = bob_node_sk.public_key(SECP256K1);
+
+        // Bob contributes 1000 sats out of a 1500 sats input.
+        let bob_contribution = FundingContribution::new(
+            Amount::from_sat(1000),
+            vec![(input(2), Amount::from_sat(1500))],
+            Some(payout_address.clone()),
+            Amount::ZERO,
+        )
+        .unwrap();
+        let bob_address = payout_address.clone();
+        let bob = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut peer = Peer::accept(stream, &bob_node_sk).await.unwrap();
+
+            let Message::OpenDualFundedChannel(open) = peer.receive().await.unwrap() else {
+                panic!("expected open_dual_funded_channel");
+            };
+            accept_dual_funded_channel(
+                &mut peer,
+                open,
+                &bob_keys,
+                0,
+                "bob.seed".to_string(),
+                &bob_contribution,
+                bob_address,
+                false,
+            )
+            .await
+            .unwrap()
+        });
+
+        // Alice contributes 2140 sats and pays the funding fee.
+        let alice_contribution = FundingContribution::new(
+            Amount::from_sat(2140),
+            vec![(input(1), Amount::from_sat(3000))],
+            Some(payout_address.clone()),
+            Amount::from_sat(FEE_AMOUNT),
+        )
+        .unwrap();
+        let mut peer = Peer::connect(address, &alice_node_sk, &bob_node_id)
+            .await
+            .unwrap();
+        let (alice_channel, funding) = open_dual_funded_channel(
+            &mut peer,
+            &alice_keys,
+            0,
+            "alice.seed".to_string(),
+            &alice_contribution,
+            payout_address,
+            false,
+        )
+        .await
+        .unwrap();
+        let (bob_channel, bob_funding) = bob.await.unwrap();
+
+        // Both sides build the same funding transaction out of both contributions.
+        assert_eq!(bob_funding, funding);
+        assert_eq!(funding.input.len(), 2);
+        assert_eq!(funding.output[0].value, confidential::Value::Explicit(3140));
+        assert_eq!(alice_channel.setup_outpoint.txid, funding.txid());
+        assert_eq!(bob_channel.setup_outpoint, alice_channel.setup_outpoint);
+
+        // Bob would find the setup output in the funding transaction, and in no
+        // other one, like one paying less to the setup address.
+        assert!(bob_channel.check_funding_transaction(&funding).is_ok());
+        let mut underfunded = funding.clone();
+        underfunded.output[0].value = confidential::Value::Explicit(3139);
+        assert!(bob_channel.check_funding_transaction(&underfunded).is_err());
+
+        // Bob starts with his contribution, and Alice with hers minus the fees.
+        assert_eq!(alice_channel.bob_balance, Amount::from_sat(1000));
+        assert_eq!(bob_channel.alice_balance, Amount::from_sat(2002));
+        assert_eq!(bob_channel.bob_balance, alice_channel.bob_balance);
+
+        // And both hold state 0, refunding each contribution.
+        assert_eq!(alice_channel.state, 0);
+        assert_eq!(bob_channel.latest_update, alice_channel.latest_update);
+        assert_eq!(
+            bob_channel.build_signed_closing_update(&bob_keys).unwrap(),
+            alice_channel
+                .build_signed_closing_update(&alice_keys)
+                .unwrap()
+        );
+        let refund_settlement = bob_channel.latest_settlement.unwrap();
+        assert_eq!(refund_settlement, alice_channel.latest_settlement.unwrap());
+        assert_eq!(
+            refund_settlement.output[1].value,
+            confidential::Value::Explicit(1000)
+        );
+    }
+
+    #[test]
+    fn closing_fee_converges() {
+        assert_eq!(
+            next_closing_fee(Amount::from_sat(69), Amount::from_sat(150)),
+            Amount::from_sat(109)
+        );
+        assert_eq!(
+            next_closing_fee(Amount::from_sat(121), Amount::from_sat(122)),
+            Amount::from_sat(122)
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_plaintext_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let bob_node_sk = derive_node_key(&[0x0b; SEED_LEN], false).unwrap();
+
+        // Mallory skips the handshake and sends a plaintext `open_channel`.
+        let mallory = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let open = Message::OpenChannel(OpenChannel {
+                is_mainnet: false,
+                funding_amount: Amount::from_sat(2140),
+                update_pk: bob_node_sk.x_only_public_key(SECP256K1).0,
+                settlement_basepoint: bob_node_sk.public_key(SECP256K1),
+                payout_address: Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap(),
+            });
+            let _ = stream.write_all(&open.encode()).await;
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            Peer::accept(stream, &bob_node_sk).await,
+            Err(Error::Handshake(_))
+        ));
+        mallory.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_peer_expecting_another_node() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let alice_node_sk = derive_node_key(&[0x0a; SEED_LEN], false).unwrap();
+        let bob_node_sk = derive_node_key(&[0x0b; SEED_LEN], false).unwrap();
+        let mallory_id = derive_node_key(&[0x0c; SEED_LEN], false)
+            .unwrap()
+            .public_key(SECP256K1);
+
+        // Alice expects Mallory's node key, so Bob cannot authenticate her.
+        let alice = tokio::spawn(async move {
+            Peer::connect(address, &alice_node_sk, &mallory_id)
+                .await
+                .is_err()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            Peer::accept(stream, &bob_node_sk).await,
+            Err(Error::Handshake(_))
+        ));
+        assert!(alice.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drop_silent_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bob_node_sk = derive_node_key(&[0x0b; SEED_LEN], false).unwrap();
+
+        // Mallory connects, but never starts the handshake.
+        let _mallory = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        match Peer::accept(stream, &bob_node_sk).await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_peers_concurrently() {
+        let dir = std::env::temp_dir().join(format!("simpleltoo-serve-{}", std::process::id()));
+        let store = ChannelStore::open(&dir).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let payout_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let alice_keys = ChannelKeys::derive(&[0x0a; SEED_LEN], 0, false).unwrap();
+        let alice_node_sk = derive_node_key(&[0x0a; SEED_LEN], false).unwrap();
+        let bob_node_id = derive_node_key(&[0x0b; SEED_LEN], false)
+            .unwrap()
+            .public_key(SECP256K1);
+        let mallory_id = derive_node_key(&[0x0c; SEED_LEN], false)
+            .unwrap()
+            .public_key(SECP256K1);
+
+        let server = tokio::spawn({
+            let store = store.clone();
+            let payout_address = payout_address.clone();
+            async move {
+                let _ = serve(
+                    listener,
+                    store,
+                    [0x0b; SEED_LEN],
+                    "bob.seed".to_string(),
+                    0,
+                    payout_address,
+                    Amount::from_sat(FEE_AMOUNT),
+                    None,
+                    "http://127.0.0.1:1/".to_string(),
+                    false,
+                )
+                .await;
+            }
+        });
+
+        // Mallory connects, but never starts the handshake.
+        let _mallory = TcpStream::connect(address).await.unwrap();
+
+        // A peer that expects another node key is turned away...
+        assert!(
+            Peer::connect(address, &alice_node_sk, &mallory_id)
+                .await
+                .is_err()
+        );
+
+        // ...while Alice still opens a channel.
+        let mut peer = Peer::connect(address, &alice_node_sk, &bob_node_id)
+            .await
+            .unwrap();
+        let setup_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x01; 32]),
+            vout: 0,
+        };
+        let mut alice_channel = open_channel(
+            &mut peer,
+            &alice_keys,
+            0,
+            "alice.seed".to_string(),
+            setup_outpoint,
+            Amount::from_sat(2140),
+            payout_address,
+            false,
+        )
+        .await
+        .unwrap();
+
+        // Bob stores the channel once he signed state 0.
+        while store.list().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // He signs no other state before he sees the funding confirmed, which
+        // his Esplora cannot tell him.
+        let capacity = alice_channel.capacity();
+        assert!(
+            propose_update(
+                &mut peer,
+                &mut alice_channel,
+                &alice_keys,
+                capacity - Amount::from_sat(1_000),
+                Amount::from_sat(1_000),
+            )
+            .await
+            .is_err()
+        );
+        server.abort();
+        let bob_channel = store.load(&alice_channel.id()).unwrap();
+        assert_eq!(bob_channel.local_party, Party::Bob);
+        assert_eq!(bob_channel.state, 0);
+        assert_eq!(
+            bob_channel.peer_id,
+            Some(alice_node_sk.public_key(SECP256K1))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,61 +1,390 @@
 #![allow(unused)]
 
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use base64::{display::Base64Display, engine::general_purpose::STANDARD};
+use elements::bitcoin::Amount;
+use elements::bitcoin::hex::DisplayHex;
+use elements::hashes::{Hash, sha256};
 use elements::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGVERIFY};
+use elements::schnorr::Signature;
 use elements::script::Builder;
-use elements::secp256k1_zkp::{self, SecretKey, XOnlyPublicKey};
+use elements::secp256k1_zkp::{self, Parity, PublicKey, SecretKey, XOnlyPublicKey};
 use elements::{
     secp256k1_zkp::SECP256K1,
-    {Address, AddressParams, Script},
+    {Address, AddressParams, Script, Transaction, confidential},
 };
-use hal_simplicity::hal_simplicity::{Program, elements_address};
-use simplicityhl::{Arguments, CompiledProgram, simplicity::jet};
+use hal_simplicity::hal_simplicity::{Program, elements_address, taproot_spend_info};
+use simplicityhl::simplicity::BitMachine;
+use simplicityhl::simplicity::jet::elements::{ElementsEnv, ElementsUtxo};
+use simplicityhl::str::WitnessName;
+use simplicityhl::types::ResolvedType;
+use simplicityhl::value::Value;
+use simplicityhl::{Arguments, CompiledProgram, WitnessValues, simplicity, simplicity::jet};
 
 use crate::error::Error;
-use crate::sign::{derive_settlement_key, prefix_pubkey};
+use crate::keys::{ensure_all_distinct_keys, ensure_distinct_keys};
+use crate::sign::{genesis_hash, prefix_pubkey};
+use crate::transaction::{build_setup_address, policy_asset};
+use crate::{CSV_DELAY, STATE_LOCK_TIME_OFFSET};
 
-const TEMPLATE_PATH: &str = "scripts/eltoo_commitment_template.simf";
-const FUNDING_TRANSACTION_TEMPLATE_PATH: &str = "scripts/funding_transaction.simf";
+/// The templates, built into the binary so it runs from any directory.
+const TEMPLATE: &str = include_str!("../scripts/eltoo_commitment_template.simf");
+const FUNDING_TRANSACTION_TEMPLATE: &str = include_str!("../scripts/funding_transaction.simf");
+const HTLC_TEMPLATE: &str = include_str!("../scripts/htlc_template.simf");
+const PTLC_TEMPLATE: &str = include_str!("../scripts/ptlc_template.simf");
+const MULTIPARTY_TEMPLATE: &str =
+    include_str!("../scripts/eltoo_multiparty_commitment_template.simf");
 
 /// Placeholder identifiers in the template file.
 const SETTLEMENT_KEY_A_PLACEHOLDER: &str = "__SETTLEMENT_KEY_A__";
 const SETTLEMENT_KEY_B_PLACEHOLDER: &str = "__SETTLEMENT_KEY_B__";
+const UPDATE_KEY_A_PLACEHOLDER: &str = "__UPDATE_KEY_A__";
+const UPDATE_KEY_B_PLACEHOLDER: &str = "__UPDATE_KEY_B__";
 const STATE_NUMBER_PLACEHOLDER: &str = "__NEXT_STATE_NUMBER__";
+const CSV_DELAY_PLACEHOLDER: &str = "__CSV_DELAY__";
+
+/// Placeholder identifiers in the HTLC and PTLC template files.
+const PAYMENT_HASH_PLACEHOLDER: &str = "__PAYMENT_HASH__";
+const RECEIVER_KEY_PLACEHOLDER: &str = "__RECEIVER_KEY__";
+const OFFERER_KEY_PLACEHOLDER: &str = "__OFFERER_KEY__";
+const CLTV_EXPIRY_PLACEHOLDER: &str = "__CLTV_EXPIRY__";
+
+/// Placeholder identifiers in the multi-party template file, which get code
+/// generated for the participant set.
+const PARTY_COUNT_PLACEHOLDER: &str = "__PARTY_COUNT__";
+const SIGNATURE_NAMES_PLACEHOLDER: &str = "__SIGNATURE_NAMES__";
+const SETTLEMENT_CHECKS_PLACEHOLDER: &str = "__SETTLEMENT_CHECKS__";
+const UPDATE_CHECKS_PLACEHOLDER: &str = "__UPDATE_CHECKS__";
+
+/// Witness identifier in the template file.
+const SETTLEMENT_OR_UPDATE_WITNESS: &str = "SETTLEMENT_OR_UPDATE";
+
+/// Witness identifier in the HTLC and PTLC template files.
+const SUCCESS_OR_TIMEOUT_WITNESS: &str = "SUCCESS_OR_TIMEOUT";
+
+/// The spending paths of an ELTOO commitment output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CommitmentPath {
+    /// Spent by a `Settlement Transaction` after the CSV delay (left branch).
+    Settlement,
+    /// Spent by a later `Update Transaction` (right branch).
+    Update,
+}
+
+/// The witness of a spend of an HTLC output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HtlcSpend {
+    /// Spent by the receiver with the payment preimage (left branch).
+    Success {
+        preimage: [u8; 32],
+        signature: Signature,
+    },
+    /// Spent by the offerer once the expiry height is reached (right branch).
+    Timeout { signature: Signature },
+}
+
+/// The witness of a spend of a PTLC output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PtlcSpend {
+    /// Spent by the receiver with its signature and the offerer's completed
+    /// adaptor signature (left branch).
+    Success {
+        receiver_signature: Signature,
+        offerer_signature: Signature,
+    },
+    /// Spent by the offerer once the expiry height is reached (right branch).
+    Timeout { signature: Signature },
+}
 
 /// Build the Witness Program for the 2-of2 setup address between Alice and Bob.
 pub(crate) fn build_setup_address_program(
     alice_update_pk: &XOnlyPublicKey,
     bob_update_pk: &XOnlyPublicKey,
 ) -> Script {
-    Builder::new()
-        .push_slice(&alice_update_pk.serialize())
-        .push_opcode(OP_CHECKSIGVERIFY)
-        .push_slice(&bob_update_pk.serialize())
-        .push_opcode(OP_CHECKSIG)
-        .into_script()
+    build_multiparty_setup_program(&[
+        setup_public_key(alice_update_pk),
+        setup_public_key(bob_update_pk),
+    ])
+}
+
+/// The compressed [`PublicKey`] checked by setup programs for `update_pk`.
+///
+/// `OP_CHECKSIG` only accepts full public keys, so the even-parity one is used:
+/// setup spends are signed with the update secret key, negated if needed.
+pub(crate) fn setup_public_key(update_pk: &XOnlyPublicKey) -> PublicKey {
+    PublicKey::from_x_only_public_key(*update_pk, Parity::Even)
+}
+
+/// Build the Witness Program for the n-of-n setup address between the
+/// participants with `update_pks`.
+///
+/// Every key but the last is checked with `OP_CHECKSIGVERIFY`, so the witness
+/// carries the signatures in reverse participant order.
+pub(crate) fn build_multiparty_setup_program(update_pks: &[PublicKey]) -> Script {
+    let mut builder = Builder::new();
+    for (index, update_pk) in update_pks.iter().enumerate() {
+        builder = builder.push_slice(&update_pk.serialize());
+        builder = match index == update_pks.len() - 1 {
+            true => builder.push_opcode(OP_CHECKSIG),
+            false => builder.push_opcode(OP_CHECKSIGVERIFY),
+        };
+    }
+
+    builder.into_script()
 }
 
-/// Build the `ELTOO` commitment script from both parties settlement [`SecretKey`]s.
+/// The scripts of a channel at one state, for debugging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ScriptInspection {
+    /// The 2-of-2 `Setup Address`.
+    pub(crate) setup_address: Address,
+    /// The witness script of the `Setup Address`.
+    pub(crate) setup_witness_script: Script,
+    /// The `SimplicityHL` source of the commitment program, once populated.
+    pub(crate) commitment_source: String,
+    /// The CMR of the commitment program.
+    pub(crate) commitment_cmr: String,
+    /// The address of the commitment output.
+    pub(crate) commitment_address: Address,
+    /// The ways the setup and commitment outputs can be spent.
+    pub(crate) spend_paths: Vec<SpendPath>,
+}
+
+/// A way to spend a channel output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SpendPath {
+    /// The output spent, `setup` or `commitment`.
+    pub(crate) output: &'static str,
+    /// The transactions taking this path.
+    pub(crate) spent_by: &'static str,
+    /// The witness expected.
+    pub(crate) witness: String,
+    /// The conditions checked by the script.
+    pub(crate) conditions: String,
+}
+
+/// Inspect the scripts of the channel between both parties update keys and
+/// settlement keys for `state`: the populated commitment program for `state`,
+/// its CMR and address, the setup witness script and every spend path.
+pub(crate) fn inspect_scripts(
+    update_pk_a: XOnlyPublicKey,
+    update_pk_b: XOnlyPublicKey,
+    settlement_pk_a: XOnlyPublicKey,
+    settlement_pk_b: XOnlyPublicKey,
+    state: u32,
+    is_mainnet: bool,
+) -> Result<ScriptInspection, Error> {
+    let next_state = state as u64 + 1;
+    let commitment_source = populate_commitment_source(
+        update_pk_a,
+        update_pk_b,
+        settlement_pk_a,
+        settlement_pk_b,
+        next_state,
+    )?;
+    let program = compile_program(commitment_source.clone())?;
+    let setup_address = build_setup_address(update_pk_a, update_pk_b, is_mainnet)?;
+    let setup_witness_script = build_setup_address_program(&update_pk_a, &update_pk_b);
+
+    let spend_paths = vec![
+        SpendPath {
+            output: "setup",
+            spent_by: "Closing Update, Cooperative Close or Splice Transaction",
+            witness: "<sig_b> <sig_a> <witness_script>".into(),
+            conditions: format!(
+                "ECDSA SIGHASH_ALL signatures by the even-parity keys of update keys {} and {}",
+                update_pk_a, update_pk_b
+            ),
+        },
+        SpendPath {
+            output: "commitment",
+            spent_by: "Settlement Transaction",
+            witness: "SETTLEMENT_OR_UPDATE = Left((sig_a, sig_b))".into(),
+            conditions: format!(
+                "sequence is a BIP68 relative lock of at least {} blocks and \
+                 SIGHASH_ANYPREVOUTANYSCRIPT signatures by settlement keys {} and {} for state {}",
+                CSV_DELAY, settlement_pk_a, settlement_pk_b, state
+            ),
+        },
+        SpendPath {
+            output: "commitment",
+            spent_by: "Update Transaction for a later state",
+            witness: "SETTLEMENT_OR_UPDATE = Right((sig_a, sig_b))".into(),
+            conditions: format!(
+                "lock_time >= {} (state {}) and SIGHASH_ANYPREVOUTANYSCRIPT signatures by \
+                 update keys {} and {}",
+                STATE_LOCK_TIME_OFFSET as u64 + next_state,
+                next_state,
+                update_pk_a,
+                update_pk_b
+            ),
+        },
+    ];
+
+    Ok(ScriptInspection {
+        setup_address,
+        setup_witness_script,
+        commitment_source,
+        commitment_cmr: program.commit().cmr().to_string(),
+        commitment_address: derive_address(&program, is_mainnet),
+        spend_paths,
+    })
+}
+
+/// Build the `ELTOO` commitment script from both parties update and settlement
+/// [`XOnlyPublicKey`]s.
+///
+/// Fails if both parties share an update key or a settlement key.
 pub(crate) fn build_new_commitment_script(
-    settlement_key_a: SecretKey,
-    settlement_key_b: SecretKey,
+    update_pk_a: XOnlyPublicKey,
+    update_pk_b: XOnlyPublicKey,
+    settlement_pk_a: XOnlyPublicKey,
+    settlement_pk_b: XOnlyPublicKey,
     next_state: u64,
 ) -> Result<CompiledProgram, Error> {
-    let prog_path = std::path::Path::new(TEMPLATE_PATH);
-    let template = std::fs::read_to_string(prog_path)?;
+    let prog_text = populate_commitment_source(
+        update_pk_a,
+        update_pk_b,
+        settlement_pk_a,
+        settlement_pk_b,
+        next_state,
+    )?;
+
+    compile_program(prog_text)
+}
 
-    let pub_settlement_key_a = settlement_key_a.x_only_public_key(SECP256K1);
-    let pub_settlement_key_b = settlement_key_b.x_only_public_key(SECP256K1);
+/// Populate the `ELTOO` commitment template with both parties update and
+/// settlement [`XOnlyPublicKey`]s, returning the `SimplicityHL` source.
+///
+/// Fails if both parties share an update key or a settlement key.
+pub(crate) fn populate_commitment_source(
+    update_pk_a: XOnlyPublicKey,
+    update_pk_b: XOnlyPublicKey,
+    settlement_pk_a: XOnlyPublicKey,
+    settlement_pk_b: XOnlyPublicKey,
+    next_state: u64,
+) -> Result<String, Error> {
+    ensure_distinct_keys(&update_pk_a, &update_pk_b)?;
+    ensure_distinct_keys(&settlement_pk_a, &settlement_pk_b)?;
 
     // Replace the placeholders with actual values.
-    let prog_text = populate_template(
-        &template,
-        pub_settlement_key_a.0,
-        pub_settlement_key_b.0,
+    Ok(populate_template(
+        TEMPLATE,
+        update_pk_a,
+        update_pk_b,
+        settlement_pk_a,
+        settlement_pk_b,
         next_state,
-    );
+    ))
+}
+
+/// Compile a `SimplicityHL` program from its source.
+fn compile_program(prog_text: String) -> Result<CompiledProgram, Error> {
+    let compiled = CompiledProgram::new(prog_text, Arguments::default(), false)
+        .map_err(simplicityhl::error::Error::CannotCompile)?;
+
+    Ok(compiled)
+}
+
+/// Build the `ELTOO` commitment script of a multi-party channel from every
+/// participant's update and settlement [`XOnlyPublicKey`]s, in participant order.
+///
+/// Fails with fewer than two participants, or if two of them share a key.
+pub(crate) fn build_multiparty_commitment_script(
+    update_pks: &[XOnlyPublicKey],
+    settlement_pks: &[XOnlyPublicKey],
+    next_state: u64,
+) -> Result<CompiledProgram, Error> {
+    let prog_text = populate_multiparty_commitment_source(update_pks, settlement_pks, next_state)?;
+
+    compile_program(prog_text)
+}
+
+/// Populate the multi-party `ELTOO` commitment template with every participant's
+/// update and settlement [`XOnlyPublicKey`]s, returning the `SimplicityHL` source.
+///
+/// Fails with fewer than two participants, or if two of them share a key.
+pub(crate) fn populate_multiparty_commitment_source(
+    update_pks: &[XOnlyPublicKey],
+    settlement_pks: &[XOnlyPublicKey],
+    next_state: u64,
+) -> Result<String, Error> {
+    if update_pks.len() < 2 || update_pks.len() != settlement_pks.len() {
+        return Err(Error::Channel(format!(
+            "expected at least two participants with one update and one settlement key each, \
+             got {} update and {} settlement keys",
+            update_pks.len(),
+            settlement_pks.len()
+        )));
+    }
+    ensure_all_distinct_keys(update_pks)?;
+    ensure_all_distinct_keys(settlement_pks)?;
+
+    let signature_names: Vec<String> = (0..update_pks.len())
+        .map(|index| format!("sig_{}", index))
+        .collect();
+    let checks = |pks: &[XOnlyPublicKey]| {
+        pks.iter()
+            .zip(&signature_names)
+            .map(|(pk, sig)| format!("    checksig({}, {});", prefix_pubkey(*pk), sig))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    Ok(MULTIPARTY_TEMPLATE
+        .replace(PARTY_COUNT_PLACEHOLDER, &update_pks.len().to_string())
+        .replace(SIGNATURE_NAMES_PLACEHOLDER, &signature_names.join(", "))
+        .replace(SETTLEMENT_CHECKS_PLACEHOLDER, &checks(settlement_pks))
+        .replace(UPDATE_CHECKS_PLACEHOLDER, &checks(update_pks))
+        .replace(STATE_NUMBER_PLACEHOLDER, &next_state.to_string())
+        .replace(CSV_DELAY_PLACEHOLDER, &CSV_DELAY.to_string()))
+}
+
+/// Build the HTLC script paying `receiver_pk` against the preimage of
+/// `payment_hash`, or `offerer_pk` from block height `cltv_expiry` on.
+///
+/// Fails if both parties share a key.
+pub(crate) fn build_htlc_script(
+    receiver_pk: XOnlyPublicKey,
+    offerer_pk: XOnlyPublicKey,
+    payment_hash: sha256::Hash,
+    cltv_expiry: u32,
+) -> Result<CompiledProgram, Error> {
+    ensure_distinct_keys(&receiver_pk, &offerer_pk)?;
+
+    let prog_text = HTLC_TEMPLATE
+        .replace(
+            PAYMENT_HASH_PLACEHOLDER,
+            &format!("0x{}", payment_hash.to_byte_array().as_hex()),
+        )
+        .replace(RECEIVER_KEY_PLACEHOLDER, &prefix_pubkey(receiver_pk))
+        .replace(OFFERER_KEY_PLACEHOLDER, &prefix_pubkey(offerer_pk))
+        .replace(CLTV_EXPIRY_PLACEHOLDER, &cltv_expiry.to_string());
+
+    let compiled = CompiledProgram::new(prog_text, Arguments::default(), false)
+        .map_err(simplicityhl::error::Error::CannotCompile)?;
+
+    Ok(compiled)
+}
+
+/// Build the PTLC script paying `receiver_pk` and `offerer_pk` together, or
+/// `offerer_pk` alone from block height `cltv_expiry` on.
+///
+/// `offerer_pk` is the offerer's key tweaked by the payment point, which is
+/// otherwise absent from the script. Fails if both parties share a key.
+pub(crate) fn build_ptlc_script(
+    receiver_pk: XOnlyPublicKey,
+    offerer_pk: XOnlyPublicKey,
+    cltv_expiry: u32,
+) -> Result<CompiledProgram, Error> {
+    ensure_distinct_keys(&receiver_pk, &offerer_pk)?;
+
+    let prog_text = PTLC_TEMPLATE
+        .replace(RECEIVER_KEY_PLACEHOLDER, &prefix_pubkey(receiver_pk))
+        .replace(OFFERER_KEY_PLACEHOLDER, &prefix_pubkey(offerer_pk))
+        .replace(CLTV_EXPIRY_PLACEHOLDER, &cltv_expiry.to_string());
 
-    // Compile the `SimplicityHL` program from it's string.
     let compiled = CompiledProgram::new(prog_text, Arguments::default(), false)
         .map_err(simplicityhl::error::Error::CannotCompile)?;
 
@@ -66,8 +395,7 @@ pub(crate) fn build_funding_transaction(
     key1: SecretKey,
     key2: SecretKey,
 ) -> Result<CompiledProgram, Error> {
-    let prog_path = std::path::Path::new(FUNDING_TRANSACTION_TEMPLATE_PATH);
-    let prog_text = std::fs::read_to_string(prog_path).unwrap();
+    let prog_text = FUNDING_TRANSACTION_TEMPLATE.to_string();
     let compiled = CompiledProgram::new(prog_text, Arguments::default(), false)
         .map_err(simplicityhl::error::Error::CannotCompile)?;
 
@@ -89,25 +417,237 @@ pub(crate) fn derive_address(program: &CompiledProgram, is_mainnet: bool) -> Add
     }
 }
 
-/// Populate the ELTOO commitment template with both parties pubkeys and the state index.
+/// Build the Taproot script witness that spends an ELTOO commitment output
+/// through `path` with both parties signatures.
+///
+/// The witness stack is `<witness> <program> <cmr> <control_block>`.
+pub(crate) fn build_commitment_witness(
+    program: &CompiledProgram,
+    path: CommitmentPath,
+    sig_a: &Signature,
+    sig_b: &Signature,
+) -> Result<Vec<Vec<u8>>, Error> {
+    satisfy_taproot_witness(program, commitment_witness_values(path, sig_a, sig_b)?)
+}
+
+/// Execute an ELTOO commitment `program` offline, as a Liquid node would when
+/// validating input `index` of `transaction`, which spends the commitment
+/// output of `input_amount` through `path` with both parties signatures.
+///
+/// Every input of `transaction` is taken to spend an L-BTC output of
+/// `input_amount` locked by `program`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn execute_commitment_spend(
+    program: &CompiledProgram,
+    path: CommitmentPath,
+    sig_a: &Signature,
+    sig_b: &Signature,
+    transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
+    is_mainnet: bool,
+) -> Result<(), Error> {
+    let satisfied = program
+        .satisfy(commitment_witness_values(path, sig_a, sig_b)?)
+        .map_err(Error::Witness)?;
+
+    let cmr = program.commit().cmr();
+    let script = Script::from(cmr.as_ref().to_vec());
+    let control_block = taproot_spend_info(cmr)
+        .control_block(&(script, simplicity::leaf_version()))
+        .ok_or(Error::ControlBlock)?;
+    let utxo = ElementsUtxo {
+        script_pubkey: derive_address(program, is_mainnet).script_pubkey(),
+        asset: confidential::Asset::Explicit(policy_asset(is_mainnet)),
+        value: confidential::Value::Explicit(input_amount.to_sat()),
+    };
+    let env = ElementsEnv::new(
+        Arc::new(transaction.clone()),
+        vec![utxo; transaction.input.len()],
+        index as u32,
+        cmr,
+        control_block,
+        None,
+        genesis_hash(is_mainnet),
+    );
+
+    let mut machine =
+        BitMachine::for_program(satisfied.redeem()).map_err(|e| Error::Execution(e.to_string()))?;
+    machine
+        .exec(satisfied.redeem(), &env)
+        .map_err(|e| Error::Execution(e.to_string()))?;
+
+    Ok(())
+}
+
+/// The witness values that spend an ELTOO commitment program through `path`
+/// with both parties signatures.
+fn commitment_witness_values(
+    path: CommitmentPath,
+    sig_a: &Signature,
+    sig_b: &Signature,
+) -> Result<WitnessValues, Error> {
+    let branch = match path {
+        CommitmentPath::Settlement => "Left",
+        CommitmentPath::Update => "Right",
+    };
+    let ty = ResolvedType::parse_from_str("Either<(Signature, Signature), (Signature, Signature)>")
+        .map_err(|e| Error::Witness(e.to_string()))?;
+    let value = Value::parse_from_str(&format!("{}((0x{}, 0x{}))", branch, sig_a, sig_b), &ty)
+        .map_err(|e| Error::Witness(e.to_string()))?;
+
+    Ok(WitnessValues::from(HashMap::from([(
+        WitnessName::from_str_unchecked(SETTLEMENT_OR_UPDATE_WITNESS),
+        value,
+    )])))
+}
+
+/// Build the Taproot script witness that spends the commitment output of a
+/// multi-party channel through `path`, with every participant's signature in
+/// participant order.
+///
+/// The witness stack is `<witness> <program> <cmr> <control_block>`.
+pub(crate) fn build_multiparty_commitment_witness(
+    program: &CompiledProgram,
+    path: CommitmentPath,
+    signatures: &[Signature],
+) -> Result<Vec<Vec<u8>>, Error> {
+    let branch = match path {
+        CommitmentPath::Settlement => "Left",
+        CommitmentPath::Update => "Right",
+    };
+    let ty = ResolvedType::parse_from_str(&format!(
+        "Either<[Signature; {0}], [Signature; {0}]>",
+        signatures.len()
+    ))
+    .map_err(|e| Error::Witness(e.to_string()))?;
+    let signatures: Vec<String> = signatures
+        .iter()
+        .map(|signature| format!("0x{}", signature))
+        .collect();
+    let value = Value::parse_from_str(&format!("{}([{}])", branch, signatures.join(", ")), &ty)
+        .map_err(|e| Error::Witness(e.to_string()))?;
+
+    let witness_values = WitnessValues::from(HashMap::from([(
+        WitnessName::from_str_unchecked(SETTLEMENT_OR_UPDATE_WITNESS),
+        value,
+    )]));
+
+    satisfy_taproot_witness(program, witness_values)
+}
+
+/// Build the Taproot script witness that spends an HTLC output through `spend`.
+///
+/// The witness stack is `<witness> <program> <cmr> <control_block>`.
+pub(crate) fn build_htlc_witness(
+    program: &CompiledProgram,
+    spend: &HtlcSpend,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let value = match spend {
+        HtlcSpend::Success {
+            preimage,
+            signature,
+        } => format!("Left((0x{}, 0x{}))", preimage.as_hex(), signature),
+        HtlcSpend::Timeout { signature } => format!("Right(0x{})", signature),
+    };
+    let ty = ResolvedType::parse_from_str("Either<(u256, Signature), Signature>")
+        .map_err(|e| Error::Witness(e.to_string()))?;
+    let value = Value::parse_from_str(&value, &ty).map_err(|e| Error::Witness(e.to_string()))?;
+
+    let witness_values = WitnessValues::from(HashMap::from([(
+        WitnessName::from_str_unchecked(SUCCESS_OR_TIMEOUT_WITNESS),
+        value,
+    )]));
+
+    satisfy_taproot_witness(program, witness_values)
+}
+
+/// Build the Taproot script witness that spends a PTLC output through `spend`.
+///
+/// The witness stack is `<witness> <program> <cmr> <control_block>`.
+pub(crate) fn build_ptlc_witness(
+    program: &CompiledProgram,
+    spend: &PtlcSpend,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let value = match spend {
+        PtlcSpend::Success {
+            receiver_signature,
+            offerer_signature,
+        } => format!("Left((0x{}, 0x{}))", receiver_signature, offerer_signature),
+        PtlcSpend::Timeout { signature } => format!("Right(0x{})", signature),
+    };
+    let ty = ResolvedType::parse_from_str("Either<(Signature, Signature), Signature>")
+        .map_err(|e| Error::Witness(e.to_string()))?;
+    let value = Value::parse_from_str(&value, &ty).map_err(|e| Error::Witness(e.to_string()))?;
+
+    let witness_values = WitnessValues::from(HashMap::from([(
+        WitnessName::from_str_unchecked(SUCCESS_OR_TIMEOUT_WITNESS),
+        value,
+    )]));
+
+    satisfy_taproot_witness(program, witness_values)
+}
+
+/// Satisfy `program` with `witness_values` and wrap it in a Taproot script
+/// spend of the single leaf committing to its CMR.
+fn satisfy_taproot_witness(
+    program: &CompiledProgram,
+    witness_values: WitnessValues,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let satisfied = program.satisfy(witness_values).map_err(Error::Witness)?;
+    let (program_bytes, witness_bytes) = satisfied.redeem().to_vec_with_witness();
+
+    let cmr = program.commit().cmr();
+    let script = Script::from(cmr.as_ref().to_vec());
+    let control_block = taproot_spend_info(cmr)
+        .control_block(&(script.clone(), simplicity::leaf_version()))
+        .ok_or(Error::ControlBlock)?;
+
+    Ok(vec![
+        witness_bytes,
+        program_bytes,
+        script.into_bytes(),
+        control_block.serialize(),
+    ])
+}
+
+/// Populate the ELTOO commitment template with both parties pubkeys, the state
+/// index and the [`CSV_DELAY`].
 fn populate_template(
     template: &str,
+    update_pk_a: XOnlyPublicKey,
+    update_pk_b: XOnlyPublicKey,
     pub_key_a: XOnlyPublicKey,
     pub_key_b: XOnlyPublicKey,
     state: u64,
 ) -> String {
     template
+        .replace(UPDATE_KEY_A_PLACEHOLDER, &prefix_pubkey(update_pk_a))
+        .replace(UPDATE_KEY_B_PLACEHOLDER, &prefix_pubkey(update_pk_b))
         .replace(SETTLEMENT_KEY_A_PLACEHOLDER, &prefix_pubkey(pub_key_a))
         .replace(SETTLEMENT_KEY_B_PLACEHOLDER, &prefix_pubkey(pub_key_b))
         .replace(STATE_NUMBER_PLACEHOLDER, &state.to_string())
+        .replace(CSV_DELAY_PLACEHOLDER, &CSV_DELAY.to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use elements::bitcoin::secp256k1::SecretKey;
+    use elements::{OutPoint, Sequence, Txid};
     use lwk_wollet::secp256k1;
+    use proptest::prelude::*;
     use simplicityhl::CompiledProgram;
 
+    use crate::channel::build_commitment_program;
+
+    use crate::sign::{
+        complete_adaptor, derive_ptlc_key, derive_settlement_key, derive_settlement_pubkey,
+        extract_adaptor_secret, find_completed_signature, sign_adaptor, sign_anyprevout_input,
+    };
+    use crate::transaction::{
+        build_settlement_transaction, build_update_transaction, settlement_sequence,
+    };
+
     use super::*;
 
     #[test]
@@ -116,9 +656,340 @@ mod tests {
         let update_key_b = SecretKey::from_slice(&[0xee; 32]).unwrap();
         let next_state = 1;
 
-        let settlement_key_a = derive_settlement_key(&update_key_a, next_state);
-        let settlement_key_b = derive_settlement_key(&update_key_b, next_state);
-        let compiled =
-            build_new_commitment_script(settlement_key_a, settlement_key_b, next_state).unwrap();
+        let update_pk_a = update_key_a.x_only_public_key(SECP256K1).0;
+        let update_pk_b = update_key_b.x_only_public_key(SECP256K1).0;
+
+        let settlement_key_a = derive_settlement_key(&update_key_a, next_state)
+            .x_only_public_key(SECP256K1)
+            .0;
+        let settlement_key_b =
+            derive_settlement_pubkey(&update_key_b.public_key(SECP256K1), next_state)
+                .x_only_public_key()
+                .0;
+        let compiled = build_new_commitment_script(
+            update_pk_a,
+            update_pk_b,
+            settlement_key_a,
+            settlement_key_b,
+            next_state,
+        )
+        .unwrap();
+
+        // Alice reusing Bob's update key is refused.
+        assert!(
+            build_new_commitment_script(
+                update_pk_b,
+                update_pk_b,
+                settlement_key_a,
+                settlement_key_b,
+                next_state,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn htlc_script_and_witness() {
+        let receiver_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let offerer_sk = SecretKey::from_slice(&[0xee; 32]).unwrap();
+        let receiver_pk = receiver_sk.x_only_public_key(SECP256K1).0;
+        let offerer_pk = offerer_sk.x_only_public_key(SECP256K1).0;
+
+        let preimage = [0x42; 32];
+        let payment_hash = sha256::Hash::hash(&preimage);
+        let program = build_htlc_script(receiver_pk, offerer_pk, payment_hash, 3_000_000).unwrap();
+
+        // Another payment hash or expiry locks the HTLC in another output.
+        let other_program =
+            build_htlc_script(receiver_pk, offerer_pk, payment_hash, 3_000_001).unwrap();
+        assert_ne!(
+            derive_address(&program, false),
+            derive_address(&other_program, false)
+        );
+
+        let signature = SECP256K1.sign_schnorr_no_aux_rand(
+            &secp256k1_zkp::Message::from_digest([0x01; 32]),
+            &elements::schnorr::Keypair::from_secret_key(SECP256K1, &receiver_sk),
+        );
+        let success = HtlcSpend::Success {
+            preimage,
+            signature,
+        };
+        let timeout = HtlcSpend::Timeout { signature };
+        assert_eq!(build_htlc_witness(&program, &success).unwrap().len(), 4);
+        assert_eq!(build_htlc_witness(&program, &timeout).unwrap().len(), 4);
+
+        // The receiver cannot also be the offerer.
+        assert!(build_htlc_script(receiver_pk, receiver_pk, payment_hash, 3_000_000).is_err());
+    }
+
+    #[test]
+    fn inspect_channel_scripts() {
+        let update_pk_a = SecretKey::from_slice(&[0xcd; 32])
+            .unwrap()
+            .x_only_public_key(SECP256K1)
+            .0;
+        let update_pk_b = SecretKey::from_slice(&[0xee; 32])
+            .unwrap()
+            .x_only_public_key(SECP256K1)
+            .0;
+        let settlement_pk_a = SecretKey::from_slice(&[0xab; 32])
+            .unwrap()
+            .x_only_public_key(SECP256K1)
+            .0;
+        let settlement_pk_b = SecretKey::from_slice(&[0xba; 32])
+            .unwrap()
+            .x_only_public_key(SECP256K1)
+            .0;
+
+        let inspection = inspect_scripts(
+            update_pk_a,
+            update_pk_b,
+            settlement_pk_a,
+            settlement_pk_b,
+            4,
+            false,
+        )
+        .unwrap();
+
+        // The source is the one compiled for the next state, with every
+        // placeholder replaced.
+        assert!(!inspection.commitment_source.contains("__"));
+        let program = build_new_commitment_script(
+            update_pk_a,
+            update_pk_b,
+            settlement_pk_a,
+            settlement_pk_b,
+            5,
+        )
+        .unwrap();
+        assert_eq!(
+            inspection.commitment_cmr,
+            program.commit().cmr().to_string()
+        );
+        assert_eq!(
+            inspection.commitment_address,
+            derive_address(&program, false)
+        );
+
+        assert_eq!(
+            inspection.setup_witness_script,
+            build_setup_address_program(&update_pk_a, &update_pk_b)
+        );
+        assert_eq!(
+            inspection.setup_address,
+            build_setup_address(update_pk_a, update_pk_b, false).unwrap()
+        );
+        assert_eq!(inspection.spend_paths.len(), 3);
+        assert!(
+            inspection.spend_paths[2]
+                .conditions
+                .contains("lock_time >= 500000005 (state 5)")
+        );
+    }
+
+    #[test]
+    fn ptlc_witness_reveals_payment_secret() {
+        let receiver_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let offerer_sk = SecretKey::from_slice(&[0xee; 32]).unwrap();
+        let payment_secret = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let payment_point = payment_secret.public_key(SECP256K1);
+
+        let offerer_ptlc_sk = derive_ptlc_key(&offerer_sk, &payment_point).unwrap();
+        let program = build_ptlc_script(
+            receiver_sk.x_only_public_key(SECP256K1).0,
+            offerer_ptlc_sk.x_only_public_key(SECP256K1).0,
+            3_000_000,
+        )
+        .unwrap();
+
+        // The receiver completes the offerer's adaptor signature to claim the PTLC.
+        let message = secp256k1_zkp::Message::from_digest([0x01; 32]);
+        let adaptor = sign_adaptor(&message, &offerer_ptlc_sk, &payment_point).unwrap();
+        let receiver_signature = SECP256K1.sign_schnorr_no_aux_rand(
+            &message,
+            &elements::schnorr::Keypair::from_secret_key(SECP256K1, &receiver_sk),
+        );
+        let success = PtlcSpend::Success {
+            receiver_signature,
+            offerer_signature: complete_adaptor(&adaptor, &payment_secret).unwrap(),
+        };
+        let witness = build_ptlc_witness(&program, &success).unwrap();
+
+        // The offerer learns the payment secret from the witness.
+        let signature = find_completed_signature(&adaptor, &payment_point, &witness[0]).unwrap();
+        assert_eq!(
+            extract_adaptor_secret(&adaptor, &payment_point, &signature).unwrap(),
+            payment_secret
+        );
+    }
+
+    /// The commitment program for `state`, between the update keys `0xcd` and
+    /// `0xee` and the settlement basepoints of the roots `0xab` and `0xba`.
+    fn commitment_program(state: u32) -> CompiledProgram {
+        let update_pk = |byte| {
+            SecretKey::from_slice(&[byte; 32])
+                .unwrap()
+                .x_only_public_key(SECP256K1)
+                .0
+        };
+        let basepoint = |byte| {
+            SecretKey::from_slice(&[byte; 32])
+                .unwrap()
+                .public_key(SECP256K1)
+        };
+
+        build_commitment_program(
+            update_pk(0xcd),
+            update_pk(0xee),
+            &basepoint(0xab),
+            &basepoint(0xba),
+            state,
+        )
+        .unwrap()
+    }
+
+    /// Execute the `Settlement Transaction` for `state` with the input `sequence`
+    /// and transaction `version`, spending the commitment output locked by
+    /// `program` through the settlement path.
+    fn execute_settlement(
+        program: &CompiledProgram,
+        state: u32,
+        sequence: Sequence,
+        version: u32,
+    ) -> Result<(), Error> {
+        let settlement_sk = |byte| {
+            derive_settlement_key(&SecretKey::from_slice(&[byte; 32]).unwrap(), state as u64)
+        };
+        let input_amount = Amount::from_sat(10_000);
+        let address = derive_address(program, false);
+
+        let mut settlement = build_settlement_transaction(
+            OutPoint::new(Txid::all_zeros(), 0),
+            input_amount,
+            &address,
+            Amount::from_sat(6_000),
+            &address,
+            Amount::from_sat(3_931),
+            &[],
+            &[],
+            false,
+        );
+        settlement.input[0].sequence = sequence;
+        settlement.version = version;
+        let sig_a = sign_anyprevout_input(&settlement, input_amount, &settlement_sk(0xab), false);
+        let sig_b = sign_anyprevout_input(&settlement, input_amount, &settlement_sk(0xba), false);
+
+        execute_commitment_spend(
+            program,
+            CommitmentPath::Settlement,
+            &sig_a,
+            &sig_b,
+            &settlement,
+            0,
+            input_amount,
+            false,
+        )
+    }
+
+    #[test]
+    fn settlement_requires_a_block_based_relative_lock() {
+        let commitment = commitment_program(7);
+        let blocks = |delay: u16| Sequence::from_height(delay);
+
+        assert!(execute_settlement(&commitment, 7, settlement_sequence(), 2).is_ok());
+        assert!(execute_settlement(&commitment, 7, blocks(CSV_DELAY + 1), 2).is_ok());
+        assert!(execute_settlement(&commitment, 7, blocks(CSV_DELAY - 1), 2).is_err());
+        assert!(execute_settlement(&commitment, 7, blocks(0), 2).is_err());
+
+        // Sequences that compare above the delay, but do not lock it by consensus:
+        // with the disable flag set, final, or counting time instead of blocks.
+        let disabled = Sequence::from_consensus((1 << 31) | u32::from(CSV_DELAY));
+        assert!(execute_settlement(&commitment, 7, disabled, 2).is_err());
+        assert!(execute_settlement(&commitment, 7, Sequence::MAX, 2).is_err());
+        let time_based = Sequence::from_512_second_intervals(CSV_DELAY);
+        assert!(execute_settlement(&commitment, 7, time_based, 2).is_err());
+
+        // BIP68 only applies from version 2.
+        assert!(execute_settlement(&commitment, 7, settlement_sequence(), 1).is_err());
+    }
+
+    #[test]
+    fn commitment_templates_enforce_the_csv_delay() {
+        let pk = |byte| {
+            SecretKey::from_slice(&[byte; 32])
+                .unwrap()
+                .x_only_public_key(SECP256K1)
+                .0
+        };
+        let check = format!("check_csv_delay({});", CSV_DELAY);
+
+        let source = populate_commitment_source(pk(0xcd), pk(0xee), pk(0xab), pk(0xba), 7).unwrap();
+        assert!(source.contains(&check));
+        assert!(!source.contains(CSV_DELAY_PLACEHOLDER));
+        let source = populate_multiparty_commitment_source(
+            &[pk(0x01), pk(0x02), pk(0x03)],
+            &[pk(0x11), pk(0x12), pk(0x13)],
+            7,
+        )
+        .unwrap();
+        assert!(source.contains(&check));
+        assert!(!source.contains(CSV_DELAY_PLACEHOLDER));
+
+        // The compiled program settles after exactly `CSV_DELAY` blocks.
+        let commitment = commitment_program(7);
+        let blocks = |delay: u16| Sequence::from_height(delay);
+        assert!(execute_settlement(&commitment, 7, blocks(CSV_DELAY), 2).is_ok());
+        assert!(execute_settlement(&commitment, 7, blocks(CSV_DELAY - 1), 2).is_err());
+    }
+
+    /// Execute the `Update Transaction` for `state`, spending the commitment
+    /// output locked by `program` through the update path.
+    fn execute_update(program: &CompiledProgram, state: u32) -> Result<(), Error> {
+        let update_sk_a = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let update_sk_b = SecretKey::from_slice(&[0xee; 32]).unwrap();
+        let input_amount = Amount::from_sat(10_000);
+
+        let update = build_update_transaction(
+            OutPoint::new(Txid::all_zeros(), 0),
+            input_amount,
+            &derive_address(program, false),
+            state,
+            &[],
+            false,
+        );
+        let sig_a = sign_anyprevout_input(&update, input_amount, &update_sk_a, false);
+        let sig_b = sign_anyprevout_input(&update, input_amount, &update_sk_b, false);
+
+        execute_commitment_spend(
+            program,
+            CommitmentPath::Update,
+            &sig_a,
+            &sig_b,
+            &update,
+            0,
+            input_amount,
+            false,
+        )
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn only_later_updates_spend_a_commitment(
+            i in 0..250_000_000u32,
+            delta in 1..250_000_000u32,
+        ) {
+            let j = i + delta;
+            let (commitment_i, commitment_j) = (commitment_program(i), commitment_program(j));
+
+            // The state-`j` update satisfies the `check_cltv_state_number` of the
+            // state-`i` commitment, but neither the state-`i` update, nor the
+            // state-`j` one, spends the state-`j` commitment.
+            prop_assert!(execute_update(&commitment_i, j).is_ok());
+            prop_assert!(execute_update(&commitment_j, i).is_err());
+            prop_assert!(execute_update(&commitment_j, j).is_err());
+        }
     }
 }
@@ -1,27 +1,122 @@
 //! Signature and Keys
 
+use std::str::FromStr;
+
+use elements::AssetId;
+use elements::BlockHash;
 use elements::EcdsaSighashType;
+use elements::OutPoint;
+use elements::PubkeyHash;
+use elements::Script;
 use elements::Transaction;
 use elements::TxInWitness;
+use elements::bitcoin::Amount;
 use elements::confidential;
-use elements::hashes::{Hash, sha256};
+use elements::encode::serialize;
+use elements::hashes::{Hash, HashEngine, sha256};
 use elements::schnorr::Keypair;
+#[cfg(any(test, feature = "fuzzing"))]
+use elements::secp256k1_zkp::constants::CURVE_ORDER;
 use elements::secp256k1_zkp::{
-    Message, Parity, PublicKey, SECP256K1, SecretKey, XOnlyPublicKey, ecdsa,
+    Message, Parity, PublicKey, SECP256K1, Scalar, SecretKey, XOnlyPublicKey, ecdsa, schnorr,
 };
 use elements::sighash::SighashCache;
 
+use simplicityhl::CompiledProgram;
+
 use crate::FEE_AMOUNT;
-use crate::script::build_setup_address_program;
+use crate::error::Error;
+use crate::script::{
+    CommitmentPath, build_commitment_witness, build_multiparty_setup_program,
+    build_setup_address_program, setup_public_key,
+};
+#[cfg(test)]
+use crate::script::{
+    HtlcSpend, PtlcSpend, build_htlc_witness, build_multiparty_commitment_witness,
+    build_ptlc_witness,
+};
+use crate::transaction::{IssuedAsset, lock_time_state, policy_asset};
+
+/// Liquid V1 genesis block hash.
+pub(crate) const LIQUIDV1_MAIN_GENESIS_HASH: &str =
+    "1466275836220db2944ca059a3a10ef6fd2ea684b0688d2c379296888a206003";
+/// Liquid TestnetV1 genesis block hash.
+pub(crate) const LIQUIDV1_TEST_GENESIS_HASH: &str =
+    "a771da8e52ee6ad581ed1e9a99825e5b3b7992225534eaa2ae23244fe26ab1c1";
+
+/// An `Update Transaction` signed by both parties.
+///
+/// The signatures are kept apart from the transaction because the witness
+/// also carries the program of the spent commitment output, which changes
+/// whenever the update is rebound to another commitment output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SignedUpdate {
+    /// The state number of the update.
+    pub(crate) state: u32,
+    /// The unsigned `Update Transaction`.
+    pub(crate) transaction: Transaction,
+    /// Alice's update key signature.
+    pub(crate) alice_sig: schnorr::Signature,
+    /// Bob's update key signature.
+    pub(crate) bob_sig: schnorr::Signature,
+    /// Alice's and Bob's signatures on the inputs carrying issued assets, in
+    /// input order.
+    pub(crate) asset_sigs: Vec<(schnorr::Signature, schnorr::Signature)>,
+}
+
+/// An `Update Transaction` of a multi-party channel signed by every participant.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MultipartySignedUpdate {
+    /// The state number of the update.
+    pub(crate) state: u32,
+    /// The unsigned `Update Transaction`.
+    pub(crate) transaction: Transaction,
+    /// Every participant's update key signature, in participant order.
+    pub(crate) signatures: Vec<schnorr::Signature>,
+}
+
+/// A fee output signed to pay the fee of an `Update Transaction` when it
+/// overrides a stale one.
+///
+/// The output must hold exactly [`FEE_AMOUNT`] of explicit L-BTC at the fee
+/// address of the signer. It is signed with `SIGHASH_ALL | SIGHASH_ANYONECANPAY`,
+/// which commits to neither the other inputs nor their outpoints, so it stays
+/// valid whichever commitment output the update is rebound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FeeInput {
+    /// The spent fee output.
+    pub(crate) outpoint: OutPoint,
+    /// The fee key.
+    pub(crate) fee_pk: PublicKey,
+    /// The fee key signature.
+    pub(crate) signature: ecdsa::Signature,
+}
+
+impl FeeInput {
+    /// The witness spending the fee output.
+    pub(crate) fn witness(&self) -> Vec<Vec<u8>> {
+        vec![
+            [
+                &self.signature.serialize_der()[..],
+                &[EcdsaSighashType::AllPlusAnyoneCanPay as u8],
+            ]
+            .concat(),
+            self.fee_pk.serialize().to_vec(),
+        ]
+    }
+}
 
-/// Sign the `Setup Withdrawal Transaction`.
+/// Sign a transaction that spends the 2-of-2 setup output of `funding_amount`,
+/// such as the `Update Transaction` that closes the channel straight from the
+/// setup output.
 ///
-/// Alice has Bob sign the transaction that refunds Alice in case
-/// Bob becomes uncooperative before actually broadcasting the 2-of-2
-/// funding transaction, so that she is assured to not have her BTC locked
-/// on the multisig forever.
-pub(crate) fn sign_setup_withdrawal_transaction(
+/// Input 0 spends the L-BTC setup output, and each following input the setup
+/// output of the issued asset at the same position in `issued_assets`.
+pub(crate) fn sign_setup_spending_transaction(
     transaction: &Transaction,
+    funding_amount: Amount,
+    issued_assets: &[IssuedAsset],
     alice_update_sk: &SecretKey,
     bob_update_sk: &SecretKey,
 ) -> Transaction {
@@ -31,147 +126,1290 @@ pub(crate) fn sign_setup_withdrawal_transaction(
     let bob_update_pk =
         XOnlyPublicKey::from_keypair(&Keypair::from_secret_key(SECP256K1, bob_update_sk)).0;
 
-    // Re-build the program.
-    let witness_program = build_setup_address_program(&alice_update_pk, &bob_update_pk);
-
-    // Re-derive the output value from the transaction and the fee.
-    let output_value = match transaction.output[0].value {
-        confidential::Value::Explicit(v) => v,
-        _ => panic!("Expected explicit value"),
+    let sign = |update_sk: &SecretKey| {
+        sign_setup_spending_inputs(
+            transaction,
+            funding_amount,
+            issued_assets,
+            &alice_update_pk,
+            &bob_update_pk,
+            update_sk,
+        )
     };
-    let value = output_value + FEE_AMOUNT;
+    let signatures: Vec<_> = sign(alice_update_sk)
+        .into_iter()
+        .zip(sign(bob_update_sk))
+        .collect();
 
-    // Hash the unsigned transaction and wrap in in a [`Message`] for signing.
-    let sighash = SighashCache::new(&transaction.clone()).segwitv0_sighash(
-        0,
-        &witness_program,
-        confidential::Value::Explicit(value),
-        EcdsaSighashType::All,
+    finalize_setup_spending_transaction(transaction, &alice_update_pk, &bob_update_pk, &signatures)
+}
+
+/// Sign every input of a transaction that spends the 2-of-2 setup outputs of
+/// `funding_amount` and of `issued_assets` with one party's update key,
+/// returning one signature per input.
+pub(crate) fn sign_setup_spending_inputs(
+    transaction: &Transaction,
+    funding_amount: Amount,
+    issued_assets: &[IssuedAsset],
+    alice_update_pk: &XOnlyPublicKey,
+    bob_update_pk: &XOnlyPublicKey,
+    update_sk: &SecretKey,
+) -> Vec<ecdsa::Signature> {
+    setup_input_amounts(funding_amount, issued_assets)
+        .enumerate()
+        .map(|(index, input_amount)| {
+            sign_setup_spending_input(
+                transaction,
+                index,
+                input_amount,
+                alice_update_pk,
+                bob_update_pk,
+                update_sk,
+            )
+        })
+        .collect()
+}
+
+/// The amounts of the setup outputs spent by input 0, the L-BTC one, and by
+/// the inputs carrying `issued_assets`.
+fn setup_input_amounts(
+    funding_amount: Amount,
+    issued_assets: &[IssuedAsset],
+) -> impl Iterator<Item = Amount> + '_ {
+    std::iter::once(funding_amount).chain(issued_assets.iter().map(|issued| issued.amount()))
+}
+
+/// Sign input `index` of a transaction, which spends a 2-of-2 setup output of
+/// `input_amount`, with one party's update key.
+///
+/// The setup program checks the even-parity public key of each update key, so
+/// the key is negated if needed to sign for it.
+pub(crate) fn sign_setup_spending_input(
+    transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
+    alice_update_pk: &XOnlyPublicKey,
+    bob_update_pk: &XOnlyPublicKey,
+    update_sk: &SecretKey,
+) -> ecdsa::Signature {
+    let message = setup_spending_message(
+        transaction,
+        index,
+        input_amount,
+        alice_update_pk,
+        bob_update_pk,
     );
-    let message = Message::from_digest_slice(&sighash[..]).unwrap();
 
-    // Alice and Bob sign the message. In a production implementation,
-    // they would sign separately, and only exchange signatures.
-    let alice_sig = SECP256K1.sign_ecdsa(&message, alice_update_sk);
-    let bob_sig = SECP256K1.sign_ecdsa(&message, bob_update_sk);
+    SECP256K1.sign_ecdsa(&message, &even_secret_key(update_sk))
+}
 
-    // Push the signatures into the witness.
+/// Verify one party's signature on input `index` of a transaction, which
+/// spends a 2-of-2 setup output of `input_amount`.
+pub(crate) fn verify_setup_spending_input(
+    transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
+    alice_update_pk: &XOnlyPublicKey,
+    bob_update_pk: &XOnlyPublicKey,
+    update_pk: &XOnlyPublicKey,
+    signature: &ecdsa::Signature,
+) -> Result<(), Error> {
+    let message = setup_spending_message(
+        transaction,
+        index,
+        input_amount,
+        alice_update_pk,
+        bob_update_pk,
+    );
+    let public_key = setup_public_key(update_pk);
+
+    SECP256K1
+        .verify_ecdsa(&message, signature, &public_key)
+        .map_err(|e| Error::Signature(format!("{}: {}", update_pk, e)))
+}
+
+/// Push both parties signatures into the witnesses of a transaction that
+/// spends 2-of-2 setup outputs, with one pair of `signatures` per input.
+pub(crate) fn finalize_setup_spending_transaction(
+    transaction: &Transaction,
+    alice_update_pk: &XOnlyPublicKey,
+    bob_update_pk: &XOnlyPublicKey,
+    signatures: &[(ecdsa::Signature, ecdsa::Signature)],
+) -> Transaction {
+    let witness_program = build_setup_address_program(alice_update_pk, bob_update_pk);
+
+    // Push the signatures into the witness. Alice's key is checked first,
+    // against the top of the stack.
     //
-    // <alice_sig>
     // <bob_sig>
+    // <alice_sig>
     // <witness_program>
+    let mut signed_transaction = transaction.clone();
+    for (input, (alice_sig, bob_sig)) in signed_transaction.input.iter_mut().zip(signatures) {
+        input.witness = TxInWitness {
+            amount_rangeproof: None,
+            inflation_keys_rangeproof: None,
+            script_witness: vec![
+                [&bob_sig.serialize_der()[..], &[EcdsaSighashType::All as u8]].concat(),
+                [
+                    &alice_sig.serialize_der()[..],
+                    &[EcdsaSighashType::All as u8],
+                ]
+                .concat(),
+                witness_program.to_bytes(),
+            ],
+            pegin_witness: vec![],
+        };
+    }
+
+    signed_transaction
+}
+
+/// Sign input `index` of a transaction, which spends the n-of-n setup output
+/// of `input_amount` of a multi-party channel, with one participant's update key.
+#[cfg(test)]
+pub(crate) fn sign_multiparty_setup_spending_input(
+    transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
+    update_pks: &[XOnlyPublicKey],
+    update_sk: &SecretKey,
+) -> ecdsa::Signature {
+    let message = multiparty_setup_spending_message(transaction, index, input_amount, update_pks);
+
+    SECP256K1.sign_ecdsa(&message, &even_secret_key(update_sk))
+}
+
+/// Verify one participant's signature on input `index` of a transaction, which
+/// spends the n-of-n setup output of `input_amount` of a multi-party channel.
+#[cfg(test)]
+pub(crate) fn verify_multiparty_setup_spending_input(
+    transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
+    update_pks: &[XOnlyPublicKey],
+    update_pk: &XOnlyPublicKey,
+    signature: &ecdsa::Signature,
+) -> Result<(), Error> {
+    let message = multiparty_setup_spending_message(transaction, index, input_amount, update_pks);
+    let public_key = setup_public_key(update_pk);
+
+    SECP256K1
+        .verify_ecdsa(&message, signature, &public_key)
+        .map_err(|e| Error::Signature(format!("{}: {}", update_pk, e)))
+}
+
+/// Push every participant's signature, in participant order, into the witness
+/// of input 0 of a transaction that spends the n-of-n setup output of a
+/// multi-party channel.
+#[cfg(test)]
+pub(crate) fn finalize_multiparty_setup_spending_transaction(
+    transaction: &Transaction,
+    update_pks: &[XOnlyPublicKey],
+    signatures: &[ecdsa::Signature],
+) -> Transaction {
+    let setup_pks: Vec<_> = update_pks.iter().map(setup_public_key).collect();
+    let witness_program = build_multiparty_setup_program(&setup_pks);
+
+    // The first key is checked first, against the top of the stack.
+    //
+    // <sig_n>
+    // ...
+    // <sig_1>
+    // <witness_program>
+    let mut script_witness: Vec<Vec<u8>> = signatures
+        .iter()
+        .rev()
+        .map(|sig| [&sig.serialize_der()[..], &[EcdsaSighashType::All as u8]].concat())
+        .collect();
+    script_witness.push(witness_program.to_bytes());
+
     let mut signed_transaction = transaction.clone();
     signed_transaction.input[0].witness = TxInWitness {
         amount_rangeproof: None,
         inflation_keys_rangeproof: None,
-        script_witness: vec![
-            [
-                &alice_sig.serialize_der()[..],
-                &[EcdsaSighashType::All as u8],
-            ]
-            .concat(),
-            [&bob_sig.serialize_der()[..], &[EcdsaSighashType::All as u8]].concat(),
-            witness_program.to_bytes(),
-        ],
+        script_witness,
         pegin_witness: vec![],
     };
 
     signed_transaction
 }
 
-/// Verify the `Setup Withdrawal Transaction` signatures.
-///
-/// This checks that both Alice and Bob have correctly signed the transaction.
-pub(crate) fn verify_setup_withdrawal_transaction(
+/// Hash input `index` of a transaction that spends a 2-of-2 setup output and
+/// wrap it in a [`Message`] for signing.
+pub(crate) fn setup_spending_message(
     transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
     alice_update_pk: &XOnlyPublicKey,
     bob_update_pk: &XOnlyPublicKey,
-) -> Result<(), String> {
-    // Re-build the witness program
-    let witness_program = build_setup_address_program(alice_update_pk, bob_update_pk);
+) -> Message {
+    multiparty_setup_spending_message(
+        transaction,
+        index,
+        input_amount,
+        &[*alice_update_pk, *bob_update_pk],
+    )
+}
 
-    // Re-derive the output value from the transaction and the fee
-    let output_value = match transaction.output[0].value {
-        confidential::Value::Explicit(v) => v,
-        _ => return Err("Expected explicit value".to_string()),
-    };
-    let value = output_value + FEE_AMOUNT;
+/// Hash input `index` of a transaction that spends an n-of-n setup output and
+/// wrap it in a [`Message`] for signing.
+fn multiparty_setup_spending_message(
+    transaction: &Transaction,
+    index: usize,
+    input_amount: Amount,
+    update_pks: &[XOnlyPublicKey],
+) -> Message {
+    let setup_pks: Vec<_> = update_pks.iter().map(setup_public_key).collect();
+    let witness_program = build_multiparty_setup_program(&setup_pks);
 
-    // Calculate the sighash
     let sighash = SighashCache::new(transaction).segwitv0_sighash(
-        0,
+        index,
         &witness_program,
-        confidential::Value::Explicit(value),
+        confidential::Value::Explicit(input_amount.to_sat()),
         EcdsaSighashType::All,
     );
 
-    let message = Message::from_digest_slice(&sighash[..])
-        .map_err(|e| format!("Failed to create message: {}", e))?;
+    Message::from_digest_slice(&sighash[..]).unwrap()
+}
 
-    // Extract signatures from witness
-    let witness = &transaction.input[0].witness.script_witness;
-    if witness.len() != 3 {
-        return Err(format!(
-            "Expected 3 witness elements, got {}",
-            witness.len()
-        ));
+/// Sign the fee output at `outpoint` with `fee_sk`, to pay the fee of
+/// `update` when it overrides a stale update. The fee input is the last input
+/// of the update.
+pub(crate) fn sign_fee_input(
+    update: &Transaction,
+    outpoint: OutPoint,
+    fee_sk: &SecretKey,
+) -> FeeInput {
+    let fee_pk = fee_sk.public_key(SECP256K1);
+    let message = fee_input_message(update, outpoint, &fee_pk);
+
+    FeeInput {
+        outpoint,
+        fee_pk,
+        signature: SECP256K1.sign_ecdsa(&message, fee_sk),
     }
+}
+
+/// Verify the signature of `fee_input` for `update`.
+#[cfg(test)]
+pub(crate) fn verify_fee_input(update: &Transaction, fee_input: &FeeInput) -> Result<(), Error> {
+    let message = fee_input_message(update, fee_input.outpoint, &fee_input.fee_pk);
+
+    SECP256K1
+        .verify_ecdsa(&message, &fee_input.signature, &fee_input.fee_pk)
+        .map_err(|e| Error::Signature(format!("fee input {}: {}", fee_input.outpoint, e)))
+}
+
+/// Hash the fee input of `update` spending the fee output at `outpoint`, locked
+/// to `fee_pk`, and wrap it in a [`Message`] for signing.
+fn fee_input_message(update: &Transaction, outpoint: OutPoint, fee_pk: &PublicKey) -> Message {
+    let mut transaction = update.clone();
+    let index = transaction.input.len() - 1;
+    transaction.input[index].previous_output = outpoint;
+    let pubkey_hash = PubkeyHash::hash(&fee_pk.serialize());
+
+    let sighash = SighashCache::new(&transaction).segwitv0_sighash(
+        index,
+        &Script::new_p2pkh(&pubkey_hash),
+        confidential::Value::Explicit(FEE_AMOUNT),
+        EcdsaSighashType::AllPlusAnyoneCanPay,
+    );
+
+    Message::from_digest_slice(&sighash[..]).unwrap()
+}
+
+/// Sign an `Update Transaction` that spends a commitment output of `input_amount`.
+///
+/// Both signatures use `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`, so the update
+/// can later be rebound to any commitment output holding the same amount. The
+/// inputs carrying `issued_assets` are signed the same way.
+///
+/// Fails if the `lock_time` of `transaction` does not encode a state number.
+pub(crate) fn sign_update_transaction(
+    transaction: &Transaction,
+    input_amount: Amount,
+    issued_assets: &[IssuedAsset],
+    alice_update_sk: &SecretKey,
+    bob_update_sk: &SecretKey,
+    is_mainnet: bool,
+) -> Result<SignedUpdate, Error> {
+    let state = lock_time_state(transaction.lock_time)
+        .ok_or_else(|| Error::Channel("update lock time does not encode a state".into()))?;
+    let mut signatures = sign_anyprevout(
+        transaction,
+        input_amount,
+        issued_assets,
+        alice_update_sk,
+        bob_update_sk,
+        is_mainnet,
+    );
+    let (alice_sig, bob_sig) = signatures.remove(0);
 
-    let alice_sig_bytes = &witness[0];
-    let bob_sig_bytes = &witness[1];
+    Ok(SignedUpdate {
+        state,
+        transaction: transaction.clone(),
+        alice_sig,
+        bob_sig,
+        asset_sigs: signatures,
+    })
+}
 
-    // Parse signatures (remove the sighash type byte at the end)
-    if alice_sig_bytes.is_empty() || bob_sig_bytes.is_empty() {
-        return Err("Empty signature found".to_string());
+/// Sign a `Settlement Transaction` that spends the commitment output of
+/// `input_amount` locked by `commitment_program`.
+///
+/// Like updates, settlements are signed with `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`,
+/// since the outpoint of the commitment output depends on which update got it confirmed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sign_settlement_transaction(
+    transaction: &Transaction,
+    input_amount: Amount,
+    issued_assets: &[IssuedAsset],
+    commitment_program: &CompiledProgram,
+    alice_settlement_sk: &SecretKey,
+    bob_settlement_sk: &SecretKey,
+    is_mainnet: bool,
+) -> Result<Transaction, Error> {
+    let mut signatures = sign_anyprevout(
+        transaction,
+        input_amount,
+        issued_assets,
+        alice_settlement_sk,
+        bob_settlement_sk,
+        is_mainnet,
+    );
+    let (alice_sig, bob_sig) = signatures.remove(0);
+
+    finalize_settlement_transaction(
+        transaction,
+        commitment_program,
+        &alice_sig,
+        &bob_sig,
+        &signatures,
+    )
+}
+
+/// Put both parties settlement signatures into the witnesses of a `Settlement Transaction`
+/// that spends the commitment outputs locked by `commitment_program`, with
+/// `asset_sigs` for the inputs carrying issued assets.
+pub(crate) fn finalize_settlement_transaction(
+    transaction: &Transaction,
+    commitment_program: &CompiledProgram,
+    alice_sig: &schnorr::Signature,
+    bob_sig: &schnorr::Signature,
+    asset_sigs: &[(schnorr::Signature, schnorr::Signature)],
+) -> Result<Transaction, Error> {
+    let mut signed_transaction = transaction.clone();
+    let signatures = std::iter::once((alice_sig, bob_sig))
+        .chain(asset_sigs.iter().map(|(alice, bob)| (alice, bob)));
+    for (input, (alice_sig, bob_sig)) in signed_transaction.input.iter_mut().zip(signatures) {
+        input.witness.script_witness = build_commitment_witness(
+            commitment_program,
+            CommitmentPath::Settlement,
+            alice_sig,
+            bob_sig,
+        )?;
     }
 
-    let alice_sig = ecdsa::Signature::from_der(&alice_sig_bytes[..alice_sig_bytes.len() - 1])
-        .map_err(|e| format!("Failed to parse Alice's signature: {}", e))?;
-    let bob_sig = ecdsa::Signature::from_der(&bob_sig_bytes[..bob_sig_bytes.len() - 1])
-        .map_err(|e| format!("Failed to parse Bob's signature: {}", e))?;
+    Ok(signed_transaction)
+}
 
-    // Convert XOnlyPublicKey to PublicKey for ECDSA verification
-    // We use even parity since the witness script doesn't encode parity
-    let alice_pubkey = PublicKey::from_x_only_public_key(*alice_update_pk, Parity::Even);
-    let bob_pubkey = PublicKey::from_x_only_public_key(*bob_update_pk, Parity::Even);
+/// Put every participant's settlement signature, in participant order, into
+/// the witness of a multi-party `Settlement Transaction` that spends the
+/// commitment output locked by `commitment_program`.
+#[cfg(test)]
+pub(crate) fn finalize_multiparty_settlement_transaction(
+    transaction: &Transaction,
+    commitment_program: &CompiledProgram,
+    signatures: &[schnorr::Signature],
+) -> Result<Transaction, Error> {
+    let mut signed_transaction = transaction.clone();
+    signed_transaction.input[0].witness.script_witness = build_multiparty_commitment_witness(
+        commitment_program,
+        CommitmentPath::Settlement,
+        signatures,
+    )?;
 
-    // Verify signatures
-    SECP256K1
-        .verify_ecdsa(&message, &alice_sig, &alice_pubkey)
-        .map_err(|e| format!("Alice's signature verification failed: {}", e))?;
+    Ok(signed_transaction)
+}
+
+/// Put the witness of `spend` into input 0 of a transaction that spends the
+/// HTLC output locked by `htlc_program`.
+#[cfg(test)]
+pub(crate) fn finalize_htlc_spend_transaction(
+    transaction: &Transaction,
+    htlc_program: &CompiledProgram,
+    spend: &HtlcSpend,
+) -> Result<Transaction, Error> {
+    let mut signed_transaction = transaction.clone();
+    signed_transaction.input[0].witness.script_witness = build_htlc_witness(htlc_program, spend)?;
+
+    Ok(signed_transaction)
+}
+
+/// Put the witness of `spend` into input 0 of a transaction that spends the
+/// PTLC output locked by `ptlc_program`.
+#[cfg(test)]
+pub(crate) fn finalize_ptlc_spend_transaction(
+    transaction: &Transaction,
+    ptlc_program: &CompiledProgram,
+    spend: &PtlcSpend,
+) -> Result<Transaction, Error> {
+    let mut signed_transaction = transaction.clone();
+    signed_transaction.input[0].witness.script_witness = build_ptlc_witness(ptlc_program, spend)?;
+
+    Ok(signed_transaction)
+}
+
+/// Have Alice and Bob sign every input of `transaction` with
+/// `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`, returning one pair of signatures
+/// per input.
+///
+/// Input 0 spends the L-BTC commitment output of `input_amount`, and each
+/// following input the commitment output of the issued asset at the same
+/// position in `issued_assets`.
+fn sign_anyprevout(
+    transaction: &Transaction,
+    input_amount: Amount,
+    issued_assets: &[IssuedAsset],
+    alice_sk: &SecretKey,
+    bob_sk: &SecretKey,
+    is_mainnet: bool,
+) -> Vec<(schnorr::Signature, schnorr::Signature)> {
+    // Alice and Bob sign every input. Over the wire, they sign separately,
+    // and only exchange signatures.
+    let sign = |secret_key: &SecretKey| {
+        sign_anyprevout_inputs(
+            transaction,
+            input_amount,
+            issued_assets,
+            secret_key,
+            is_mainnet,
+        )
+    };
+
+    sign(alice_sk).into_iter().zip(sign(bob_sk)).collect()
+}
+
+/// Sign every input of `transaction` with one party's key and
+/// `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`, returning one signature per input.
+///
+/// Input 0 spends the L-BTC commitment output of `input_amount`, and each
+/// following input the commitment output of the issued asset at the same
+/// position in `issued_assets`.
+pub(crate) fn sign_anyprevout_inputs(
+    transaction: &Transaction,
+    input_amount: Amount,
+    issued_assets: &[IssuedAsset],
+    secret_key: &SecretKey,
+    is_mainnet: bool,
+) -> Vec<schnorr::Signature> {
+    let keypair = Keypair::from_secret_key(SECP256K1, secret_key);
+
+    commitment_inputs(input_amount, issued_assets, is_mainnet)
+        .enumerate()
+        .map(|(index, (asset, amount))| {
+            let message = anyprevout_sighash(transaction, index, asset, amount, is_mainnet);
+            SECP256K1.sign_schnorr_no_aux_rand(&message, &keypair)
+        })
+        .collect()
+}
+
+/// The asset and amount of the L-BTC commitment output of `input_amount`, and
+/// of the commitment outputs carrying `issued_assets`.
+fn commitment_inputs(
+    input_amount: Amount,
+    issued_assets: &[IssuedAsset],
+    is_mainnet: bool,
+) -> impl Iterator<Item = (AssetId, Amount)> + '_ {
+    std::iter::once((policy_asset(is_mainnet), input_amount)).chain(
+        issued_assets
+            .iter()
+            .map(|issued| (issued.asset, issued.amount())),
+    )
+}
+
+/// Sign input 0 of `transaction`, which spends a commitment output of `input_amount`,
+/// with one party's key and `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`.
+pub(crate) fn sign_anyprevout_input(
+    transaction: &Transaction,
+    input_amount: Amount,
+    secret_key: &SecretKey,
+    is_mainnet: bool,
+) -> schnorr::Signature {
+    let asset = policy_asset(is_mainnet);
+    let message = anyprevout_sighash(transaction, 0, asset, input_amount, is_mainnet);
+    let keypair = Keypair::from_secret_key(SECP256K1, secret_key);
+
+    SECP256K1.sign_schnorr_no_aux_rand(&message, &keypair)
+}
+
+/// Verify one party's `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT` signature on
+/// input 0 of `transaction`, which spends a commitment output of `input_amount`.
+pub(crate) fn verify_anyprevout_input(
+    transaction: &Transaction,
+    input_amount: Amount,
+    public_key: &XOnlyPublicKey,
+    signature: &schnorr::Signature,
+    is_mainnet: bool,
+) -> Result<(), Error> {
+    let asset = policy_asset(is_mainnet);
+    let message = anyprevout_sighash(transaction, 0, asset, input_amount, is_mainnet);
 
     SECP256K1
-        .verify_ecdsa(&message, &bob_sig, &bob_pubkey)
-        .map_err(|e| format!("Bob's signature verification failed: {}", e))?;
+        .verify_schnorr(signature, &message, public_key)
+        .map_err(|e| Error::Signature(format!("{}: {}", public_key, e)))
+}
+
+/// Compute the `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT` [`Message`] for input
+/// `index` of `transaction`, which spends an output of `input_amount` of `asset`.
+///
+/// This mirrors `sighash_all_anyprevout` in the commitment template.
+pub(crate) fn anyprevout_sighash(
+    transaction: &Transaction,
+    index: usize,
+    asset: AssetId,
+    input_amount: Amount,
+    is_mainnet: bool,
+) -> Message {
+    let genesis_hash = genesis_hash(is_mainnet);
+    let input = &transaction.input[index];
+
+    let mut engine = sha256::Hash::engine();
+    // Blockchain
+    engine.input(genesis_hash.as_byte_array());
+    engine.input(genesis_hash.as_byte_array());
+    // Transaction
+    engine.input(&transaction.version.to_be_bytes());
+    engine.input(&transaction.lock_time.to_consensus_u32().to_be_bytes());
+    // Current input without outpoint and without script
+    engine.input(&input.sequence.to_consensus_u32().to_be_bytes());
+    // No annex
+    engine.input(&[0x00]);
+    engine.input(&serialize(&confidential::Asset::Explicit(asset)));
+    engine.input(&serialize(&confidential::Value::Explicit(
+        input_amount.to_sat(),
+    )));
+    // All outputs
+    engine.input(outputs_hash(transaction).as_byte_array());
+    engine.input(issuances_hash(transaction).as_byte_array());
+    engine.input(output_surjection_proofs_hash(transaction).as_byte_array());
+
+    Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+/// Return the genesis [`BlockHash`] of the network.
+pub(crate) fn genesis_hash(is_mainnet: bool) -> BlockHash {
+    match is_mainnet {
+        true => BlockHash::from_str(LIQUIDV1_MAIN_GENESIS_HASH).unwrap(),
+        false => BlockHash::from_str(LIQUIDV1_TEST_GENESIS_HASH).unwrap(),
+    }
+}
+
+/// Hash of all outputs' assets, amounts, nonces, scripts and range proofs.
+fn outputs_hash(transaction: &Transaction) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    for output in &transaction.output {
+        let rangeproof = output
+            .witness
+            .rangeproof
+            .as_ref()
+            .map(|proof| proof.serialize())
+            .unwrap_or_default();
+
+        engine.input(&serialize(&output.asset));
+        engine.input(&serialize(&output.value));
+        engine.input(&serialize(&output.nonce));
+        engine.input(sha256::Hash::hash(output.script_pubkey.as_bytes()).as_byte_array());
+        engine.input(sha256::Hash::hash(&rangeproof).as_byte_array());
+    }
+
+    sha256::Hash::from_engine(engine)
+}
+
+/// Hash of all inputs' issuances.
+fn issuances_hash(transaction: &Transaction) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    for input in &transaction.input {
+        match input.has_issuance() {
+            true => {
+                engine.input(&[0x01]);
+                engine.input(&serialize(&input.asset_issuance));
+            }
+            false => engine.input(&[0x00]),
+        }
+    }
+
+    sha256::Hash::from_engine(engine)
+}
+
+/// Hash of all outputs' surjection proofs.
+fn output_surjection_proofs_hash(transaction: &Transaction) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    for output in &transaction.output {
+        let surjection_proof = output
+            .witness
+            .surjection_proof
+            .as_ref()
+            .map(|proof| proof.serialize())
+            .unwrap_or_default();
+
+        engine.input(sha256::Hash::hash(&surjection_proof).as_byte_array());
+    }
+
+    sha256::Hash::from_engine(engine)
+}
+
+/// Tag of the Settlement key derivation.
+const SETTLEMENT_KEY_TAG: &[u8] = b"SimplEltoo/settlement";
+
+/// Derive the Settlement [`SecretKey`] for a state from a settlement root [`SecretKey`].
+///
+/// Settlement Key := root + settlement_tweak(root·G, state_idx)
+///
+/// Its public key is the one [`derive_settlement_pubkey`] derives from the
+/// settlement basepoint `root·G`, so the root never has to leave its owner.
+pub(crate) fn derive_settlement_key(settlement_root_sk: &SecretKey, state_idx: u64) -> SecretKey {
+    let basepoint = settlement_root_sk.public_key(SECP256K1);
+    let tweak = settlement_tweak(&basepoint, state_idx);
+
+    // The tweak is chosen so that the tweaked basepoint is valid, hence so is
+    // the tweaked secret key.
+    settlement_root_sk
+        .add_tweak(&tweak)
+        .expect("settlement tweak is valid for the basepoint")
+}
+
+/// Derive the Settlement [`PublicKey`] for a state from a settlement basepoint.
+///
+/// Settlement Public Key := basepoint + settlement_tweak(basepoint, state_idx)·G
+///
+/// Both parties exchange their basepoints once, when the channel is opened, and
+/// can then compute each other's settlement public key for any state.
+pub(crate) fn derive_settlement_pubkey(basepoint: &PublicKey, state_idx: u64) -> PublicKey {
+    let tweak = settlement_tweak(basepoint, state_idx);
+
+    basepoint
+        .add_exp_tweak(SECP256K1, &tweak)
+        .expect("settlement tweak is valid for the basepoint")
+}
+
+/// The additive tweak of the settlement basepoint for a state.
+///
+/// Tweak := TaggedHash("SimplEltoo/settlement", basepoint || state_idx || counter)
+///
+/// Where `basepoint` is compressed, `state_idx` is a big-endian `u64` and `counter`
+/// a big-endian `u32` that starts at zero, and is only incremented in the negligible
+/// case that the digest is not a valid scalar or tweaks the basepoint to infinity.
+fn settlement_tweak(basepoint: &PublicKey, state_idx: u64) -> Scalar {
+    first_valid_tweak(basepoint, |counter| {
+        tagged_hash(
+            SETTLEMENT_KEY_TAG,
+            &[
+                &basepoint.serialize(),
+                &state_idx.to_be_bytes(),
+                &counter.to_be_bytes(),
+            ],
+        )
+        .to_byte_array()
+    })
+}
+
+/// The first `candidate` digest, by increasing counter, that is a valid scalar
+/// and does not tweak `basepoint` to infinity.
+fn first_valid_tweak(basepoint: &PublicKey, candidate: impl Fn(u32) -> [u8; 32]) -> Scalar {
+    let mut counter: u32 = 0;
+    loop {
+        if let Ok(tweak) = Scalar::from_be_bytes(candidate(counter))
+            && basepoint.add_exp_tweak(SECP256K1, &tweak).is_ok()
+        {
+            break tweak;
+        }
+        counter += 1;
+    }
+}
+
+/// Tag of the PTLC key derivation.
+const PTLC_KEY_TAG: &[u8] = b"SimplEltoo/ptlc";
+
+/// Derive the offerer's PTLC [`SecretKey`] from its settlement [`SecretKey`]
+/// for the state that creates the PTLC output.
+///
+/// PTLC Key := settlement + TaggedHash("SimplEltoo/ptlc", settlement·G || payment_point)
+///
+/// Where `settlement·G` is x-only and `payment_point` is compressed. Spends of
+/// PTLC outputs are signed with `SIGHASH_ALL | SIGHASH_ANYPREVOUTANYSCRIPT`, so
+/// without a key per payment point, a signature for one PTLC would also spend
+/// any other PTLC of the same amount.
+#[cfg(test)]
+pub(crate) fn derive_ptlc_key(
+    settlement_sk: &SecretKey,
+    payment_point: &PublicKey,
+) -> Result<SecretKey, Error> {
+    let settlement_sk = even_secret_key(settlement_sk);
+    let settlement_pk = settlement_sk.x_only_public_key(SECP256K1).0;
+
+    settlement_sk
+        .add_tweak(&ptlc_tweak(&settlement_pk, payment_point)?)
+        .map_err(|e| Error::Key(format!("invalid PTLC key: {}", e)))
+}
+
+/// Derive the offerer's PTLC [`XOnlyPublicKey`], the public key of [`derive_ptlc_key`].
+pub(crate) fn derive_ptlc_pubkey(
+    settlement_pk: &XOnlyPublicKey,
+    payment_point: &PublicKey,
+) -> Result<XOnlyPublicKey, Error> {
+    PublicKey::from_x_only_public_key(*settlement_pk, Parity::Even)
+        .add_exp_tweak(SECP256K1, &ptlc_tweak(settlement_pk, payment_point)?)
+        .map(|ptlc_pk| ptlc_pk.x_only_public_key().0)
+        .map_err(|e| Error::Key(format!("invalid PTLC key: {}", e)))
+}
+
+/// The additive tweak of a settlement key for the PTLC of `payment_point`.
+fn ptlc_tweak(settlement_pk: &XOnlyPublicKey, payment_point: &PublicKey) -> Result<Scalar, Error> {
+    let digest = tagged_hash(
+        PTLC_KEY_TAG,
+        &[&settlement_pk.serialize(), &payment_point.serialize()],
+    );
+
+    Scalar::from_be_bytes(digest.to_byte_array())
+        .map_err(|e| Error::Key(format!("invalid PTLC tweak: {}", e)))
+}
+
+/// Tag of the adaptor signature nonce derivation.
+#[cfg(any(test, feature = "fuzzing"))]
+const ADAPTOR_NONCE_TAG: &[u8] = b"SimplEltoo/adaptor";
+
+/// Tag of the BIP340 challenge.
+#[cfg(any(test, feature = "fuzzing"))]
+const BIP340_CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+
+/// A BIP340 signature encrypted to an adaptor point `T = t·G`.
+///
+/// It is not a valid signature by itself, but anyone who knows `t` can complete
+/// it into one, and anyone who sees the completed signature can then extract `t`.
+/// This is what makes a PTLC atomic: the receiver claims the payment by
+/// completing the offerer's signature, which reveals the payment secret.
+#[cfg(any(test, feature = "fuzzing"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct AdaptorSignature {
+    /// The signer's nonce `R`, before the adaptor point is added to it.
+    pub(crate) nonce: PublicKey,
+    /// The partial scalar `s`, which the adaptor secret completes.
+    pub(crate) partial: SecretKey,
+}
+
+/// Create an [`AdaptorSignature`] of `message` with `secret_key`, encrypted to
+/// `adaptor_point`.
+///
+/// The completed signature has nonce `R + T`, so the partial scalar is
+/// `s = k + e·x` where `e` already commits to `R + T`, and `k` and `x` are
+/// negated as needed for `R + T` and `x·G` to have even y coordinates.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(crate) fn sign_adaptor(
+    message: &Message,
+    secret_key: &SecretKey,
+    adaptor_point: &PublicKey,
+) -> Result<AdaptorSignature, Error> {
+    let secret_key = even_secret_key(secret_key);
+    let public_key = secret_key.x_only_public_key(SECP256K1).0;
+
+    let digest = tagged_hash(
+        ADAPTOR_NONCE_TAG,
+        &[
+            &secret_key.secret_bytes(),
+            &adaptor_point.serialize(),
+            &message[..],
+        ],
+    );
+    let nonce_sk = SecretKey::from_slice(digest.as_byte_array())
+        .map_err(|e| Error::Signature(format!("adaptor nonce: {}", e)))?;
+    let nonce = nonce_sk.public_key(SECP256K1);
+
+    let (final_nonce, parity) = adaptor_final_nonce(&nonce, adaptor_point)?;
+    let nonce_sk = match parity {
+        Parity::Even => nonce_sk,
+        Parity::Odd => nonce_sk.negate(),
+    };
+    let challenge = bip340_challenge(&final_nonce, &public_key, message);
+
+    let partial = secret_key
+        .mul_tweak(&challenge)
+        .and_then(|ex| ex.add_tweak(&Scalar::from(nonce_sk)))
+        .map_err(|e| Error::Signature(format!("adaptor signature: {}", e)))?;
+
+    Ok(AdaptorSignature { nonce, partial })
+}
+
+/// Verify that `adaptor` is an [`AdaptorSignature`] of `message` by `public_key`,
+/// encrypted to `adaptor_point`: `s·G = ±R + e·P`.
+#[cfg(test)]
+pub(crate) fn verify_adaptor(
+    message: &Message,
+    public_key: &XOnlyPublicKey,
+    adaptor_point: &PublicKey,
+    adaptor: &AdaptorSignature,
+) -> Result<(), Error> {
+    let (final_nonce, parity) = adaptor_final_nonce(&adaptor.nonce, adaptor_point)?;
+    let nonce = match parity {
+        Parity::Even => adaptor.nonce,
+        Parity::Odd => adaptor.nonce.negate(SECP256K1),
+    };
+    let challenge = bip340_challenge(&final_nonce, public_key, message);
+
+    let expected = PublicKey::from_x_only_public_key(*public_key, Parity::Even)
+        .mul_tweak(SECP256K1, &challenge)
+        .and_then(|ep| ep.combine(&nonce));
+    if expected != Ok(adaptor.partial.public_key(SECP256K1)) {
+        return Err(Error::Signature(format!(
+            "{}: invalid adaptor signature",
+            public_key
+        )));
+    }
 
     Ok(())
 }
 
-/// Derive a Settlement [`SecretKey`] from an Update [`SecretKey`] and the state index.
+/// Complete `adaptor` with the `adaptor_secret` it is encrypted to, into a
+/// BIP340 signature.
+#[cfg(test)]
+pub(crate) fn complete_adaptor(
+    adaptor: &AdaptorSignature,
+    adaptor_secret: &SecretKey,
+) -> Result<schnorr::Signature, Error> {
+    let adaptor_point = adaptor_secret.public_key(SECP256K1);
+    let (final_nonce, parity) = adaptor_final_nonce(&adaptor.nonce, &adaptor_point)?;
+    let adaptor_secret = match parity {
+        Parity::Even => *adaptor_secret,
+        Parity::Odd => adaptor_secret.negate(),
+    };
+
+    let completed = adaptor
+        .partial
+        .add_tweak(&Scalar::from(adaptor_secret))
+        .map_err(|e| Error::Signature(format!("adaptor completion: {}", e)))?;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&final_nonce.serialize());
+    signature[32..].copy_from_slice(&completed.secret_bytes());
+
+    schnorr::Signature::from_slice(&signature)
+        .map_err(|e| Error::Signature(format!("adaptor completion: {}", e)))
+}
+
+/// Extract the secret of `adaptor_point` from `signature`, the completion of `adaptor`.
 ///
-/// Settlement Key := SHA256(update_key || state_idx)
-pub(crate) fn derive_settlement_key(sk_update: &SecretKey, state_idx: u64) -> SecretKey {
-    let mut sk_settlement_seed = Vec::with_capacity(32 + 8);
+/// The completed scalar is `s ± t`, so the secret is `±(s' - s)`, which is only
+/// accepted if it matches `adaptor_point`.
+#[cfg(test)]
+pub(crate) fn extract_adaptor_secret(
+    adaptor: &AdaptorSignature,
+    adaptor_point: &PublicKey,
+    signature: &schnorr::Signature,
+) -> Result<SecretKey, Error> {
+    let (final_nonce, parity) = adaptor_final_nonce(&adaptor.nonce, adaptor_point)?;
+    let signature = signature.serialize();
+    if signature[..32] != final_nonce.serialize() {
+        return Err(Error::Signature(
+            "signature does not complete the adaptor signature".into(),
+        ));
+    }
 
-    sk_settlement_seed.extend_from_slice(&sk_update.secret_bytes());
-    sk_settlement_seed.extend_from_slice(&state_idx.to_be_bytes());
+    let secret = SecretKey::from_slice(&signature[32..])
+        .and_then(|completed| completed.add_tweak(&Scalar::from(adaptor.partial.negate())))
+        .map_err(|e| Error::Signature(format!("adaptor extraction: {}", e)))?;
+    let secret = match parity {
+        Parity::Even => secret,
+        Parity::Odd => secret.negate(),
+    };
 
-    let sk_settlement_digest = sha256::Hash::hash(&sk_settlement_seed);
+    if secret.public_key(SECP256K1) != *adaptor_point {
+        return Err(Error::Signature(
+            "extracted secret does not match the adaptor point".into(),
+        ));
+    }
 
-    SecretKey::from_slice(sk_settlement_digest.as_ref()).unwrap_or_else(|_| {
-        let mut attempt = sk_settlement_seed;
-        loop {
-            attempt.extend_from_slice("eltoo".as_bytes());
-            let attempt_digest = sha256::Hash::hash(&attempt);
+    Ok(secret)
+}
 
-            if let Ok(key) = SecretKey::from_slice(attempt_digest.as_ref()) {
-                break key;
-            }
+/// Find the completion of `adaptor` in a serialized Simplicity `witness`.
+///
+/// Simplicity witnesses are bit strings, so the signature is not byte aligned in
+/// general. It is found by its nonce, which is known from `adaptor` in advance.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(crate) fn find_completed_signature(
+    adaptor: &AdaptorSignature,
+    adaptor_point: &PublicKey,
+    witness: &[u8],
+) -> Option<schnorr::Signature> {
+    let (final_nonce, _) = adaptor_final_nonce(&adaptor.nonce, adaptor_point).ok()?;
+    let final_nonce = final_nonce.serialize();
+
+    (0..=(witness.len() * 8).checked_sub(512)?).find_map(|offset| {
+        let mut candidate = [0u8; 64];
+        for (i, byte) in candidate.iter_mut().enumerate() {
+            let (index, shift) = ((offset + i * 8) / 8, (offset + i * 8) % 8);
+            *byte = match shift {
+                0 => witness[index],
+                _ => (witness[index] << shift) | (witness[index + 1] >> (8 - shift)),
+            };
+        }
+
+        match candidate[..32] == final_nonce {
+            true => schnorr::Signature::from_slice(&candidate).ok(),
+            false => None,
+        }
+    })
+}
+
+/// The nonce `R + T` of the signature completed from an adaptor signature,
+/// with the parity of its y coordinate.
+#[cfg(any(test, feature = "fuzzing"))]
+fn adaptor_final_nonce(
+    nonce: &PublicKey,
+    adaptor_point: &PublicKey,
+) -> Result<(XOnlyPublicKey, Parity), Error> {
+    nonce
+        .combine(adaptor_point)
+        .map(|final_nonce| final_nonce.x_only_public_key())
+        .map_err(|e| Error::Signature(format!("adaptor nonce: {}", e)))
+}
+
+/// The BIP340 challenge `e = TaggedHash("BIP0340/challenge", R || P || m) mod n`.
+#[cfg(any(test, feature = "fuzzing"))]
+fn bip340_challenge(
+    nonce: &XOnlyPublicKey,
+    public_key: &XOnlyPublicKey,
+    message: &Message,
+) -> Scalar {
+    let digest = tagged_hash(
+        BIP340_CHALLENGE_TAG,
+        &[&nonce.serialize(), &public_key.serialize(), &message[..]],
+    )
+    .to_byte_array();
+
+    Scalar::from_be_bytes(digest).unwrap_or_else(|_| {
+        // The digest is at least the curve order, and less than twice it.
+        let mut reduced = [0u8; 32];
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let difference = digest[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+            borrow = (difference < 0) as i16;
+            reduced[i] = difference.rem_euclid(256) as u8;
         }
+        Scalar::from_be_bytes(reduced).expect("reduced below the curve order")
     })
 }
 
+/// Negate `secret_key` if needed, so that its public key has an even y coordinate,
+/// as BIP340 public keys do.
+fn even_secret_key(secret_key: &SecretKey) -> SecretKey {
+    match secret_key.x_only_public_key(SECP256K1).1 {
+        Parity::Even => *secret_key,
+        Parity::Odd => secret_key.negate(),
+    }
+}
+
+/// BIP340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || data).
+pub(crate) fn tagged_hash(tag: &[u8], data: &[&[u8]]) -> sha256::Hash {
+    let tag_hash = sha256::Hash::hash(tag);
+
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash.as_byte_array());
+    engine.input(tag_hash.as_byte_array());
+    for chunk in data {
+        engine.input(chunk);
+    }
+
+    sha256::Hash::from_engine(engine)
+}
+
 /// Add the `0x` prefix to a [`XOnlyPublicKey`].
 pub(crate) fn prefix_pubkey(pubkey: XOnlyPublicKey) -> String {
     format!("0x{}", pubkey)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use elements::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGVERIFY};
+    use elements::script::{Builder, Instruction};
+    use elements::{Address, OutPoint, Script};
+    use proptest::prelude::*;
+
+    use crate::transaction::{
+        build_multiparty_setup_address, build_setup_address,
+        build_setup_spending_update_transaction,
+    };
+
+    use super::*;
+
+    #[test]
+    fn adaptor_signature_roundtrip() {
+        let message = Message::from_digest([0x42; 32]);
+
+        // Enough keys for every combination of nonce and key parities.
+        for i in 1..=8u8 {
+            let secret_key = SecretKey::from_slice(&[i; 32]).unwrap();
+            let public_key = secret_key.x_only_public_key(SECP256K1).0;
+            let adaptor_secret = SecretKey::from_slice(&[i + 0x10; 32]).unwrap();
+            let adaptor_point = adaptor_secret.public_key(SECP256K1);
+
+            let adaptor = sign_adaptor(&message, &secret_key, &adaptor_point).unwrap();
+            verify_adaptor(&message, &public_key, &adaptor_point, &adaptor).unwrap();
+
+            // It is encrypted to this adaptor point only.
+            let other_point = SecretKey::from_slice(&[0xff; 32])
+                .unwrap()
+                .public_key(SECP256K1);
+            assert!(verify_adaptor(&message, &public_key, &other_point, &adaptor).is_err());
+
+            let signature = complete_adaptor(&adaptor, &adaptor_secret).unwrap();
+            SECP256K1
+                .verify_schnorr(&signature, &message, &public_key)
+                .unwrap();
+            assert_eq!(
+                extract_adaptor_secret(&adaptor, &adaptor_point, &signature).unwrap(),
+                adaptor_secret
+            );
+
+            // Find the signature at an offset that is not byte aligned.
+            let mut witness = vec![0u8; 70];
+            for (j, byte) in signature.serialize().iter().enumerate() {
+                witness[2 + j] |= byte >> 3;
+                witness[3 + j] |= byte << 5;
+            }
+            assert_eq!(
+                find_completed_signature(&adaptor, &adaptor_point, &witness),
+                Some(signature)
+            );
+        }
+    }
+
+    #[test]
+    fn ptlc_key_matches_pubkey() {
+        let settlement_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let settlement_pk = settlement_sk.x_only_public_key(SECP256K1).0;
+        let payment_point = SecretKey::from_slice(&[0x42; 32])
+            .unwrap()
+            .public_key(SECP256K1);
+
+        let ptlc_sk = derive_ptlc_key(&settlement_sk, &payment_point).unwrap();
+        assert_eq!(
+            ptlc_sk.x_only_public_key(SECP256K1).0,
+            derive_ptlc_pubkey(&settlement_pk, &payment_point).unwrap()
+        );
+        assert_ne!(ptlc_sk.x_only_public_key(SECP256K1).0, settlement_pk);
+    }
+
+    /// Evaluate input `index` of `transaction`, which spends the P2WSH output
+    /// `script_pubkey` of `input_amount`, the way Elements evaluates the chains
+    /// of `OP_CHECKSIGVERIFY` and `OP_CHECKSIG` of setup programs.
+    fn execute_setup_spend(
+        transaction: &Transaction,
+        index: usize,
+        script_pubkey: &Script,
+        input_amount: Amount,
+    ) -> Result<(), String> {
+        let (witness_script, signatures) = transaction.input[index]
+            .witness
+            .script_witness
+            .split_last()
+            .ok_or("empty witness")?;
+        let witness_script = Script::from(witness_script.clone());
+        if witness_script.to_v0_p2wsh() != *script_pubkey {
+            return Err("witness script does not match the output".into());
+        }
+        let sighash = SighashCache::new(transaction).segwitv0_sighash(
+            index,
+            &witness_script,
+            confidential::Value::Explicit(input_amount.to_sat()),
+            EcdsaSighashType::All,
+        );
+        let message = Message::from_digest_slice(&sighash[..]).unwrap();
+
+        // Only compressed or uncompressed keys, and DER signatures with their
+        // sighash type, pass `OP_CHECKSIG`.
+        let check_signature = |public_key: &[u8], signature: &[u8]| {
+            let Some((sighash_type, der)) = signature.split_last() else {
+                return false;
+            };
+            let (Ok(public_key), Ok(signature)) = (
+                PublicKey::from_slice(public_key),
+                ecdsa::Signature::from_der(der),
+            ) else {
+                return false;
+            };
+            *sighash_type == EcdsaSighashType::All as u8
+                && SECP256K1
+                    .verify_ecdsa(&message, &signature, &public_key)
+                    .is_ok()
+        };
+
+        let mut stack = signatures.to_vec();
+        for instruction in witness_script.instructions() {
+            match instruction.map_err(|e| e.to_string())? {
+                Instruction::PushBytes(bytes) => stack.push(bytes.to_vec()),
+                Instruction::Op(op) if op == OP_CHECKSIG || op == OP_CHECKSIGVERIFY => {
+                    let public_key = stack.pop().ok_or("stack underflow")?;
+                    let signature = stack.pop().ok_or("stack underflow")?;
+                    let valid = check_signature(&public_key, &signature);
+                    if op == OP_CHECKSIGVERIFY && !valid {
+                        return Err("OP_CHECKSIGVERIFY failed".into());
+                    }
+                    if op == OP_CHECKSIG {
+                        stack.push(if valid { vec![1] } else { Vec::new() });
+                    }
+                }
+                Instruction::Op(op) => return Err(format!("unexpected opcode {:?}", op)),
+            }
+        }
+
+        // Segwit scripts must leave a single true element.
+        match stack.as_slice() {
+            [top] if top.as_slice() == [1] => Ok(()),
+            _ => Err(format!("script left {:?} on the stack", stack)),
+        }
+    }
+
+    #[test]
+    fn signed_setup_spends_pass_the_interpreter() {
+        let address = Address::from_str(crate::ALICE_RESOLUTION_ADDRESS).unwrap();
+        let setup_outpoint = OutPoint::new(elements::Txid::from_byte_array([0x01; 32]), 0);
+        let funding_amount = Amount::from_sat(100_000);
+        let refund = build_setup_spending_update_transaction(
+            setup_outpoint,
+            funding_amount,
+            &address,
+            0,
+            &[],
+            false,
+        );
+
+        // Keys of both parities, which are negated for the even-parity key.
+        let update_sks: Vec<_> = (1..=3u8)
+            .map(|byte| SecretKey::from_slice(&[byte; 32]).unwrap())
+            .collect();
+        let update_pks: Vec<_> = update_sks
+            .iter()
+            .map(|update_sk| update_sk.x_only_public_key(SECP256K1).0)
+            .collect();
+        assert!(
+            update_sks
+                .iter()
+                .any(|update_sk| update_sk.x_only_public_key(SECP256K1).1 == Parity::Odd)
+        );
+
+        // Between Alice and Bob.
+        let setup_script_pubkey = build_setup_address(update_pks[0], update_pks[1], false)
+            .unwrap()
+            .script_pubkey();
+        let signed = sign_setup_spending_transaction(
+            &refund,
+            funding_amount,
+            &[],
+            &update_sks[0],
+            &update_sks[1],
+        );
+        execute_setup_spend(&signed, 0, &setup_script_pubkey, funding_amount).unwrap();
+
+        let mut swapped = signed.clone();
+        swapped.input[0].witness.script_witness.swap(0, 1);
+        assert!(execute_setup_spend(&swapped, 0, &setup_script_pubkey, funding_amount).is_err());
+
+        // Between three participants.
+        let multiparty_script_pubkey = build_multiparty_setup_address(&update_pks, false)
+            .unwrap()
+            .script_pubkey();
+        let signatures: Vec<_> = update_sks
+            .iter()
+            .map(|update_sk| {
+                sign_multiparty_setup_spending_input(
+                    &refund,
+                    0,
+                    funding_amount,
+                    &update_pks,
+                    update_sk,
+                )
+            })
+            .collect();
+        let signed =
+            finalize_multiparty_setup_spending_transaction(&refund, &update_pks, &signatures);
+        execute_setup_spend(&signed, 0, &multiparty_script_pubkey, funding_amount).unwrap();
+
+        // X-only keys, which `OP_CHECKSIG` rejects, would lock the funds for good.
+        let mut x_only_program = Builder::new();
+        for (index, update_pk) in update_pks.iter().enumerate() {
+            x_only_program = x_only_program.push_slice(&update_pk.serialize());
+            x_only_program = match index == update_pks.len() - 1 {
+                true => x_only_program.push_opcode(OP_CHECKSIG),
+                false => x_only_program.push_opcode(OP_CHECKSIGVERIFY),
+            };
+        }
+        let x_only_program = x_only_program.into_script();
+        let mut x_only_spend = signed.clone();
+        *x_only_spend.input[0]
+            .witness
+            .script_witness
+            .last_mut()
+            .unwrap() = x_only_program.to_bytes();
+        assert!(
+            execute_setup_spend(
+                &x_only_spend,
+                0,
+                &x_only_program.to_v0_p2wsh(),
+                funding_amount
+            )
+            .is_err()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn settlement_keys_are_deterministic(root in any::<[u8; 32]>(), state in any::<u64>()) {
+            prop_assume!(SecretKey::from_slice(&root).is_ok());
+            let root = SecretKey::from_slice(&root).unwrap();
+
+            let settlement_sk = derive_settlement_key(&root, state);
+            prop_assert_eq!(settlement_sk, derive_settlement_key(&root, state));
+            prop_assert_eq!(
+                settlement_sk.public_key(SECP256K1),
+                derive_settlement_pubkey(&root.public_key(SECP256K1), state)
+            );
+        }
+
+        #[test]
+        fn settlement_keys_do_not_collide(root in any::<[u8; 32]>(), start in any::<u64>()) {
+            prop_assume!(SecretKey::from_slice(&root).is_ok());
+            let root = SecretKey::from_slice(&root).unwrap();
+
+            let settlement_sks: HashSet<_> = (0..64)
+                .map(|offset| start.wrapping_add(offset))
+                .map(|state| derive_settlement_key(&root, state).secret_bytes())
+                .collect();
+            prop_assert_eq!(settlement_sks.len(), 64);
+        }
+
+        #[test]
+        fn settlement_tweak_fallback_terminates(
+            secret in any::<[u8; 32]>(),
+            invalid_count in 0..32u32,
+        ) {
+            prop_assume!(SecretKey::from_slice(&secret).is_ok());
+            let secret = SecretKey::from_slice(&secret).unwrap();
+            let basepoint = secret.public_key(SECP256K1);
+
+            // Out of range candidates come first, then one that tweaks the
+            // basepoint to infinity, and only then a valid one.
+            let tweak = first_valid_tweak(&basepoint, |counter| {
+                match counter.cmp(&invalid_count) {
+                    std::cmp::Ordering::Less => [0xff; 32],
+                    std::cmp::Ordering::Equal => secret.negate().secret_bytes(),
+                    std::cmp::Ordering::Greater => [0x42; 32],
+                }
+            });
+            prop_assert_eq!(tweak, Scalar::from_be_bytes([0x42; 32]).unwrap());
+        }
+    }
+}
@@ -0,0 +1,901 @@
+//! Channel Store
+//!
+//! A file-backed store that keeps a single record per channel, holding only its
+//! latest state. Records are written to a temporary file, synced and renamed over
+//! the previous record, so a crash leaves either the old or the new state on disk.
+//! The progress of a unilateral close is kept next to the channel record, the same way.
+//! A watchtower keeps the channels it watches in a store of its own.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use elements::bitcoin::Amount;
+use elements::encode::serialize_hex;
+use elements::hashes::sha256;
+use elements::schnorr::{Signature, XOnlyPublicKey};
+use elements::secp256k1_zkp::{PublicKey, ecdsa};
+use elements::{Address, AssetId, OutPoint, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+use crate::channel::{Channel, CloseSignatures, Htlc, Party, PendingSplice, Ptlc};
+use crate::close::CloseProgress;
+use crate::error::Error;
+use crate::sign::{FeeInput, SignedUpdate};
+use crate::tower::WatchedChannel;
+use crate::transaction::{IssuedAsset, import_transaction};
+
+/// The version of the on-disk record format.
+const RECORD_VERSION: u32 = 1;
+
+/// The extension of channel record files.
+const RECORD_EXTENSION: &str = "json";
+
+/// The extension of unilateral close progress files.
+const CLOSE_EXTENSION: &str = "close";
+
+/// The extension of watchtower records.
+const WATCHED_EXTENSION: &str = "watched";
+
+/// A directory of channel records.
+#[derive(Clone, Debug)]
+pub(crate) struct ChannelStore {
+    dir: PathBuf,
+}
+
+/// The on-disk representation of a [`Channel`].
+#[derive(Serialize, Deserialize)]
+struct ChannelRecord {
+    version: u32,
+    is_mainnet: bool,
+    setup_txid: String,
+    setup_vout: u32,
+    funding_amount: u64,
+    alice_update_pk: String,
+    bob_update_pk: String,
+    alice_settlement_basepoint: String,
+    bob_settlement_basepoint: String,
+    alice_address: String,
+    bob_address: String,
+    channel_index: u32,
+    key_reference: String,
+    local_party: Party,
+    peer_id: Option<String>,
+    state: u32,
+    alice_balance: u64,
+    bob_balance: u64,
+    #[serde(default)]
+    issued_assets: Vec<IssuedAssetRecord>,
+    #[serde(default)]
+    htlcs: Vec<HtlcRecord>,
+    #[serde(default)]
+    ptlcs: Vec<PtlcRecord>,
+    latest_update: Option<SignedUpdateRecord>,
+    latest_settlement: Option<String>,
+    #[serde(default)]
+    close_sigs: CloseSignaturesRecord,
+    #[serde(default)]
+    pending_splice: Option<PendingSpliceRecord>,
+}
+
+/// The on-disk representation of a [`SignedUpdate`].
+#[derive(Serialize, Deserialize)]
+struct SignedUpdateRecord {
+    state: u32,
+    transaction: String,
+    alice_sig: String,
+    bob_sig: String,
+    #[serde(default)]
+    asset_sigs: Vec<(String, String)>,
+}
+
+/// The on-disk representation of a [`WatchedChannel`].
+#[derive(Serialize, Deserialize)]
+struct WatchedChannelRecord {
+    version: u32,
+    setup_txid: String,
+    setup_vout: u32,
+    alice_update_pk: String,
+    bob_update_pk: String,
+    alice_settlement_basepoint: String,
+    bob_settlement_basepoint: String,
+    latest_update: SignedUpdateRecord,
+    latest_settlement: String,
+    fee_input: FeeInputRecord,
+}
+
+/// The on-disk representation of a [`FeeInput`].
+#[derive(Serialize, Deserialize)]
+struct FeeInputRecord {
+    txid: String,
+    vout: u32,
+    fee_pk: String,
+    signature: String,
+}
+
+/// The on-disk representation of a [`PendingSplice`].
+#[derive(Serialize, Deserialize)]
+struct PendingSpliceRecord {
+    transaction: String,
+    funding_amount: u64,
+    alice_balance: u64,
+    bob_balance: u64,
+    update: SignedUpdateRecord,
+    settlement: String,
+    #[serde(default)]
+    close_sigs: CloseSignaturesRecord,
+}
+
+/// The on-disk representation of [`CloseSignatures`].
+#[derive(Default, Serialize, Deserialize)]
+struct CloseSignaturesRecord {
+    closing_update: Vec<String>,
+}
+
+/// The on-disk representation of an [`IssuedAsset`].
+#[derive(Serialize, Deserialize)]
+struct IssuedAssetRecord {
+    asset: String,
+    setup_txid: String,
+    setup_vout: u32,
+    alice_balance: u64,
+    bob_balance: u64,
+}
+
+/// The on-disk representation of a [`CloseProgress`].
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+enum CloseProgressRecord {
+    UpdateBroadcast { txid: String },
+    UpdateConfirmed { txid: String, height: u32 },
+    SettlementBroadcast { txid: String },
+    Settled { txid: String },
+}
+
+/// The on-disk representation of an [`Htlc`].
+#[derive(Serialize, Deserialize)]
+struct HtlcRecord {
+    offerer: Party,
+    amount: u64,
+    payment_hash: String,
+    cltv_expiry: u32,
+}
+
+/// The on-disk representation of a [`Ptlc`].
+#[derive(Serialize, Deserialize)]
+struct PtlcRecord {
+    offerer: Party,
+    amount: u64,
+    payment_point: String,
+    cltv_expiry: u32,
+}
+
+impl ChannelStore {
+    /// Open the store at `dir`, creating the directory if needed.
+    pub(crate) fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(ChannelStore {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Persist `channel`, replacing its previous record.
+    pub(crate) fn save(&self, channel: &Channel) -> Result<(), Error> {
+        let record = serde_json::to_vec_pretty(&ChannelRecord::from(channel))?;
+
+        self.write(&self.record_path(&channel.id()), &record)
+    }
+
+    /// Persist the newly opened `channel`, failing if a channel with the same
+    /// identifier is stored already.
+    pub(crate) fn create(&self, channel: &Channel) -> Result<(), Error> {
+        let record = serde_json::to_vec_pretty(&ChannelRecord::from(channel))?;
+
+        self.write_new(&self.record_path(&channel.id()), &record)
+            .map_err(|e| match e {
+                Error::Io(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    Error::Store(format!("channel {} is stored already", channel.id()))
+                }
+                e => e,
+            })
+    }
+
+    /// Reload the channel with identifier `channel_id`.
+    pub(crate) fn load(&self, channel_id: &str) -> Result<Channel, Error> {
+        let record = fs::read(self.record_path(channel_id))?;
+        let record: ChannelRecord = serde_json::from_slice(&record)?;
+
+        Channel::try_from(record)
+    }
+
+    /// List the identifiers of all stored channels.
+    pub(crate) fn list(&self) -> Result<Vec<String>, Error> {
+        self.list_extension(RECORD_EXTENSION)
+    }
+
+    /// Persist the channel watched by a watchtower, replacing its previous record.
+    pub(crate) fn save_watched(&self, channel: &WatchedChannel) -> Result<(), Error> {
+        let record = serde_json::to_vec_pretty(&WatchedChannelRecord::from(channel))?;
+
+        self.write(&self.watched_path(&channel.id()), &record)
+    }
+
+    /// Reload the watched channel with identifier `channel_id`, if any.
+    pub(crate) fn load_watched(&self, channel_id: &str) -> Result<Option<WatchedChannel>, Error> {
+        match fs::read(self.watched_path(channel_id)) {
+            Ok(record) => {
+                let record: WatchedChannelRecord = serde_json::from_slice(&record)?;
+                Ok(Some(WatchedChannel::try_from(record)?))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// List the identifiers of all watched channels.
+    pub(crate) fn list_watched(&self) -> Result<Vec<String>, Error> {
+        self.list_extension(WATCHED_EXTENSION)
+    }
+
+    /// Stop watching the channel with identifier `channel_id`.
+    pub(crate) fn remove_watched(&self, channel_id: &str) -> Result<(), Error> {
+        fs::remove_file(self.watched_path(channel_id))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// List the identifiers of the records with `extension`.
+    fn list_extension(&self, extension: &str) -> Result<Vec<String>, Error> {
+        let mut channel_ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == extension)
+                && let Some(stem) = path.file_stem()
+            {
+                channel_ids.push(stem.to_string_lossy().into_owned());
+            }
+        }
+        channel_ids.sort();
+
+        Ok(channel_ids)
+    }
+
+    /// Remove the record of the channel with identifier `channel_id`, once it
+    /// is stored under another identifier.
+    pub(crate) fn remove(&self, channel_id: &str) -> Result<(), Error> {
+        fs::remove_file(self.record_path(channel_id))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Persist the `progress` of the unilateral close of the channel with
+    /// identifier `channel_id`, replacing the previous one.
+    pub(crate) fn save_close_progress(
+        &self,
+        channel_id: &str,
+        progress: &CloseProgress,
+    ) -> Result<(), Error> {
+        let record = serde_json::to_vec_pretty(&CloseProgressRecord::from(progress))?;
+
+        self.write(&self.close_path(channel_id), &record)
+    }
+
+    /// Reload the progress of the unilateral close of the channel with
+    /// identifier `channel_id`, if it was started.
+    pub(crate) fn load_close_progress(
+        &self,
+        channel_id: &str,
+    ) -> Result<Option<CloseProgress>, Error> {
+        let record = match fs::read(self.close_path(channel_id)) {
+            Ok(record) => record,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let record: CloseProgressRecord = serde_json::from_slice(&record)?;
+
+        Ok(Some(CloseProgress::try_from(record)?))
+    }
+
+    /// Atomically replace the file at `path` with `contents`.
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        // Write and sync the new record before it replaces the old one.
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        // Sync the directory so the rename itself survives a crash.
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Write `contents` to `path` like [`Self::write`], unless a file is there
+    /// already. Linking the new record in place fails if one appeared since.
+    fn write_new(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+        let linked = fs::hard_link(&tmp_path, path);
+        fs::remove_file(&tmp_path)?;
+        linked?;
+
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// The path of a channel's record.
+    fn record_path(&self, channel_id: &str) -> PathBuf {
+        self.dir.join(channel_id).with_extension(RECORD_EXTENSION)
+    }
+
+    /// The path of a watched channel's record.
+    fn watched_path(&self, channel_id: &str) -> PathBuf {
+        self.dir.join(channel_id).with_extension(WATCHED_EXTENSION)
+    }
+
+    /// The path of the progress of a channel's unilateral close.
+    fn close_path(&self, channel_id: &str) -> PathBuf {
+        self.dir.join(channel_id).with_extension(CLOSE_EXTENSION)
+    }
+}
+
+impl From<&Channel> for ChannelRecord {
+    fn from(channel: &Channel) -> Self {
+        ChannelRecord {
+            version: RECORD_VERSION,
+            is_mainnet: channel.is_mainnet,
+            setup_txid: channel.setup_outpoint.txid.to_string(),
+            setup_vout: channel.setup_outpoint.vout,
+            funding_amount: channel.funding_amount.to_sat(),
+            alice_update_pk: channel.alice_update_pk.to_string(),
+            bob_update_pk: channel.bob_update_pk.to_string(),
+            alice_settlement_basepoint: channel.alice_settlement_basepoint.to_string(),
+            bob_settlement_basepoint: channel.bob_settlement_basepoint.to_string(),
+            alice_address: channel.alice_address.to_string(),
+            bob_address: channel.bob_address.to_string(),
+            channel_index: channel.channel_index,
+            key_reference: channel.key_reference.clone(),
+            local_party: channel.local_party,
+            peer_id: channel.peer_id.map(|peer_id| peer_id.to_string()),
+            state: channel.state,
+            alice_balance: channel.alice_balance.to_sat(),
+            bob_balance: channel.bob_balance.to_sat(),
+            issued_assets: channel
+                .issued_assets
+                .iter()
+                .map(|issued| IssuedAssetRecord {
+                    asset: issued.asset.to_string(),
+                    setup_txid: issued.setup_outpoint.txid.to_string(),
+                    setup_vout: issued.setup_outpoint.vout,
+                    alice_balance: issued.alice_balance.to_sat(),
+                    bob_balance: issued.bob_balance.to_sat(),
+                })
+                .collect(),
+            htlcs: channel
+                .htlcs
+                .iter()
+                .map(|htlc| HtlcRecord {
+                    offerer: htlc.offerer,
+                    amount: htlc.amount.to_sat(),
+                    payment_hash: htlc.payment_hash.to_string(),
+                    cltv_expiry: htlc.cltv_expiry,
+                })
+                .collect(),
+            ptlcs: channel
+                .ptlcs
+                .iter()
+                .map(|ptlc| PtlcRecord {
+                    offerer: ptlc.offerer,
+                    amount: ptlc.amount.to_sat(),
+                    payment_point: ptlc.payment_point.to_string(),
+                    cltv_expiry: ptlc.cltv_expiry,
+                })
+                .collect(),
+            latest_update: channel.latest_update.as_ref().map(SignedUpdateRecord::from),
+            latest_settlement: channel.latest_settlement.as_ref().map(serialize_hex),
+            close_sigs: CloseSignaturesRecord::from(&channel.close_sigs),
+            pending_splice: channel
+                .pending_splice
+                .as_ref()
+                .map(|splice| PendingSpliceRecord {
+                    transaction: serialize_hex(&splice.transaction),
+                    funding_amount: splice.funding_amount.to_sat(),
+                    alice_balance: splice.alice_balance.to_sat(),
+                    bob_balance: splice.bob_balance.to_sat(),
+                    update: SignedUpdateRecord::from(&splice.update),
+                    settlement: serialize_hex(&splice.settlement),
+                    close_sigs: CloseSignaturesRecord::from(&splice.close_sigs),
+                }),
+        }
+    }
+}
+
+impl From<&WatchedChannel> for WatchedChannelRecord {
+    fn from(channel: &WatchedChannel) -> Self {
+        WatchedChannelRecord {
+            version: RECORD_VERSION,
+            setup_txid: channel.setup_outpoint.txid.to_string(),
+            setup_vout: channel.setup_outpoint.vout,
+            alice_update_pk: channel.alice_update_pk.to_string(),
+            bob_update_pk: channel.bob_update_pk.to_string(),
+            alice_settlement_basepoint: channel.alice_settlement_basepoint.to_string(),
+            bob_settlement_basepoint: channel.bob_settlement_basepoint.to_string(),
+            latest_update: SignedUpdateRecord::from(&channel.latest_update),
+            latest_settlement: serialize_hex(&channel.latest_settlement),
+            fee_input: FeeInputRecord {
+                txid: channel.fee_input.outpoint.txid.to_string(),
+                vout: channel.fee_input.outpoint.vout,
+                fee_pk: channel.fee_input.fee_pk.to_string(),
+                signature: channel.fee_input.signature.to_string(),
+            },
+        }
+    }
+}
+
+impl TryFrom<WatchedChannelRecord> for WatchedChannel {
+    type Error = Error;
+
+    fn try_from(record: WatchedChannelRecord) -> Result<Self, Error> {
+        if record.version != RECORD_VERSION {
+            return Err(Error::Store(format!(
+                "unsupported record version {}",
+                record.version
+            )));
+        }
+
+        Ok(WatchedChannel {
+            setup_outpoint: OutPoint {
+                txid: Txid::from_str(&record.setup_txid)?,
+                vout: record.setup_vout,
+            },
+            alice_update_pk: parse_pubkey(&record.alice_update_pk)?,
+            bob_update_pk: parse_pubkey(&record.bob_update_pk)?,
+            alice_settlement_basepoint: parse_basepoint(&record.alice_settlement_basepoint)?,
+            bob_settlement_basepoint: parse_basepoint(&record.bob_settlement_basepoint)?,
+            latest_update: SignedUpdate::try_from(record.latest_update)?,
+            latest_settlement: parse_transaction(&record.latest_settlement)?,
+            fee_input: FeeInput {
+                outpoint: OutPoint {
+                    txid: Txid::from_str(&record.fee_input.txid)?,
+                    vout: record.fee_input.vout,
+                },
+                fee_pk: parse_fee_pk(&record.fee_input.fee_pk)?,
+                signature: parse_ecdsa_signature(&record.fee_input.signature)?,
+            },
+        })
+    }
+}
+
+impl From<&SignedUpdate> for SignedUpdateRecord {
+    fn from(update: &SignedUpdate) -> Self {
+        SignedUpdateRecord {
+            state: update.state,
+            transaction: serialize_hex(&update.transaction),
+            alice_sig: update.alice_sig.to_string(),
+            bob_sig: update.bob_sig.to_string(),
+            asset_sigs: update
+                .asset_sigs
+                .iter()
+                .map(|(alice_sig, bob_sig)| (alice_sig.to_string(), bob_sig.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<SignedUpdateRecord> for SignedUpdate {
+    type Error = Error;
+
+    fn try_from(record: SignedUpdateRecord) -> Result<Self, Error> {
+        Ok(SignedUpdate {
+            state: record.state,
+            transaction: parse_transaction(&record.transaction)?,
+            alice_sig: parse_signature(&record.alice_sig)?,
+            bob_sig: parse_signature(&record.bob_sig)?,
+            asset_sigs: record
+                .asset_sigs
+                .iter()
+                .map(|(alice_sig, bob_sig)| {
+                    Ok((parse_signature(alice_sig)?, parse_signature(bob_sig)?))
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+        })
+    }
+}
+
+impl From<&CloseSignatures> for CloseSignaturesRecord {
+    fn from(close_sigs: &CloseSignatures) -> Self {
+        CloseSignaturesRecord {
+            closing_update: close_sigs
+                .closing_update
+                .iter()
+                .map(|signature| signature.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<CloseSignaturesRecord> for CloseSignatures {
+    type Error = Error;
+
+    fn try_from(record: CloseSignaturesRecord) -> Result<Self, Error> {
+        Ok(CloseSignatures {
+            closing_update: record
+                .closing_update
+                .iter()
+                .map(|signature| parse_ecdsa_signature(signature))
+                .collect::<Result<Vec<_>, Error>>()?,
+        })
+    }
+}
+
+impl TryFrom<ChannelRecord> for Channel {
+    type Error = Error;
+
+    fn try_from(record: ChannelRecord) -> Result<Self, Error> {
+        if record.version != RECORD_VERSION {
+            return Err(Error::Store(format!(
+                "unsupported record version {}",
+                record.version
+            )));
+        }
+
+        let latest_update = record
+            .latest_update
+            .map(SignedUpdate::try_from)
+            .transpose()?;
+        let issued_assets = record
+            .issued_assets
+            .into_iter()
+            .map(|issued| {
+                Ok(IssuedAsset {
+                    asset: AssetId::from_str(&issued.asset)
+                        .map_err(|e| Error::Store(format!("invalid asset: {}", e)))?,
+                    setup_outpoint: OutPoint {
+                        txid: Txid::from_str(&issued.setup_txid)?,
+                        vout: issued.setup_vout,
+                    },
+                    alice_balance: Amount::from_sat(issued.alice_balance),
+                    bob_balance: Amount::from_sat(issued.bob_balance),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let htlcs = record
+            .htlcs
+            .into_iter()
+            .map(|htlc| {
+                Ok(Htlc {
+                    offerer: htlc.offerer,
+                    amount: Amount::from_sat(htlc.amount),
+                    payment_hash: sha256::Hash::from_str(&htlc.payment_hash)
+                        .map_err(|e| Error::Store(format!("invalid payment hash: {}", e)))?,
+                    cltv_expiry: htlc.cltv_expiry,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let ptlcs = record
+            .ptlcs
+            .into_iter()
+            .map(|ptlc| {
+                Ok(Ptlc {
+                    offerer: ptlc.offerer,
+                    amount: Amount::from_sat(ptlc.amount),
+                    payment_point: PublicKey::from_str(&ptlc.payment_point)
+                        .map_err(|e| Error::Store(format!("invalid payment point: {}", e)))?,
+                    cltv_expiry: ptlc.cltv_expiry,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let latest_settlement = match record.latest_settlement {
+            Some(settlement) => Some(parse_transaction(&settlement)?),
+            None => None,
+        };
+        let pending_splice = match record.pending_splice {
+            Some(splice) => Some(PendingSplice {
+                transaction: parse_transaction(&splice.transaction)?,
+                funding_amount: Amount::from_sat(splice.funding_amount),
+                alice_balance: Amount::from_sat(splice.alice_balance),
+                bob_balance: Amount::from_sat(splice.bob_balance),
+                update: SignedUpdate::try_from(splice.update)?,
+                settlement: parse_transaction(&splice.settlement)?,
+                close_sigs: CloseSignatures::try_from(splice.close_sigs)?,
+            }),
+            None => None,
+        };
+
+        Ok(Channel {
+            is_mainnet: record.is_mainnet,
+            setup_outpoint: OutPoint {
+                txid: Txid::from_str(&record.setup_txid)?,
+                vout: record.setup_vout,
+            },
+            funding_amount: Amount::from_sat(record.funding_amount),
+            alice_update_pk: parse_pubkey(&record.alice_update_pk)?,
+            bob_update_pk: parse_pubkey(&record.bob_update_pk)?,
+            alice_settlement_basepoint: parse_basepoint(&record.alice_settlement_basepoint)?,
+            bob_settlement_basepoint: parse_basepoint(&record.bob_settlement_basepoint)?,
+            alice_address: parse_address(&record.alice_address)?,
+            bob_address: parse_address(&record.bob_address)?,
+            channel_index: record.channel_index,
+            key_reference: record.key_reference,
+            local_party: record.local_party,
+            peer_id: record.peer_id.as_deref().map(parse_node_id).transpose()?,
+            state: record.state,
+            alice_balance: Amount::from_sat(record.alice_balance),
+            bob_balance: Amount::from_sat(record.bob_balance),
+            issued_assets,
+            htlcs,
+            ptlcs,
+            latest_update,
+            latest_settlement,
+            close_sigs: CloseSignatures::try_from(record.close_sigs)?,
+            pending_splice,
+        })
+    }
+}
+
+impl From<&CloseProgress> for CloseProgressRecord {
+    fn from(progress: &CloseProgress) -> Self {
+        match *progress {
+            CloseProgress::UpdateBroadcast { txid } => CloseProgressRecord::UpdateBroadcast {
+                txid: txid.to_string(),
+            },
+            CloseProgress::UpdateConfirmed { txid, height } => {
+                CloseProgressRecord::UpdateConfirmed {
+                    txid: txid.to_string(),
+                    height,
+                }
+            }
+            CloseProgress::SettlementBroadcast { txid } => {
+                CloseProgressRecord::SettlementBroadcast {
+                    txid: txid.to_string(),
+                }
+            }
+            CloseProgress::Settled { txid } => CloseProgressRecord::Settled {
+                txid: txid.to_string(),
+            },
+        }
+    }
+}
+
+impl TryFrom<CloseProgressRecord> for CloseProgress {
+    type Error = Error;
+
+    fn try_from(record: CloseProgressRecord) -> Result<Self, Error> {
+        Ok(match record {
+            CloseProgressRecord::UpdateBroadcast { txid } => CloseProgress::UpdateBroadcast {
+                txid: Txid::from_str(&txid)?,
+            },
+            CloseProgressRecord::UpdateConfirmed { txid, height } => {
+                CloseProgress::UpdateConfirmed {
+                    txid: Txid::from_str(&txid)?,
+                    height,
+                }
+            }
+            CloseProgressRecord::SettlementBroadcast { txid } => {
+                CloseProgress::SettlementBroadcast {
+                    txid: Txid::from_str(&txid)?,
+                }
+            }
+            CloseProgressRecord::Settled { txid } => CloseProgress::Settled {
+                txid: Txid::from_str(&txid)?,
+            },
+        })
+    }
+}
+
+/// Parse a consensus-encoded hex [`Transaction`].
+fn parse_transaction(hex: &str) -> Result<Transaction, Error> {
+    import_transaction(hex)
+}
+
+/// Parse a hex BIP340 [`Signature`].
+fn parse_signature(hex: &str) -> Result<Signature, Error> {
+    Signature::from_str(hex).map_err(|e| Error::Store(format!("invalid signature: {}", e)))
+}
+
+/// Parse a hex DER ECDSA [`ecdsa::Signature`].
+fn parse_ecdsa_signature(hex: &str) -> Result<ecdsa::Signature, Error> {
+    ecdsa::Signature::from_str(hex).map_err(|e| Error::Store(format!("invalid signature: {}", e)))
+}
+
+/// Parse a hex [`XOnlyPublicKey`].
+fn parse_pubkey(hex: &str) -> Result<XOnlyPublicKey, Error> {
+    XOnlyPublicKey::from_str(hex).map_err(|e| Error::Store(format!("invalid public key: {}", e)))
+}
+
+/// Parse a hex compressed settlement basepoint [`PublicKey`].
+fn parse_basepoint(hex: &str) -> Result<PublicKey, Error> {
+    PublicKey::from_str(hex).map_err(|e| Error::Store(format!("invalid basepoint: {}", e)))
+}
+
+/// Parse a hex compressed node [`PublicKey`].
+fn parse_node_id(hex: &str) -> Result<PublicKey, Error> {
+    PublicKey::from_str(hex).map_err(|e| Error::Store(format!("invalid node key: {}", e)))
+}
+
+/// Parse a hex compressed fee [`PublicKey`].
+fn parse_fee_pk(hex: &str) -> Result<PublicKey, Error> {
+    PublicKey::from_str(hex).map_err(|e| Error::Store(format!("invalid fee key: {}", e)))
+}
+
+/// Parse an Elements [`Address`].
+fn parse_address(address: &str) -> Result<Address, Error> {
+    Address::from_str(address).map_err(|e| Error::Store(format!("invalid address: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::hashes::Hash;
+    use elements::secp256k1_zkp::{Message, SECP256K1, SecretKey};
+    use elements::{Address, OutPoint, Txid};
+
+    use crate::transaction::{build_settlement_transaction, build_update_transaction};
+    use crate::{ALICE_RESOLUTION_ADDRESS, sign::sign_update_transaction};
+
+    use super::*;
+
+    #[test]
+    fn save_and_reload_channel() {
+        let dir = std::env::temp_dir().join(format!("simpleltoo-store-{}", std::process::id()));
+        let store = ChannelStore::open(&dir).unwrap();
+
+        let alice_update_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let bob_update_sk = SecretKey::from_slice(&[0xee; 32]).unwrap();
+        let setup_outpoint = OutPoint {
+            txid: Txid::from_str(
+                "000000000000000000001cd7e92aaf365e841cdd39f19360139b7baef188992f",
+            )
+            .unwrap(),
+            vout: 1,
+        };
+        let mut channel = Channel::new(
+            false,
+            setup_outpoint,
+            Amount::from_sat(2140),
+            alice_update_sk.x_only_public_key(SECP256K1).0,
+            bob_update_sk.x_only_public_key(SECP256K1).0,
+            alice_update_sk.public_key(SECP256K1),
+            bob_update_sk.public_key(SECP256K1),
+            Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap(),
+            Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap(),
+            7,
+            "alice.seed".to_string(),
+            Party::Alice,
+        )
+        .unwrap();
+        let asset = AssetId::from_inner(sha256::Midstate([0x11; 32]));
+        channel
+            .add_issued_asset(
+                asset,
+                OutPoint {
+                    vout: 2,
+                    ..setup_outpoint
+                },
+                Amount::from_sat(5000),
+            )
+            .unwrap();
+        store.save(&channel).unwrap();
+        assert_eq!(store.load(&channel.id()).unwrap(), channel);
+        // A channel is only created once.
+        assert!(matches!(store.create(&channel), Err(Error::Store(_))));
+        assert_eq!(store.load(&channel.id()).unwrap(), channel);
+
+        // Advance the channel to state 3, which replaces the stored record.
+        let address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let update = build_update_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &address,
+            3,
+            &channel.issued_assets,
+            false,
+        );
+        let signed_update = sign_update_transaction(
+            &update,
+            Amount::from_sat(2071),
+            &channel.issued_assets,
+            &alice_update_sk,
+            &bob_update_sk,
+            false,
+        )
+        .unwrap();
+        let settlement = build_settlement_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &address,
+            Amount::from_sat(1000),
+            &address,
+            Amount::from_sat(1002),
+            &[],
+            &[],
+            false,
+        );
+        let (alice_balance, bob_balance) = (Amount::from_sat(700), Amount::from_sat(802));
+        let issued_assets = vec![IssuedAsset {
+            alice_balance: Amount::from_sat(3000),
+            bob_balance: Amount::from_sat(2000),
+            ..channel.issued_assets[0]
+        }];
+        let htlc = Htlc {
+            offerer: Party::Alice,
+            amount: Amount::from_sat(300),
+            payment_hash: sha256::Hash::hash(&[0x42; 32]),
+            cltv_expiry: 3_000_000,
+        };
+        let ptlc = Ptlc {
+            offerer: Party::Bob,
+            amount: Amount::from_sat(200),
+            payment_point: bob_update_sk.public_key(SECP256K1),
+            cltv_expiry: 3_000_000,
+        };
+        let close_sigs = CloseSignatures {
+            closing_update: vec![
+                SECP256K1.sign_ecdsa(&Message::from_digest([0x42; 32]), &bob_update_sk),
+            ],
+        };
+        assert!(channel.advance(
+            signed_update.clone(),
+            settlement,
+            close_sigs.clone(),
+            alice_balance,
+            bob_balance,
+            issued_assets.clone(),
+            vec![htlc],
+            vec![ptlc],
+        ));
+        assert!(!channel.advance(
+            signed_update,
+            update,
+            CloseSignatures::default(),
+            alice_balance,
+            bob_balance,
+            Vec::new(),
+            Vec::new(),
+            Vec::new()
+        ));
+        store.save(&channel).unwrap();
+
+        let reloaded = store.load(&channel.id()).unwrap();
+        assert_eq!(reloaded, channel);
+        assert_eq!(reloaded.state, 3);
+        assert_eq!(reloaded.bob_balance, bob_balance);
+        assert_eq!(reloaded.issued_assets, issued_assets);
+        assert_eq!(
+            reloaded.latest_update.map(|update| update.asset_sigs.len()),
+            Some(1)
+        );
+        assert_eq!(reloaded.close_sigs, close_sigs);
+        assert_eq!(reloaded.htlcs, vec![htlc]);
+        assert_eq!(reloaded.ptlcs, vec![ptlc]);
+        assert_eq!(store.list().unwrap(), vec![channel.id()]);
+
+        // A unilateral close resumes from its last step, without showing up as a channel.
+        assert_eq!(store.load_close_progress(&channel.id()).unwrap(), None);
+        let progress = CloseProgress::UpdateConfirmed {
+            txid: setup_outpoint.txid,
+            height: 42,
+        };
+        store.save_close_progress(&channel.id(), &progress).unwrap();
+        assert_eq!(
+            store.load_close_progress(&channel.id()).unwrap(),
+            Some(progress)
+        );
+        assert_eq!(store.list().unwrap(), vec![channel.id()]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
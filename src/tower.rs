@@ -0,0 +1,418 @@
+//! Watchtower
+//!
+//! A watchtower keeps, for each channel it watches, only the latest signed
+//! `Update Transaction` and `Settlement Transaction` handed over by one of the
+//! parties, along with the public keys needed to rebind that update and a fee
+//! input the party signed to pay for it. It checks
+//! the chain of spends from each setup output through Esplora and, when a stale
+//! update confirms, overrides it with the latest one. No secret key is involved,
+//! so a party can leave the tower running while it is offline.
+
+use std::path::Path;
+use std::time::Duration;
+
+use elements::schnorr::XOnlyPublicKey;
+use elements::secp256k1_zkp::PublicKey;
+use elements::{OutPoint, Transaction};
+use simplicityhl::CompiledProgram;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::channel::{Channel, build_commitment_program};
+use crate::error::Error;
+use crate::sign::{FeeInput, SignedUpdate};
+use crate::store::ChannelStore;
+use crate::watcher::{WatchOutcome, override_stale_update};
+
+/// A channel as seen by a watchtower.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct WatchedChannel {
+    /// The setup output the chain of updates hangs off.
+    pub(crate) setup_outpoint: OutPoint,
+    /// Alice's update public key.
+    pub(crate) alice_update_pk: XOnlyPublicKey,
+    /// Bob's update public key.
+    pub(crate) bob_update_pk: XOnlyPublicKey,
+    /// Alice's settlement basepoint.
+    pub(crate) alice_settlement_basepoint: PublicKey,
+    /// Bob's settlement basepoint.
+    pub(crate) bob_settlement_basepoint: PublicKey,
+    /// The latest signed `Update Transaction`.
+    pub(crate) latest_update: SignedUpdate,
+    /// The `Settlement Transaction` for the latest state.
+    pub(crate) latest_settlement: Transaction,
+    /// The fee input paying the fee of the latest update when it overrides a
+    /// stale one.
+    pub(crate) fee_input: FeeInput,
+}
+
+impl WatchedChannel {
+    /// Take the latest signed state of `channel` to hand over to a watchtower,
+    /// with the `fee_input` signed for its latest update.
+    pub(crate) fn from_channel(channel: &Channel, fee_input: FeeInput) -> Result<Self, Error> {
+        let (Some(latest_update), Some(latest_settlement)) =
+            (&channel.latest_update, &channel.latest_settlement)
+        else {
+            return Err(Error::Channel("channel has no signed update".into()));
+        };
+
+        Ok(WatchedChannel {
+            setup_outpoint: channel.setup_outpoint,
+            alice_update_pk: channel.alice_update_pk,
+            bob_update_pk: channel.bob_update_pk,
+            alice_settlement_basepoint: channel.alice_settlement_basepoint,
+            bob_settlement_basepoint: channel.bob_settlement_basepoint,
+            latest_update: latest_update.clone(),
+            latest_settlement: latest_settlement.clone(),
+            fee_input,
+        })
+    }
+
+    /// The channel identifier, derived from the setup [`OutPoint`].
+    pub(crate) fn id(&self) -> String {
+        format!("{}_{}", self.setup_outpoint.txid, self.setup_outpoint.vout)
+    }
+
+    /// The state of the latest signed update.
+    pub(crate) fn state(&self) -> u32 {
+        self.latest_update.state
+    }
+
+    /// The program locking the commitment output of the update for `state`.
+    pub(crate) fn commitment_program(&self, state: u32) -> Result<CompiledProgram, Error> {
+        build_commitment_program(
+            self.alice_update_pk,
+            self.bob_update_pk,
+            &self.alice_settlement_basepoint,
+            &self.bob_settlement_basepoint,
+            state,
+        )
+    }
+}
+
+/// A watchtower, keeping the channels it watches in a store of its own.
+#[derive(Clone, Debug)]
+pub(crate) struct Watchtower {
+    store: ChannelStore,
+}
+
+impl Watchtower {
+    /// Open the watchtower keeping its channels in `dir`.
+    pub(crate) fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Watchtower {
+            store: ChannelStore::open(dir)?,
+        })
+    }
+
+    /// Watch `channel`, replacing the state previously handed over for it.
+    ///
+    /// Only a newer state is accepted, so that a party cannot roll the tower
+    /// back to a state it would rather see published.
+    pub(crate) fn watch(&self, channel: &WatchedChannel) -> Result<(), Error> {
+        if let Some(watched) = self.store.load_watched(&channel.id())?
+            && watched.state() >= channel.state()
+        {
+            return Err(Error::Channel(format!(
+                "already watching state {} of channel {}",
+                watched.state(),
+                channel.id()
+            )));
+        }
+
+        self.store.save_watched(channel)?;
+        info!(
+            "Watching state {} of channel {}",
+            channel.state(),
+            channel.id()
+        );
+
+        Ok(())
+    }
+
+    /// The channels being watched.
+    pub(crate) fn channels(&self) -> Result<Vec<WatchedChannel>, Error> {
+        let mut channels = Vec::new();
+        for channel_id in self.store.list_watched()? {
+            channels.extend(self.store.load_watched(&channel_id)?);
+        }
+
+        Ok(channels)
+    }
+
+    /// Check every watched channel once, overriding the stale updates that
+    /// confirmed, and stop watching the channels that were settled.
+    ///
+    /// A channel that cannot be checked is skipped, and checked again next time.
+    pub(crate) async fn check(&self, url: &str) -> Result<Vec<(String, WatchOutcome)>, Error> {
+        let mut outcomes = Vec::new();
+        for channel in self.channels()? {
+            let channel_id = channel.id();
+            let outcome = match override_stale_update(
+                url,
+                channel.setup_outpoint,
+                &channel.latest_update,
+                &channel.fee_input,
+                |state| channel.commitment_program(state),
+            )
+            .await
+            {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!("Failed to check channel {}: {}", channel_id, e);
+                    continue;
+                }
+            };
+
+            if outcome == WatchOutcome::Settled {
+                info!("Channel {} was settled, no longer watching it", channel_id);
+                self.store.remove_watched(&channel_id)?;
+            }
+            outcomes.push((channel_id, outcome));
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Check every watched channel each `interval`, until the store fails.
+    pub(crate) async fn run(&self, url: &str, interval: Duration) -> Result<(), Error> {
+        loop {
+            for (channel_id, outcome) in self.check(url).await? {
+                info!("Channel {}: {:?}", channel_id, outcome);
+            }
+            sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    use elements::bitcoin::Amount;
+    use elements::bitcoin::hex::FromHex;
+    use elements::encode::{deserialize, serialize, serialize_hex};
+    use elements::hashes::Hash;
+    use elements::{Address, Txid};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::channel::Party;
+    use crate::keys::{ChannelKeys, SEED_LEN};
+    use crate::transaction::lock_time_state;
+
+    use super::*;
+
+    /// The transactions known to the Esplora stand-in, with whether they
+    /// confirmed.
+    type Chain = Arc<Mutex<HashMap<Txid, (Transaction, bool)>>>;
+
+    /// Serve the few Esplora endpoints the watchtower uses from `chain`, adding
+    /// the broadcast transactions to it, unconfirmed.
+    async fn serve_esplora(listener: TcpListener, chain: Chain) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let chain = chain.clone();
+            tokio::spawn(async move { handle_request(stream, chain).await });
+        }
+    }
+
+    async fn handle_request(stream: TcpStream, chain: Chain) {
+        let mut stream = BufReader::new(stream);
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await.unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            stream.read_line(&mut header).await.unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap();
+        let path: Vec<&str> = parts
+            .next()
+            .unwrap()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let (status, response) = {
+            let mut chain = chain.lock().unwrap();
+            match (method, path.as_slice()) {
+                ("POST", ["tx"]) => {
+                    let hex = String::from_utf8(body).unwrap();
+                    let transaction: Transaction =
+                        deserialize(&Vec::<u8>::from_hex(hex.trim()).unwrap()).unwrap();
+                    let txid = transaction.txid();
+                    chain.insert(txid, (transaction, false));
+                    ("200 OK", txid.to_string().into_bytes())
+                }
+                ("GET", ["tx", txid, "outspend", vout]) => {
+                    let outpoint = OutPoint {
+                        txid: Txid::from_str(txid).unwrap(),
+                        vout: vout.parse().unwrap(),
+                    };
+                    let spend = chain.iter().find(|(_, (transaction, _))| {
+                        transaction
+                            .input
+                            .iter()
+                            .any(|input| input.previous_output == outpoint)
+                    });
+                    let response = match spend {
+                        Some((txid, (_, confirmed))) => format!(
+                            r#"{{"spent":true,"txid":"{}","vin":0,"status":{{"confirmed":{}}}}}"#,
+                            txid, confirmed
+                        ),
+                        None => r#"{"spent":false}"#.to_string(),
+                    };
+                    ("200 OK", response.into_bytes())
+                }
+                ("GET", ["tx", txid, format @ ("raw" | "hex")]) => {
+                    match chain.get(&Txid::from_str(txid).unwrap()) {
+                        Some((transaction, _)) if *format == "raw" => {
+                            ("200 OK", serialize(transaction))
+                        }
+                        Some((transaction, _)) => {
+                            ("200 OK", serialize_hex(transaction).into_bytes())
+                        }
+                        None => ("404 Not Found", b"Transaction not found".to_vec()),
+                    }
+                }
+                _ => ("404 Not Found", Vec::new()),
+            }
+        };
+
+        let mut stream = stream.into_inner();
+        let header = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            response.len()
+        );
+        stream.write_all(header.as_bytes()).await.unwrap();
+        stream.write_all(&response).await.unwrap();
+        stream.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn override_stale_update_from_tower() {
+        let address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let alice_keys = ChannelKeys::derive(&[0x0a; SEED_LEN], 0, false).unwrap();
+        let bob_keys = ChannelKeys::derive(&[0x0b; SEED_LEN], 0, false).unwrap();
+        let mut channel = Channel::new(
+            false,
+            OutPoint {
+                txid: Txid::from_byte_array([0x01; 32]),
+                vout: 0,
+            },
+            Amount::from_sat(10_000),
+            alice_keys.update_pk(),
+            bob_keys.update_pk(),
+            alice_keys.settlement_basepoint(),
+            bob_keys.settlement_basepoint(),
+            address.clone(),
+            address,
+            0,
+            String::new(),
+            Party::Alice,
+        )
+        .unwrap();
+
+        // Bob keeps the update for state 1, then both move on to state 2.
+        channel
+            .update(
+                Amount::from_sat(6_000),
+                channel.capacity() - Amount::from_sat(6_000),
+                &alice_keys,
+                &bob_keys,
+            )
+            .unwrap();
+        let stale_update = channel.build_signed_closing_update(&alice_keys).unwrap();
+        channel
+            .update(
+                Amount::from_sat(9_000),
+                channel.capacity() - Amount::from_sat(9_000),
+                &alice_keys,
+                &bob_keys,
+            )
+            .unwrap();
+
+        // Alice hands state 2 over to the tower, along with a fee input paying
+        // for it, and the tower refuses to go back.
+        let dir = std::env::temp_dir().join(format!("simpleltoo-tower-{}", std::process::id()));
+        let tower = Watchtower::open(&dir).unwrap();
+        let fee_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x02; 32]),
+            vout: 1,
+        };
+        let fee_input = channel.sign_fee_input(fee_outpoint, &alice_keys).unwrap();
+        let watched = WatchedChannel::from_channel(&channel, fee_input).unwrap();
+        tower.watch(&watched).unwrap();
+        assert!(tower.watch(&watched).is_err());
+        assert_eq!(tower.channels().unwrap(), vec![watched]);
+
+        let chain = Chain::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(serve_esplora(listener, chain.clone()));
+
+        let outcomes = tower.check(&url).await.unwrap();
+        assert_eq!(outcomes, vec![(channel.id(), WatchOutcome::Unspent)]);
+
+        // Bob publishes state 1 while Alice is away.
+        let stale_txid = stale_update.txid();
+        chain
+            .lock()
+            .unwrap()
+            .insert(stale_txid, (stale_update, false));
+        let outcomes = tower.check(&url).await.unwrap();
+        assert_eq!(
+            outcomes,
+            vec![(channel.id(), WatchOutcome::Pending { stale_state: 1 })]
+        );
+
+        // Once it confirms, the tower overrides it with state 2.
+        chain.lock().unwrap().get_mut(&stale_txid).unwrap().1 = true;
+        let outcomes = tower.check(&url).await.unwrap();
+        let [
+            (
+                _,
+                WatchOutcome::Overridden {
+                    stale_state: 1,
+                    txid,
+                },
+            ),
+        ] = outcomes.as_slice()
+        else {
+            panic!("expected the stale update to be overridden: {:?}", outcomes);
+        };
+        let chain = chain.lock().unwrap();
+        let (rebound, confirmed) = &chain[txid];
+        assert!(!confirmed);
+        assert_eq!(lock_time_state(rebound.lock_time), Some(2));
+        assert_eq!(
+            rebound.input[0].previous_output,
+            OutPoint {
+                txid: stale_txid,
+                vout: 0,
+            }
+        );
+        assert_eq!(rebound.input[1].previous_output, fee_outpoint);
+        assert_eq!(
+            rebound.output[0].value,
+            chain[&stale_txid].0.output[0].value
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,27 @@
 use elements::{
-    Address, AddressParams, AssetId, LockTime, OutPoint, Transaction, TxIn, TxOut, TxOutWitness,
-    bitcoin::Amount, confidential, confidential::Asset, schnorr::XOnlyPublicKey,
+    Address, AddressParams, AssetId, LockTime, OutPoint, Sequence, Transaction, TxIn, TxOut,
+    TxOutWitness, bitcoin::Amount, confidential, confidential::Asset, schnorr::XOnlyPublicKey,
+    secp256k1_zkp::PublicKey,
 };
 
-use crate::script::build_setup_address_program;
-use crate::{FEE_AMOUNT, LBTC_TEST_MIDSTATE};
+use elements::bitcoin::hex::{DisplayHex, FromHex};
+use elements::encode::{deserialize, serialize_hex};
+use serde::Serialize;
+use simplicityhl::CompiledProgram;
+
+use crate::error::Error;
+#[cfg(test)]
+use crate::keys::ensure_all_distinct_keys;
+use crate::keys::ensure_distinct_keys;
+use crate::script::{CommitmentPath, build_commitment_witness, build_setup_address_program};
+#[cfg(test)]
+use crate::script::{
+    build_multiparty_commitment_witness, build_multiparty_setup_program, setup_public_key,
+};
+#[cfg(test)]
+use crate::sign::MultipartySignedUpdate;
+use crate::sign::{FeeInput, SignedUpdate};
+use crate::{CSV_DELAY, FEE_AMOUNT, LBTC_TEST_MIDSTATE, MAX_STATE, STATE_LOCK_TIME_OFFSET};
 
 /// Build a `Setup Address`. It is a 2-of-2 multisig created between
 /// both parties Settlement (A_s, B_s) or Update (A_u, B_u) keys
@@ -12,56 +29,924 @@ use crate::{FEE_AMOUNT, LBTC_TEST_MIDSTATE};
 ///
 /// The channel's initial balance is reflected in the transaction
 /// that funds this address.
+///
+/// Fails if both keys are the same, since the 2-of-2 would then be a 1-of-1.
 pub(crate) fn build_setup_address(
     alice_update_pk: XOnlyPublicKey,
     bob_update_pk: XOnlyPublicKey,
     is_mainnet: bool,
-) -> Address {
+) -> Result<Address, Error> {
+    ensure_distinct_keys(&alice_update_pk, &bob_update_pk)?;
+
     // The setup script is just a 2-of-2 between Alice and Bob.
     let setup_witness_program = build_setup_address_program(&alice_update_pk, &bob_update_pk);
 
-    match is_mainnet {
+    Ok(match is_mainnet {
         true => Address::p2wsh(&setup_witness_program, None, &AddressParams::LIQUID),
         false => Address::p2wsh(&setup_witness_program, None, &AddressParams::LIQUID_TESTNET),
+    })
+}
+
+/// Build the `Setup Address` of a multi-party channel: an n-of-n multisig
+/// between every participant's update key.
+///
+/// Fails if two participants share a key.
+#[cfg(test)]
+pub(crate) fn build_multiparty_setup_address(
+    update_pks: &[XOnlyPublicKey],
+    is_mainnet: bool,
+) -> Result<Address, Error> {
+    ensure_all_distinct_keys(update_pks)?;
+
+    let setup_pks: Vec<_> = update_pks.iter().map(setup_public_key).collect();
+    let setup_witness_program = build_multiparty_setup_program(&setup_pks);
+
+    Ok(match is_mainnet {
+        true => Address::p2wsh(&setup_witness_program, None, &AddressParams::LIQUID),
+        false => Address::p2wsh(&setup_witness_program, None, &AddressParams::LIQUID_TESTNET),
+    })
+}
+
+/// Build the fee address of one party: a P2WPKH of its fee key.
+///
+/// Its outputs of exactly [`FEE_AMOUNT`] pay the fee of the `Update Transaction`s
+/// that override a stale one.
+pub(crate) fn build_fee_address(fee_pk: PublicKey, is_mainnet: bool) -> Address {
+    let fee_pk = elements::bitcoin::PublicKey::new(fee_pk);
+
+    match is_mainnet {
+        true => Address::p2wpkh(&fee_pk, None, &AddressParams::LIQUID),
+        false => Address::p2wpkh(&fee_pk, None, &AddressParams::LIQUID_TESTNET),
+    }
+}
+
+/// An issued asset carried by a channel next to its L-BTC, which pays every fee.
+///
+/// The asset has its own output wherever the channel's L-BTC has one: at the
+/// `Setup Address`, and right after the L-BTC output of each commitment. As it
+/// never pays fees, each of these outputs holds the sum of both balances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct IssuedAsset {
+    /// The issued asset.
+    pub(crate) asset: AssetId,
+    /// The output holding the asset at the `Setup Address`.
+    pub(crate) setup_outpoint: OutPoint,
+    /// Alice's balance of the asset.
+    pub(crate) alice_balance: Amount,
+    /// Bob's balance of the asset.
+    pub(crate) bob_balance: Amount,
+}
+
+impl IssuedAsset {
+    /// The amount of the asset held by the channel.
+    pub(crate) fn amount(&self) -> Amount {
+        self.alice_balance + self.bob_balance
+    }
+}
+
+/// One party's share of a dual-funded `Setup Address`: the `amount` it locks in
+/// the channel, the L-BTC `inputs` paying for it, and where its change goes.
+/// Whatever the inputs hold beyond the amount and the change goes to the fee
+/// of the funding transaction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct FundingContribution {
+    /// The amount locked in the `Setup Address`.
+    pub(crate) amount: Amount,
+    /// The spent outputs, with their explicit L-BTC amounts.
+    pub(crate) inputs: Vec<(OutPoint, Amount)>,
+    /// The change output, if any.
+    pub(crate) change: Option<(Address, Amount)>,
+}
+
+impl FundingContribution {
+    /// Contribute `amount` from `inputs`, paying `fee` towards the funding
+    /// transaction and the rest back to `change_address`.
+    pub(crate) fn new(
+        amount: Amount,
+        inputs: Vec<(OutPoint, Amount)>,
+        change_address: Option<Address>,
+        fee: Amount,
+    ) -> Result<Self, Error> {
+        let input_amount: Amount = inputs.iter().map(|(_, amount)| *amount).sum();
+        let change_amount = input_amount
+            .checked_sub(amount + fee)
+            .ok_or_else(|| Error::Channel("inputs do not cover the contribution".into()))?;
+
+        let change = match change_amount {
+            Amount::ZERO => None,
+            change_amount => {
+                let address = change_address.ok_or_else(|| {
+                    Error::Channel(format!("no change address for {} of change", change_amount))
+                })?;
+                Some((address, change_amount))
+            }
+        };
+
+        Ok(FundingContribution {
+            amount,
+            inputs,
+            change,
+        })
+    }
+
+    /// What the contribution pays towards the fee of the funding transaction.
+    pub(crate) fn fee(&self) -> Result<Amount, Error> {
+        let input_amount: Amount = self.inputs.iter().map(|(_, amount)| *amount).sum();
+        let change_amount = self
+            .change
+            .as_ref()
+            .map_or(Amount::ZERO, |(_, amount)| *amount);
+
+        input_amount
+            .checked_sub(self.amount + change_amount)
+            .ok_or_else(|| Error::Channel("inputs do not cover the contribution".into()))
     }
 }
 
-/// Build the unsigned `Setup Withdrawal Transaction`. This transaction spends
-/// from the initial 2-of-2 and pays out back to Alice, and is signed
-/// by Bob **before** Alice funds the 2-of2 and broadcasts it. This
-/// guarantees that Alice can recover her funds if Bob becomes uncooperative.
+/// Build the unsigned funding transaction of a dual-funded channel, which pays
+/// both contributions into the `Setup Address` at output 0.
 ///
-/// The `funding_prevout` and `amount` comes from the unbroadcast funding transaction to the 2-of-2.
-pub(crate) fn build_setup_withdrawal_transaction(
-    funding_prevout: OutPoint,
+/// Alice's inputs come before Bob's, and are followed by their change outputs
+/// in the same order and the fee. Its [`elements::Txid`] does not commit to the
+/// witnesses, so the setup output is known before either party signs its
+/// inputs, which must therefore be segwit.
+pub(crate) fn build_funding_transaction(
+    setup_address: &Address,
+    alice: &FundingContribution,
+    bob: &FundingContribution,
+    is_mainnet: bool,
+) -> Result<Transaction, Error> {
+    let asset_id = policy_asset(is_mainnet);
+    let fee_amount = alice.fee()? + bob.fee()?;
+    if fee_amount < Amount::from_sat(FEE_AMOUNT) {
+        return Err(Error::Channel(format!(
+            "funding transaction fee of {} is below {} sats",
+            fee_amount, FEE_AMOUNT
+        )));
+    }
+
+    let mut input: Vec<TxIn> = Vec::new();
+    for (previous_output, _) in alice.inputs.iter().chain(&bob.inputs) {
+        if input.iter().any(|i| i.previous_output == *previous_output) {
+            return Err(Error::Channel(format!(
+                "input {} is spent twice",
+                previous_output
+            )));
+        }
+        input.push(TxIn {
+            previous_output: *previous_output,
+            ..Default::default()
+        });
+    }
+
+    let mut output = vec![explicit_output(
+        setup_address,
+        asset_id,
+        alice.amount + bob.amount,
+    )];
+    output.extend(
+        [&alice.change, &bob.change]
+            .into_iter()
+            .flatten()
+            .map(|(address, amount)| explicit_output(address, asset_id, *amount)),
+    );
+    output.push(TxOut::new_fee(fee_amount.to_sat(), asset_id));
+
+    Ok(Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input,
+        output,
+    })
+}
+
+/// The funds one party adds to, and takes out of, an open channel in a splice.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SpliceContribution {
+    /// The funds added from the party's own inputs.
+    pub(crate) splice_in: FundingContribution,
+    /// The output paid out of the party's balance.
+    pub(crate) splice_out: Option<(Address, Amount)>,
+}
+
+impl SpliceContribution {
+    /// The amount the party takes out of the channel.
+    pub(crate) fn splice_out_amount(&self) -> Amount {
+        self.splice_out
+            .as_ref()
+            .map_or(Amount::ZERO, |(_, amount)| *amount)
+    }
+}
+
+/// Build the unsigned `Splice Transaction`, which spends the setup output at
+/// `setup_outpoint` of `funding_amount` into a new setup output at
+/// `setup_address`, adding each party's splice-in and paying out its
+/// splice-out.
+///
+/// Input 0 spends the old setup output and the new one is output 0, followed
+/// by the splice-out and then the change outputs, Alice's before Bob's, and the
+/// fee. The channel pays [`FEE_AMOUNT`] of it, and the parties inputs whatever
+/// they hold beyond their splice-in and change.
+pub(crate) fn build_splice_transaction(
+    setup_outpoint: OutPoint,
     funding_amount: Amount,
-    alice_resolution_address: Address,
+    setup_address: &Address,
+    alice: &SpliceContribution,
+    bob: &SpliceContribution,
+    is_mainnet: bool,
+) -> Result<Transaction, Error> {
+    let asset_id = policy_asset(is_mainnet);
+    let fee_amount = Amount::from_sat(FEE_AMOUNT) + alice.splice_in.fee()? + bob.splice_in.fee()?;
+    let new_funding_amount = (funding_amount + alice.splice_in.amount + bob.splice_in.amount)
+        .checked_sub(
+            alice.splice_out_amount() + bob.splice_out_amount() + Amount::from_sat(FEE_AMOUNT),
+        )
+        .ok_or_else(|| Error::Channel("splice-out exceeds the funding amount".into()))?;
+
+    let mut input = vec![TxIn {
+        previous_output: setup_outpoint,
+        sequence: Sequence::MAX,
+        ..Default::default()
+    }];
+    for (previous_output, _) in alice.splice_in.inputs.iter().chain(&bob.splice_in.inputs) {
+        if input.iter().any(|i| i.previous_output == *previous_output) {
+            return Err(Error::Channel(format!(
+                "input {} is spent twice",
+                previous_output
+            )));
+        }
+        input.push(TxIn {
+            previous_output: *previous_output,
+            ..Default::default()
+        });
+    }
+
+    let mut output = vec![explicit_output(setup_address, asset_id, new_funding_amount)];
+    output.extend(
+        [
+            &alice.splice_out,
+            &bob.splice_out,
+            &alice.splice_in.change,
+            &bob.splice_in.change,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(address, amount)| explicit_output(address, asset_id, *amount)),
+    );
+    output.push(TxOut::new_fee(fee_amount.to_sat(), asset_id));
+
+    Ok(Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input,
+        output,
+    })
+}
+
+/// Build the unsigned `Update Transaction` for `state`.
+///
+/// It spends the commitment outputs starting at `prevout`, whose L-BTC output
+/// holds `commitment_amount`, and pays that same amount into the commitment
+/// address of `state`. The fee is paid by a separate input of exactly
+/// [`FEE_AMOUNT`], the last one, bound to a fee output when the update is
+/// broadcast. Every commitment output of the channel thus holds the same amount,
+/// so the update can be rebound onto any of them, however many stale updates
+/// were published before. The state number is encoded in the `lock_time` by
+/// [`state_lock_time`], which the previous commitment program checks against
+/// its own state number.
+///
+/// Each of the `issued_assets` is carried over unchanged, from the commitment
+/// output that follows `prevout` to the one that follows the new L-BTC output.
+pub(crate) fn build_update_transaction(
+    prevout: OutPoint,
+    commitment_amount: Amount,
+    commitment_address: &Address,
+    state: u32,
+    issued_assets: &[IssuedAsset],
     is_mainnet: bool,
 ) -> Transaction {
-    let asset = match is_mainnet {
-        true => Asset::Explicit(AssetId::LIQUID_BTC),
-        false => Asset::Explicit(AssetId::from_inner(LBTC_TEST_MIDSTATE)),
-    };
+    let asset_id = policy_asset(is_mainnet);
+
+    let mut output: Vec<TxOut> = std::iter::once(explicit_output(
+        commitment_address,
+        asset_id,
+        commitment_amount,
+    ))
+    .chain(
+        issued_assets
+            .iter()
+            .map(|issued| explicit_output(commitment_address, issued.asset, issued.amount())),
+    )
+    .collect();
+    output.push(TxOut::new_fee(FEE_AMOUNT, asset_id));
+
+    // A non-final sequence, so the `lock_time` is enforced.
+    let sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+    let mut input = commitment_inputs(prevout, issued_assets, sequence);
+    input.push(TxIn {
+        sequence,
+        ..Default::default()
+    });
+
+    Transaction {
+        version: 2,
+        lock_time: state_lock_time(state),
+        input,
+        output,
+    }
+}
+
+/// Build the unsigned `Update Transaction` for `state` that spends the setup
+/// output at `setup_outpoint` of `funding_amount`, and the setup outputs of
+/// `issued_assets`.
+///
+/// The setup output pays the fee itself, so the update has no fee input and
+/// its commitment output holds the same amount as that of any other update.
+pub(crate) fn build_setup_spending_update_transaction(
+    setup_outpoint: OutPoint,
+    funding_amount: Amount,
+    commitment_address: &Address,
+    state: u32,
+    issued_assets: &[IssuedAsset],
+    is_mainnet: bool,
+) -> Transaction {
+    let mut update = build_update_transaction(
+        setup_outpoint,
+        funding_amount - Amount::from_sat(FEE_AMOUNT),
+        commitment_address,
+        state,
+        issued_assets,
+        is_mainnet,
+    );
+    update.input.pop();
+    for (input, issued) in update.input[1..].iter_mut().zip(issued_assets) {
+        input.previous_output = issued.setup_outpoint;
+    }
+
+    update
+}
+
+/// Encode `state` as the `lock_time` of its `Update Transaction`.
+///
+/// States are offset by [`STATE_LOCK_TIME_OFFSET`] into the range of past
+/// timestamps, so the `lock_time` never delays the update.
+///
+/// # Panics
+///
+/// If `state` is above [`MAX_STATE`].
+pub(crate) fn state_lock_time(state: u32) -> LockTime {
+    assert!(state <= MAX_STATE, "state number {state} out of range");
+
+    LockTime::from_consensus(STATE_LOCK_TIME_OFFSET + state)
+}
+
+/// Check that `state` fits in the `lock_time` of its `Update Transaction`.
+pub(crate) fn check_state(state: u32) -> Result<(), Error> {
+    if state > MAX_STATE {
+        return Err(Error::Channel(format!(
+            "state {state} is above the maximum state {MAX_STATE}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Decode the state number encoded by [`state_lock_time`].
+///
+/// Block heights, including a `lock_time` of zero, and timestamps past the one
+/// of [`MAX_STATE`] carry no state.
+pub(crate) fn lock_time_state(lock_time: LockTime) -> Option<u32> {
+    lock_time
+        .to_consensus_u32()
+        .checked_sub(STATE_LOCK_TIME_OFFSET)
+        .filter(|state| *state <= MAX_STATE)
+}
+
+/// The `sequence` of the inputs of a `Settlement Transaction`.
+///
+/// A BIP68 relative lock of [`CSV_DELAY`] blocks: the disable flag and the
+/// type flag are both clear, so consensus only lets the settlement confirm
+/// [`CSV_DELAY`] blocks after the commitment output it spends.
+pub(crate) fn settlement_sequence() -> Sequence {
+    Sequence::from_height(CSV_DELAY)
+}
+
+/// Build the unsigned `Settlement Transaction` for the latest state.
+///
+/// It spends the commitment output of `input_amount` through the settlement
+/// path and pays each party their balance, followed by one output per pending
+/// HTLC and PTLC in `payment_outputs`. Whatever `input_amount` is left after the
+/// balances and payments goes to the fee. The input's `sequence` carries the CSV delay
+/// checked by the commitment program.
+///
+/// The commitment outputs of the `issued_assets` follow `commitment_prevout`
+/// and are spent by the next inputs. Each party's balance of every asset is paid
+/// right after the L-BTC balances, skipping empty ones.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_settlement_transaction(
+    commitment_prevout: OutPoint,
+    input_amount: Amount,
+    alice_address: &Address,
+    alice_balance: Amount,
+    bob_address: &Address,
+    bob_balance: Amount,
+    issued_assets: &[IssuedAsset],
+    payment_outputs: &[(Address, Amount)],
+    is_mainnet: bool,
+) -> Transaction {
+    let asset_id = policy_asset(is_mainnet);
+    let payment_amount: u64 = payment_outputs
+        .iter()
+        .map(|(_, amount)| amount.to_sat())
+        .sum();
+    let fee_amount =
+        input_amount.to_sat() - alice_balance.to_sat() - bob_balance.to_sat() - payment_amount;
+
+    let mut output = balance_outputs(
+        alice_address,
+        alice_balance,
+        bob_address,
+        bob_balance,
+        issued_assets,
+        is_mainnet,
+    );
+    output.extend(
+        payment_outputs
+            .iter()
+            .map(|(address, amount)| explicit_output(address, asset_id, *amount)),
+    );
+    output.push(TxOut::new_fee(fee_amount, asset_id));
+
+    Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: commitment_inputs(commitment_prevout, issued_assets, settlement_sequence()),
+        output,
+    }
+}
 
-    let funding_amount = funding_amount.to_sat();
+/// Build the unsigned `Settlement Transaction` for the latest state of a
+/// multi-party channel.
+///
+/// It spends the commitment output of `input_amount` through the settlement
+/// path and pays `outputs`, at the same positions, then each participant their
+/// balance, in participant order, skipping empty ones. Whatever `input_amount`
+/// is left after the outputs and balances goes to the fee.
+#[cfg(test)]
+pub(crate) fn build_multiparty_settlement_transaction(
+    commitment_prevout: OutPoint,
+    input_amount: Amount,
+    outputs: &[(Address, Amount)],
+    balances: &[(Address, Amount)],
+    is_mainnet: bool,
+) -> Transaction {
+    let asset_id = policy_asset(is_mainnet);
+    let paid_amount: u64 = outputs
+        .iter()
+        .chain(balances)
+        .map(|(_, amount)| amount.to_sat())
+        .sum();
+    let fee_amount = input_amount.to_sat() - paid_amount;
+
+    let mut output: Vec<TxOut> = outputs
+        .iter()
+        .chain(
+            balances
+                .iter()
+                .filter(|(_, balance)| *balance > Amount::ZERO),
+        )
+        .map(|(address, amount)| explicit_output(address, asset_id, *amount))
+        .collect();
+    output.push(TxOut::new_fee(fee_amount, asset_id));
 
     Transaction {
         version: 2,
         lock_time: LockTime::ZERO,
+        input: commitment_inputs(commitment_prevout, &[], settlement_sequence()),
+        output,
+    }
+}
+
+/// Build the unsigned `Cooperative Close Transaction`, which spends the setup
+/// output of `funding_amount` straight to both parties final balances.
+///
+/// Both parties sign it with their update keys, like the closing `Update
+/// Transaction`, so it needs neither an update nor a settlement and has no
+/// timelock. Whatever `funding_amount` is left after the balances goes to the
+/// fee. Each of the `issued_assets` is spent from its own setup output and paid
+/// out like in a settlement.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_cooperative_close_transaction(
+    setup_outpoint: OutPoint,
+    funding_amount: Amount,
+    alice_address: &Address,
+    alice_balance: Amount,
+    bob_address: &Address,
+    bob_balance: Amount,
+    issued_assets: &[IssuedAsset],
+    is_mainnet: bool,
+) -> Transaction {
+    let fee_amount = funding_amount.to_sat() - alice_balance.to_sat() - bob_balance.to_sat();
+
+    let input = std::iter::once(setup_outpoint)
+        .chain(issued_assets.iter().map(|issued| issued.setup_outpoint))
+        .map(|previous_output| TxIn {
+            previous_output,
+            sequence: Sequence::MAX,
+            ..Default::default()
+        })
+        .collect();
+    let mut output = balance_outputs(
+        alice_address,
+        alice_balance,
+        bob_address,
+        bob_balance,
+        issued_assets,
+        is_mainnet,
+    );
+    output.push(TxOut::new_fee(fee_amount, policy_asset(is_mainnet)));
+
+    Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input,
+        output,
+    }
+}
+
+/// Build the outputs paying each party their L-BTC balance, followed by their
+/// balance of each of the `issued_assets`. Empty balances get no output.
+fn balance_outputs(
+    alice_address: &Address,
+    alice_balance: Amount,
+    bob_address: &Address,
+    bob_balance: Amount,
+    issued_assets: &[IssuedAsset],
+    is_mainnet: bool,
+) -> Vec<TxOut> {
+    let asset_id = policy_asset(is_mainnet);
+    let balances = [
+        (alice_address, asset_id, alice_balance),
+        (bob_address, asset_id, bob_balance),
+    ];
+    let asset_balances = issued_assets.iter().flat_map(|issued| {
+        [
+            (alice_address, issued.asset, issued.alice_balance),
+            (bob_address, issued.asset, issued.bob_balance),
+        ]
+    });
+
+    balances
+        .into_iter()
+        .chain(asset_balances)
+        .filter(|(_, _, balance)| *balance > Amount::ZERO)
+        .map(|(address, asset, balance)| explicit_output(address, asset, balance))
+        .collect()
+}
+
+/// Build the unsigned `HTLC Success Transaction`, which spends the HTLC output
+/// at `htlc_prevout` of `htlc_amount` with the payment preimage and pays the
+/// receiver at `receiver_address`.
+///
+/// PTLC outputs are claimed by the same transaction, with the completed
+/// adaptor signature of the offerer instead of the preimage.
+#[cfg(test)]
+pub(crate) fn build_htlc_success_transaction(
+    htlc_prevout: OutPoint,
+    htlc_amount: Amount,
+    receiver_address: &Address,
+    is_mainnet: bool,
+) -> Transaction {
+    build_htlc_spend_transaction(
+        htlc_prevout,
+        htlc_amount,
+        receiver_address,
+        LockTime::ZERO,
+        Sequence::MAX,
+        is_mainnet,
+    )
+}
+
+/// Build the unsigned `HTLC Timeout Transaction`, which refunds the HTLC output
+/// at `htlc_prevout` of `htlc_amount` to the offerer at `offerer_address`.
+///
+/// The `lock_time` is set to the `cltv_expiry` height checked by the HTLC
+/// program, so it cannot confirm before the HTLC expires. PTLC outputs are
+/// refunded by the same transaction.
+#[cfg(test)]
+pub(crate) fn build_htlc_timeout_transaction(
+    htlc_prevout: OutPoint,
+    htlc_amount: Amount,
+    offerer_address: &Address,
+    cltv_expiry: u32,
+    is_mainnet: bool,
+) -> Transaction {
+    build_htlc_spend_transaction(
+        htlc_prevout,
+        htlc_amount,
+        offerer_address,
+        LockTime::from_consensus(cltv_expiry),
+        // A non-final sequence, so the `lock_time` is enforced.
+        Sequence::ENABLE_LOCKTIME_NO_RBF,
+        is_mainnet,
+    )
+}
+
+/// Build a transaction that sweeps the HTLC output at `htlc_prevout` of
+/// `htlc_amount`, minus the fee, to `address`.
+#[cfg(test)]
+fn build_htlc_spend_transaction(
+    htlc_prevout: OutPoint,
+    htlc_amount: Amount,
+    address: &Address,
+    lock_time: LockTime,
+    sequence: Sequence,
+    is_mainnet: bool,
+) -> Transaction {
+    let asset_id = policy_asset(is_mainnet);
+
+    Transaction {
+        version: 2,
+        lock_time,
         input: vec![TxIn {
-            previous_output: funding_prevout,
+            previous_output: htlc_prevout,
+            sequence,
             ..Default::default()
         }],
-        output: vec![TxOut {
-            asset,
-            value: confidential::Value::Explicit(funding_amount - FEE_AMOUNT),
-            nonce: confidential::Nonce::Null,
-            script_pubkey: alice_resolution_address.script_pubkey(),
-            witness: TxOutWitness {
-                surjection_proof: None,
-                rangeproof: None,
+        output: vec![
+            TxOut {
+                asset: Asset::Explicit(asset_id),
+                value: confidential::Value::Explicit(htlc_amount.to_sat() - FEE_AMOUNT),
+                nonce: confidential::Nonce::Null,
+                script_pubkey: address.script_pubkey(),
+                witness: TxOutWitness {
+                    surjection_proof: None,
+                    rangeproof: None,
+                },
             },
-        }],
+            TxOut::new_fee(FEE_AMOUNT, asset_id),
+        ],
+    }
+}
+
+/// Bind a [`SignedUpdate`] to the commitment outputs of the `stale` `Update
+/// Transaction`, which are locked by `spent_program`, and to the `fee_input`
+/// that pays its fee, returning a fully signed `Update Transaction`.
+///
+/// Update signatures commit to neither the outpoint nor the script of the
+/// output they spend, so the same signatures are valid against any earlier
+/// commitment output of the channel, which all hold the same amount. Only the
+/// inputs and the program revealed in the witnesses change. Inputs carrying
+/// issued assets are rebound to the commitment outputs of the assets.
+///
+/// Fails if the commitment outputs of `stale` do not hold the amounts the
+/// update is signed for.
+pub(crate) fn rebind_update_transaction(
+    update: &SignedUpdate,
+    stale: &Transaction,
+    spent_program: &CompiledProgram,
+    fee_input: &FeeInput,
+) -> Result<Transaction, Error> {
+    check_commitment_amounts(&update.transaction, stale)?;
+
+    let mut rebound = update.transaction.clone();
+    bind_commitment_inputs(
+        &mut rebound,
+        OutPoint {
+            txid: stale.txid(),
+            vout: 0,
+        },
+    );
+
+    let signatures = std::iter::once((&update.alice_sig, &update.bob_sig))
+        .chain(update.asset_sigs.iter().map(|(alice, bob)| (alice, bob)));
+    for (input, (alice_sig, bob_sig)) in rebound.input.iter_mut().zip(signatures) {
+        input.witness.script_witness =
+            build_commitment_witness(spent_program, CommitmentPath::Update, alice_sig, bob_sig)?;
+    }
+    bind_fee_input(&mut rebound, fee_input);
+
+    Ok(rebound)
+}
+
+/// Check that the commitment outputs of the `stale` `Update Transaction` hold
+/// the amounts the commitment inputs of `update` were signed for, which are
+/// those of its own commitment outputs.
+fn check_commitment_amounts(update: &Transaction, stale: &Transaction) -> Result<(), Error> {
+    let commitment_inputs = update.input.len() - 1;
+    for (index, output) in update.output.iter().take(commitment_inputs).enumerate() {
+        let signed_amount = output.value.explicit();
+        let spent_amount = stale
+            .output
+            .get(index)
+            .and_then(|spent| spent.value.explicit());
+        let (Some(signed_amount), Some(spent_amount)) = (signed_amount, spent_amount) else {
+            return Err(Error::Channel(format!(
+                "commitment output {} of {} is missing or not explicit",
+                index,
+                stale.txid()
+            )));
+        };
+        if signed_amount != spent_amount {
+            return Err(Error::Channel(format!(
+                "commitment output {} of {} holds {}, but the update is signed for {}",
+                index,
+                stale.txid(),
+                spent_amount,
+                signed_amount
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Bind a [`MultipartySignedUpdate`] to the commitment output at `prevout`,
+/// which is locked by `spent_program`, and to the `fee_input` that pays its
+/// fee, returning a fully signed `Update Transaction`.
+#[cfg(test)]
+pub(crate) fn rebind_multiparty_update_transaction(
+    update: &MultipartySignedUpdate,
+    prevout: OutPoint,
+    spent_program: &CompiledProgram,
+    fee_input: &FeeInput,
+) -> Result<Transaction, Error> {
+    let mut rebound = update.transaction.clone();
+    bind_commitment_inputs(&mut rebound, prevout);
+    rebound.input[0].witness.script_witness = build_multiparty_commitment_witness(
+        spent_program,
+        CommitmentPath::Update,
+        &update.signatures,
+    )?;
+    bind_fee_input(&mut rebound, fee_input);
+
+    Ok(rebound)
+}
+
+/// Point the last input of an `Update Transaction` at the fee output of
+/// `fee_input`, and push its signature into the witness.
+fn bind_fee_input(update: &mut Transaction, fee_input: &FeeInput) {
+    if let Some(input) = update.input.last_mut() {
+        input.previous_output = fee_input.outpoint;
+        input.witness.script_witness = fee_input.witness();
+    }
+}
+
+/// Point the inputs of a transaction spending a commitment at the commitment
+/// outputs starting at `prevout`: the L-BTC output first, then one output per
+/// issued asset.
+pub(crate) fn bind_commitment_inputs(transaction: &mut Transaction, prevout: OutPoint) {
+    for (index, input) in transaction.input.iter_mut().enumerate() {
+        input.previous_output = OutPoint {
+            txid: prevout.txid,
+            vout: prevout.vout + index as u32,
+        };
+    }
+}
+
+/// Build the inputs spending the commitment outputs starting at `prevout`.
+fn commitment_inputs(
+    prevout: OutPoint,
+    issued_assets: &[IssuedAsset],
+    sequence: Sequence,
+) -> Vec<TxIn> {
+    (0..=issued_assets.len() as u32)
+        .map(|index| TxIn {
+            previous_output: OutPoint {
+                txid: prevout.txid,
+                vout: prevout.vout + index,
+            },
+            sequence,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Build an unblinded output paying `amount` of `asset` to `address`.
+fn explicit_output(address: &Address, asset: AssetId, amount: Amount) -> TxOut {
+    TxOut {
+        asset: Asset::Explicit(asset),
+        value: confidential::Value::Explicit(amount.to_sat()),
+        nonce: confidential::Nonce::Null,
+        script_pubkey: address.script_pubkey(),
+        witness: TxOutWitness {
+            surjection_proof: None,
+            rangeproof: None,
+        },
+    }
+}
+
+/// Export a [`Transaction`] as consensus hex.
+pub(crate) fn export_transaction(transaction: &Transaction) -> String {
+    serialize_hex(transaction)
+}
+
+/// Import a [`Transaction`] from consensus hex.
+///
+/// Fails unless the hex decodes to exactly one transaction.
+pub(crate) fn import_transaction(hex: &str) -> Result<Transaction, Error> {
+    Ok(deserialize(&Vec::<u8>::from_hex(hex.trim())?)?)
+}
+
+/// A decoded, human-readable summary of a [`Transaction`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct TransactionSummary {
+    pub(crate) txid: String,
+    pub(crate) version: u32,
+    pub(crate) lock_time: u32,
+    pub(crate) inputs: Vec<InputSummary>,
+    pub(crate) outputs: Vec<OutputSummary>,
+}
+
+/// The summary of a [`TxIn`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct InputSummary {
+    /// The spent output, as `txid:vout`.
+    pub(crate) previous_output: String,
+    pub(crate) sequence: u32,
+    pub(crate) has_issuance: bool,
+    /// The size in bytes of each script witness element.
+    pub(crate) witness_sizes: Vec<usize>,
+}
+
+/// The summary of a [`TxOut`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct OutputSummary {
+    /// The address paid, unless the output is a fee or has a non-standard script.
+    pub(crate) address: Option<String>,
+    pub(crate) script_pubkey: String,
+    /// The asset, unless it is blinded.
+    pub(crate) asset: Option<String>,
+    /// The value in sats, unless it is blinded.
+    pub(crate) value: Option<u64>,
+    pub(crate) is_fee: bool,
+}
+
+impl TransactionSummary {
+    /// Summarize `transaction`, showing addresses for the network.
+    pub(crate) fn new(transaction: &Transaction, is_mainnet: bool) -> Self {
+        let params = match is_mainnet {
+            true => &AddressParams::LIQUID,
+            false => &AddressParams::LIQUID_TESTNET,
+        };
+
+        TransactionSummary {
+            txid: transaction.txid().to_string(),
+            version: transaction.version,
+            lock_time: transaction.lock_time.to_consensus_u32(),
+            inputs: transaction
+                .input
+                .iter()
+                .map(|input| InputSummary {
+                    previous_output: input.previous_output.to_string(),
+                    sequence: input.sequence.to_consensus_u32(),
+                    has_issuance: input.has_issuance(),
+                    witness_sizes: input
+                        .witness
+                        .script_witness
+                        .iter()
+                        .map(|element| element.len())
+                        .collect(),
+                })
+                .collect(),
+            outputs: transaction
+                .output
+                .iter()
+                .map(|output| OutputSummary {
+                    address: Address::from_script(&output.script_pubkey, None, params)
+                        .map(|address| address.to_string()),
+                    script_pubkey: output.script_pubkey.as_bytes().to_lower_hex_string(),
+                    asset: output.asset.explicit().map(|asset| asset.to_string()),
+                    value: output.value.explicit(),
+                    is_fee: output.is_fee(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Check that `address` is an address of the network the channel is on, so
+/// that its payouts can be spent.
+pub(crate) fn check_address_network(address: &Address, is_mainnet: bool) -> Result<(), Error> {
+    let params = match is_mainnet {
+        true => &AddressParams::LIQUID,
+        false => &AddressParams::LIQUID_TESTNET,
+    };
+    if address.params != params {
+        return Err(Error::Channel(format!(
+            "address {} is not on the channel's network",
+            address
+        )));
+    }
+
+    Ok(())
+}
+
+/// Return the policy [`AssetId`] (L-BTC) for the network.
+pub(crate) fn policy_asset(is_mainnet: bool) -> AssetId {
+    match is_mainnet {
+        true => AssetId::LIQUID_BTC,
+        false => AssetId::from_inner(LBTC_TEST_MIDSTATE),
     }
 }
 
@@ -72,7 +957,17 @@ mod tests {
     use elements::secp256k1_zkp::{SECP256K1, SecretKey};
     use tracing::info;
 
-    use crate::{ALICE_MASTER_KEY, BOB_MASTER_KEY};
+    use elements::Txid;
+    use elements::hashes::{Hash, sha256};
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::keys::{ChannelKeys, generate_seed};
+    use crate::script::{
+        build_htlc_script, build_new_commitment_script, derive_address, execute_commitment_spend,
+    };
+    use crate::sign::{
+        derive_settlement_key, sign_fee_input, sign_update_transaction, verify_fee_input,
+    };
 
     use super::*;
 
@@ -80,16 +975,591 @@ mod tests {
     fn setup_address() {
         tracing_subscriber::fmt().init();
 
-        let alice_settlement_sk = SecretKey::from_str(ALICE_MASTER_KEY).unwrap();
-        let alice_settlement_pk = alice_settlement_sk.x_only_public_key(SECP256K1).0;
+        let alice_settlement_pk = ChannelKeys::derive(&generate_seed(), 0, false)
+            .unwrap()
+            .update_pk();
 
-        let bob_settlement_sk = SecretKey::from_str(BOB_MASTER_KEY).unwrap();
-        let bob_settlement_pk = bob_settlement_sk.x_only_public_key(SECP256K1).0;
+        let bob_settlement_pk = ChannelKeys::derive(&generate_seed(), 0, false)
+            .unwrap()
+            .update_pk();
 
-        let setup_address = build_setup_address(alice_settlement_pk, bob_settlement_pk, false);
+        let setup_address =
+            build_setup_address(alice_settlement_pk, bob_settlement_pk, false).unwrap();
         info!(
             "Built address {} from A_s = {} and B_s = {}",
             setup_address, alice_settlement_pk, bob_settlement_pk
         );
+
+        // The same key twice is a 1-of-1 in disguise.
+        assert!(build_setup_address(alice_settlement_pk, alice_settlement_pk, false).is_err());
+    }
+
+    #[test]
+    fn rebind_update() {
+        let alice_update_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let bob_update_sk = SecretKey::from_slice(&[0xee; 32]).unwrap();
+        let alice_update_pk = alice_update_sk.x_only_public_key(SECP256K1).0;
+        let bob_update_pk = bob_update_sk.x_only_public_key(SECP256K1).0;
+
+        let commitment_program = |state: u64| {
+            build_new_commitment_script(
+                alice_update_pk,
+                bob_update_pk,
+                derive_settlement_key(&alice_update_sk, state)
+                    .x_only_public_key(SECP256K1)
+                    .0,
+                derive_settlement_key(&bob_update_sk, state)
+                    .x_only_public_key(SECP256K1)
+                    .0,
+                state,
+            )
+            .unwrap()
+        };
+        let latest_address = derive_address(&commitment_program(7), false);
+
+        // Alice and Bob sign state 7, spending any commitment output of 2071 sats.
+        let update = build_update_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &latest_address,
+            7,
+            &[],
+            false,
+        );
+        let signed_update = sign_update_transaction(
+            &update,
+            Amount::from_sat(2071),
+            &[],
+            &alice_update_sk,
+            &bob_update_sk,
+            false,
+        )
+        .unwrap();
+        assert_eq!(signed_update.state, 7);
+
+        // An update whose lock time carries no state is not signed.
+        let unstated = Transaction {
+            lock_time: LockTime::ZERO,
+            ..update.clone()
+        };
+        assert!(
+            sign_update_transaction(
+                &unstated,
+                Amount::from_sat(2071),
+                &[],
+                &alice_update_sk,
+                &bob_update_sk,
+                false,
+            )
+            .is_err()
+        );
+
+        // Bob publishes the update for state 3 from the setup output of 2140
+        // sats, which pays its own fee.
+        let setup_outpoint = OutPoint {
+            txid: Txid::from_str(
+                "000000000000000000001cd7e92aaf365e841cdd39f19360139b7baef188992f",
+            )
+            .unwrap(),
+            vout: 0,
+        };
+        let stale_update = build_setup_spending_update_transaction(
+            setup_outpoint,
+            Amount::from_sat(2140),
+            &derive_address(&commitment_program(3), false),
+            3,
+            &[],
+            false,
+        );
+        assert_eq!(stale_update.input.len(), 1);
+        assert_eq!(stale_update.output[0].value.explicit(), Some(2071));
+
+        // Alice rebinds state 7 to it, paying the fee from her fee output.
+        let fee_sk = SecretKey::from_slice(&[0xfe; 32]).unwrap();
+        let fee_input = |update: &Transaction, byte: u8| {
+            let outpoint = OutPoint {
+                txid: Txid::from_byte_array([byte; 32]),
+                vout: 1,
+            };
+            sign_fee_input(update, outpoint, &fee_sk)
+        };
+        let stale_program = commitment_program(3);
+        let rebound = rebind_update_transaction(
+            &signed_update,
+            &stale_update,
+            &stale_program,
+            &fee_input(&update, 0x02),
+        )
+        .unwrap();
+
+        let stale_prevout = OutPoint {
+            txid: stale_update.txid(),
+            vout: 0,
+        };
+        assert_eq!(rebound.input[0].previous_output, stale_prevout);
+        assert_eq!(rebound.input[0].witness.script_witness.len(), 4);
+        assert_eq!(rebound.input[1].witness.script_witness.len(), 2);
+        assert_eq!(rebound.output, update.output);
+        assert_eq!(lock_time_state(rebound.lock_time), Some(7));
+        let execute = |signed: &SignedUpdate,
+                       rebound: &Transaction,
+                       program: &CompiledProgram,
+                       amount: u64| {
+            execute_commitment_spend(
+                program,
+                CommitmentPath::Update,
+                &signed.alice_sig,
+                &signed.bob_sig,
+                rebound,
+                0,
+                Amount::from_sat(amount),
+                false,
+            )
+        };
+        assert!(execute(&signed_update, &rebound, &stale_program, 2071).is_ok());
+
+        // Bob instead overrides state 3 with the stale state 5 first, paying the
+        // fee from his own fee output. Its commitment output holds as much as
+        // that of state 3, so state 7 still overrides it.
+        let deeper_update = build_update_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &derive_address(&commitment_program(5), false),
+            5,
+            &[],
+            false,
+        );
+        let signed_deeper = sign_update_transaction(
+            &deeper_update,
+            Amount::from_sat(2071),
+            &[],
+            &alice_update_sk,
+            &bob_update_sk,
+            false,
+        )
+        .unwrap();
+        let deeper_update = rebind_update_transaction(
+            &signed_deeper,
+            &stale_update,
+            &stale_program,
+            &fee_input(&deeper_update, 0x03),
+        )
+        .unwrap();
+        assert!(execute(&signed_deeper, &deeper_update, &stale_program, 2071).is_ok());
+        assert_eq!(deeper_update.output[0].value.explicit(), Some(2071));
+
+        let deeper_program = commitment_program(5);
+        let rebound = rebind_update_transaction(
+            &signed_update,
+            &deeper_update,
+            &deeper_program,
+            &fee_input(&update, 0x04),
+        )
+        .unwrap();
+        assert_eq!(
+            rebound.input[0].previous_output,
+            OutPoint {
+                txid: deeper_update.txid(),
+                vout: 0,
+            }
+        );
+        assert!(execute(&signed_update, &rebound, &deeper_program, 2071).is_ok());
+        assert!(verify_fee_input(&update, &fee_input(&update, 0x04)).is_ok());
+        // The stale state 5 cannot come back on top of state 7.
+        let latest_program = commitment_program(7);
+        assert!(execute(&signed_deeper, &deeper_update, &latest_program, 2071).is_err());
+
+        // A commitment output holding another amount is not rebound to, as the
+        // signatures of state 7 do not hold against it.
+        let mut smaller_update = deeper_update.clone();
+        smaller_update.output[0].value = confidential::Value::Explicit(2002);
+        let mut misbound = rebound.clone();
+        bind_commitment_inputs(
+            &mut misbound,
+            OutPoint {
+                txid: smaller_update.txid(),
+                vout: 0,
+            },
+        );
+        assert!(execute(&signed_update, &misbound, &deeper_program, 2002).is_err());
+        assert!(matches!(
+            rebind_update_transaction(
+                &signed_update,
+                &smaller_update,
+                &deeper_program,
+                &fee_input(&update, 0x05),
+            ),
+            Err(Error::Channel(_))
+        ));
+    }
+
+    #[test]
+    fn payout_address_network() {
+        let testnet = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let mainnet = Address::from_str("Go65t19hP2FuhBMYtgbdMDgdmEzNwh1i48").unwrap();
+
+        assert!(check_address_network(&testnet, false).is_ok());
+        assert!(check_address_network(&testnet, true).is_err());
+        assert!(check_address_network(&mainnet, true).is_ok());
+        assert!(check_address_network(&mainnet, false).is_err());
+    }
+
+    #[test]
+    fn state_lock_time_is_a_past_timestamp() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for state in [0, 1, 42, MAX_STATE] {
+            let lock_time = state_lock_time(state);
+
+            assert!(lock_time.is_block_time());
+            assert!(u64::from(lock_time.to_consensus_u32()) < now);
+            assert_eq!(lock_time_state(lock_time), Some(state));
+        }
+
+        assert_eq!(lock_time_state(LockTime::ZERO), None);
+        assert_eq!(lock_time_state(LockTime::from_consensus(3_000_000)), None);
+        assert_eq!(lock_time_state(LockTime::from_consensus(u32::MAX)), None);
+    }
+
+    #[test]
+    fn settlement_with_htlc_outputs() {
+        let alice_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let bob_sk = SecretKey::from_slice(&[0xee; 32]).unwrap();
+        let alice_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let payment_hash = sha256::Hash::hash(&[0x42; 32]);
+        let htlc_program = build_htlc_script(
+            bob_sk.x_only_public_key(SECP256K1).0,
+            alice_sk.x_only_public_key(SECP256K1).0,
+            payment_hash,
+            3_000_000,
+        )
+        .unwrap();
+        let htlc_address = derive_address(&htlc_program, false);
+
+        // Alice offers Bob 300 sats out of her 1300 sats, and Bob has nothing yet.
+        let settlement = build_settlement_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &alice_address,
+            Amount::from_sat(1000),
+            &alice_address,
+            Amount::ZERO,
+            &[],
+            &[(htlc_address.clone(), Amount::from_sat(300))],
+            false,
+        );
+        assert_eq!(settlement.output.len(), 3);
+        assert_eq!(
+            settlement.output[1].script_pubkey,
+            htlc_address.script_pubkey()
+        );
+        assert_eq!(
+            settlement.output[1].value,
+            confidential::Value::Explicit(300)
+        );
+        assert_eq!(
+            settlement.output[2].value,
+            confidential::Value::Explicit(771)
+        );
+
+        let htlc_prevout = OutPoint {
+            txid: settlement.txid(),
+            vout: 1,
+        };
+        let success = build_htlc_success_transaction(
+            htlc_prevout,
+            Amount::from_sat(300),
+            &alice_address,
+            false,
+        );
+        assert_eq!(success.input[0].previous_output, htlc_prevout);
+        assert_eq!(success.output[0].value, confidential::Value::Explicit(231));
+
+        // The refund cannot confirm before the expiry height.
+        let timeout = build_htlc_timeout_transaction(
+            htlc_prevout,
+            Amount::from_sat(300),
+            &alice_address,
+            3_000_000,
+            false,
+        );
+        assert_eq!(timeout.lock_time, LockTime::from_consensus(3_000_000));
+        assert!(timeout.input[0].sequence.enables_absolute_lock_time());
+    }
+
+    #[test]
+    fn issued_asset_outputs() {
+        let alice_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let commitment_prevout = OutPoint {
+            txid: Txid::from_str(
+                "000000000000000000001cd7e92aaf365e841cdd39f19360139b7baef188992f",
+            )
+            .unwrap(),
+            vout: 0,
+        };
+        let asset = AssetId::from_inner(sha256::Midstate([0x11; 32]));
+        let issued = IssuedAsset {
+            asset,
+            setup_outpoint: OutPoint {
+                vout: 1,
+                ..commitment_prevout
+            },
+            alice_balance: Amount::from_sat(3000),
+            bob_balance: Amount::from_sat(2000),
+        };
+
+        // The update carries the asset over in the output after the L-BTC one,
+        // and only L-BTC pays the fee, from the fee input after the asset one.
+        let update = build_update_transaction(
+            commitment_prevout,
+            Amount::from_sat(2071),
+            &alice_address,
+            7,
+            &[issued],
+            false,
+        );
+        assert_eq!(update.input.len(), 3);
+        assert_eq!(update.input[1].previous_output.vout, 1);
+        assert_eq!(update.output.len(), 3);
+        assert_eq!(update.output[1].asset, Asset::Explicit(asset));
+        assert_eq!(update.output[1].value, confidential::Value::Explicit(5000));
+        assert!(update.output[2].is_fee());
+
+        // Each party gets their share of the asset in the settlement.
+        let settlement = build_settlement_transaction(
+            commitment_prevout,
+            Amount::from_sat(2071),
+            &alice_address,
+            Amount::from_sat(1000),
+            &alice_address,
+            Amount::from_sat(1002),
+            &[issued],
+            &[],
+            false,
+        );
+        assert_eq!(settlement.input.len(), 2);
+        let asset_outputs: Vec<_> = settlement
+            .output
+            .iter()
+            .filter(|output| output.asset == Asset::Explicit(asset))
+            .map(|output| output.value)
+            .collect();
+        assert_eq!(
+            asset_outputs,
+            vec![
+                confidential::Value::Explicit(3000),
+                confidential::Value::Explicit(2000)
+            ]
+        );
+        assert_eq!(
+            settlement.output[4].value,
+            confidential::Value::Explicit(69)
+        );
+
+        // The closing update spends the asset from its own setup output.
+        let closing = build_setup_spending_update_transaction(
+            commitment_prevout,
+            Amount::from_sat(2140),
+            &alice_address,
+            0,
+            &[issued],
+            false,
+        );
+        assert_eq!(closing.input.len(), 2);
+        assert_eq!(closing.input[1].previous_output, issued.setup_outpoint);
+        assert_eq!(closing.output[1].value, confidential::Value::Explicit(5000));
+    }
+
+    #[test]
+    fn dual_funded_setup() {
+        let alice_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let input = |byte| OutPoint {
+            txid: Txid::from_byte_array([byte; 32]),
+            vout: 0,
+        };
+
+        // Alice pays the funding fee, and Bob contributes a whole input.
+        let alice = FundingContribution::new(
+            Amount::from_sat(2140),
+            vec![
+                (input(1), Amount::from_sat(1000)),
+                (input(2), Amount::from_sat(5000)),
+            ],
+            Some(alice_address.clone()),
+            Amount::from_sat(FEE_AMOUNT),
+        )
+        .unwrap();
+        let bob = FundingContribution::new(
+            Amount::from_sat(1000),
+            vec![(input(3), Amount::from_sat(1000))],
+            None,
+            Amount::ZERO,
+        )
+        .unwrap();
+        assert_eq!(
+            alice.change,
+            Some((alice_address.clone(), Amount::from_sat(3791)))
+        );
+        assert_eq!(bob.change, None);
+
+        let funding = build_funding_transaction(&alice_address, &alice, &bob, false).unwrap();
+        assert_eq!(funding.input.len(), 3);
+        assert_eq!(funding.input[2].previous_output, input(3));
+        assert_eq!(funding.output[0].value, confidential::Value::Explicit(3140));
+        assert_eq!(funding.output[1].value, confidential::Value::Explicit(3791));
+        assert!(funding.output[2].is_fee());
+
+        // The state 0 settlement refunds each party's contribution, and Alice
+        // pays the fees of the closing update and the settlement.
+        let closing = build_setup_spending_update_transaction(
+            OutPoint {
+                txid: funding.txid(),
+                vout: 0,
+            },
+            Amount::from_sat(3140),
+            &alice_address,
+            0,
+            &[],
+            false,
+        );
+        assert_eq!(closing.output[0].value, confidential::Value::Explicit(3071));
+        let refund = build_settlement_transaction(
+            OutPoint {
+                txid: closing.txid(),
+                vout: 0,
+            },
+            Amount::from_sat(3071),
+            &alice_address,
+            Amount::from_sat(3071 - FEE_AMOUNT) - bob.amount,
+            &alice_address,
+            bob.amount,
+            &[],
+            &[],
+            false,
+        );
+        assert_eq!(refund.output[0].value, confidential::Value::Explicit(2002));
+        assert_eq!(refund.output[1].value, confidential::Value::Explicit(1000));
+
+        // Inputs must cover the contributions, and be spent only once.
+        assert!(
+            FundingContribution::new(
+                Amount::from_sat(1000),
+                vec![(input(3), Amount::from_sat(1000))],
+                None,
+                Amount::from_sat(FEE_AMOUNT),
+            )
+            .is_err()
+        );
+        let mut double_spend = bob.clone();
+        double_spend.inputs[0].0 = input(1);
+        assert!(build_funding_transaction(&alice_address, &alice, &double_spend, false).is_err());
+    }
+
+    #[test]
+    fn splice_in_and_out() {
+        let alice_address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let setup_outpoint = OutPoint {
+            txid: Txid::from_byte_array([0x01; 32]),
+            vout: 0,
+        };
+        let input = OutPoint {
+            txid: Txid::from_byte_array([0x02; 32]),
+            vout: 1,
+        };
+
+        // Alice adds 1000 sats from a 1200 sats input, and Bob takes 500 sats out.
+        let alice = SpliceContribution {
+            splice_in: FundingContribution::new(
+                Amount::from_sat(1000),
+                vec![(input, Amount::from_sat(1200))],
+                Some(alice_address.clone()),
+                Amount::ZERO,
+            )
+            .unwrap(),
+            splice_out: None,
+        };
+        let bob = SpliceContribution {
+            splice_in: FundingContribution::default(),
+            splice_out: Some((alice_address.clone(), Amount::from_sat(500))),
+        };
+
+        let splice = build_splice_transaction(
+            setup_outpoint,
+            Amount::from_sat(2140),
+            &alice_address,
+            &alice,
+            &bob,
+            false,
+        )
+        .unwrap();
+        assert_eq!(splice.input[0].previous_output, setup_outpoint);
+        assert_eq!(splice.input[1].previous_output, input);
+        assert_eq!(splice.output[0].value, confidential::Value::Explicit(2571));
+        assert_eq!(splice.output[1].value, confidential::Value::Explicit(500));
+        assert_eq!(splice.output[2].value, confidential::Value::Explicit(200));
+        assert!(splice.output[3].is_fee());
+        assert_eq!(splice.output[3].value, confidential::Value::Explicit(69));
+
+        // The channel cannot pay out more than it holds.
+        let bob = SpliceContribution {
+            splice_in: FundingContribution::default(),
+            splice_out: Some((alice_address.clone(), Amount::from_sat(5000))),
+        };
+        assert!(
+            build_splice_transaction(
+                setup_outpoint,
+                Amount::from_sat(2140),
+                &alice_address,
+                &alice,
+                &bob,
+                false,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn export_import_and_summarize() {
+        let address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+        let settlement = build_settlement_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &address,
+            Amount::from_sat(1000),
+            &address,
+            Amount::from_sat(1000),
+            &[],
+            &[],
+            false,
+        );
+
+        let hex = export_transaction(&settlement);
+        assert_eq!(
+            import_transaction(&format!("{}\n", hex)).unwrap(),
+            settlement
+        );
+        // Neither truncated nor trailing data is a transaction.
+        assert!(import_transaction(&hex[..hex.len() - 2]).is_err());
+        assert!(import_transaction(&format!("{}00", hex)).is_err());
+        assert!(import_transaction("not hex").is_err());
+
+        let summary = TransactionSummary::new(&settlement, false);
+        assert_eq!(summary.txid, settlement.txid().to_string());
+        assert_eq!(summary.lock_time, 0);
+        assert_eq!(summary.inputs.len(), 1);
+        assert_eq!(summary.inputs[0].sequence, u32::from(CSV_DELAY));
+        assert!(summary.inputs[0].witness_sizes.is_empty());
+
+        let values: Vec<_> = summary.outputs.iter().map(|output| output.value).collect();
+        assert_eq!(values, [Some(1000), Some(1000), Some(71)]);
+        assert_eq!(summary.outputs[0].address, Some(address.to_string()));
+        assert_eq!(
+            summary.outputs[0].asset,
+            Some(policy_asset(false).to_string())
+        );
+        assert!(summary.outputs[2].is_fee);
+        assert_eq!(summary.outputs[2].address, None);
     }
 }
@@ -0,0 +1,501 @@
+//! Test Vectors
+//!
+//! Deterministic vectors for settlement key derivations, setup and commitment
+//! addresses, CMRs and sighashes, kept in `test_vectors.json` so that a change
+//! in `script.rs`, `sign.rs`, the templates or an upstream crate that moves any
+//! of them fails here instead of on-chain.
+//!
+//! A vector missing a value fails its test. The ignored `record_test_vectors`
+//! test fills in the missing values of the commitment program vectors from the
+//! current code, and never overwrites a recorded one: a value that is meant to
+//! change has to be cleared by hand first.
+//! The ignored `record_fuzz_corpus` test builds the seed corpus of the fuzzing
+//! targets in `fuzz/` from the vectors the same way.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use elements::bitcoin::Amount;
+use elements::bitcoin::hex::{DisplayHex, FromHex};
+use elements::encode::serialize;
+use elements::secp256k1_zkp::{PublicKey, SECP256K1, SecretKey, XOnlyPublicKey};
+use elements::{Address, OutPoint};
+use serde::{Deserialize, Serialize};
+
+use crate::channel::build_commitment_program;
+use crate::message::{
+    FundingCreated, LENGTH_PREFIX_LEN, Message, OpenChannel, SettlementSigned, UpdateProposed,
+    UpdateSigned,
+};
+use crate::noise::Transport;
+use crate::script::{build_setup_address_program, derive_address};
+use crate::sign::{
+    anyprevout_sighash, derive_settlement_key, derive_settlement_pubkey, setup_spending_message,
+    sign_anyprevout_input, sign_setup_spending_input, sign_setup_spending_transaction,
+};
+use crate::transaction::{
+    build_settlement_transaction, build_setup_address, build_setup_spending_update_transaction,
+    build_update_transaction, export_transaction, import_transaction, policy_asset,
+};
+
+/// The path of the vectors file, relative to the crate root.
+const VECTORS_PATH: &str = "src/test_vectors.json";
+
+/// The directory of the fuzzing seed corpus, relative to the crate root.
+const FUZZ_CORPUS_PATH: &str = "fuzz/corpus";
+
+/// Every vector, by kind.
+#[derive(Debug, Serialize, Deserialize)]
+struct TestVectors {
+    settlement_keys: Vec<SettlementKeyVector>,
+    setup_addresses: Vec<SetupAddressVector>,
+    commitment_programs: Vec<CommitmentProgramVector>,
+    update_transactions: Vec<UpdateTransactionVector>,
+    settlement_transactions: Vec<SettlementTransactionVector>,
+}
+
+/// A settlement key derived from a settlement root for a state.
+#[derive(Debug, Serialize, Deserialize)]
+struct SettlementKeyVector {
+    settlement_root: String,
+    state: u64,
+    basepoint: String,
+    settlement_key: String,
+    settlement_pubkey: String,
+}
+
+/// The 2-of-2 `Setup Address` between two update keys.
+#[derive(Debug, Serialize, Deserialize)]
+struct SetupAddressVector {
+    alice_update_pk: String,
+    bob_update_pk: String,
+    witness_script: String,
+    liquid_address: String,
+    testnet_address: String,
+}
+
+/// The commitment program of a channel at a state.
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitmentProgramVector {
+    alice_update_pk: String,
+    bob_update_pk: String,
+    alice_settlement_basepoint: String,
+    bob_settlement_basepoint: String,
+    state: u32,
+    cmr: Option<String>,
+    liquid_address: Option<String>,
+    testnet_address: Option<String>,
+}
+
+/// An `Update Transaction` spending the setup output and the sighash of its
+/// input, and the same update spending a commitment output, with its fee input
+/// bound to `fee_prevout`, and the sighash of its commitment input.
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateTransactionVector {
+    prevout: String,
+    funding_amount: u64,
+    commitment_amount: u64,
+    fee_prevout: String,
+    commitment_address: String,
+    state: u32,
+    is_mainnet: bool,
+    alice_update_pk: String,
+    bob_update_pk: String,
+    setup_spending_transaction: String,
+    setup_sighash: String,
+    transaction: String,
+    anyprevout_sighash: String,
+}
+
+/// A `Settlement Transaction` and the sighash of its input.
+#[derive(Debug, Serialize, Deserialize)]
+struct SettlementTransactionVector {
+    prevout: String,
+    input_amount: u64,
+    alice_address: String,
+    alice_balance: u64,
+    bob_address: String,
+    bob_balance: u64,
+    is_mainnet: bool,
+    transaction: String,
+    anyprevout_sighash: String,
+}
+
+fn load_vectors() -> TestVectors {
+    serde_json::from_str(include_str!("test_vectors.json")).unwrap()
+}
+
+fn x_only(hex: &str) -> XOnlyPublicKey {
+    XOnlyPublicKey::from_str(hex).unwrap()
+}
+
+/// The CMR and both addresses of the commitment program of a vector.
+fn commitment_program(vector: &CommitmentProgramVector) -> (String, String, String) {
+    let program = build_commitment_program(
+        x_only(&vector.alice_update_pk),
+        x_only(&vector.bob_update_pk),
+        &PublicKey::from_str(&vector.alice_settlement_basepoint).unwrap(),
+        &PublicKey::from_str(&vector.bob_settlement_basepoint).unwrap(),
+        vector.state,
+    )
+    .unwrap();
+
+    (
+        program.commit().cmr().to_string(),
+        derive_address(&program, true).to_string(),
+        derive_address(&program, false).to_string(),
+    )
+}
+
+#[test]
+fn settlement_key_vectors() {
+    for vector in load_vectors().settlement_keys {
+        let root = SecretKey::from_str(&vector.settlement_root).unwrap();
+        let basepoint = root.public_key(SECP256K1);
+        assert_eq!(basepoint.to_string(), vector.basepoint);
+
+        let secret = derive_settlement_key(&root, vector.state);
+        let public = derive_settlement_pubkey(&basepoint, vector.state);
+        assert_eq!(secret.display_secret().to_string(), vector.settlement_key);
+        assert_eq!(public.to_string(), vector.settlement_pubkey);
+    }
+}
+
+#[test]
+fn setup_address_vectors() {
+    for vector in load_vectors().setup_addresses {
+        let alice_pk = x_only(&vector.alice_update_pk);
+        let bob_pk = x_only(&vector.bob_update_pk);
+
+        let witness_script = build_setup_address_program(&alice_pk, &bob_pk);
+        assert_eq!(
+            witness_script.as_bytes().to_lower_hex_string(),
+            vector.witness_script
+        );
+        assert_eq!(
+            build_setup_address(alice_pk, bob_pk, true)
+                .unwrap()
+                .to_string(),
+            vector.liquid_address
+        );
+        assert_eq!(
+            build_setup_address(alice_pk, bob_pk, false)
+                .unwrap()
+                .to_string(),
+            vector.testnet_address
+        );
+    }
+}
+
+#[test]
+fn commitment_program_vectors() {
+    for vector in load_vectors().commitment_programs {
+        let (cmr, liquid_address, testnet_address) = commitment_program(&vector);
+        let recorded = |value: &Option<String>, name: &str| {
+            value.clone().unwrap_or_else(|| {
+                panic!(
+                    "commitment vector for state {} has no recorded {}",
+                    vector.state, name
+                )
+            })
+        };
+
+        assert_eq!(cmr, recorded(&vector.cmr, "CMR"));
+        assert_eq!(
+            liquid_address,
+            recorded(&vector.liquid_address, "Liquid address")
+        );
+        assert_eq!(
+            testnet_address,
+            recorded(&vector.testnet_address, "testnet address")
+        );
+    }
+}
+
+#[test]
+fn update_transaction_vectors() {
+    for vector in load_vectors().update_transactions {
+        let prevout = OutPoint::from_str(&vector.prevout).unwrap();
+        let commitment_address = Address::from_str(&vector.commitment_address).unwrap();
+        let setup_spending = build_setup_spending_update_transaction(
+            prevout,
+            Amount::from_sat(vector.funding_amount),
+            &commitment_address,
+            vector.state,
+            &[],
+            vector.is_mainnet,
+        );
+        assert_eq!(
+            export_transaction(&setup_spending),
+            vector.setup_spending_transaction
+        );
+
+        let setup_sighash = setup_spending_message(
+            &setup_spending,
+            0,
+            Amount::from_sat(vector.funding_amount),
+            &x_only(&vector.alice_update_pk),
+            &x_only(&vector.bob_update_pk),
+        );
+        assert_eq!(setup_sighash.to_string(), vector.setup_sighash);
+
+        let mut transaction = build_update_transaction(
+            prevout,
+            Amount::from_sat(vector.commitment_amount),
+            &commitment_address,
+            vector.state,
+            &[],
+            vector.is_mainnet,
+        );
+        transaction.input[1].previous_output = OutPoint::from_str(&vector.fee_prevout).unwrap();
+        assert_eq!(export_transaction(&transaction), vector.transaction);
+        assert_eq!(
+            import_transaction(&vector.transaction).unwrap(),
+            transaction
+        );
+
+        let anyprevout_sighash = anyprevout_sighash(
+            &transaction,
+            0,
+            policy_asset(vector.is_mainnet),
+            Amount::from_sat(vector.commitment_amount),
+            vector.is_mainnet,
+        );
+        assert_eq!(anyprevout_sighash.to_string(), vector.anyprevout_sighash);
+    }
+}
+
+#[test]
+fn settlement_transaction_vectors() {
+    for vector in load_vectors().settlement_transactions {
+        let transaction = build_settlement_transaction(
+            OutPoint::from_str(&vector.prevout).unwrap(),
+            Amount::from_sat(vector.input_amount),
+            &Address::from_str(&vector.alice_address).unwrap(),
+            Amount::from_sat(vector.alice_balance),
+            &Address::from_str(&vector.bob_address).unwrap(),
+            Amount::from_sat(vector.bob_balance),
+            &[],
+            &[],
+            vector.is_mainnet,
+        );
+        assert_eq!(export_transaction(&transaction), vector.transaction);
+
+        let anyprevout_sighash = anyprevout_sighash(
+            &transaction,
+            0,
+            policy_asset(vector.is_mainnet),
+            Amount::from_sat(vector.input_amount),
+            vector.is_mainnet,
+        );
+        assert_eq!(anyprevout_sighash.to_string(), vector.anyprevout_sighash);
+    }
+}
+
+/// Record the missing values of the commitment program vectors from the
+/// current code.
+///
+/// Recorded values and every other vector are left as is, so that a regression
+/// is never recorded by accident.
+#[test]
+#[ignore]
+fn record_test_vectors() {
+    let mut vectors = load_vectors();
+    for vector in &mut vectors.commitment_programs {
+        let (cmr, liquid_address, testnet_address) = commitment_program(vector);
+        vector.cmr.get_or_insert(cmr);
+        vector.liquid_address.get_or_insert(liquid_address);
+        vector.testnet_address.get_or_insert(testnet_address);
+    }
+
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(VECTORS_PATH);
+    let json = serde_json::to_string_pretty(&vectors).unwrap();
+    std::fs::write(path, json + "\n").unwrap();
+}
+
+/// Record the seed corpus of the fuzzing targets: every transaction of the
+/// vectors, the signed closing `Update Transaction` of state 0 that refunds the
+/// first update vector's setup output, the signatures in their witnesses, and
+/// the peer messages carrying them, in the clear and as encrypted frames.
+#[test]
+#[ignore]
+fn record_fuzz_corpus() {
+    let vectors = load_vectors();
+    let mut seeds: Vec<(&str, String, Vec<u8>)> = Vec::new();
+    for (index, vector) in vectors.update_transactions.iter().enumerate() {
+        let transaction = Vec::<u8>::from_hex(&vector.transaction).unwrap();
+        seeds.push((
+            "transaction_verification",
+            format!("update-{}", index),
+            transaction,
+        ));
+    }
+    for (index, vector) in vectors.settlement_transactions.iter().enumerate() {
+        let transaction = Vec::<u8>::from_hex(&vector.transaction).unwrap();
+        seeds.push((
+            "transaction_verification",
+            format!("settlement-{}", index),
+            transaction,
+        ));
+    }
+
+    // The update keys of the first setup address vector.
+    let alice_update_sk = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+    let bob_update_sk = SecretKey::from_slice(&[0xee; 32]).unwrap();
+    let alice_update_pk = x_only(&vectors.setup_addresses[0].alice_update_pk);
+    let bob_update_pk = x_only(&vectors.setup_addresses[0].bob_update_pk);
+    assert_eq!(
+        alice_update_sk.x_only_public_key(SECP256K1).0,
+        alice_update_pk
+    );
+
+    let setup_outpoint = OutPoint::from_str(&vectors.update_transactions[0].prevout).unwrap();
+    let funding_amount = Amount::from_sat(100_000);
+    let payout_address = Address::from_str(&vectors.setup_addresses[1].testnet_address).unwrap();
+    let update = &vectors.update_transactions[0];
+    let commitment_address = Address::from_str(&update.commitment_address).unwrap();
+    let refund = build_setup_spending_update_transaction(
+        setup_outpoint,
+        funding_amount,
+        &commitment_address,
+        0,
+        &[],
+        false,
+    );
+    let signed_refund = sign_setup_spending_transaction(
+        &refund,
+        funding_amount,
+        &[],
+        &alice_update_sk,
+        &bob_update_sk,
+    );
+    seeds.push((
+        "transaction_verification",
+        "closing-update".into(),
+        serialize(&signed_refund),
+    ));
+    for (index, element) in signed_refund.input[0]
+        .witness
+        .script_witness
+        .iter()
+        .enumerate()
+    {
+        seeds.push((
+            "witness_signatures",
+            format!("closing-update-{}", index),
+            element.clone(),
+        ));
+    }
+
+    // The closing signature of the update vector's own state.
+    let closing_update = build_setup_spending_update_transaction(
+        setup_outpoint,
+        funding_amount,
+        &commitment_address,
+        update.state,
+        &[],
+        false,
+    );
+    let closing_sig = sign_setup_spending_input(
+        &closing_update,
+        0,
+        funding_amount,
+        &alice_update_pk,
+        &bob_update_pk,
+        &alice_update_sk,
+    );
+
+    let update_sig = sign_anyprevout_input(
+        &import_transaction(&update.transaction).unwrap(),
+        Amount::from_sat(update.commitment_amount),
+        &alice_update_sk,
+        update.is_mainnet,
+    );
+    let settlement = &vectors.settlement_transactions[0];
+    let settlement_sig = sign_anyprevout_input(
+        &import_transaction(&settlement.transaction).unwrap(),
+        Amount::from_sat(settlement.input_amount),
+        &alice_update_sk,
+        settlement.is_mainnet,
+    );
+    seeds.push((
+        "witness_signatures",
+        "update".into(),
+        update_sig.serialize().to_vec(),
+    ));
+    seeds.push((
+        "witness_signatures",
+        "settlement".into(),
+        settlement_sig.serialize().to_vec(),
+    ));
+
+    let messages = [
+        (
+            "open-channel",
+            Message::OpenChannel(OpenChannel {
+                is_mainnet: false,
+                funding_amount,
+                update_pk: alice_update_pk,
+                settlement_basepoint: PublicKey::from_str(
+                    &vectors.commitment_programs[0].alice_settlement_basepoint,
+                )
+                .unwrap(),
+                payout_address,
+            }),
+        ),
+        (
+            "funding-created",
+            Message::FundingCreated(FundingCreated { setup_outpoint }),
+        ),
+        (
+            "update-proposed",
+            Message::UpdateProposed(UpdateProposed {
+                channel: setup_outpoint,
+                state: update.state,
+                alice_balance: Amount::from_sat(settlement.alice_balance),
+                bob_balance: Amount::from_sat(settlement.bob_balance),
+            }),
+        ),
+        (
+            "update-signed",
+            Message::UpdateSigned(UpdateSigned {
+                channel: setup_outpoint,
+                state: update.state,
+                signature: update_sig,
+                closing_signature: closing_sig,
+            }),
+        ),
+        (
+            "settlement-signed",
+            Message::SettlementSigned(SettlementSigned {
+                channel: setup_outpoint,
+                state: update.state,
+                signature: settlement_sig,
+            }),
+        ),
+    ];
+    // The frames are encrypted with the keys of the peer in the fuzzing
+    // target, each on a fresh transport.
+    let remote_node_id = SecretKey::from_slice(&[0x0b; 32])
+        .unwrap()
+        .public_key(SECP256K1);
+    for (name, message) in messages {
+        let frame = message.encode();
+        let body = frame[LENGTH_PREFIX_LEN..].to_vec();
+        let mut peer = Transport::with_keys([0x01; 32], [0x02; 32], remote_node_id);
+        seeds.push((
+            "transport_frame",
+            name.into(),
+            peer.encrypt_frame(body.len() as u32, &body),
+        ));
+        seeds.push(("peer_message", name.into(), body));
+    }
+
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join(FUZZ_CORPUS_PATH);
+    for (target, name, seed) in seeds {
+        let dir = corpus.join(target);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), seed).unwrap();
+    }
+}
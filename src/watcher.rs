@@ -0,0 +1,170 @@
+//! Stale Update Watcher
+//!
+//! Publishing an old channel state is harmless in ELTOO: any later update can
+//! spend the commitment output of an earlier one. This module follows the chain
+//! of updates hanging off the setup output and, when the counterparty publishes
+//! a stale state, rebinds our latest signed update onto it and broadcasts it.
+
+use elements::{OutPoint, Transaction, Txid};
+use simplicityhl::CompiledProgram;
+use tracing::info;
+
+use crate::error::Error;
+use crate::esplora::{OutputSpend, broadcast_transaction, get_output_spend, get_transaction};
+use crate::sign::{FeeInput, SignedUpdate};
+use crate::transaction::{lock_time_state, rebind_update_transaction};
+
+/// The outcome of checking a channel for stale updates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WatchOutcome {
+    /// The setup output is still unspent.
+    Unspent,
+    /// The latest published update is at least as recent as ours.
+    UpToDate { state: u32 },
+    /// A stale update was published but is not confirmed yet.
+    Pending { stale_state: u32 },
+    /// A stale update was overridden by broadcasting our latest update.
+    Overridden { stale_state: u32, txid: Txid },
+    /// The channel was already settled.
+    Settled,
+}
+
+/// The last transaction in the chain of spends from the setup output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ChannelTip {
+    /// The setup output is still unspent.
+    Unspent,
+    /// The last published `Update Transaction`.
+    Update {
+        transaction: Transaction,
+        state: u32,
+        confirmed: bool,
+    },
+    /// The channel was settled, or its setup output withdrawn.
+    Settled,
+}
+
+/// Follow the chain of spends from `setup_outpoint` to its tip.
+pub(crate) async fn find_channel_tip(
+    url: &str,
+    setup_outpoint: OutPoint,
+) -> Result<ChannelTip, Error> {
+    let mut outpoint = setup_outpoint;
+    let mut tip = ChannelTip::Unspent;
+    while let OutputSpend::Spent { txid, confirmed } = get_output_spend(url, &outpoint).await? {
+        let transaction = get_transaction(url, &txid).await?;
+
+        let Some(state) = published_state(&transaction) else {
+            return Ok(ChannelTip::Settled);
+        };
+        tip = ChannelTip::Update {
+            transaction,
+            state,
+            confirmed,
+        };
+        outpoint = OutPoint { txid, vout: 0 };
+    }
+
+    Ok(tip)
+}
+
+/// Check the channel funded at `setup_outpoint` for stale updates.
+///
+/// If the last update published on-chain is confirmed and older than
+/// `latest_update`, the latter is rebound to its commitment output and broadcast,
+/// with its fee paid by `fee_input`. The `commitment_program` closure rebuilds
+/// the commitment program for a given state, which is needed to reveal it in
+/// the rebound witness.
+pub(crate) async fn override_stale_update<F>(
+    url: &str,
+    setup_outpoint: OutPoint,
+    latest_update: &SignedUpdate,
+    fee_input: &FeeInput,
+    commitment_program: F,
+) -> Result<WatchOutcome, Error>
+where
+    F: Fn(u32) -> Result<CompiledProgram, Error>,
+{
+    let (stale, stale_state, confirmed) = match find_channel_tip(url, setup_outpoint).await? {
+        ChannelTip::Unspent => return Ok(WatchOutcome::Unspent),
+        ChannelTip::Settled => return Ok(WatchOutcome::Settled),
+        ChannelTip::Update {
+            transaction,
+            state,
+            confirmed,
+        } => (transaction, state, confirmed),
+    };
+
+    if stale_state >= latest_update.state {
+        return Ok(WatchOutcome::UpToDate { state: stale_state });
+    }
+    if !confirmed {
+        return Ok(WatchOutcome::Pending { stale_state });
+    }
+
+    info!(
+        "Found stale update for state {} in {}, overriding it with state {}",
+        stale_state,
+        stale.txid(),
+        latest_update.state
+    );
+    let stale_program = commitment_program(stale_state)?;
+    let rebound = rebind_update_transaction(latest_update, &stale, &stale_program, fee_input)?;
+    let txid = broadcast_transaction(url, &rebound).await?;
+    info!(
+        "Broadcast update for state {}: {}",
+        latest_update.state, txid
+    );
+
+    Ok(WatchOutcome::Overridden { stale_state, txid })
+}
+
+/// Decode the state number of an `Update Transaction` from its `lock_time`.
+///
+/// Transactions without a state encoded as a past timestamp, like the
+/// `Cooperative Close Transaction` and `Settlement Transaction`s, return
+/// [`None`].
+pub(crate) fn published_state(transaction: &Transaction) -> Option<u32> {
+    lock_time_state(transaction.lock_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::bitcoin::Amount;
+    use elements::{Address, OutPoint};
+
+    use crate::ALICE_RESOLUTION_ADDRESS;
+    use crate::transaction::{build_settlement_transaction, build_update_transaction};
+
+    use super::*;
+
+    #[test]
+    fn test_published_state() {
+        let address = Address::from_str(ALICE_RESOLUTION_ADDRESS).unwrap();
+
+        let update = build_update_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2140),
+            &address,
+            42,
+            &[],
+            false,
+        );
+        assert_eq!(published_state(&update), Some(42));
+
+        let settlement = build_settlement_transaction(
+            OutPoint::default(),
+            Amount::from_sat(2071),
+            &address,
+            Amount::from_sat(1000),
+            &address,
+            Amount::from_sat(1002),
+            &[],
+            &[],
+            false,
+        );
+        assert_eq!(published_state(&settlement), None);
+    }
+}